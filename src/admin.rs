@@ -0,0 +1,97 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use async_graphql_axum::GraphQL;
+use axum::extract::State;
+use axum::http::{header, Request, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::Response;
+use axum::routing::get;
+use axum::Router;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::{Stream, StreamExt};
+
+use crate::forward::Forwarder;
+use crate::{auth, graphql, jmap};
+
+/// Where and how to serve the admin API
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub addr: SocketAddr,
+    /// Bearer token clients have to present
+    pub token: String,
+}
+
+/// The admin API, with the GraphQL endpoint at `/graphql`,
+/// the server-sent events stream of accepted mail at `/stream`
+/// and a JMAP server, its session resource at `/.well-known/jmap`
+pub fn router(token: String, forwarder: Arc<Forwarder>) -> Router {
+    Router::new()
+        .route_service("/graphql", GraphQL::new(graphql::schema(forwarder.clone())))
+        .route("/stream", get(stream))
+        .merge(jmap::router())
+        .with_state(forwarder)
+        .layer(middleware::from_fn_with_state(Arc::new(token), authorize))
+}
+
+/// Serves the admin API until the listener fails
+pub async fn serve(config: Config, forwarder: Arc<Forwarder>) -> Result<()> {
+    tracing::info!("Admin API listening on: {}", config.addr);
+    axum::Server::try_bind(&config.addr)
+        .with_context(|| format!("cannot listen on {}", config.addr))?
+        .serve(router(config.token, forwarder).into_make_service())
+        .await?;
+    Ok(())
+}
+
+/// Streams every accepted mail as an `accepted` event holding its payload as JSON.
+/// Subscribers too slow to keep up are told how many mails they missed with a `lagged` event.
+async fn stream(
+    State(forwarder): State<Arc<Forwarder>>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let events = BroadcastStream::new(forwarder.subscribe()).map(|accepted| {
+        Ok(match accepted {
+            Ok(accepted) => Event::default()
+                .event("accepted")
+                .id(accepted.id.as_str())
+                .json_data(&*accepted)
+                .unwrap_or_else(|err| Event::default().event("error").data(err.to_string())),
+            Err(BroadcastStreamRecvError::Lagged(missed)) => {
+                Event::default().event("lagged").data(missed.to_string())
+            }
+        })
+    });
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+/// Checks the bearer token, from the `Authorization` header or, for clients
+/// like `EventSource` that cannot set headers, the `access_token` query parameter.
+/// A token in the query string is written to proxy and access logs, so clients
+/// should send the header whenever they can.
+async fn authorize<B>(
+    State(token): State<Arc<String>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Result<Response, StatusCode> {
+    let from_query = request.uri().query().and_then(|query| {
+        url::form_urlencoded::parse(query.as_bytes())
+            .find(|(name, _)| name == "access_token")
+            .map(|(_, value)| value.into_owned())
+    });
+    let authorized = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::to_string)
+        .or(from_query)
+        .is_some_and(|presented| auth::secrets_match(&token, &presented));
+    if !authorized {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    Ok(next.run(request).await)
+}
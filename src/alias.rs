@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+use crate::tenant::wildcard_match;
+
+/// Where the mail of local addresses is forwarded to, e.g.
+/// `hello@mydomain.com` to `me@gmail.com`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Aliases {
+    /// Destinations of whole addresses, by lowercase address
+    exact: HashMap<String, Vec<String>>,
+    /// Lowercase patterns with `*` wildcards, in the order they were listed
+    patterns: Vec<(String, Vec<String>)>,
+}
+
+impl Aliases {
+    /// Reads the aliases from a file, see [`Aliases::parse`] for its format
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = std::fs::read_to_string(path)
+            .with_context(|| format!("cannot read aliases {}", path.display()))?;
+        Self::parse(&file).with_context(|| format!("invalid aliases {}", path.display()))
+    }
+
+    /// Parses one alias per line, the local address then its destinations,
+    /// comma-separated, e.g. `hello@mydomain.com me@gmail.com, you@gmail.com`.
+    /// The address may have `*` wildcards, `@mydomain.com` standing for the
+    /// whole domain. Blank lines and lines starting with `#` are skipped.
+    pub fn parse(file: &str) -> Result<Self> {
+        let mut aliases = Self::default();
+        for (number, line) in file.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (address, destinations) = line
+                .split_once(char::is_whitespace)
+                .with_context(|| format!("line {}: {line} has no destination", number + 1))?;
+            let destinations = destinations
+                .split(',')
+                .map(str::trim)
+                .filter(|destination| !destination.is_empty())
+                .map(String::from)
+                .collect::<Vec<_>>();
+            anyhow::ensure!(
+                !destinations.is_empty()
+                    && destinations
+                        .iter()
+                        .all(|destination| destination.contains('@')),
+                "line {}: destinations must be addresses",
+                number + 1
+            );
+            let address = address.to_lowercase();
+            if address.contains('*') || address.starts_with('@') {
+                let pattern = match address.starts_with('@') {
+                    true => format!("*{address}"),
+                    false => address,
+                };
+                aliases.patterns.push((pattern, destinations));
+            } else {
+                anyhow::ensure!(
+                    !aliases.exact.contains_key(&address),
+                    "line {}: duplicate alias {address}",
+                    number + 1
+                );
+                aliases.exact.insert(address, destinations);
+            }
+        }
+        Ok(aliases)
+    }
+
+    /// Destinations of a recipient: those of its address, or else of the first
+    /// pattern it matches
+    pub fn resolve(&self, recipient: &str) -> Option<&[String]> {
+        let recipient = recipient
+            .trim_matches(|c| c == '<' || c == '>')
+            .to_lowercase();
+        self.exact
+            .get(&recipient)
+            .or_else(|| {
+                self.patterns
+                    .iter()
+                    .find(|(pattern, _)| wildcard_match(pattern, &recipient))
+                    .map(|(_, destinations)| destinations)
+            })
+            .map(Vec::as_slice)
+    }
+
+    /// The destinations of the recipients, each listed once. Recipients without
+    /// an alias are left out, as they have nowhere to be forwarded to.
+    pub fn expand(&self, recipients: &[String]) -> Vec<String> {
+        let mut expanded: Vec<String> = Vec::new();
+        for recipient in recipients {
+            let Some(destinations) = self.resolve(recipient) else {
+                tracing::debug!("No alias for {recipient}");
+                continue;
+            };
+            for destination in destinations {
+                if !expanded
+                    .iter()
+                    .any(|other| other.eq_ignore_ascii_case(destination))
+                {
+                    expanded.push(destination.clone());
+                }
+            }
+        }
+        expanded
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_aliases() {
+        let aliases = Aliases::parse(
+            "# Forwarded to Gmail
+             hello@MyDomain.com me@gmail.com
+             sales-*@mydomain.com me@gmail.com, sales@example.org
+
+             @mydomain.com catchall@example.org",
+        )
+        .unwrap();
+        assert_eq!(
+            aliases.resolve("<Hello@mydomain.com>").unwrap(),
+            ["me@gmail.com"]
+        );
+        assert_eq!(
+            aliases.resolve("sales-eu@mydomain.com").unwrap(),
+            ["me@gmail.com", "sales@example.org"]
+        );
+        assert_eq!(
+            aliases.resolve("anyone@mydomain.com").unwrap(),
+            ["catchall@example.org"]
+        );
+        assert_eq!(aliases.resolve("hello@other.com"), None);
+        assert_eq!(
+            aliases.expand(
+                &[
+                    "<hello@mydomain.com>",
+                    "<sales-us@mydomain.com>",
+                    "<a@other.com>"
+                ]
+                .map(String::from)
+            ),
+            ["me@gmail.com", "sales@example.org"]
+        );
+
+        assert!(Aliases::parse("hello@mydomain.com").is_err());
+        assert!(Aliases::parse("hello@mydomain.com me").is_err());
+        assert!(
+            Aliases::parse("a@mydomain.com b@example.org\nA@mydomain.com c@example.org").is_err()
+        );
+    }
+}
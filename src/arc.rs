@@ -0,0 +1,173 @@
+use chrono::{DateTime, Utc};
+
+use crate::authentication;
+use crate::dkim::{self, Signer};
+use crate::header;
+use crate::protocol;
+
+/// Most ARC sets a message may carry (RFC 8617 §4.2.1)
+const MAX_INSTANCE: u32 = 50;
+
+/// ARC sealer: before a message is relayed, the results of the authentication
+/// checks it passed on receipt are recorded in a new ARC set, signed with our
+/// key, so the final recipient can still trust them when forwarding broke SPF
+/// and maybe DKIM
+pub struct Sealer {
+    signer: Signer,
+    /// authserv-id of the `Authentication-Results` fields we add on receipt
+    pub authserv_id: String,
+}
+
+impl Sealer {
+    pub fn new(signer: Signer, authserv_id: impl Into<String>) -> Self {
+        Self {
+            signer,
+            authserv_id: authserv_id.into(),
+        }
+    }
+
+    /// The message with a new ARC set on top, sealed at `now`. The chain is said
+    /// to pass only when our `Authentication-Results` say `arc=pass`, so a chain
+    /// nobody validated is sealed as failed. Messages already carrying the most
+    /// sets allowed are returned as they are.
+    pub fn seal(&self, data: &[u8], now: DateTime<Utc>) -> Vec<u8> {
+        let message = dkim::to_crlf(protocol::strip_terminator(data));
+        let (fields, _) = header::split(&message);
+        let sets = sets(&fields);
+        let instance = sets.len() as u32 + 1;
+        if instance > MAX_INSTANCE {
+            tracing::warn!("Not sealing a message with {} ARC sets", sets.len());
+            return message;
+        }
+        let results = self.results(&fields);
+        let chain = match instance {
+            1 => "none",
+            _ if dkim::tag(&results, "arc").is_some_and(|arc| arc.starts_with("pass")) => "pass",
+            _ => "fail",
+        };
+
+        let authentication_results = format!(
+            "ARC-Authentication-Results: i={instance}; {}; {results}\r\n",
+            self.authserv_id
+        );
+        let message_signature = self.signer.signature(
+            "ARC-Message-Signature",
+            &format!("i={instance}"),
+            &message,
+            now,
+        );
+        // The seal covers every set in order, its own last with an empty b=
+        let mut hashed = Vec::new();
+        for set in &sets {
+            for field in set {
+                hashed.extend(dkim::relaxed_header(field));
+            }
+        }
+        hashed.extend(dkim::relaxed_header(authentication_results.as_bytes()));
+        hashed.extend(dkim::relaxed_header(message_signature.as_bytes()));
+        let seal = self.signer.complete(
+            hashed,
+            format!(
+                "ARC-Seal: i={instance}; a=rsa-sha256; t={}; cv={chain};\r\n\
+                 \td={}; s={};\r\n\tb=",
+                now.timestamp(),
+                self.signer.domain,
+                self.signer.selector,
+            ),
+        );
+
+        let mut sealed = seal.into_bytes();
+        sealed.extend(message_signature.into_bytes());
+        sealed.extend(authentication_results.into_bytes());
+        sealed.extend(message);
+        sealed
+    }
+
+    /// Results of our topmost `Authentication-Results` field, or `none` when there is none
+    fn results(&self, fields: &[&[u8]]) -> String {
+        authentication::results(fields, &self.authserv_id)
+            .filter(|results| !results.is_empty())
+            .unwrap_or_else(|| "none".into())
+    }
+}
+
+/// The ARC sets of a message by instance, each as its
+/// `ARC-Authentication-Results`, `ARC-Message-Signature` and `ARC-Seal` fields.
+/// Only complete sets numbered from 1 are returned.
+fn sets<'a>(fields: &[&'a [u8]]) -> Vec<[&'a [u8]; 3]> {
+    let mut sets = Vec::new();
+    for instance in 1..=MAX_INSTANCE + 1 {
+        let find = |name: &str| {
+            fields.iter().copied().find(|field| {
+                header::field_name(field) == name
+                    && dkim::tag(&header::field_value(field), "i")
+                        .and_then(|i| i.parse::<u32>().ok())
+                        == Some(instance)
+            })
+        };
+        let (Some(results), Some(signature), Some(seal)) = (
+            find("arc-authentication-results"),
+            find("arc-message-signature"),
+            find("arc-seal"),
+        ) else {
+            break;
+        };
+        sets.push([results, signature, seal]);
+    }
+    sets
+}
+
+#[cfg(test)]
+mod tests {
+    use rsa::pkcs1v15::VerifyingKey;
+    use rsa::signature::Verifier;
+    use rsa::RsaPrivateKey;
+    use sha2::Sha256;
+
+    use super::*;
+
+    #[test]
+    fn test_seal() {
+        let key = RsaPrivateKey::new(&mut rand::thread_rng(), 1024).unwrap();
+        let verifying_key = VerifyingKey::<Sha256>::new(key.to_public_key());
+        let sealer = Sealer::new(Signer::new("fwd.example", "arc", key), "mx.fwd.example");
+        let message =
+            b"Authentication-Results: mx.fwd.example;\r\n\tspf=pass smtp.mailfrom=example.com\r\n\
+                        From: a@example.com\r\nSubject: hi\r\n\r\nhello\r\n";
+        let sealed = sealer.seal(message, Utc::now());
+        let (fields, _) = header::split(&sealed);
+        let chain = sets(&fields);
+        assert_eq!(chain.len(), 1);
+        assert_eq!(
+            header::field_value(chain[0][0]),
+            "i=1; mx.fwd.example; spf=pass smtp.mailfrom=example.com"
+        );
+        let seal = header::field_value(chain[0][2]);
+        assert_eq!(dkim::tag(&seal, "cv"), Some("none"));
+
+        // The seal signs the set, itself last with an empty b=
+        let field = String::from_utf8(chain[0][2].to_vec()).unwrap();
+        let (unsigned, b) = field.split_once("\tb=").unwrap();
+        let mut hashed = dkim::relaxed_header(chain[0][0]);
+        hashed.extend(dkim::relaxed_header(chain[0][1]));
+        let mut canonical = dkim::relaxed_header(format!("{unsigned}\tb=").as_bytes());
+        canonical.truncate(canonical.len() - 2);
+        hashed.extend(canonical);
+        let b =
+            base64::Engine::decode(&base64::engine::general_purpose::STANDARD, b.trim()).unwrap();
+        verifying_key
+            .verify(&hashed, &b.as_slice().try_into().unwrap())
+            .unwrap();
+
+        // Sealed again by a forwarder that didn't validate the chain
+        let resealed = sealer.seal(&sealed, Utc::now());
+        let (fields, _) = header::split(&resealed);
+        let chain = sets(&fields);
+        assert_eq!(chain.len(), 2);
+        assert_eq!(dkim::tag(&header::field_value(chain[1][0]), "i"), Some("2"));
+        assert_eq!(
+            dkim::tag(&header::field_value(chain[1][2]), "cv"),
+            Some("fail")
+        );
+    }
+}
@@ -0,0 +1,96 @@
+use chrono::Utc;
+
+use crate::format;
+use crate::protocol::Mail;
+use crate::queue::QueueId;
+use crate::schema::Message;
+use crate::sink::{recipient_domain, DeliveryResult, MailSink};
+use crate::storage::{key_part, ObjectStore};
+
+/// S3-compatible bucket the raw messages are archived to, e.g. to retain them
+/// for compliance, whatever becomes of their forwarding
+pub struct Archive {
+    client: reqwest::Client,
+    store: ObjectStore,
+}
+
+impl Archive {
+    pub fn new(store: ObjectStore) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            store,
+        }
+    }
+}
+
+impl MailSink for Archive {
+    fn endpoint(&self) -> String {
+        format!("archive://{}", self.store.bucket)
+    }
+
+    /// Uploads the message as received, under `{domain}/{date}/{queue id}-{message id}.eml`
+    async fn deliver(&self, id: &QueueId, mail: &Mail, _: &Message) -> DeliveryResult {
+        let key = key(id, mail, &Utc::now().format("%Y-%m-%d").to_string());
+        let url = self.store.upload(&self.client, &key, &mail.data).await?;
+        tracing::debug!("Archived mail {id} to {url}");
+        Ok(())
+    }
+}
+
+/// Key of a message archived on `date`: the recipient domain, the date, and the
+/// queue id, followed by the Message-ID when there is one. The sender picks the
+/// Message-ID, so it only names the object: another mail sent with the same one
+/// gets an object of its own rather than overwriting this one.
+fn key(id: &QueueId, mail: &Mail, date: &str) -> String {
+    let message_id = format::header_fields(format::headers(mail))
+        .into_iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("Message-ID"))
+        .map(|(_, value)| {
+            value
+                .trim()
+                .trim_matches(|c| c == '<' || c == '>')
+                .to_string()
+        })
+        .filter(|message_id| !message_id.is_empty());
+    let domain = match key_part(&recipient_domain(mail)) {
+        // Not a segment `Url` would take as the parent directory
+        domain if domain.chars().all(|c| c == '.') => "_".to_string(),
+        domain => domain,
+    };
+    match message_id {
+        Some(message_id) => format!("{domain}/{date}/{id}-{}.eml", key_part(&message_id)),
+        None => format!("{domain}/{date}/{id}.eml"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key() {
+        let id = QueueId::generate();
+        let mail = Mail {
+            to: vec!["<b@Example.com>".into()],
+            data: b"Message-ID: <abc/1@mx.example.org>\r\nSubject: hi\r\n\r\nhello\r\n".to_vec(),
+            ..Mail::default()
+        };
+        assert_eq!(
+            key(&id, &mail, "2024-01-02"),
+            format!("example.com/2024-01-02/{id}-abc_1_mx.example.org.eml")
+        );
+        let again = QueueId::generate();
+        assert_ne!(
+            key(&again, &mail, "2024-01-02"),
+            key(&id, &mail, "2024-01-02")
+        );
+        let anonymous = Mail {
+            data: b"Subject: hi\r\n\r\nhello\r\n".to_vec(),
+            ..Mail::default()
+        };
+        assert_eq!(
+            key(&id, &anonymous, "2024-01-02"),
+            format!("_/2024-01-02/{id}.eml")
+        );
+    }
+}
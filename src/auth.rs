@@ -0,0 +1,438 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use md5::Md5;
+
+/// Accounts clients may authenticate as, with their passwords.
+/// Passwords are kept in clear, as CRAM-MD5 needs them to check the digest.
+#[derive(Clone, Debug, Default)]
+pub struct Users(HashMap<String, String>);
+
+impl Users {
+    /// Reads the accounts from a JSON file, an object mapping user names to passwords
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = std::fs::read_to_string(path)
+            .with_context(|| format!("cannot read users {}", path.display()))?;
+        Self::parse(&file).with_context(|| format!("invalid users {}", path.display()))
+    }
+
+    pub fn parse(file: &str) -> Result<Self> {
+        Ok(Self(serde_json::from_str(file)?))
+    }
+
+    fn verify_password(&self, user: &str, password: &str) -> bool {
+        self.0
+            .get(user)
+            .is_some_and(|expected| secrets_match(expected, password))
+    }
+
+    fn verify_cram_md5(&self, challenge: &str, user: &str, digest: &str) -> bool {
+        let (Some(password), Some(digest)) = (self.0.get(user), decode_hex(digest)) else {
+            return false;
+        };
+        let mut mac = Hmac::<Md5>::new_from_slice(password.as_bytes())
+            .expect("HMAC accepts keys of any length");
+        mac.update(challenge.as_bytes());
+        mac.verify_slice(&digest).is_ok()
+    }
+}
+
+/// Validates the bearer tokens of XOAUTH2
+#[derive(Clone, Debug)]
+pub enum Tokens {
+    /// Known tokens, and the user each one authenticates
+    Static(HashMap<String, String>),
+    /// An OAuth 2.0 token introspection endpoint (RFC 7662),
+    /// authenticating with the client id and secret. Tokens must be
+    /// issued for the audience.
+    #[cfg(feature = "forward")]
+    Introspection {
+        url: String,
+        client_id: String,
+        client_secret: String,
+        audience: String,
+        client: reqwest::Client,
+    },
+}
+
+/// Response of an introspection endpoint, as far as it matters here
+#[cfg(feature = "forward")]
+#[derive(serde::Deserialize)]
+struct Introspected {
+    active: bool,
+    username: Option<String>,
+    email: Option<String>,
+    sub: Option<String>,
+    aud: Option<Audience>,
+}
+
+/// Audience of a token, one or several
+#[cfg(feature = "forward")]
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum Audience {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[cfg(feature = "forward")]
+impl Introspected {
+    /// Whether the token is active, issued for `audience` and owned by `user`.
+    /// Tokens may name their owner in any of the fields, but must in one.
+    fn accepts(&self, user: &str, audience: &str) -> bool {
+        let for_audience = match &self.aud {
+            Some(Audience::One(aud)) => aud == audience,
+            Some(Audience::Many(auds)) => auds.iter().any(|aud| aud == audience),
+            None => false,
+        };
+        let owners = [&self.username, &self.email, &self.sub];
+        self.active && for_audience && owners.into_iter().flatten().any(|owner| owner == user)
+    }
+}
+
+impl Tokens {
+    /// Reads the known tokens from a JSON file mapping tokens to user names
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = std::fs::read_to_string(path)
+            .with_context(|| format!("cannot read tokens {}", path.display()))?;
+        let tokens = serde_json::from_str(&file)
+            .with_context(|| format!("invalid tokens {}", path.display()))?;
+        Ok(Self::Static(tokens))
+    }
+
+    /// Validates the tokens at an introspection endpoint, queried as `client_id`
+    /// with `client_secret`, accepting the tokens issued for `audience`
+    #[cfg(feature = "forward")]
+    pub fn introspection(
+        url: impl Into<String>,
+        client_id: impl Into<String>,
+        client_secret: impl Into<String>,
+        audience: impl Into<String>,
+    ) -> Self {
+        Self::Introspection {
+            url: url.into(),
+            client_id: client_id.into(),
+            client_secret: client_secret.into(),
+            audience: audience.into(),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Whether the token is valid and was issued to the user
+    pub async fn validate(&self, user: &str, token: &str) -> Result<bool> {
+        match self {
+            Tokens::Static(tokens) => Ok(tokens.get(token).is_some_and(|owner| owner == user)),
+            #[cfg(feature = "forward")]
+            Tokens::Introspection {
+                url,
+                client_id,
+                client_secret,
+                audience,
+                client,
+            } => {
+                let response = client
+                    .post(url)
+                    .basic_auth(client_id, Some(client_secret))
+                    .form(&[("token", token), ("token_type_hint", "access_token")])
+                    .send()
+                    .await?
+                    .error_for_status()
+                    .context("introspection endpoint failed")?
+                    .text()
+                    .await?;
+                let introspected: Introspected = serde_json::from_str(&response)?;
+                Ok(introspected.accepts(user, audience))
+            }
+        }
+    }
+}
+
+/// SASL exchange in progress, waiting for the client's next response
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Exchange {
+    /// PLAIN without an initial response
+    Plain,
+    LoginUser,
+    LoginPassword(String),
+    /// CRAM-MD5, with the challenge sent to the client
+    CramMd5(String),
+    /// XOAUTH2 without an initial response
+    XOAuth2,
+    /// XOAUTH2 with a rejected token, the client acknowledging the error details
+    XOAuth2Failed,
+}
+
+/// Outcome of a step of a SASL exchange
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Step {
+    /// Send the challenge, base64-encoded, and wait for the response
+    Continue(Exchange, String),
+    /// Check the XOAUTH2 bearer token for the user,
+    /// then report with [`Exchange::token_verified`]
+    Token { user: String, token: String },
+    /// Authenticated as the user
+    Success(String),
+    /// Unknown user or wrong password
+    Failure,
+    /// Cancelled by the client, or a malformed response
+    Cancelled,
+}
+
+impl Exchange {
+    /// Starts authenticating with `mechanism` and the optional initial response.
+    /// `nonce` makes the CRAM-MD5 challenge unique. None if the mechanism isn't supported.
+    pub fn start(
+        mechanism: &str,
+        initial: Option<&str>,
+        users: &Users,
+        nonce: &str,
+        domain: &str,
+    ) -> Option<Step> {
+        let exchange = match mechanism.to_uppercase().as_str() {
+            "PLAIN" => Exchange::Plain,
+            "LOGIN" => Exchange::LoginUser,
+            "XOAUTH2" => Exchange::XOAuth2,
+            "CRAM-MD5" => {
+                // RFC 2195 challenges look like message ids
+                let challenge = format!("<{nonce}@{domain}>");
+                return Some(Step::Continue(
+                    Exchange::CramMd5(challenge.clone()),
+                    encode(&challenge),
+                ));
+            }
+            _ => return None,
+        };
+        Some(match initial {
+            Some(initial) => exchange.respond(users, initial),
+            None => exchange.challenge(),
+        })
+    }
+
+    /// Handles the client's response to the last challenge
+    pub fn respond(self, users: &Users, response: &str) -> Step {
+        if response == "*" {
+            return Step::Cancelled;
+        }
+        // An empty initial response is sent as "="
+        let response = if response == "=" { "" } else { response };
+        let Some(response) = base64::engine::general_purpose::STANDARD
+            .decode(response)
+            .ok()
+            .and_then(|response| String::from_utf8(response).ok())
+        else {
+            return Step::Cancelled;
+        };
+        let verified = |user: &str, verified: bool| match verified {
+            true => Step::Success(user.to_string()),
+            false => Step::Failure,
+        };
+        match self {
+            Exchange::Plain => {
+                // authzid NUL authcid NUL password
+                let mut parts = response.split('\0');
+                match (parts.next(), parts.next(), parts.next(), parts.next()) {
+                    (Some(_), Some(user), Some(password), None) => {
+                        verified(user, users.verify_password(user, password))
+                    }
+                    _ => Step::Cancelled,
+                }
+            }
+            Exchange::LoginUser => Exchange::LoginPassword(response).challenge(),
+            Exchange::LoginPassword(user) => {
+                let valid = users.verify_password(&user, &response);
+                verified(&user, valid)
+            }
+            Exchange::CramMd5(challenge) => match response.rsplit_once(' ') {
+                Some((user, digest)) => {
+                    verified(user, users.verify_cram_md5(&challenge, user, digest))
+                }
+                None => Step::Cancelled,
+            },
+            Exchange::XOAuth2 => {
+                // user=<user> ^A auth=Bearer <token> ^A ^A
+                let mut user = None;
+                let mut token = None;
+                for field in response.split('\x01') {
+                    if let Some(value) = field.strip_prefix("user=") {
+                        user = Some(value);
+                    } else if let Some(value) = field.strip_prefix("auth=Bearer ") {
+                        token = Some(value);
+                    }
+                }
+                match (user, token) {
+                    (Some(user), Some(token)) => Step::Token {
+                        user: user.to_string(),
+                        token: token.to_string(),
+                    },
+                    _ => Step::Cancelled,
+                }
+            }
+            Exchange::XOAuth2Failed => Step::Failure,
+        }
+    }
+
+    /// Outcome of checking the XOAUTH2 token. A rejected token is reported
+    /// with error details first, which the client acknowledges with an empty line.
+    pub fn token_verified(user: String, valid: bool) -> Step {
+        match valid {
+            true => Step::Success(user),
+            false => Step::Continue(
+                Exchange::XOAuth2Failed,
+                encode(r#"{"status":"401","schemes":"bearer"}"#),
+            ),
+        }
+    }
+
+    fn challenge(self) -> Step {
+        let challenge = match &self {
+            Exchange::Plain | Exchange::XOAuth2 => String::new(),
+            Exchange::LoginUser => encode("Username:"),
+            Exchange::LoginPassword(_) => encode("Password:"),
+            Exchange::CramMd5(challenge) => encode(challenge),
+            Exchange::XOAuth2Failed => String::new(),
+        };
+        Step::Continue(self, challenge)
+    }
+}
+
+fn encode(text: &str) -> String {
+    base64::engine::general_purpose::STANDARD.encode(text)
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Whether a secret presented by a client is the expected one, compared in
+/// constant time, not to leak how much of it matched
+pub(crate) fn secrets_match(expected: &str, presented: &str) -> bool {
+    expected.len() == presented.len()
+        && expected
+            .bytes()
+            .zip(presented.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn users() -> Users {
+        Users::parse(r#"{"tim": "tanstaaftanstaaf"}"#).unwrap()
+    }
+
+    #[test]
+    fn test_cram_md5() {
+        // The example exchange of RFC 2195
+        let challenge = "<1896.697170952@postoffice.reston.mci.net>";
+        let exchange = Exchange::CramMd5(challenge.into());
+        assert_eq!(
+            exchange
+                .clone()
+                .respond(&users(), &encode("tim b913a602c7eda7a495b4e6e7334d3890")),
+            Step::Success("tim".into())
+        );
+        assert_eq!(
+            exchange.respond(&users(), &encode("tim b913a602c7eda7a495b4e6e7334d3891")),
+            Step::Failure
+        );
+        let Some(Step::Continue(Exchange::CramMd5(challenge), encoded)) =
+            Exchange::start("cram-md5", None, &users(), "1.2", "example.com")
+        else {
+            panic!("CRAM-MD5 must be supported");
+        };
+        assert_eq!(challenge, "<1.2@example.com>");
+        assert_eq!(encoded, encode(&challenge));
+    }
+
+    #[test]
+    fn test_plain_and_login() {
+        let users = users();
+        let plain = Exchange::start(
+            "PLAIN",
+            Some(&encode("\0tim\0tanstaaftanstaaf")),
+            &users,
+            "1",
+            "example.com",
+        );
+        assert_eq!(plain, Some(Step::Success("tim".into())));
+        let plain = Exchange::start("PLAIN", Some(&encode("\0tim\0nope")), &users, "1", "x");
+        assert_eq!(plain, Some(Step::Failure));
+
+        let Some(Step::Continue(exchange, _)) = Exchange::start("LOGIN", None, &users, "1", "x")
+        else {
+            panic!("LOGIN must be supported");
+        };
+        let Step::Continue(exchange, _) = exchange.respond(&users, &encode("tim")) else {
+            panic!("LOGIN asks for the password");
+        };
+        assert_eq!(exchange.clone().respond(&users, "*"), Step::Cancelled);
+        assert_eq!(
+            exchange.respond(&users, &encode("tanstaaftanstaaf")),
+            Step::Success("tim".into())
+        );
+        assert_eq!(Exchange::start("GSSAPI", None, &users, "1", "x"), None);
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    async fn test_xoauth2() {
+        let users = Users::default();
+        let response = encode("user=tim@example.com\x01auth=Bearer ya29.token\x01\x01");
+        let step = Exchange::start("XOAUTH2", Some(&response), &users, "1", "x");
+        let Some(Step::Token { user, token }) = step else {
+            panic!("XOAUTH2 asks for the token to be checked, got {step:?}");
+        };
+        assert_eq!(token, "ya29.token");
+
+        let tokens = Tokens::Static(HashMap::from([(
+            "ya29.token".to_string(),
+            "tim@example.com".to_string(),
+        )]));
+        assert!(tokens.validate(&user, &token).await.unwrap());
+        assert!(!tokens.validate("eve@example.com", &token).await.unwrap());
+        assert_eq!(
+            Exchange::token_verified(user.clone(), true),
+            Step::Success(user.clone())
+        );
+        let Step::Continue(exchange, _) = Exchange::token_verified(user, false) else {
+            panic!("a rejected token is reported with details");
+        };
+        assert_eq!(exchange.respond(&users, ""), Step::Failure);
+    }
+
+    #[cfg(feature = "forward")]
+    #[test]
+    fn test_introspected() {
+        let introspected = |json| serde_json::from_value::<Introspected>(json).unwrap();
+        let token = introspected(serde_json::json!({
+            "active": true,
+            "sub": "tim@example.com",
+            "aud": ["smtp", "imap"],
+        }));
+        assert!(token.accepts("tim@example.com", "smtp"));
+        assert!(!token.accepts("eve@example.com", "smtp"));
+        assert!(!token.accepts("tim@example.com", "api"));
+        // Tokens naming no owner, or no audience, are refused
+        let ownerless = introspected(serde_json::json!({ "active": true, "aud": "smtp" }));
+        assert!(!ownerless.accepts("tim@example.com", "smtp"));
+        let audienceless = introspected(serde_json::json!({
+            "active": true,
+            "username": "tim@example.com",
+        }));
+        assert!(!audienceless.accepts("tim@example.com", "smtp"));
+        let inactive = introspected(serde_json::json!({
+            "active": false,
+            "username": "tim@example.com",
+            "aud": "smtp",
+        }));
+        assert!(!inactive.accepts("tim@example.com", "smtp"));
+    }
+}
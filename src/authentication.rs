@@ -0,0 +1,204 @@
+use std::collections::BTreeMap;
+
+use crate::header::{field_name, field_value, split};
+use crate::schema::{AuthResult, Authentication};
+
+/// The message with an `Authentication-Results` field on top (RFC 8601), recording
+/// the results of the checks run on receipt, e.g. `spf=pass smtp.mailfrom=example.com`.
+/// Fields already claiming our authserv-id are forged, and removed.
+pub fn stamp(message: &[u8], authserv_id: &str, results: &[String]) -> Vec<u8> {
+    let mut stamped = format!("Authentication-Results: {authserv_id}").into_bytes();
+    for result in results {
+        stamped.extend_from_slice(format!(";\r\n\t{result}").as_bytes());
+    }
+    if results.is_empty() {
+        stamped.extend_from_slice(b"; none");
+    }
+    stamped.extend_from_slice(b"\r\n");
+    stamped.extend_from_slice(&strip(message, authserv_id));
+    stamped
+}
+
+/// The message without the `Authentication-Results` fields claiming our
+/// authserv-id, which the sender forged, as we only add ours on receipt
+pub fn strip(message: &[u8], authserv_id: &str) -> Vec<u8> {
+    let mut stripped = Vec::with_capacity(message.len());
+    let (fields, _) = split(message);
+    let mut copied = 0;
+    for field in fields {
+        if !ours(field, authserv_id) {
+            continue;
+        }
+        let start = field.as_ptr() as usize - message.as_ptr() as usize;
+        tracing::info!("Removing a forged {}", String::from_utf8_lossy(field));
+        stripped.extend_from_slice(&message[copied..start]);
+        copied = (start + field.len() + 2).min(message.len());
+    }
+    stripped.extend_from_slice(&message[copied..]);
+    stripped
+}
+
+/// The results of our topmost `Authentication-Results` field, without the
+/// authserv-id, none when there is none
+pub(crate) fn results(fields: &[&[u8]], authserv_id: &str) -> Option<String> {
+    fields
+        .iter()
+        .find(|field| ours(field, authserv_id))
+        .and_then(|field| Some(field_value(field).split_once(';')?.1.trim().to_string()))
+}
+
+/// The results of the checks run on receipt, for the payload, none when the
+/// message has no `Authentication-Results` field of ours
+pub fn parse(message: &[u8], authserv_id: &str) -> Option<Authentication> {
+    let (fields, _) = split(message);
+    let results = results(&fields, authserv_id)?;
+    let mut authentication = Authentication::default();
+    for result in results.split(';') {
+        let result = without_comments(result);
+        let mut tokens = tokens(&result).into_iter();
+        let Some((method, value)) = tokens.next().and_then(|token| token.split_once('=')) else {
+            continue;
+        };
+        let mut properties = tokens
+            .filter_map(|token| token.split_once('='))
+            .map(|(name, value)| (name.to_lowercase(), value.trim_matches('"').to_string()))
+            .collect::<BTreeMap<_, _>>();
+        if let Some(helo) = properties.get("smtp.helo") {
+            authentication.helo = Some(helo.clone());
+        }
+        let result = AuthResult {
+            result: value.to_lowercase(),
+            reason: properties.remove("reason"),
+            properties,
+        };
+        // The method may have a version, e.g. `spf/1`
+        let method = method.split('/').next().unwrap_or_default();
+        match method.to_lowercase().as_str() {
+            "spf" => authentication.spf = Some(result),
+            "dkim" => authentication.dkim.push(result),
+            "dmarc" => {
+                authentication.quarantine = result
+                    .properties
+                    .get("policy.disposition")
+                    .is_some_and(|disposition| disposition == "quarantine");
+                authentication.dmarc = Some(result);
+            }
+            "dnsbl" => authentication.dnsbl = Some(result),
+            "iprev" => authentication.iprev = Some(result),
+            _ => {}
+        }
+    }
+    Some(authentication)
+}
+
+/// Whether a field is an `Authentication-Results` one with our authserv-id
+fn ours(field: &[u8], authserv_id: &str) -> bool {
+    if field_name(field) != "authentication-results" {
+        return false;
+    }
+    let value = field_value(field);
+    // The authserv-id may be followed by a version
+    let id = value.split(';').next().unwrap_or_default();
+    id.split_whitespace()
+        .next()
+        .is_some_and(|id| id.eq_ignore_ascii_case(authserv_id))
+}
+
+/// The tokens of a result, split on the whitespace out of quoted strings,
+/// e.g. `reason="body hash mismatch"`
+fn tokens(result: &str) -> Vec<&str> {
+    let mut tokens = Vec::new();
+    let mut quoted = false;
+    let mut start = None;
+    for (i, c) in result.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => {
+                if let Some(start) = start.take() {
+                    tokens.push(&result[start..i]);
+                }
+                continue;
+            }
+            _ => {}
+        }
+        start.get_or_insert(i);
+    }
+    if let Some(start) = start {
+        tokens.push(&result[start..]);
+    }
+    tokens
+}
+
+/// A result with its comments, in parentheses, removed
+fn without_comments(result: &str) -> String {
+    let mut depth = 0usize;
+    result
+        .chars()
+        .filter(|&c| {
+            match c {
+                '(' => depth += 1,
+                ')' if depth > 0 => {
+                    depth -= 1;
+                    return false;
+                }
+                _ => {}
+            }
+            depth == 0
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stamp_and_parse() {
+        let message =
+            b"Authentication-Results: mx.example.org;\r\n\tspf=pass smtp.mailfrom=evil.example\r\n\
+                        Authentication-Results: other.example; spf=fail\r\n\
+                        From: a@example.com\r\n\r\nhello\r\n";
+        let results = [
+            "spf=softfail (not permitted) smtp.mailfrom=example.com smtp.helo=mail.example.com"
+                .into(),
+            "dkim=pass header.d=example.com header.s=mail header.b=dGhpcyBp".into(),
+            "dkim=fail reason=\"body hash mismatch\" header.d=example.net header.s=s1".into(),
+            "dmarc=fail policy.dmarc=reject header.from=example.com policy.disposition=quarantine"
+                .into(),
+        ];
+        let stamped = stamp(message, "mx.example.org", &results);
+        assert_eq!(
+            stamped,
+            b"Authentication-Results: mx.example.org;\r\n\tspf=softfail (not permitted) smtp.mailfrom=example.com smtp.helo=mail.example.com;\r\n\
+              \tdkim=pass header.d=example.com header.s=mail header.b=dGhpcyBp;\r\n\
+              \tdkim=fail reason=\"body hash mismatch\" header.d=example.net header.s=s1;\r\n\
+              \tdmarc=fail policy.dmarc=reject header.from=example.com policy.disposition=quarantine\r\n\
+              Authentication-Results: other.example; spf=fail\r\n\
+              From: a@example.com\r\n\r\nhello\r\n"
+        );
+
+        let authentication = parse(&stamped, "mx.example.org").unwrap();
+        let spf = authentication.spf.unwrap();
+        assert_eq!(spf.result, "softfail");
+        assert_eq!(spf.properties["smtp.mailfrom"], "example.com");
+        assert_eq!(authentication.helo.as_deref(), Some("mail.example.com"));
+        assert_eq!(authentication.dkim.len(), 2);
+        assert_eq!(authentication.dkim[0].result, "pass");
+        assert_eq!(authentication.dkim[0].reason, None);
+        assert_eq!(authentication.dkim[1].result, "fail");
+        assert_eq!(
+            authentication.dkim[1].reason.as_deref(),
+            Some("body hash mismatch")
+        );
+        assert_eq!(authentication.dkim[1].properties["header.s"], "s1");
+        let dmarc = authentication.dmarc.unwrap();
+        assert_eq!(dmarc.result, "fail");
+        assert_eq!(dmarc.properties["policy.dmarc"], "reject");
+        assert!(authentication.quarantine);
+        assert_eq!(parse(&stamped, "mx.example.net"), None);
+        assert_eq!(
+            parse(&stamp(message, "mx.example.net", &[]), "mx.example.net"),
+            Some(Authentication::default())
+        );
+    }
+}
@@ -0,0 +1,159 @@
+use anyhow::{Context, Result};
+use aws_config::{BehaviorVersion, SdkConfig};
+
+use crate::protocol::Mail;
+use crate::queue::QueueId;
+use crate::schema::Message;
+use crate::sink::{recipient_domain, DeliveryResult, MailSink};
+
+/// Largest message SQS and SNS take, in bytes
+const MAX_MESSAGE_SIZE: usize = 256 * 1024;
+
+/// SQS queue the messages are sent to, as JSON
+pub struct SqsSink {
+    client: aws_sdk_sqs::Client,
+    pub queue_url: String,
+}
+
+/// SNS topic the messages are published to, as JSON
+pub struct SnsSink {
+    client: aws_sdk_sns::Client,
+    pub topic_arn: String,
+}
+
+/// Connects to the queue, given its URL, and the topic, given its ARN, each none
+/// when not given. Credentials and region come from the AWS SDK's default provider
+/// chain: `AWS_ACCESS_KEY_ID`, profiles, web identity, or the instance or task role.
+pub async fn connect(
+    queue_url: Option<String>,
+    topic_arn: Option<String>,
+) -> Result<(Option<SqsSink>, Option<SnsSink>)> {
+    if queue_url.is_none() && topic_arn.is_none() {
+        return Ok((None, None));
+    }
+    let config = aws_config::load_defaults(BehaviorVersion::latest()).await;
+    anyhow::ensure!(
+        config.region().is_some(),
+        "no AWS region, set AWS_REGION for sqs-queue-url or sns-topic-arn"
+    );
+    Ok((
+        queue_url.map(|queue_url| SqsSink::new(&config, queue_url)),
+        topic_arn.map(|topic_arn| SnsSink::new(&config, topic_arn)),
+    ))
+}
+
+impl SqsSink {
+    pub fn new(config: &SdkConfig, queue_url: String) -> Self {
+        Self {
+            client: aws_sdk_sqs::Client::new(config),
+            queue_url,
+        }
+    }
+}
+
+impl MailSink for SqsSink {
+    fn endpoint(&self) -> String {
+        format!("sqs://{}", self.queue_url)
+    }
+
+    /// Sends a message, with the queue id in the `id` attribute. On a FIFO queue,
+    /// the mail of a recipient domain stays in order and the queue id deduplicates retries.
+    async fn deliver(&self, id: &QueueId, mail: &Mail, message: &Message) -> DeliveryResult {
+        let attribute = aws_sdk_sqs::types::MessageAttributeValue::builder()
+            .data_type("String")
+            .string_value(id.as_str())
+            .build()?;
+        let mut request = self
+            .client
+            .send_message()
+            .queue_url(&self.queue_url)
+            .message_body(body(message)?)
+            .message_attributes("id", attribute);
+        if self.queue_url.ends_with(".fifo") {
+            request = request
+                .message_group_id(group(mail))
+                .message_deduplication_id(id.as_str());
+        }
+        request
+            .send()
+            .await
+            .map_err(aws_sdk_sqs::Error::from)
+            .with_context(|| format!("cannot send to SQS queue {}", self.queue_url))?;
+        Ok(())
+    }
+}
+
+impl SnsSink {
+    pub fn new(config: &SdkConfig, topic_arn: String) -> Self {
+        Self {
+            client: aws_sdk_sns::Client::new(config),
+            topic_arn,
+        }
+    }
+}
+
+impl MailSink for SnsSink {
+    fn endpoint(&self) -> String {
+        format!("sns://{}", self.topic_arn)
+    }
+
+    /// Publishes a message, with the queue id in the `id` attribute. On a FIFO topic,
+    /// the mail of a recipient domain stays in order and the queue id deduplicates retries.
+    async fn deliver(&self, id: &QueueId, mail: &Mail, message: &Message) -> DeliveryResult {
+        let attribute = aws_sdk_sns::types::MessageAttributeValue::builder()
+            .data_type("String")
+            .string_value(id.as_str())
+            .build()?;
+        let mut request = self
+            .client
+            .publish()
+            .topic_arn(&self.topic_arn)
+            .message(body(message)?)
+            .message_attributes("id", attribute);
+        if self.topic_arn.ends_with(".fifo") {
+            request = request
+                .message_group_id(group(mail))
+                .message_deduplication_id(id.as_str());
+        }
+        request
+            .send()
+            .await
+            .map_err(aws_sdk_sns::Error::from)
+            .with_context(|| format!("cannot publish to SNS topic {}", self.topic_arn))?;
+        Ok(())
+    }
+}
+
+/// The message as JSON, as SQS and SNS only carry text. Larger messages than they
+/// take need an object store for their attachments.
+fn body(message: &Message) -> Result<String> {
+    let body = serde_json::to_string(message)?;
+    anyhow::ensure!(
+        body.len() <= MAX_MESSAGE_SIZE,
+        "message of {} bytes is over the {MAX_MESSAGE_SIZE} SQS and SNS take",
+        body.len()
+    );
+    Ok(body)
+}
+
+/// FIFO message group of a mail, its recipient domain, as a group id cannot be empty
+fn group(mail: &Mail) -> String {
+    Some(recipient_domain(mail))
+        .filter(|domain| !domain.is_empty())
+        .unwrap_or_else(|| "-".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_group() {
+        let mail = Mail {
+            to: vec!["<ann@example.com>".into()],
+            ..Mail::default()
+        };
+        assert_eq!(group(&mail), "example.com");
+        assert_eq!(group(&Mail::default()), "-");
+    }
+}
@@ -0,0 +1,81 @@
+use std::time::Duration;
+
+use crate::queue::{QueuedMail, ShardedQueue};
+
+/// How mail is batched: each delivery worker waits up to `window` after taking
+/// a mail for more, posting up to `max_messages` of them as a JSON array in one request
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Batching {
+    pub max_messages: usize,
+    pub window: Duration,
+}
+
+impl Batching {
+    /// Posts up to `max_messages` per request, none when that is 1 or less, a batch
+    /// waiting `window` to fill up
+    pub fn new(max_messages: usize, window: Duration) -> Option<Self> {
+        (max_messages > 1).then_some(Self {
+            max_messages,
+            window,
+        })
+    }
+
+    /// Takes more mail from the worker's shard after `first`, until the batch is full
+    /// or the window is over
+    pub async fn collect(
+        &self,
+        queue: &ShardedQueue,
+        shard: usize,
+        first: QueuedMail,
+    ) -> Vec<QueuedMail> {
+        let deadline = tokio::time::Instant::now() + self.window;
+        let mut batch = vec![first];
+        while batch.len() < self.max_messages {
+            match tokio::time::timeout_at(deadline, queue.pop(shard)).await {
+                Ok(entry) => batch.push(entry),
+                Err(_) => break,
+            }
+        }
+        batch
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::priority::Priority;
+    use crate::protocol::Mail;
+    use crate::queue::QueueId;
+
+    fn entry() -> QueuedMail {
+        QueuedMail {
+            id: QueueId::generate(),
+            mail: Mail::default(),
+            priority: Priority::Normal,
+            not_before: None,
+            tenant: None,
+            attempts: 0,
+            duplicate_of: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_collect() {
+        let queue = ShardedQueue::new(1);
+        let batching = Batching {
+            max_messages: 3,
+            window: Duration::from_millis(100),
+        };
+        for _ in 0..4 {
+            queue.push(entry());
+        }
+        let first = queue.pop(0).await;
+        assert_eq!(batching.collect(&queue, 0, first).await.len(), 3);
+
+        // Sent with what came within the window
+        let first = queue.pop(0).await;
+        let started = std::time::Instant::now();
+        assert_eq!(batching.collect(&queue, 0, first).await.len(), 1);
+        assert!(started.elapsed() >= Duration::from_millis(90));
+    }
+}
@@ -0,0 +1,108 @@
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+
+/// How long an injected sink timeout hangs before failing
+const SINK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Probabilities, between 0 and 1, of the faults injected to soak-test
+/// the queue and retry behavior. Nothing is injected by default.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Faults {
+    /// Delivery hangs, then fails as timed out
+    pub sink_timeout: f64,
+    /// Delivery fails as if the destination replied 503
+    pub sink_error: f64,
+    /// Writing to the WAL or the ledger fails
+    pub storage_error: f64,
+    /// The SMTP connection is dropped after reading from the client
+    pub disconnect: f64,
+}
+
+impl Faults {
+    fn roll(probability: f64) -> bool {
+        probability > 0.0 && rand::random::<f64>() < probability
+    }
+
+    /// Possibly fails a delivery, before it reaches the destination
+    pub async fn sink(&self) -> Result<()> {
+        if Self::roll(self.sink_timeout) {
+            tracing::warn!("Injecting sink timeout");
+            tokio::time::sleep(SINK_TIMEOUT).await;
+            bail!("injected fault: destination timed out");
+        }
+        if Self::roll(self.sink_error) {
+            tracing::warn!("Injecting sink error");
+            bail!("injected fault: destination replied 503 Service Unavailable");
+        }
+        Ok(())
+    }
+
+    /// Possibly fails a write to persistent storage
+    pub fn storage(&self) -> Result<()> {
+        if Self::roll(self.storage_error) {
+            tracing::warn!("Injecting storage error");
+            bail!("injected fault: storage write failed");
+        }
+        Ok(())
+    }
+
+    /// Whether to drop the client connection now
+    pub fn disconnect(&self) -> bool {
+        let disconnect = Self::roll(self.disconnect);
+        if disconnect {
+            tracing::warn!("Injecting disconnect");
+        }
+        disconnect
+    }
+}
+
+impl std::str::FromStr for Faults {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut faults = Self::default();
+        for fault in s.split(',').filter(|fault| !fault.trim().is_empty()) {
+            let (name, probability) = fault
+                .split_once('=')
+                .with_context(|| format!("invalid fault {fault:?}"))?;
+            let probability = probability
+                .trim()
+                .parse::<f64>()
+                .with_context(|| format!("invalid probability for {name}"))?;
+            if !(0.0..=1.0).contains(&probability) {
+                bail!("probability for {name} must be between 0 and 1");
+            }
+            match name.trim() {
+                "sink_timeout" => faults.sink_timeout = probability,
+                "sink_error" => faults.sink_error = probability,
+                "storage_error" => faults.storage_error = probability,
+                "disconnect" => faults.disconnect = probability,
+                name => bail!("unknown fault {name}"),
+            }
+        }
+        Ok(faults)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_parse_and_inject() {
+        let faults = "sink_error=1, storage_error=0".parse::<Faults>().unwrap();
+        assert_eq!(
+            faults,
+            Faults {
+                sink_error: 1.0,
+                ..Faults::default()
+            }
+        );
+        assert!(faults.sink().await.is_err());
+        assert!(faults.storage().is_ok());
+        assert!(!faults.disconnect());
+        assert!("sink_error=2".parse::<Faults>().is_err());
+        assert!("fire=0.5".parse::<Faults>().is_err());
+    }
+}
@@ -0,0 +1,330 @@
+use std::net::IpAddr;
+
+use chrono::Utc;
+use hickory_resolver::TokioAsyncResolver;
+
+use crate::dkim;
+use crate::dmarc::{self, Policy};
+use crate::dns::Resolver;
+use crate::dnsbl::{self, Blocklist};
+use crate::iprev::{self, IprevResult};
+use crate::protocol::{self, Reply, StateMachine};
+use crate::spf::{self, SpfResult};
+
+/// Authentication checks of inbound mail, run by the server as it is received.
+/// Their results are recorded in an `Authentication-Results` field added on top
+/// of the message, and reported in the payload.
+pub struct Checks<R = TokioAsyncResolver> {
+    resolver: R,
+    /// authserv-id of the `Authentication-Results` field
+    pub authserv_id: String,
+    /// Whether the sender's domain is checked with SPF
+    pub spf: bool,
+    /// Whether the DKIM signatures of the messages are verified
+    pub dkim: bool,
+    /// Whether DMARC is evaluated, with the results of SPF and DKIM
+    pub dmarc: bool,
+    /// DNS blocklists the clients are looked up in on connection
+    pub blocklists: Vec<Blocklist>,
+    /// Score from the blocklists from which a client counts as listed
+    pub dnsbl_threshold: u32,
+    /// Whether listed clients are refused, rather than only reported
+    pub reject_listed: bool,
+    /// Whether the clients are checked for forward-confirmed reverse DNS
+    pub iprev: bool,
+    /// Whether clients failing the reverse DNS check are refused, rather than only reported
+    pub reject_iprev_fail: bool,
+    /// Whether mail failing SPF is refused, rather than only reported
+    pub reject_spf_fail: bool,
+    /// What is done of mail failing DMARC, at most what the policy of its domain says
+    pub dmarc_enforcement: Policy,
+}
+
+/// What the checks of a client, a sender or a message concluded
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Verdict {
+    /// For the `Authentication-Results` field, e.g. `spf=pass smtp.mailfrom=example.com`
+    pub results: Vec<String>,
+    /// Reply refusing the client or its mail, when refused
+    pub refusal: Option<Reply>,
+    /// SPF result of a sender and the domain checked, for DMARC
+    pub spf: Option<(SpfResult, String)>,
+    /// Whether the client is listed by the DNS blocklists, past the threshold
+    pub listed: bool,
+}
+
+impl<R: Resolver> Checks<R> {
+    /// Runs all the checks but the blocklists, without refusing any mail
+    pub fn new(resolver: R, authserv_id: impl Into<String>) -> Self {
+        Self {
+            resolver,
+            authserv_id: authserv_id.into(),
+            spf: true,
+            dkim: true,
+            dmarc: true,
+            blocklists: Vec::new(),
+            dnsbl_threshold: 1,
+            reject_listed: false,
+            iprev: true,
+            reject_iprev_fail: false,
+            reject_spf_fail: false,
+            dmarc_enforcement: Policy::None,
+        }
+    }
+
+    /// Checks the client at `ip` on connection, before it says anything
+    pub async fn client(&self, ip: IpAddr) -> Verdict {
+        let mut verdict = Verdict::default();
+        if self.iprev {
+            let iprev = iprev::check(&self.resolver, ip).await;
+            tracing::debug!("{iprev}");
+            if iprev.result == IprevResult::Fail && self.reject_iprev_fail {
+                verdict.refusal = Some(StateMachine::NAMELESS);
+            }
+            verdict.results.push(iprev.to_string());
+        }
+        if self.blocklists.is_empty() {
+            return verdict;
+        }
+        let listing = dnsbl::check(&self.resolver, &self.blocklists, ip).await;
+        let listed = listing.score >= self.dnsbl_threshold;
+        let mut result = format!("dnsbl={}", if listed { "listed" } else { "pass" });
+        if !listing.zones.is_empty() {
+            tracing::info!("{ip} is listed by {:?}", listing.zones);
+            result.push_str(&format!(
+                " policy.score={} policy.zones={}",
+                listing.score,
+                listing.zones.join(",")
+            ));
+        }
+        verdict.listed = listed;
+        if listed && self.reject_listed {
+            verdict.refusal.get_or_insert(StateMachine::BLOCKED);
+        }
+        verdict.results.push(result);
+        verdict
+    }
+
+    /// Checks the sender of a mail from the client at `ip`, introduced as `helo`.
+    /// The HELO name is checked instead for bounces, which have no sender.
+    pub async fn sender(&self, ip: IpAddr, sender: &str, helo: Option<&str>) -> Verdict {
+        if !self.spf {
+            return Verdict::default();
+        }
+        let sender = sender.trim_matches(|c| c == '<' || c == '>');
+        let helo = helo.unwrap_or_default();
+        let (identity, domain, property) = match sender.rsplit_once('@') {
+            Some((_, domain)) => (sender.to_string(), domain, "smtp.mailfrom"),
+            None => (format!("postmaster@{helo}"), helo, "smtp.helo"),
+        };
+        let result = spf::check(&self.resolver, ip, domain, &identity, helo).await;
+        tracing::debug!("SPF {result} for {identity} from {ip}");
+        let refusal = (result == SpfResult::Fail && self.reject_spf_fail)
+            .then_some(StateMachine::NOT_FROM_THERE);
+        let mut recorded = format!("spf={result} {property}={domain}");
+        if property == "smtp.mailfrom" && !helo.is_empty() {
+            recorded.push_str(&format!(" smtp.helo={helo}"));
+        }
+        Verdict {
+            results: vec![recorded],
+            refusal,
+            spf: Some((result, domain.to_string())),
+            ..Default::default()
+        }
+    }
+
+    /// Checks the message received, its data as sent by the client, with
+    /// the verdict on its sender
+    pub async fn message(&self, data: &[u8], sender: &Verdict) -> Verdict {
+        let mut verdict = Verdict::default();
+        if !self.dkim {
+            return verdict;
+        }
+        let message = dkim::to_crlf(protocol::strip_terminator(data));
+        let verifications = dkim::verify(&self.resolver, &message, Utc::now()).await;
+        for verification in &verifications {
+            tracing::debug!("DKIM {verification}");
+            verdict.results.push(verification.to_string());
+        }
+        if verifications.is_empty() {
+            verdict.results.push("dkim=none".to_string());
+        }
+        if !self.dmarc {
+            return verdict;
+        }
+
+        let Some(from) = dmarc::from_domain(&message) else {
+            verdict
+                .results
+                .push("dmarc=permerror reason=\"no single From domain\"".to_string());
+            return verdict;
+        };
+        let spf = sender
+            .spf
+            .as_ref()
+            .map(|(result, domain)| (*result, domain.as_str()));
+        let evaluation = dmarc::check(&self.resolver, &from, spf, &verifications).await;
+        tracing::debug!("DMARC {evaluation}");
+        let mut result = evaluation.to_string();
+        match evaluation.disposition(self.dmarc_enforcement) {
+            Policy::None => {}
+            Policy::Quarantine => result.push_str(" policy.disposition=quarantine"),
+            Policy::Reject => verdict.refusal = Some(StateMachine::NOT_ALIGNED),
+        }
+        verdict.results.push(result);
+        verdict
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dns::Zone;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_sender() {
+        let zone = Zone::default()
+            .with("txt", "example.com", "v=spf1 ip4:192.0.2.1 -all")
+            .with("txt", "mail.example.com", "v=spf1 a -all")
+            .with("ip", "mail.example.com", "192.0.2.2");
+        let mut checks = Checks::new(zone, "mx.example.org");
+        let ip = "192.0.2.2".parse().unwrap();
+        let verdict = checks
+            .sender(ip, "<alice@example.com>", Some("mail.example.com"))
+            .await;
+        assert_eq!(
+            verdict.results,
+            ["spf=fail smtp.mailfrom=example.com smtp.helo=mail.example.com"]
+        );
+        assert_eq!(verdict.refusal, None);
+        // Bounces are checked against the HELO name
+        let verdict = checks.sender(ip, "<>", Some("mail.example.com")).await;
+        assert_eq!(verdict.results, ["spf=pass smtp.helo=mail.example.com"]);
+
+        checks.reject_spf_fail = true;
+        let verdict = checks
+            .sender(ip, "<alice@example.com>", Some("mail.example.com"))
+            .await;
+        assert_eq!(verdict.refusal, Some(StateMachine::NOT_FROM_THERE));
+
+        checks.spf = false;
+        let verdict = checks
+            .sender(ip, "<alice@example.com>", Some("mail.example.com"))
+            .await;
+        assert_eq!(verdict, Verdict::default());
+    }
+
+    #[tokio::test]
+    async fn test_client() {
+        let zone = Zone::default()
+            .with("ip", "2.2.0.192.zen.example", "127.0.0.2")
+            .with("ip", "3.2.0.192.zen.example", "127.0.0.2")
+            .with("ip", "3.2.0.192.cop.example", "127.0.0.2");
+        let mut checks = Checks::new(zone, "mx.example.org");
+        checks.iprev = false;
+        let ip = "192.0.2.2".parse().unwrap();
+        assert_eq!(checks.client(ip).await, Verdict::default());
+
+        checks.blocklists = vec![
+            "zen.example".parse().unwrap(),
+            "cop.example".parse().unwrap(),
+        ];
+        checks.dnsbl_threshold = 2;
+        let verdict = checks.client(ip).await;
+        assert_eq!(
+            verdict.results,
+            ["dnsbl=pass policy.score=1 policy.zones=zen.example"]
+        );
+        let verdict = checks.client("192.0.2.3".parse().unwrap()).await;
+        assert_eq!(
+            verdict.results,
+            ["dnsbl=listed policy.score=2 policy.zones=zen.example,cop.example"]
+        );
+        assert!(verdict.listed);
+        assert_eq!(verdict.refusal, None);
+        checks.reject_listed = true;
+        let verdict = checks.client("192.0.2.3".parse().unwrap()).await;
+        assert_eq!(verdict.refusal, Some(StateMachine::BLOCKED));
+        let verdict = checks.client("192.0.2.4".parse().unwrap()).await;
+        assert_eq!(verdict.results, ["dnsbl=pass"]);
+    }
+
+    #[tokio::test]
+    async fn test_client_iprev() {
+        let zone = Zone::default()
+            .with("ptr", "192.0.2.1", "mail.example.com")
+            .with("ip", "mail.example.com", "192.0.2.1");
+        let mut checks = Checks::new(zone, "mx.example.org");
+        let verdict = checks.client("192.0.2.1".parse().unwrap()).await;
+        assert_eq!(
+            verdict.results,
+            ["iprev=pass policy.iprev=192.0.2.1 policy.ptr=mail.example.com"]
+        );
+        let verdict = checks.client("192.0.2.2".parse().unwrap()).await;
+        assert_eq!(verdict.results, ["iprev=fail policy.iprev=192.0.2.2"]);
+        assert_eq!(verdict.refusal, None);
+        checks.reject_iprev_fail = true;
+        let verdict = checks.client("192.0.2.2".parse().unwrap()).await;
+        assert_eq!(verdict.refusal, Some(StateMachine::NAMELESS));
+        let verdict = checks.client("192.0.2.1".parse().unwrap()).await;
+        assert_eq!(verdict.refusal, None);
+    }
+
+    #[tokio::test]
+    async fn test_message() {
+        let mut checks = Checks::new(Zone::default(), "mx.example.org");
+        checks.dmarc = false;
+        let data = b"From: a@example.com\r\n\r\nhello\r\n.\r\n";
+        let sender = Verdict::default();
+        assert_eq!(checks.message(data, &sender).await.results, ["dkim=none"]);
+        let signed = b"DKIM-Signature: v=1; a=rsa-sha256; d=example.com; s=mail;\r\n\
+                       \th=from; bh=AAAA; b=AAAA\r\nFrom: a@example.com\r\n\r\nhello\r\n.\r\n";
+        assert_eq!(
+            checks.message(signed, &sender).await.results,
+            ["dkim=permerror reason=\"no key\" header.d=example.com header.s=mail header.b=AAAA"]
+        );
+        checks.dkim = false;
+        assert_eq!(checks.message(signed, &sender).await, Verdict::default());
+    }
+
+    #[tokio::test]
+    async fn test_dmarc() {
+        let zone = Zone::default()
+            .with("txt", "example.com", "v=spf1 ip4:192.0.2.1 -all")
+            .with("txt", "_dmarc.example.com", "v=DMARC1; p=reject");
+        let mut checks = Checks::new(zone, "mx.example.org");
+        let data = b"From: Alice <alice@example.com>\r\n\r\nhello\r\n.\r\n";
+        let ip = "192.0.2.1".parse().unwrap();
+        let sender = checks.sender(ip, "<alice@example.com>", None).await;
+        let verdict = checks.message(data, &sender).await;
+        assert_eq!(
+            verdict.results,
+            [
+                "dkim=none",
+                "dmarc=pass policy.dmarc=reject header.from=example.com"
+            ]
+        );
+        assert_eq!(verdict.refusal, None);
+
+        let sender = checks
+            .sender("192.0.2.2".parse().unwrap(), "<alice@example.com>", None)
+            .await;
+        let verdict = checks.message(data, &sender).await;
+        assert_eq!(
+            verdict.results[1],
+            "dmarc=fail policy.dmarc=reject header.from=example.com"
+        );
+        assert_eq!(verdict.refusal, None);
+        checks.dmarc_enforcement = Policy::Quarantine;
+        let verdict = checks.message(data, &sender).await;
+        assert_eq!(
+            verdict.results[1],
+            "dmarc=fail policy.dmarc=reject header.from=example.com policy.disposition=quarantine"
+        );
+        assert_eq!(verdict.refusal, None);
+        checks.dmarc_enforcement = Policy::Reject;
+        let verdict = checks.message(data, &sender).await;
+        assert_eq!(verdict.refusal, Some(StateMachine::NOT_ALIGNED));
+    }
+}
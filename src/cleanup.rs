@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Duration, Utc};
+
+#[cfg(feature = "sqlite")]
+use crate::database::Database;
+use crate::deadletter::DeadLetters;
+use crate::eml::EmlDir;
+use crate::maildir::Maildir;
+use crate::sink::recipient_domain;
+
+/// How long stored mail is kept, by recipient domain
+#[derive(Clone, Debug, PartialEq)]
+pub struct Retention {
+    pub default: Duration,
+    /// Overrides of the default, by lowercase domain
+    pub domains: HashMap<String, Duration>,
+}
+
+impl Retention {
+    /// Parses `domain=days` overrides, comma separated
+    pub fn with_domains(default: Duration, domains: &str) -> Result<Self> {
+        let mut retention = Self {
+            default,
+            domains: HashMap::new(),
+        };
+        for entry in domains
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+        {
+            let (domain, days) = entry
+                .split_once('=')
+                .with_context(|| format!("invalid retention {entry}, expected domain=days"))?;
+            let days = days
+                .trim()
+                .parse()
+                .with_context(|| format!("invalid retention days for {domain}"))?;
+            retention
+                .domains
+                .insert(domain.trim().to_lowercase(), Duration::days(days));
+        }
+        Ok(retention)
+    }
+
+    /// How long the mail of a recipient domain is kept
+    pub fn of(&self, domain: &str) -> Duration {
+        self.domains.get(domain).copied().unwrap_or(self.default)
+    }
+
+    /// The shortest retention, before which no stored mail is past its retention
+    #[cfg(any(feature = "sqlite", test))]
+    fn shortest(&self) -> Duration {
+        self.domains
+            .values()
+            .copied()
+            .fold(self.default, Duration::min)
+    }
+}
+
+/// Deletes the stored mail past its retention: the rows of the database and the
+/// dead letters, by the domain of their first recipient, and the `.eml` dumps and
+/// the Maildir messages, which have no envelope to tell their domain, after the
+/// default retention.
+///
+/// What is left alone:
+/// - the mbox file, which can only be rewritten as a whole, to be rotated with
+///   the tools reading it;
+/// - the S3 archive, kept for compliance and expired by the lifecycle rules of
+///   its bucket;
+/// - the spool, whose mail is removed as it is delivered or given up on;
+/// - the WAL history, the records of finished mail only, which JMAP and the
+///   admin API tell about, JMAP counting them as the state of the mailbox.
+pub struct Cleanup {
+    pub retention: Arc<Retention>,
+    /// Time between two cleanups, when run in the background
+    pub interval: std::time::Duration,
+    #[cfg(feature = "sqlite")]
+    pub database: Option<Database>,
+    pub eml: Option<EmlDir>,
+    pub maildir: Option<Maildir>,
+    pub dead_letters: Option<DeadLetters>,
+}
+
+impl Cleanup {
+    /// Deletes what is past its retention at `now`, returning how many messages
+    pub async fn run(&self, now: DateTime<Utc>) -> Result<usize> {
+        let mut removed = 0;
+        #[cfg(feature = "sqlite")]
+        if let Some(database) = &self.database {
+            let retention = self.retention.clone();
+            removed += database
+                .remove_expired(now - retention.shortest(), move |mail, stored_at| {
+                    stored_at < now - retention.of(&recipient_domain(mail))
+                })
+                .await?;
+        }
+        if let Some(eml) = &self.eml {
+            removed += eml.remove_older(now - self.retention.default).await?;
+        }
+        if let Some(maildir) = &self.maildir {
+            removed += maildir.remove_older(now - self.retention.default).await?;
+        }
+        if let Some(dead_letters) = &self.dead_letters {
+            removed += dead_letters
+                .remove_expired(|letter| {
+                    letter.failed_at < now - self.retention.of(&recipient_domain(&letter.mail))
+                })
+                .await?;
+        }
+        Ok(removed)
+    }
+
+    /// Cleans up every interval, for as long as the server runs
+    pub fn spawn(self) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(self.interval);
+            loop {
+                interval.tick().await;
+                match self.run(Utc::now()).await {
+                    Ok(0) => {}
+                    Ok(removed) => {
+                        tracing::info!("Removed {removed} messages past their retention")
+                    }
+                    Err(err) => tracing::error!("Cannot clean up stored mail: {err:#}"),
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retention() {
+        let retention =
+            Retention::with_domains(Duration::days(30), "Example.com=7, archive.org = 365")
+                .unwrap();
+        assert_eq!(retention.of("example.com"), Duration::days(7));
+        assert_eq!(retention.of("archive.org"), Duration::days(365));
+        assert_eq!(retention.of("other.net"), Duration::days(30));
+        assert_eq!(retention.shortest(), Duration::days(7));
+        assert!(Retention::with_domains(Duration::days(30), "example.com").is_err());
+        assert!(Retention::with_domains(Duration::days(30), "example.com=soon").is_err());
+    }
+}
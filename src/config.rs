@@ -0,0 +1,1593 @@
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use clap::builder::BoolishValueParser;
+use clap::{Parser, Subcommand};
+#[cfg(any(feature = "dns", feature = "relay"))]
+use hickory_resolver::TokioAsyncResolver;
+use serde::{Deserialize, Deserializer};
+
+#[cfg(feature = "relay")]
+use crate::alias::Aliases;
+#[cfg(feature = "relay")]
+use crate::arc::Sealer;
+use crate::archive::Archive;
+use crate::auth::{Tokens, Users};
+#[cfg(feature = "aws")]
+use crate::aws;
+use crate::batch::Batching;
+use crate::chaos::Faults;
+#[cfg(feature = "dns")]
+use crate::checks::Checks;
+use crate::cleanup::{Cleanup, Retention};
+#[cfg(feature = "sqlite")]
+use crate::database::Database;
+use crate::deadletter::DeadLetters;
+use crate::dedup::MessageIds;
+#[cfg(feature = "relay")]
+use crate::dkim::Signer;
+#[cfg(feature = "dns")]
+use crate::dmarc::Policy;
+#[cfg(feature = "dns")]
+use crate::dnsbl;
+use crate::domains::Domains;
+use crate::eml::EmlDir;
+use crate::format::{Compression, Format};
+use crate::forward::{self, Destination, Webhook};
+use crate::helo::HeloPolicy;
+#[cfg(feature = "kafka")]
+use crate::kafka::KafkaSink;
+use crate::leader::Lease;
+use crate::maildir::Maildir;
+use crate::mbox::Mbox;
+use crate::protocol::StateMachine;
+#[cfg(feature = "pubsub")]
+use crate::pubsub::PubSubSink;
+use crate::ratelimit::ClientLimits;
+use crate::recipients::Recipients;
+#[cfg(feature = "redis")]
+use crate::redis_stream::RedisSink;
+#[cfg(feature = "relay")]
+use crate::relay::{Relay, Route};
+use crate::retry::RetryPolicy;
+#[cfg(feature = "rspamd")]
+use crate::rspamd::Rspamd;
+use crate::senders::Senders;
+use crate::sink::SinkRegistry;
+use crate::smtp::{Settings, Timeouts};
+#[cfg(feature = "relay")]
+use crate::smtp_client::{self, Credentials, Tls};
+#[cfg(feature = "sqlite")]
+use crate::spool::Spool;
+#[cfg(feature = "relay")]
+use crate::srs::Srs;
+use crate::storage::{self, ObjectStore};
+use crate::tarpit::Tarpit;
+use crate::tenant::{self, Tenant};
+use crate::urls::Blocklist;
+use crate::usage::Reporter;
+use crate::{payload, priority, schedule};
+
+/// Command line of the server
+#[derive(Debug, Parser)]
+#[command(
+    version,
+    about = "Receives mail over SMTP and forwards it to a webhook"
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// TOML file with the settings, which flags and environment variables override
+    #[arg(long, env = "CONFIG")]
+    pub config: Option<PathBuf>,
+    #[command(flatten)]
+    pub flags: Config,
+}
+
+/// Tools run instead of the server, each parsing its own arguments
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Sends generated mail to a server, reporting the throughput
+    Loadgen {
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        args: Vec<String>,
+    },
+    /// Runs stored messages through the delivery pipeline again
+    Replay {
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        args: Vec<String>,
+    },
+    /// Lists the mail given up on, or delivers it again
+    DeadLetters {
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        args: Vec<String>,
+    },
+    /// Deletes the stored mail past its retention, once
+    Cleanup,
+    /// Exports the stored mail to an mbox file
+    #[cfg(feature = "sqlite")]
+    Export {
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        args: Vec<String>,
+    },
+}
+
+/// A listener port, or `off` to leave the listener out
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Port {
+    Off,
+    On(u16),
+}
+
+impl FromStr for Port {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(port: &str) -> Result<Self, Self::Err> {
+        match port {
+            "off" => Ok(Port::Off),
+            port => port.parse().map(Port::On),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Port {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Number(u16),
+            Text(String),
+        }
+        match Raw::deserialize(deserializer)? {
+            Raw::Number(port) => Ok(Port::On(port)),
+            Raw::Text(port) => port.parse().map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+/// Settings of the server. Each can be set in the TOML file, in kebab-case,
+/// or with a flag or environment variable, which override the file.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, clap::Args)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    /// Domain the server answers as [default: smtp.deepwith.in]
+    #[arg(long, env = "DOMAIN")]
+    pub domain: Option<String>,
+    /// Port of the SMTP listener, or `off` [default: 25]
+    #[arg(long, env = "PORT")]
+    pub port: Option<Port>,
+    /// Port of the implicit TLS (SMTPS) listener, usually 465
+    #[arg(long, env = "SMTPS_PORT")]
+    pub smtps_port: Option<u16>,
+    /// Port of the submission listener for authenticated clients, usually 587
+    #[arg(long, env = "SUBMISSION_PORT")]
+    pub submission_port: Option<u16>,
+    /// Unix socket local MTAs hand mail off on, speaking SMTP or LMTP
+    #[arg(long, env = "UNIX_SOCKET")]
+    pub unix_socket: Option<PathBuf>,
+    /// Permissions of the Unix socket, in octal [default: 660]
+    #[arg(long, env = "UNIX_SOCKET_MODE")]
+    pub unix_socket_mode: Option<String>,
+    /// Endpoint the messages are posted to,
+    /// e.g. https://worker-email-production.deepgauravraj.workers.dev/api/email
+    #[arg(long, env = "FORWARD_URL")]
+    pub forward_url: Option<String>,
+    /// Endpoints tried in order when the forward URL is down or fails with a 5xx,
+    /// comma-separated
+    #[arg(long, env = "FALLBACK_URLS", value_delimiter = ',')]
+    pub fallback_urls: Vec<String>,
+    /// More endpoints each getting a copy of the messages, comma-separated,
+    /// with the same token and secret
+    #[arg(long, env = "FANOUT_URLS", value_delimiter = ',')]
+    pub fanout_urls: Vec<String>,
+    /// Sent to the endpoint as the `Authorization` header
+    #[arg(long, env = "EMAIL_TOKEN", hide_env_values = true)]
+    pub email_token: Option<String>,
+    /// Secret the requests to the endpoint are signed with, in `X-Edgemail-Signature`
+    #[arg(long, env = "WEBHOOK_SECRET", hide_env_values = true)]
+    pub webhook_secret: Option<String>,
+    /// Format the messages are posted in: `json`, `sendgrid` and `mailgun` for
+    /// receivers written against SendGrid's Inbound Parse or Mailgun Routes,
+    /// `cloudevents` for event routers, or the binary `msgpack` and `protobuf`
+    /// [default: json]
+    #[arg(long, env = "FORWARD_FORMAT")]
+    pub forward_format: Option<Format>,
+    /// Compress the request bodies, `gzip` or `deflate`, sent as `Content-Encoding`
+    #[arg(long, env = "COMPRESSION")]
+    pub compression: Option<Compression>,
+    /// Size in bytes above which request bodies are compressed [default: 1024]
+    #[arg(long, env = "COMPRESSION_THRESHOLD")]
+    pub compression_threshold: Option<usize>,
+    /// PEM certificate chain, offering STARTTLS along with the key
+    #[arg(long, env = "TLS_CERT")]
+    pub tls_cert: Option<PathBuf>,
+    /// PEM private key of the certificate
+    #[arg(long, env = "TLS_KEY")]
+    pub tls_key: Option<PathBuf>,
+    /// Take AUTH PLAIN and LOGIN without TLS, the passwords crossing the wire
+    /// in the clear, rather than refusing them with a 538 [default: false]
+    #[arg(long, env = "ALLOW_INSECURE_AUTH", value_parser = BoolishValueParser::new())]
+    pub allow_insecure_auth: Option<bool>,
+    /// Deliver each mail before replying to its DATA, the endpoint's verdict
+    /// deciding the reply: 2xx accepts, 403 and 410 reject, anything else defers
+    #[arg(long, env = "SYNC_DELIVERY", value_parser = BoolishValueParser::new())]
+    pub sync_delivery: Option<bool>,
+    /// Journal of the accepted mail [default: smtp_forward.wal]
+    #[arg(long, env = "WAL_PATH")]
+    pub wal_path: Option<PathBuf>,
+    /// Record of the deliveries [default: smtp_forward.ledger]
+    #[arg(long, env = "LEDGER_PATH")]
+    pub ledger_path: Option<PathBuf>,
+    /// Directory the mail given up on is kept in [default: dead-letters]
+    #[arg(long, env = "DEAD_LETTER_DIR")]
+    pub dead_letter_dir: Option<PathBuf>,
+    /// SQLite database the accepted mail is kept in until it is delivered,
+    /// created if needed
+    #[arg(long, env = "SPOOL_PATH")]
+    pub spool_path: Option<PathBuf>,
+    /// Days deliveries are kept in the ledger [default: 7]
+    #[arg(long, env = "LEDGER_RETENTION_DAYS")]
+    pub ledger_retention_days: Option<i64>,
+    /// Sessions served at once on the TCP listeners [default: 1000]
+    #[arg(long, env = "MAX_CONNECTIONS")]
+    pub max_connections: Option<usize>,
+    /// Sessions served at once for a single client address [default: no limit]
+    #[arg(long, env = "MAX_CONNECTIONS_PER_IP")]
+    pub max_connections_per_ip: Option<usize>,
+    /// Largest message accepted, in bytes [default: 25 MiB]
+    #[arg(long, env = "MAX_MESSAGE_SIZE")]
+    pub max_message_size: Option<usize>,
+    /// Bad commands a client may send before it is disconnected [default: 10]
+    #[arg(long, env = "MAX_PROTOCOL_ERRORS")]
+    pub max_protocol_errors: Option<usize>,
+    /// Seconds a client has for each command [default: 300]
+    #[arg(long, env = "COMMAND_TIMEOUT")]
+    pub command_timeout: Option<u64>,
+    /// Seconds a client has for its whole session [default: no limit]
+    #[arg(long, env = "SESSION_TIMEOUT")]
+    pub session_timeout: Option<u64>,
+    /// Milliseconds the greeting is held back, catching clients talking before it
+    /// [default: none]
+    #[arg(long, env = "GREETING_DELAY")]
+    pub greeting_delay: Option<u64>,
+    /// Turn away the clients talking before the greeting, rather than tarpitting them
+    #[arg(long, env = "REJECT_EARLY_TALKERS", value_parser = BoolishValueParser::new())]
+    pub reject_early_talkers: Option<bool>,
+    /// Rewriting of the URLs in the bodies, `defang` or `redirect:<prefix>`
+    #[arg(long, env = "URL_REWRITE")]
+    pub url_rewrite: Option<String>,
+    /// Deliveries to the endpoint running at once [default: 4]
+    #[arg(long, env = "DELIVERY_CONCURRENCY")]
+    pub delivery_concurrency: Option<usize>,
+    /// Requests per second sent to the endpoint at most [default: no limit]
+    #[arg(long, env = "DELIVERY_RATE")]
+    pub delivery_rate: Option<f64>,
+    /// Recipients whose mail jumps the queue, addresses or `@domain`s, comma-separated
+    #[arg(long, env = "PRIORITY_RECIPIENTS", value_delimiter = ',')]
+    pub priority_recipients: Vec<String>,
+    /// Size in bytes above which messages are delivered last [default: 1 MiB]
+    #[arg(long, env = "BULK_SIZE")]
+    pub bulk_size: Option<usize>,
+    /// Delivery schedule, `;`-separated rules `<matcher> <defer>`: the matcher is
+    /// `bulk`, `to:<address|@domain>` or `from:<address|@domain>`, the deferral
+    /// `delay:<minutes>` or `window:<HH:MM>-<HH:MM>`
+    #[arg(long, env = "SCHEDULE")]
+    pub schedule: Option<String>,
+    /// Messages posted per request, as a JSON array [default: 1]
+    #[arg(long, env = "BATCH_SIZE")]
+    pub batch_size: Option<usize>,
+    /// Milliseconds a batch waits to fill up [default: 1000]
+    #[arg(long, env = "BATCH_WINDOW")]
+    pub batch_window: Option<u64>,
+    /// Deliveries attempted before a mail is given up on [default: 10]
+    #[arg(long, env = "RETRY_MAX_ATTEMPTS")]
+    pub retry_max_attempts: Option<u32>,
+    /// Seconds before the first retry, doubling with each one after [default: 30]
+    #[arg(long, env = "RETRY_BASE_DELAY")]
+    pub retry_base_delay: Option<u64>,
+    /// Longest wait between two retries, in seconds [default: 3600]
+    #[arg(long, env = "RETRY_MAX_DELAY")]
+    pub retry_max_delay: Option<u64>,
+    /// Fraction of the delays taken off at random, between 0 and 1 [default: 0.2]
+    #[arg(long, env = "RETRY_JITTER")]
+    pub retry_jitter: Option<f64>,
+    /// What becomes of the mail whose Message-ID was accepted already: `skip`
+    /// acknowledges it without delivering it, `flag` delivers it marked as a duplicate
+    #[arg(long, env = "DUPLICATES")]
+    pub duplicates: Option<String>,
+    /// File the Message-IDs of the accepted mail are kept in
+    /// [default: smtp_forward.message-ids]
+    #[arg(long, env = "MESSAGE_ID_PATH")]
+    pub message_id_path: Option<PathBuf>,
+    /// Message-IDs remembered [default: 100000]
+    #[arg(long, env = "MESSAGE_ID_CAPACITY")]
+    pub message_id_capacity: Option<usize>,
+    /// JSON file of the tenants, each with its recipients and its own destination
+    #[arg(long, env = "TENANTS")]
+    pub tenants: Option<PathBuf>,
+    /// Faults injected to soak-test the retries, comma-separated `<fault>=<probability>`
+    /// with faults `sink_timeout`, `sink_error`, `storage_error` and `disconnect`
+    #[arg(long, env = "CHAOS")]
+    pub chaos: Option<String>,
+    /// Lease file of a high-availability pair sharing the WAL, electing which
+    /// instance delivers
+    #[arg(long, env = "LEADER_LEASE")]
+    pub leader_lease: Option<PathBuf>,
+    /// Seconds the lease is held for without being renewed [default: 15]
+    #[arg(long, env = "LEADER_LEASE_TTL")]
+    pub leader_lease_ttl: Option<i64>,
+    /// Name of the instance in the lease file [default: host name and process id]
+    #[arg(long, env = "INSTANCE_ID")]
+    pub instance_id: Option<String>,
+    /// Name of the host, in the Maildir file names and the relay's EHLO
+    /// [default: localhost]
+    #[arg(long, env = "HOSTNAME")]
+    pub hostname: Option<String>,
+    /// Log of the metered usage [default: smtp_forward.usage]
+    #[arg(long, env = "USAGE_PATH")]
+    pub usage_path: Option<PathBuf>,
+    /// Seconds between two usage reports [default: 3600]
+    #[arg(long, env = "USAGE_INTERVAL")]
+    pub usage_interval: Option<u64>,
+    /// Billing endpoint each period's usage is posted to
+    #[arg(long, env = "USAGE_WEBHOOK")]
+    pub usage_webhook: Option<String>,
+    /// Sent to the billing endpoint as the `Authorization` header
+    #[arg(long, env = "USAGE_WEBHOOK_TOKEN", hide_env_values = true)]
+    pub usage_webhook_token: Option<String>,
+    /// Days the mail stored in the database, the .eml directory, the Maildir and
+    /// the dead letters is kept [default: forever]
+    #[arg(long, env = "RETENTION_DAYS")]
+    pub retention_days: Option<i64>,
+    /// Retention of the mail of some recipient domains, comma-separated `domain=days`
+    #[arg(long, env = "RETENTION_DOMAINS")]
+    pub retention_domains: Option<String>,
+    /// Seconds between two cleanups of the stored mail [default: 3600]
+    #[arg(long, env = "RETENTION_INTERVAL")]
+    pub retention_interval: Option<u64>,
+    /// Address the admin API listens on [default: disabled]
+    #[arg(long, env = "ADMIN_ADDR")]
+    pub admin_addr: Option<SocketAddr>,
+    /// Bearer token the admin API requires from clients
+    #[arg(long, env = "ADMIN_TOKEN", hide_env_values = true)]
+    pub admin_token: Option<String>,
+    /// Longest edge in pixels of the previews of the image attachments
+    /// [default: no previews]
+    #[arg(long, env = "THUMBNAIL_SIZE")]
+    pub thumbnail_size: Option<u32>,
+    /// Blocklist files the URLs in the bodies are checked against, comma-separated
+    #[arg(long, env = "URL_BLOCKLISTS", value_delimiter = ',')]
+    pub url_blocklists: Vec<PathBuf>,
+    /// Remove the tracking pixels and remote content from HTML parts [default: false]
+    #[arg(long, env = "STRIP_REMOTE_CONTENT", value_parser = BoolishValueParser::new())]
+    pub strip_remote_content: Option<bool>,
+    /// Add the message as received to the payload, base64-encoded [default: false]
+    #[arg(long, env = "INCLUDE_RAW", value_parser = BoolishValueParser::new())]
+    pub include_raw: Option<bool>,
+    /// S3-compatible bucket large attachments are uploaded to, linked to in the payload
+    #[arg(long, env = "S3_BUCKET")]
+    pub s3_bucket: Option<String>,
+    /// URL of the S3-compatible service, e.g. https://s3.eu-west-1.amazonaws.com
+    #[arg(long, env = "S3_ENDPOINT")]
+    pub s3_endpoint: Option<String>,
+    /// Region of the S3-compatible service [default: auto]
+    #[arg(long, env = "S3_REGION")]
+    pub s3_region: Option<String>,
+    /// Access key of the S3-compatible service
+    #[arg(long, env = "S3_ACCESS_KEY_ID")]
+    pub s3_access_key_id: Option<String>,
+    /// Secret of the access key
+    #[arg(long, env = "S3_SECRET_ACCESS_KEY", hide_env_values = true)]
+    pub s3_secret_access_key: Option<String>,
+    /// Seconds the links to the uploaded attachments stay valid [default: 7 days]
+    #[arg(long, env = "S3_URL_EXPIRY")]
+    pub s3_url_expiry: Option<u64>,
+    /// Size in bytes above which attachments are uploaded [default: 1 MiB]
+    #[arg(long, env = "OFFLOAD_THRESHOLD")]
+    pub offload_threshold: Option<usize>,
+    /// Bucket the raw messages are archived to, on the S3-compatible service
+    #[arg(long, env = "ARCHIVE_BUCKET")]
+    pub archive_bucket: Option<String>,
+    /// Maildir the messages are delivered to, created if needed
+    #[arg(long, env = "MAILDIR")]
+    pub maildir: Option<PathBuf>,
+    /// mbox file the messages are appended to, created if needed
+    #[arg(long, env = "MBOX_PATH")]
+    pub mbox_path: Option<PathBuf>,
+    /// Directory each message is dumped to as a .eml file, created if needed
+    #[arg(long, env = "EML_DIR")]
+    pub eml_dir: Option<PathBuf>,
+    /// SQLite database the messages are stored in, created if needed
+    #[arg(long, env = "DATABASE_PATH")]
+    pub database_path: Option<PathBuf>,
+    /// Bootstrap servers of the Kafka cluster the messages are published to,
+    /// comma-separated
+    #[arg(long, env = "KAFKA_BROKERS")]
+    pub kafka_brokers: Option<String>,
+    /// Kafka topic the messages are published to
+    #[arg(long, env = "KAFKA_TOPIC")]
+    pub kafka_topic: Option<String>,
+    /// Acknowledgements waited for from Kafka, `all`, `1` or `0` [default: all]
+    #[arg(long, env = "KAFKA_ACKS")]
+    pub kafka_acks: Option<String>,
+    /// Format of the Kafka records, `json`, `msgpack` or `protobuf` [default: json]
+    #[arg(long, env = "KAFKA_FORMAT")]
+    pub kafka_format: Option<Format>,
+    /// URL of the SQS queue the messages are sent to
+    #[arg(long, env = "SQS_QUEUE_URL")]
+    pub sqs_queue_url: Option<String>,
+    /// ARN of the SNS topic the messages are published to
+    #[arg(long, env = "SNS_TOPIC_ARN")]
+    pub sns_topic_arn: Option<String>,
+    /// Pub/Sub topic the messages are published to, its full name or its id
+    /// in the project of the service account
+    #[arg(long, env = "PUBSUB_TOPIC")]
+    pub pubsub_topic: Option<String>,
+    /// Format of the Pub/Sub messages, `json`, `msgpack` or `protobuf` [default: json]
+    #[arg(long, env = "PUBSUB_FORMAT")]
+    pub pubsub_format: Option<Format>,
+    /// Pub/Sub emulator the messages go to instead, unauthenticated
+    #[arg(long, env = "PUBSUB_EMULATOR_HOST")]
+    pub pubsub_emulator_host: Option<String>,
+    /// Key file of the service account publishing to Pub/Sub
+    #[arg(long, env = "GOOGLE_APPLICATION_CREDENTIALS")]
+    pub google_application_credentials: Option<PathBuf>,
+    /// Project of the Pub/Sub topic [default: the service account's]
+    #[arg(long, env = "PUBSUB_PROJECT_ID")]
+    pub pubsub_project_id: Option<String>,
+    /// Redis server the messages are added to a stream of, e.g. redis://localhost:6379/0
+    #[arg(long, env = "REDIS_URL")]
+    pub redis_url: Option<String>,
+    /// Redis stream the messages are added to [default: smtp_forward]
+    #[arg(long, env = "REDIS_STREAM")]
+    pub redis_stream: Option<String>,
+    /// Entries the Redis stream is trimmed to [default: unbounded]
+    #[arg(long, env = "REDIS_STREAM_MAXLEN")]
+    pub redis_stream_maxlen: Option<usize>,
+    /// Redis channel each entry is announced on with PUBLISH
+    #[arg(long, env = "REDIS_CHANNEL")]
+    pub redis_channel: Option<String>,
+    /// Format of the Redis entries, `json`, `msgpack` or `protobuf` [default: json]
+    #[arg(long, env = "REDIS_FORMAT")]
+    pub redis_format: Option<Format>,
+    /// Relays the messages over SMTP: `mx` to the mail exchangers of the recipients,
+    /// or the `host:port` of a smarthost, port 587 by default
+    #[arg(long, env = "RELAY")]
+    pub relay: Option<String>,
+    /// Port of the mail exchangers relayed to [default: 25]
+    #[arg(long, env = "RELAY_PORT")]
+    pub relay_port: Option<u16>,
+    /// Name the relay sends in EHLO [default: the host name]
+    #[arg(long, env = "RELAY_HELO_NAME")]
+    pub relay_helo_name: Option<String>,
+    /// STARTTLS of the relay, `never`, `opportunistic` or `required`
+    /// [default: opportunistic]
+    #[arg(long, env = "RELAY_TLS")]
+    pub relay_tls: Option<String>,
+    /// User the relay authenticates as
+    #[arg(long, env = "RELAY_USERNAME")]
+    pub relay_username: Option<String>,
+    /// Password the relay authenticates with
+    #[arg(long, env = "RELAY_PASSWORD", hide_env_values = true)]
+    pub relay_password: Option<String>,
+    /// File of the aliases the relay forwards the mail of, one per line:
+    /// the local address then its destinations, comma-separated. The recipients
+    /// of unauthenticated clients without one are refused.
+    #[arg(long, env = "ALIASES")]
+    pub aliases: Option<PathBuf>,
+    /// Key of the SRS hashes, rewriting the senders of the relayed mail
+    #[arg(long, env = "SRS_SECRET", hide_env_values = true)]
+    pub srs_secret: Option<String>,
+    /// Domain of the SRS addresses, one this server receives mail for
+    #[arg(long, env = "SRS_DOMAIN")]
+    pub srs_domain: Option<String>,
+    /// Days bounces to SRS addresses are taken back [default: 21]
+    #[arg(long, env = "SRS_MAX_AGE")]
+    pub srs_max_age: Option<u16>,
+    /// RSA private key the relayed mail is signed with, PEM-encoded in PKCS#8 or PKCS#1
+    #[arg(long, env = "DKIM_PRIVATE_KEY")]
+    pub dkim_private_key: Option<PathBuf>,
+    /// Domain of the DKIM signatures
+    #[arg(long, env = "DKIM_DOMAIN")]
+    pub dkim_domain: Option<String>,
+    /// Selector of the DKIM public key
+    #[arg(long, env = "DKIM_SELECTOR")]
+    pub dkim_selector: Option<String>,
+    /// authserv-id of the `Authentication-Results` whose results the relay seals
+    /// in an ARC set, signed with the DKIM key
+    #[arg(long, env = "ARC_AUTHSERV_ID")]
+    pub arc_authserv_id: Option<String>,
+    /// JSON file mapping the users clients authenticate as to their passwords.
+    /// Without it, AUTH is acknowledged without checking
+    #[arg(long, env = "AUTH_USERS")]
+    pub auth_users: Option<PathBuf>,
+    /// JSON file mapping the XOAUTH2 tokens to their users
+    #[arg(long, env = "XOAUTH2_TOKENS")]
+    pub xoauth2_tokens: Option<PathBuf>,
+    /// Introspection endpoint validating the XOAUTH2 tokens
+    #[arg(long, env = "XOAUTH2_INTROSPECTION_URL")]
+    pub xoauth2_introspection_url: Option<String>,
+    /// Client the introspection endpoint is queried as
+    #[arg(long, env = "XOAUTH2_CLIENT_ID")]
+    pub xoauth2_client_id: Option<String>,
+    /// Secret of the introspection client
+    #[arg(long, env = "XOAUTH2_CLIENT_SECRET", hide_env_values = true)]
+    pub xoauth2_client_secret: Option<String>,
+    /// Audience the XOAUTH2 tokens must be issued for [default: the client id]
+    #[arg(long, env = "XOAUTH2_AUDIENCE")]
+    pub xoauth2_audience: Option<String>,
+    /// Refuse the clients introducing themselves with an address [default: false]
+    #[arg(long, env = "HELO_REJECT_IP", value_parser = BoolishValueParser::new())]
+    pub helo_reject_ip: Option<bool>,
+    /// Refuse the clients introducing themselves with our domain [default: false]
+    #[arg(long, env = "HELO_REJECT_OWN_DOMAIN", value_parser = BoolishValueParser::new())]
+    pub helo_reject_own_domain: Option<bool>,
+    /// Refuse the clients introducing themselves with a name that is not fully
+    /// qualified [default: false]
+    #[arg(long, env = "HELO_REQUIRE_FQDN", value_parser = BoolishValueParser::new())]
+    pub helo_require_fqdn: Option<bool>,
+    /// Domains whose mail is ours, comma-separated [default: any]
+    #[arg(long, env = "LOCAL_DOMAINS", value_delimiter = ',')]
+    pub local_domains: Vec<String>,
+    /// Domains mail is accepted for on behalf of others, comma-separated
+    #[arg(long, env = "RELAY_DOMAINS", value_delimiter = ',')]
+    pub relay_domains: Vec<String>,
+    /// File of the rules recipients are accepted by, read again on SIGHUP
+    /// [default: denying the administrative addresses]
+    #[arg(long, env = "RECIPIENT_RULES")]
+    pub recipient_rules: Option<PathBuf>,
+    /// Mail accepted from each sender domain per hour, only the mail SPF
+    /// authorized counting when checked [default: no limit]
+    #[arg(long, env = "SENDER_MESSAGES_PER_HOUR")]
+    pub sender_messages_per_hour: Option<u32>,
+    /// Quotas of some sender domains, comma-separated `domain=messages`
+    #[arg(long, env = "SENDER_QUOTAS")]
+    pub sender_quotas: Option<String>,
+    /// Milliseconds the first reply to a suspicious client is delayed, doubling
+    /// with each one after [default: no tarpit]
+    #[arg(long, env = "TARPIT_DELAY")]
+    pub tarpit_delay: Option<u64>,
+    /// Longest delay of a reply to a suspicious client, in milliseconds [default: 30000]
+    #[arg(long, env = "TARPIT_MAX_DELAY")]
+    pub tarpit_max_delay: Option<u64>,
+    /// Protocol errors making a client suspicious [default: 3]
+    #[arg(long, env = "TARPIT_ERRORS")]
+    pub tarpit_errors: Option<usize>,
+    /// Refused recipients making a client suspicious [default: 3]
+    #[arg(long, env = "TARPIT_REFUSED_RECIPIENTS")]
+    pub tarpit_refused_recipients: Option<usize>,
+    /// Connections accepted from each client address per minute [default: no limit]
+    #[arg(long, env = "CLIENT_CONNECTIONS_PER_MINUTE")]
+    pub client_connections_per_minute: Option<u32>,
+    /// Mail accepted from each client address per hour [default: no limit]
+    #[arg(long, env = "CLIENT_MESSAGES_PER_HOUR")]
+    pub client_messages_per_hour: Option<u32>,
+    /// Recipients a mail may have [default: no limit]
+    #[arg(long, env = "CLIENT_RECIPIENTS_PER_MESSAGE")]
+    pub client_recipients_per_message: Option<usize>,
+    /// Check the sender's domain allows the client with SPF [default: false]
+    #[arg(long, env = "SPF_CHECK", value_parser = BoolishValueParser::new())]
+    pub spf_check: Option<bool>,
+    /// Verify the DKIM signatures of the messages [default: false]
+    #[arg(long, env = "DKIM_CHECK", value_parser = BoolishValueParser::new())]
+    pub dkim_check: Option<bool>,
+    /// Evaluate DMARC, checking SPF and DKIM with it [default: false]
+    #[arg(long, env = "DMARC_CHECK", value_parser = BoolishValueParser::new())]
+    pub dmarc_check: Option<bool>,
+    /// Refuse the mail failing SPF, rather than only reporting it [default: false]
+    #[arg(long, env = "SPF_REJECT_FAIL", value_parser = BoolishValueParser::new())]
+    pub spf_reject_fail: Option<bool>,
+    /// What is done of mail failing DMARC, at most what its domain's policy says:
+    /// `none`, `quarantine` or `reject` [default: none]
+    #[arg(long, env = "DMARC_ENFORCE")]
+    pub dmarc_enforce: Option<String>,
+    /// DNS blocklists the clients are looked up in, comma-separated, each `<zone>`
+    /// or `<zone>*<weight>`
+    #[arg(long, env = "DNSBL_ZONES", value_delimiter = ',')]
+    pub dnsbl_zones: Vec<String>,
+    /// Score from the blocklists from which clients are refused [default: 1]
+    #[arg(long, env = "DNSBL_THRESHOLD")]
+    pub dnsbl_threshold: Option<u32>,
+    /// Only report the listed clients, rather than refusing them [default: false]
+    #[arg(long, env = "DNSBL_TAG_ONLY", value_parser = BoolishValueParser::new())]
+    pub dnsbl_tag_only: Option<bool>,
+    /// Check the reverse DNS of the clients [default: false]
+    #[arg(long, env = "IPREV_CHECK", value_parser = BoolishValueParser::new())]
+    pub iprev_check: Option<bool>,
+    /// Refuse the clients without a confirmed reverse DNS name [default: false]
+    #[arg(long, env = "IPREV_REJECT_FAIL", value_parser = BoolishValueParser::new())]
+    pub iprev_reject_fail: Option<bool>,
+    /// Name of the server in the `Authentication-Results` field [default: the domain]
+    #[arg(long, env = "AUTHSERV_ID")]
+    pub authserv_id: Option<String>,
+    /// Normal worker of a local rspamd scanning the mail, e.g. http://localhost:11333
+    #[arg(long, env = "RSPAMD_URL")]
+    pub rspamd_url: Option<String>,
+    /// Password rspamd requires
+    #[arg(long, env = "RSPAMD_PASSWORD", hide_env_values = true)]
+    pub rspamd_password: Option<String>,
+    /// Spam score mail is refused from [default: rspamd's reject action]
+    #[arg(long, env = "RSPAMD_REJECT_SCORE")]
+    pub rspamd_reject_score: Option<f64>,
+    /// Spam score mail is tagged as spam from [default: rspamd's add header action]
+    #[arg(long, env = "RSPAMD_TAG_SCORE")]
+    pub rspamd_tag_score: Option<f64>,
+    /// Milliseconds a scan may take [default: 10000]
+    #[arg(long, env = "RSPAMD_TIMEOUT")]
+    pub rspamd_timeout: Option<u64>,
+}
+
+impl Config {
+    /// Parses the command line, then reads the configuration file it names, if any
+    pub fn load() -> Result<(Option<Command>, Self)> {
+        let cli = Cli::parse();
+        let config = match &cli.config {
+            Some(path) => cli.flags.or(Self::read(path)?),
+            None => cli.flags,
+        };
+        Ok((cli.command, config))
+    }
+
+    /// Reads a TOML configuration file
+    pub fn read(path: &Path) -> Result<Self> {
+        let toml = std::fs::read_to_string(path)
+            .with_context(|| format!("cannot read {}", path.display()))?;
+        toml::from_str(&toml).with_context(|| format!("invalid {}", path.display()))
+    }
+
+    /// Takes the settings missing here from `other`
+    pub fn or(self, other: Self) -> Self {
+        Self {
+            domain: self.domain.or(other.domain),
+            port: self.port.or(other.port),
+            smtps_port: self.smtps_port.or(other.smtps_port),
+            submission_port: self.submission_port.or(other.submission_port),
+            unix_socket: self.unix_socket.or(other.unix_socket),
+            unix_socket_mode: self.unix_socket_mode.or(other.unix_socket_mode),
+            forward_url: self.forward_url.or(other.forward_url),
+            fallback_urls: match self.fallback_urls.is_empty() {
+                true => other.fallback_urls,
+                false => self.fallback_urls,
+            },
+            fanout_urls: match self.fanout_urls.is_empty() {
+                true => other.fanout_urls,
+                false => self.fanout_urls,
+            },
+            email_token: self.email_token.or(other.email_token),
+            webhook_secret: self.webhook_secret.or(other.webhook_secret),
+            forward_format: self.forward_format.or(other.forward_format),
+            compression: self.compression.or(other.compression),
+            compression_threshold: self.compression_threshold.or(other.compression_threshold),
+            tls_cert: self.tls_cert.or(other.tls_cert),
+            tls_key: self.tls_key.or(other.tls_key),
+            allow_insecure_auth: self.allow_insecure_auth.or(other.allow_insecure_auth),
+            sync_delivery: self.sync_delivery.or(other.sync_delivery),
+            wal_path: self.wal_path.or(other.wal_path),
+            ledger_path: self.ledger_path.or(other.ledger_path),
+            dead_letter_dir: self.dead_letter_dir.or(other.dead_letter_dir),
+            spool_path: self.spool_path.or(other.spool_path),
+            ledger_retention_days: self.ledger_retention_days.or(other.ledger_retention_days),
+            max_connections: self.max_connections.or(other.max_connections),
+            max_connections_per_ip: self.max_connections_per_ip.or(other.max_connections_per_ip),
+            max_message_size: self.max_message_size.or(other.max_message_size),
+            max_protocol_errors: self.max_protocol_errors.or(other.max_protocol_errors),
+            command_timeout: self.command_timeout.or(other.command_timeout),
+            session_timeout: self.session_timeout.or(other.session_timeout),
+            greeting_delay: self.greeting_delay.or(other.greeting_delay),
+            reject_early_talkers: self.reject_early_talkers.or(other.reject_early_talkers),
+            url_rewrite: self.url_rewrite.or(other.url_rewrite),
+            delivery_concurrency: self.delivery_concurrency.or(other.delivery_concurrency),
+            delivery_rate: self.delivery_rate.or(other.delivery_rate),
+            priority_recipients: match self.priority_recipients.is_empty() {
+                true => other.priority_recipients,
+                false => self.priority_recipients,
+            },
+            bulk_size: self.bulk_size.or(other.bulk_size),
+            schedule: self.schedule.or(other.schedule),
+            batch_size: self.batch_size.or(other.batch_size),
+            batch_window: self.batch_window.or(other.batch_window),
+            retry_max_attempts: self.retry_max_attempts.or(other.retry_max_attempts),
+            retry_base_delay: self.retry_base_delay.or(other.retry_base_delay),
+            retry_max_delay: self.retry_max_delay.or(other.retry_max_delay),
+            retry_jitter: self.retry_jitter.or(other.retry_jitter),
+            duplicates: self.duplicates.or(other.duplicates),
+            message_id_path: self.message_id_path.or(other.message_id_path),
+            message_id_capacity: self.message_id_capacity.or(other.message_id_capacity),
+            tenants: self.tenants.or(other.tenants),
+            chaos: self.chaos.or(other.chaos),
+            leader_lease: self.leader_lease.or(other.leader_lease),
+            leader_lease_ttl: self.leader_lease_ttl.or(other.leader_lease_ttl),
+            instance_id: self.instance_id.or(other.instance_id),
+            hostname: self.hostname.or(other.hostname),
+            usage_path: self.usage_path.or(other.usage_path),
+            usage_interval: self.usage_interval.or(other.usage_interval),
+            usage_webhook: self.usage_webhook.or(other.usage_webhook),
+            usage_webhook_token: self.usage_webhook_token.or(other.usage_webhook_token),
+            retention_days: self.retention_days.or(other.retention_days),
+            retention_domains: self.retention_domains.or(other.retention_domains),
+            retention_interval: self.retention_interval.or(other.retention_interval),
+            admin_addr: self.admin_addr.or(other.admin_addr),
+            admin_token: self.admin_token.or(other.admin_token),
+            thumbnail_size: self.thumbnail_size.or(other.thumbnail_size),
+            url_blocklists: match self.url_blocklists.is_empty() {
+                true => other.url_blocklists,
+                false => self.url_blocklists,
+            },
+            strip_remote_content: self.strip_remote_content.or(other.strip_remote_content),
+            include_raw: self.include_raw.or(other.include_raw),
+            s3_bucket: self.s3_bucket.or(other.s3_bucket),
+            s3_endpoint: self.s3_endpoint.or(other.s3_endpoint),
+            s3_region: self.s3_region.or(other.s3_region),
+            s3_access_key_id: self.s3_access_key_id.or(other.s3_access_key_id),
+            s3_secret_access_key: self.s3_secret_access_key.or(other.s3_secret_access_key),
+            s3_url_expiry: self.s3_url_expiry.or(other.s3_url_expiry),
+            offload_threshold: self.offload_threshold.or(other.offload_threshold),
+            archive_bucket: self.archive_bucket.or(other.archive_bucket),
+            maildir: self.maildir.or(other.maildir),
+            mbox_path: self.mbox_path.or(other.mbox_path),
+            eml_dir: self.eml_dir.or(other.eml_dir),
+            database_path: self.database_path.or(other.database_path),
+            kafka_brokers: self.kafka_brokers.or(other.kafka_brokers),
+            kafka_topic: self.kafka_topic.or(other.kafka_topic),
+            kafka_acks: self.kafka_acks.or(other.kafka_acks),
+            kafka_format: self.kafka_format.or(other.kafka_format),
+            sqs_queue_url: self.sqs_queue_url.or(other.sqs_queue_url),
+            sns_topic_arn: self.sns_topic_arn.or(other.sns_topic_arn),
+            pubsub_topic: self.pubsub_topic.or(other.pubsub_topic),
+            pubsub_format: self.pubsub_format.or(other.pubsub_format),
+            pubsub_emulator_host: self.pubsub_emulator_host.or(other.pubsub_emulator_host),
+            google_application_credentials: self
+                .google_application_credentials
+                .or(other.google_application_credentials),
+            pubsub_project_id: self.pubsub_project_id.or(other.pubsub_project_id),
+            redis_url: self.redis_url.or(other.redis_url),
+            redis_stream: self.redis_stream.or(other.redis_stream),
+            redis_stream_maxlen: self.redis_stream_maxlen.or(other.redis_stream_maxlen),
+            redis_channel: self.redis_channel.or(other.redis_channel),
+            redis_format: self.redis_format.or(other.redis_format),
+            relay: self.relay.or(other.relay),
+            relay_port: self.relay_port.or(other.relay_port),
+            relay_helo_name: self.relay_helo_name.or(other.relay_helo_name),
+            relay_tls: self.relay_tls.or(other.relay_tls),
+            relay_username: self.relay_username.or(other.relay_username),
+            relay_password: self.relay_password.or(other.relay_password),
+            aliases: self.aliases.or(other.aliases),
+            srs_secret: self.srs_secret.or(other.srs_secret),
+            srs_domain: self.srs_domain.or(other.srs_domain),
+            srs_max_age: self.srs_max_age.or(other.srs_max_age),
+            dkim_private_key: self.dkim_private_key.or(other.dkim_private_key),
+            dkim_domain: self.dkim_domain.or(other.dkim_domain),
+            dkim_selector: self.dkim_selector.or(other.dkim_selector),
+            arc_authserv_id: self.arc_authserv_id.or(other.arc_authserv_id),
+            auth_users: self.auth_users.or(other.auth_users),
+            xoauth2_tokens: self.xoauth2_tokens.or(other.xoauth2_tokens),
+            xoauth2_introspection_url: self
+                .xoauth2_introspection_url
+                .or(other.xoauth2_introspection_url),
+            xoauth2_client_id: self.xoauth2_client_id.or(other.xoauth2_client_id),
+            xoauth2_client_secret: self.xoauth2_client_secret.or(other.xoauth2_client_secret),
+            xoauth2_audience: self.xoauth2_audience.or(other.xoauth2_audience),
+            helo_reject_ip: self.helo_reject_ip.or(other.helo_reject_ip),
+            helo_reject_own_domain: self.helo_reject_own_domain.or(other.helo_reject_own_domain),
+            helo_require_fqdn: self.helo_require_fqdn.or(other.helo_require_fqdn),
+            local_domains: match self.local_domains.is_empty() {
+                true => other.local_domains,
+                false => self.local_domains,
+            },
+            relay_domains: match self.relay_domains.is_empty() {
+                true => other.relay_domains,
+                false => self.relay_domains,
+            },
+            recipient_rules: self.recipient_rules.or(other.recipient_rules),
+            sender_messages_per_hour: self
+                .sender_messages_per_hour
+                .or(other.sender_messages_per_hour),
+            sender_quotas: self.sender_quotas.or(other.sender_quotas),
+            tarpit_delay: self.tarpit_delay.or(other.tarpit_delay),
+            tarpit_max_delay: self.tarpit_max_delay.or(other.tarpit_max_delay),
+            tarpit_errors: self.tarpit_errors.or(other.tarpit_errors),
+            tarpit_refused_recipients: self
+                .tarpit_refused_recipients
+                .or(other.tarpit_refused_recipients),
+            client_connections_per_minute: self
+                .client_connections_per_minute
+                .or(other.client_connections_per_minute),
+            client_messages_per_hour: self
+                .client_messages_per_hour
+                .or(other.client_messages_per_hour),
+            client_recipients_per_message: self
+                .client_recipients_per_message
+                .or(other.client_recipients_per_message),
+            spf_check: self.spf_check.or(other.spf_check),
+            dkim_check: self.dkim_check.or(other.dkim_check),
+            dmarc_check: self.dmarc_check.or(other.dmarc_check),
+            spf_reject_fail: self.spf_reject_fail.or(other.spf_reject_fail),
+            dmarc_enforce: self.dmarc_enforce.or(other.dmarc_enforce),
+            dnsbl_zones: match self.dnsbl_zones.is_empty() {
+                true => other.dnsbl_zones,
+                false => self.dnsbl_zones,
+            },
+            dnsbl_threshold: self.dnsbl_threshold.or(other.dnsbl_threshold),
+            dnsbl_tag_only: self.dnsbl_tag_only.or(other.dnsbl_tag_only),
+            iprev_check: self.iprev_check.or(other.iprev_check),
+            iprev_reject_fail: self.iprev_reject_fail.or(other.iprev_reject_fail),
+            authserv_id: self.authserv_id.or(other.authserv_id),
+            rspamd_url: self.rspamd_url.or(other.rspamd_url),
+            rspamd_password: self.rspamd_password.or(other.rspamd_password),
+            rspamd_reject_score: self.rspamd_reject_score.or(other.rspamd_reject_score),
+            rspamd_tag_score: self.rspamd_tag_score.or(other.rspamd_tag_score),
+            rspamd_timeout: self.rspamd_timeout.or(other.rspamd_timeout),
+        }
+    }
+
+    /// Checks the settings hang together, so mistakes fail at startup
+    pub fn validate(&self) -> Result<()> {
+        self.forward_url()?;
+        for url in self.fallback_urls.iter().chain(&self.fanout_urls) {
+            forward::check_url(url)?;
+        }
+        self.tls()?;
+        self.unix_socket_mode()?;
+        anyhow::ensure!(
+            self.max_connections() > 0,
+            "max-connections must be at least 1"
+        );
+        anyhow::ensure!(
+            self.max_connections_per_ip != Some(0),
+            "max-connections-per-ip must be at least 1"
+        );
+        anyhow::ensure!(
+            self.command_timeout != Some(0),
+            "command-timeout must be at least 1 second"
+        );
+        Ok(())
+    }
+
+    pub fn domain(&self) -> &str {
+        self.domain.as_deref().unwrap_or("smtp.deepwith.in")
+    }
+
+    /// Port of the SMTP listener, if any
+    pub fn port(&self) -> Option<u16> {
+        match self.port.unwrap_or(Port::On(25)) {
+            Port::Off => None,
+            Port::On(port) => Some(port),
+        }
+    }
+
+    pub fn forward_url(&self) -> Result<&str> {
+        let url = self
+            .forward_url
+            .as_deref()
+            .context("forward-url (FORWARD_URL) is not set")?;
+        forward::check_url(url)?;
+        Ok(url)
+    }
+
+    /// Paths of the certificate chain and key, when TLS is configured
+    pub fn tls(&self) -> Result<Option<(&Path, &Path)>> {
+        match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => Ok(Some((cert, key))),
+            (None, None) => Ok(None),
+            _ => anyhow::bail!("tls-cert and tls-key must be set together"),
+        }
+    }
+
+    pub fn unix_socket_mode(&self) -> Result<u32> {
+        self.unix_socket_mode
+            .as_deref()
+            .map_or(Ok(0o660), |mode| u32::from_str_radix(mode, 8))
+            .context("invalid unix-socket-mode")
+    }
+
+    pub fn wal_path(&self) -> &Path {
+        self.wal_path
+            .as_deref()
+            .unwrap_or("smtp_forward.wal".as_ref())
+    }
+
+    pub fn ledger_path(&self) -> &Path {
+        self.ledger_path
+            .as_deref()
+            .unwrap_or("smtp_forward.ledger".as_ref())
+    }
+
+    pub fn dead_letter_dir(&self) -> &Path {
+        self.dead_letter_dir
+            .as_deref()
+            .unwrap_or("dead-letters".as_ref())
+    }
+
+    pub fn ledger_retention(&self) -> chrono::Duration {
+        chrono::Duration::days(self.ledger_retention_days.unwrap_or(7))
+    }
+
+    pub fn max_connections(&self) -> usize {
+        self.max_connections.unwrap_or(1000)
+    }
+
+    /// What each SMTP session is handed
+    pub fn smtp(&self) -> Settings {
+        let defaults = Settings::new(self.domain());
+        Settings {
+            timeouts: Timeouts {
+                command: self
+                    .command_timeout
+                    .map_or(defaults.timeouts.command, Duration::from_secs),
+                session: self.session_timeout.map(Duration::from_secs),
+            },
+            max_size: self.max_message_size.unwrap_or(defaults.max_size),
+            max_errors: self
+                .max_protocol_errors
+                .unwrap_or(StateMachine::DEFAULT_MAX_ERRORS),
+            greeting_delay: self.greeting_delay.map(Duration::from_millis),
+            reject_early_talkers: self.reject_early_talkers.unwrap_or(false),
+            allow_insecure_auth: self.allow_insecure_auth.unwrap_or(false),
+            ..defaults
+        }
+    }
+
+    /// The endpoint at `url`, with the token, secret, format and compression
+    /// every endpoint shares
+    pub fn destination_for(&self, url: &str) -> Result<Destination> {
+        let mut destination = Destination::new(url)?;
+        destination.token = self.email_token.clone().unwrap_or_default();
+        destination.url_rewrite = self
+            .url_rewrite
+            .as_deref()
+            .map(str::parse)
+            .transpose()
+            .context("invalid url-rewrite")?;
+        destination.concurrency = self.delivery_concurrency.unwrap_or(4);
+        destination.rate_limit = self.delivery_rate.filter(|rate| *rate > 0.0);
+        destination.signing_secret = self
+            .webhook_secret
+            .clone()
+            .filter(|secret| !secret.is_empty());
+        destination.format = self.forward_format.unwrap_or_default();
+        destination.compression = self.compression;
+        destination.compression_threshold = self
+            .compression_threshold
+            .unwrap_or(forward::DEFAULT_COMPRESSION_THRESHOLD);
+        Ok(destination)
+    }
+
+    /// The endpoint of the forward URL, failing over to the fallback ones
+    pub fn destination(&self) -> Result<Destination> {
+        Ok(Destination {
+            fallback_urls: self.fallback_urls.clone(),
+            ..self.destination_for(self.forward_url()?)?
+        })
+    }
+
+    /// The endpoints each getting a copy of the messages
+    pub fn fanout(&self) -> Result<Vec<Destination>> {
+        self.fanout_urls
+            .iter()
+            .map(|url| self.destination_for(url))
+            .collect()
+    }
+
+    /// How the messages are turned into payloads, but for what the checks
+    /// and the spam scanning add
+    pub fn payload_options(&self) -> Result<payload::Options> {
+        Ok(payload::Options {
+            thumbnail_size: self.thumbnail_size,
+            url_blocklist: (!self.url_blocklists.is_empty())
+                .then(|| Blocklist::load(&self.url_blocklists))
+                .transpose()?,
+            strip_remote_content: self.strip_remote_content.unwrap_or(false),
+            include_raw: self.include_raw.unwrap_or(false),
+            ..Default::default()
+        })
+    }
+
+    pub fn priority(&self) -> priority::Rules {
+        let defaults = priority::Rules::default();
+        priority::Rules {
+            high_recipients: self
+                .priority_recipients
+                .iter()
+                .map(|recipient| recipient.trim().to_lowercase())
+                .filter(|recipient| !recipient.is_empty())
+                .collect(),
+            bulk_size: self.bulk_size.unwrap_or(defaults.bulk_size),
+        }
+    }
+
+    pub fn schedule(&self) -> Result<Vec<schedule::Rule>> {
+        schedule::parse_rules(self.schedule.as_deref().unwrap_or_default())
+            .context("invalid schedule")
+    }
+
+    pub fn chaos(&self) -> Result<Faults> {
+        self.chaos
+            .as_deref()
+            .map_or(Ok(Faults::default()), str::parse)
+            .context("invalid chaos")
+    }
+
+    pub fn batching(&self) -> Option<Batching> {
+        Batching::new(
+            self.batch_size.unwrap_or(1),
+            self.batch_window
+                .map_or(Duration::from_secs(1), Duration::from_millis),
+        )
+    }
+
+    pub fn retry(&self) -> Result<RetryPolicy> {
+        let defaults = RetryPolicy::default();
+        let policy = RetryPolicy {
+            max_attempts: self.retry_max_attempts.unwrap_or(defaults.max_attempts),
+            base_delay: self
+                .retry_base_delay
+                .map_or(defaults.base_delay, Duration::from_secs),
+            max_delay: self
+                .retry_max_delay
+                .map_or(defaults.max_delay, Duration::from_secs),
+            jitter: self.retry_jitter.unwrap_or(defaults.jitter),
+        };
+        policy.validate()?;
+        Ok(policy)
+    }
+
+    /// The Message-IDs duplicates are caught by, when duplicates are handled
+    pub async fn message_ids(&self) -> Result<Option<MessageIds>> {
+        let Some(duplicates) = &self.duplicates else {
+            return Ok(None);
+        };
+        let duplicates = duplicates.parse().context("invalid duplicates")?;
+        let path = self
+            .message_id_path
+            .as_deref()
+            .unwrap_or("smtp_forward.message-ids".as_ref());
+        let capacity = self.message_id_capacity.unwrap_or(100_000);
+        MessageIds::open(path, capacity, duplicates).await.map(Some)
+    }
+
+    pub fn tenants(&self) -> Result<Vec<Tenant>> {
+        match &self.tenants {
+            Some(path) => tenant::load(path),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    pub fn hostname(&self) -> &str {
+        self.hostname.as_deref().unwrap_or("localhost")
+    }
+
+    /// The lease electing the delivering instance of a pair, none when on its own
+    pub fn lease(&self) -> Result<Option<Lease>> {
+        let Some(path) = &self.leader_lease else {
+            return Ok(None);
+        };
+        let ttl = self.leader_lease_ttl.unwrap_or(15);
+        anyhow::ensure!(ttl > 0, "leader-lease-ttl must be positive");
+        Ok(Some(Lease {
+            path: path.clone(),
+            holder: self
+                .instance_id
+                .clone()
+                .unwrap_or_else(|| format!("{}-{}", self.hostname(), std::process::id())),
+            ttl: chrono::Duration::seconds(ttl),
+        }))
+    }
+
+    pub fn usage(&self) -> Result<Reporter> {
+        let interval = self.usage_interval.unwrap_or(3600);
+        anyhow::ensure!(interval > 0, "usage-interval must be positive");
+        Ok(Reporter {
+            path: self
+                .usage_path
+                .clone()
+                .unwrap_or_else(|| "smtp_forward.usage".into()),
+            interval: Duration::from_secs(interval),
+            webhook: self.usage_webhook.clone(),
+            webhook_token: self.usage_webhook_token.clone().unwrap_or_default(),
+        })
+    }
+
+    /// The cleanup of the stored mail and the dead letters, none without a retention
+    pub async fn cleanup(&self) -> Result<Option<Cleanup>> {
+        let Some(days) = self.retention_days else {
+            return Ok(None);
+        };
+        anyhow::ensure!(days > 0, "retention-days must be positive");
+        let retention = Retention::with_domains(
+            chrono::Duration::days(days),
+            self.retention_domains.as_deref().unwrap_or_default(),
+        )?;
+        let interval = self.retention_interval.unwrap_or(3600);
+        anyhow::ensure!(interval > 0, "retention-interval must be positive");
+        Ok(Some(Cleanup {
+            retention: Arc::new(retention),
+            interval: Duration::from_secs(interval),
+            #[cfg(feature = "sqlite")]
+            database: self.database().await?,
+            eml: match &self.eml_dir {
+                Some(dir) => Some(EmlDir::open(dir).await?),
+                None => None,
+            },
+            maildir: match &self.maildir {
+                Some(dir) => Some(Maildir::open(dir, self.hostname()).await?),
+                None => None,
+            },
+            dead_letters: Some(DeadLetters::open(self.dead_letter_dir()).await?),
+        }))
+    }
+
+    #[cfg(feature = "sqlite")]
+    pub async fn database(&self) -> Result<Option<Database>> {
+        match &self.database_path {
+            Some(path) => Database::open(path).await.map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// The spool the accepted mail is kept in until it is delivered, if any
+    #[cfg(feature = "sqlite")]
+    pub async fn spool(&self) -> Result<Option<Spool>> {
+        match &self.spool_path {
+            Some(path) => Spool::open(path).await.map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// The bucket large attachments are uploaded to, if any
+    pub fn object_store(&self) -> Result<Option<ObjectStore>> {
+        self.s3_bucket
+            .as_deref()
+            .map(|bucket| self.object_store_for(bucket))
+            .transpose()
+    }
+
+    /// `bucket` on the S3-compatible service
+    fn object_store_for(&self, bucket: &str) -> Result<ObjectStore> {
+        let expiry = self
+            .s3_url_expiry
+            .map_or(storage::MAX_EXPIRY, Duration::from_secs);
+        anyhow::ensure!(
+            expiry <= storage::MAX_EXPIRY,
+            "s3-url-expiry cannot exceed 7 days"
+        );
+        let mut store = ObjectStore::new(
+            self.s3_endpoint
+                .as_deref()
+                .context("s3-endpoint (S3_ENDPOINT) is not set")?,
+            bucket,
+            self.s3_access_key_id
+                .as_deref()
+                .context("s3-access-key-id (S3_ACCESS_KEY_ID) is not set")?,
+            self.s3_secret_access_key
+                .as_deref()
+                .context("s3-secret-access-key (S3_SECRET_ACCESS_KEY) is not set")?,
+        )?;
+        if let Some(region) = &self.s3_region {
+            store.region = region.clone();
+        }
+        if let Some(threshold) = self.offload_threshold {
+            store.threshold = threshold;
+        }
+        store.expiry = expiry;
+        Ok(store)
+    }
+
+    /// The sinks configured, the ones needing a client library behind its feature
+    pub async fn sinks(&self) -> Result<SinkRegistry> {
+        let mut registry = SinkRegistry::default();
+        // The fanout endpoints, each getting a copy of the mail of no tenant
+        for destination in self.fanout()? {
+            registry.register(Webhook::new(destination))?;
+        }
+        if let Some(dir) = &self.maildir {
+            registry.register(Maildir::open(dir, self.hostname()).await?)?;
+        }
+        if let Some(path) = &self.mbox_path {
+            registry.register(Mbox::open(path).await?)?;
+        }
+        if let Some(dir) = &self.eml_dir {
+            registry.register(EmlDir::open(dir).await?)?;
+        }
+        if let Some(bucket) = &self.archive_bucket {
+            registry.register(Archive::new(self.object_store_for(bucket)?))?;
+        }
+        #[cfg(feature = "kafka")]
+        if let Some(brokers) = &self.kafka_brokers {
+            let topic = self
+                .kafka_topic
+                .as_deref()
+                .context("kafka-topic (KAFKA_TOPIC) is required by kafka-brokers")?;
+            let acks = self.kafka_acks.as_deref().unwrap_or("all");
+            let format = self.kafka_format.unwrap_or_default();
+            registry.register(KafkaSink::new(brokers, topic, acks, format)?)?;
+        }
+        #[cfg(feature = "aws")]
+        {
+            let (sqs, sns) =
+                aws::connect(self.sqs_queue_url.clone(), self.sns_topic_arn.clone()).await?;
+            if let Some(sqs) = sqs {
+                registry.register(sqs)?;
+            }
+            if let Some(sns) = sns {
+                registry.register(sns)?;
+            }
+        }
+        #[cfg(feature = "pubsub")]
+        if let Some(topic) = &self.pubsub_topic {
+            registry.register(PubSubSink::new(
+                topic,
+                self.pubsub_format.unwrap_or_default(),
+                self.google_application_credentials.as_deref(),
+                self.pubsub_emulator_host.as_deref(),
+                self.pubsub_project_id.as_deref(),
+            )?)?;
+        }
+        #[cfg(feature = "redis")]
+        if let Some(url) = &self.redis_url {
+            let redis = RedisSink::connect(
+                url,
+                self.redis_stream.as_deref().unwrap_or("smtp_forward"),
+                self.redis_stream_maxlen,
+                self.redis_channel.clone(),
+                self.redis_format.unwrap_or_default(),
+            )
+            .await?;
+            registry.register(redis)?;
+        }
+        #[cfg(feature = "sqlite")]
+        if let Some(database) = self.database().await? {
+            registry.register(database)?;
+        }
+        #[cfg(feature = "relay")]
+        if let Some(relay) = self.relay()? {
+            registry.register(relay)?;
+        }
+        Ok(registry)
+    }
+
+    /// The aliases the relay forwards the mail of, the server refusing the
+    /// recipients without one
+    #[cfg(feature = "relay")]
+    pub fn aliases(&self) -> Result<Option<Aliases>> {
+        self.aliases.as_deref().map(Aliases::load).transpose()
+    }
+
+    /// The outbound SMTP relay, with its aliases, SRS, DKIM signing and ARC sealing
+    #[cfg(feature = "relay")]
+    pub fn relay(&self) -> Result<Option<Relay>> {
+        let Some(relay) = &self.relay else {
+            return Ok(None);
+        };
+        let route = match relay.as_str() {
+            "mx" => Route::Mx {
+                resolver: Box::new(
+                    TokioAsyncResolver::tokio_from_system_conf()
+                        .context("cannot read the system's DNS configuration")?,
+                ),
+                port: self.relay_port.unwrap_or(25),
+            },
+            smarthost => {
+                let (host, port) = match smarthost.rsplit_once(':') {
+                    Some((host, port)) => (host, port.parse().context("invalid relay port")?),
+                    None => (smarthost, 587),
+                };
+                Route::Smarthost {
+                    host: host.to_string(),
+                    port,
+                }
+            }
+        };
+        let tls = match self.relay_tls.as_deref() {
+            None | Some("opportunistic") => Tls::Opportunistic,
+            Some("never") => Tls::Never,
+            Some("required") => Tls::Required,
+            Some(tls) => anyhow::bail!("invalid relay-tls {tls}"),
+        };
+        let credentials = match &self.relay_username {
+            Some(username) => Some(Credentials {
+                username: username.clone(),
+                password: self
+                    .relay_password
+                    .clone()
+                    .context("relay-username is set but not relay-password")?,
+            }),
+            None => None,
+        };
+        let client = smtp_client::Config {
+            helo_name: self
+                .relay_helo_name
+                .clone()
+                .unwrap_or_else(|| self.hostname().to_string()),
+            tls,
+            credentials,
+            ..smtp_client::Config::default()
+        };
+        let mut relay = Relay::new(route, client);
+        if let Some(aliases) = self.aliases()? {
+            relay = relay.with_aliases(aliases);
+        }
+        if let Some(secret) = &self.srs_secret {
+            let domain = self
+                .srs_domain
+                .as_deref()
+                .context("srs-secret is set but not srs-domain")?;
+            relay = relay.with_srs(Srs::new(secret, domain, self.srs_max_age.unwrap_or(21)));
+        }
+        if let Some(signer) = self.dkim()? {
+            relay = relay.with_dkim(signer);
+        }
+        if let Some(authserv_id) = &self.arc_authserv_id {
+            let signer = self
+                .dkim()?
+                .context("arc-authserv-id is set but not dkim-private-key, to sign the ARC sets")?;
+            relay = relay.with_arc(Sealer::new(signer, authserv_id));
+        }
+        Ok(Some(relay))
+    }
+
+    /// The DKIM signer of the relayed mail, none without a private key
+    #[cfg(feature = "relay")]
+    fn dkim(&self) -> Result<Option<Signer>> {
+        let Some(path) = &self.dkim_private_key else {
+            return Ok(None);
+        };
+        let domain = self
+            .dkim_domain
+            .as_deref()
+            .context("dkim-private-key is set but not dkim-domain")?;
+        let selector = self
+            .dkim_selector
+            .as_deref()
+            .context("dkim-private-key is set but not dkim-selector")?;
+        Signer::load(domain, selector, path).map(Some)
+    }
+
+    /// The accounts clients authenticate as, none when AUTH takes anyone
+    pub fn users(&self) -> Result<Option<Users>> {
+        self.auth_users.as_deref().map(Users::load).transpose()
+    }
+
+    /// The validation of the XOAUTH2 tokens, none when XOAUTH2 is not offered
+    pub fn tokens(&self) -> Result<Option<Tokens>> {
+        if let Some(path) = &self.xoauth2_tokens {
+            return Tokens::load(path).map(Some);
+        }
+        let Some(url) = &self.xoauth2_introspection_url else {
+            return Ok(None);
+        };
+        let client_id = self.xoauth2_client_id.clone().unwrap_or_default();
+        Ok(Some(Tokens::introspection(
+            url,
+            &client_id,
+            self.xoauth2_client_secret.clone().unwrap_or_default(),
+            self.xoauth2_audience.clone().unwrap_or(client_id.clone()),
+        )))
+    }
+
+    /// The names clients may introduce themselves with, any unless restricted
+    pub fn helo_policy(&self) -> Option<HeloPolicy> {
+        let policy = HeloPolicy {
+            reject_ip: self.helo_reject_ip.unwrap_or(false),
+            reject_own: self
+                .helo_reject_own_domain
+                .unwrap_or(false)
+                .then(|| self.domain().to_string()),
+            require_fqdn: self.helo_require_fqdn.unwrap_or(false),
+        };
+        (policy != HeloPolicy::default()).then_some(policy)
+    }
+
+    /// The domains recipients are accepted for, any unless some are listed
+    pub fn domains(&self) -> Option<Domains> {
+        let domains = Domains::new(&self.local_domains.join(","), &self.relay_domains.join(","));
+        (domains != Domains::default()).then_some(domains)
+    }
+
+    /// The rules recipients are accepted by, the default ones without a file
+    pub fn recipients(&self) -> Result<Arc<Recipients>> {
+        match &self.recipient_rules {
+            Some(path) => Recipients::load(path),
+            None => Ok(Recipients::builtin()),
+        }
+    }
+
+    /// The quotas of the sender domains, none unless set
+    pub fn senders(&self) -> Result<Option<Senders>> {
+        if self.sender_messages_per_hour.is_none() && self.sender_quotas.is_none() {
+            return Ok(None);
+        }
+        Senders::new(
+            self.sender_messages_per_hour,
+            self.sender_quotas.as_deref().unwrap_or_default(),
+        )
+        .map(Some)
+    }
+
+    pub fn tarpit(&self) -> Option<Tarpit> {
+        let defaults = Tarpit::new(Duration::from_millis(self.tarpit_delay?));
+        Some(Tarpit {
+            max_delay: self
+                .tarpit_max_delay
+                .map_or(defaults.max_delay, Duration::from_millis),
+            errors: self.tarpit_errors.unwrap_or(defaults.errors),
+            refused_recipients: self
+                .tarpit_refused_recipients
+                .unwrap_or(defaults.refused_recipients),
+            ..defaults
+        })
+    }
+
+    /// What each client address may do, none unless limited
+    pub fn client_limits(&self) -> Result<Option<ClientLimits>> {
+        let limits = ClientLimits {
+            connections_per_minute: self.client_connections_per_minute,
+            messages_per_hour: self.client_messages_per_hour,
+            recipients_per_message: self.client_recipients_per_message,
+        };
+        anyhow::ensure!(
+            limits.connections_per_minute != Some(0) && limits.messages_per_hour != Some(0),
+            "client rate limits must be positive"
+        );
+        Ok((limits != ClientLimits::default()).then_some(limits))
+    }
+
+    /// The checks of inbound mail, none unless one is enabled. DMARC checks SPF and
+    /// DKIM with it. DNS is looked up with the system configuration.
+    #[cfg(feature = "dns")]
+    pub fn checks(&self) -> Result<Option<Checks>> {
+        let dmarc = self.dmarc_check.unwrap_or(false);
+        let spf = self.spf_check.unwrap_or(false) || dmarc;
+        let dkim = self.dkim_check.unwrap_or(false) || dmarc;
+        let blocklists = self
+            .dnsbl_zones
+            .iter()
+            .map(|zone| zone.parse())
+            .collect::<Result<Vec<dnsbl::Blocklist>>>()
+            .context("invalid dnsbl-zones")?;
+        let iprev = self.iprev_check.unwrap_or(false);
+        if !spf && !dkim && blocklists.is_empty() && !iprev {
+            return Ok(None);
+        }
+        let resolver = TokioAsyncResolver::tokio_from_system_conf()
+            .context("cannot read the system DNS configuration")?;
+        let authserv_id = self.authserv_id.as_deref().unwrap_or(self.domain());
+        let mut checks = Checks::new(resolver, authserv_id);
+        checks.spf = spf;
+        checks.dkim = dkim;
+        checks.dmarc = dmarc;
+        checks.blocklists = blocklists;
+        checks.dnsbl_threshold = self.dnsbl_threshold.unwrap_or(1);
+        checks.reject_listed = !self.dnsbl_tag_only.unwrap_or(false);
+        checks.iprev = iprev;
+        checks.reject_iprev_fail = self.iprev_reject_fail.unwrap_or(false);
+        checks.reject_spf_fail = self.spf_reject_fail.unwrap_or(false);
+        checks.dmarc_enforcement = self
+            .dmarc_enforce
+            .as_deref()
+            .map(str::parse)
+            .transpose()
+            .context("invalid dmarc-enforce")?
+            .unwrap_or(Policy::None);
+        Ok(Some(checks))
+    }
+
+    /// The rspamd scanning inbound mail, if any
+    #[cfg(feature = "rspamd")]
+    pub fn rspamd(&self) -> Option<Rspamd> {
+        let mut rspamd = Rspamd::new(self.rspamd_url.as_deref()?);
+        rspamd.password = self.rspamd_password.clone();
+        rspamd.reject_score = self.rspamd_reject_score;
+        rspamd.tag_score = self.rspamd_tag_score;
+        if let Some(timeout) = self.rspamd_timeout {
+            rspamd.timeout = Duration::from_millis(timeout);
+        }
+        Some(rspamd)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_and_flags() {
+        let file: Config = toml::from_str(
+            r#"
+            domain = "mx.example.com"
+            port = "off"
+            smtps-port = 465
+            forward-url = "https://hooks.example.com/mail"
+            fanout-urls = ["https://archive.example.com/mail"]
+            max-message-size = 1000
+            command-timeout = 30
+            greeting-delay = 500
+            "#,
+        )
+        .unwrap();
+        assert_eq!(file.port(), None);
+        assert_eq!(file.smtps_port, Some(465));
+        file.validate().unwrap();
+
+        let cli =
+            Cli::try_parse_from(["smtp_forward", "--port", "2525", "--max-message-size", "50"])
+                .unwrap();
+        let config = cli.flags.or(file);
+        assert_eq!(config.port(), Some(2525));
+        assert_eq!(config.domain(), "mx.example.com");
+        assert_eq!(config.fanout_urls, ["https://archive.example.com/mail"]);
+        let smtp = config.smtp();
+        assert_eq!(smtp.max_size, 50);
+        assert_eq!(smtp.timeouts.command, Duration::from_secs(30));
+        assert_eq!(smtp.max_errors, StateMachine::DEFAULT_MAX_ERRORS);
+        assert_eq!(smtp.greeting_delay, Some(Duration::from_millis(500)));
+        assert!(!smtp.reject_early_talkers);
+    }
+
+    #[tokio::test]
+    async fn test_services() {
+        let config: Config = toml::from_str(
+            r#"
+            forward-url = "https://hooks.example.com/mail"
+            email-token = "secret"
+            delivery-concurrency = 8
+            batch-size = 10
+            tarpit-delay = 500
+            local-domains = ["Example.com"]
+            schedule = "bulk window:22:00-06:00"
+            "#,
+        )
+        .unwrap();
+        let destination = config.destination().unwrap();
+        assert_eq!(
+            (destination.token.as_str(), destination.concurrency),
+            ("secret", 8)
+        );
+        assert_eq!(config.batching().unwrap().max_messages, 10);
+        let tarpit = config.tarpit().unwrap();
+        assert_eq!(tarpit.delay, Duration::from_millis(500));
+        assert_eq!(tarpit.max_delay, Duration::from_secs(30));
+        assert!(config.domains().unwrap().accepts("<a@example.com>"));
+        assert_eq!(config.schedule().unwrap().len(), 1);
+        assert!(config.helo_policy().is_none());
+        assert!(config.message_ids().await.unwrap().is_none());
+
+        // Booleans take 1 as environment variables always did
+        let cli = Cli::try_parse_from(["smtp_forward", "--helo-require-fqdn", "1"]).unwrap();
+        assert!(cli.flags.helo_policy().unwrap().require_fqdn);
+        let bad_jitter = Config {
+            retry_jitter: Some(2.0),
+            ..config
+        };
+        assert!(bad_jitter.retry().is_err());
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(toml::from_str::<Config>("unknown = 1").is_err());
+        let config = Config {
+            forward_url: Some("https://hooks.example.com/mail".into()),
+            ..Default::default()
+        };
+        config.validate().unwrap();
+        assert!(Config::default().validate().is_err());
+        let half_tls = Config {
+            tls_cert: Some("cert.pem".into()),
+            ..config.clone()
+        };
+        assert!(half_tls.validate().is_err());
+        let no_connections = Config {
+            max_connections_per_ip: Some(0),
+            ..config.clone()
+        };
+        assert!(no_connections.validate().is_err());
+        let bad_url = Config {
+            forward_url: Some("hooks.example.com".into()),
+            ..config
+        };
+        assert!(bad_url.validate().is_err());
+    }
+}
@@ -0,0 +1,294 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Serialize;
+
+use crate::protocol::Mail;
+use crate::queue::QueueId;
+use crate::schema::Message;
+use crate::sink::{DeliveryResult, MailSink};
+
+/// Migrations of the schema, in order. The database's `user_version`
+/// is the number of them applied.
+const MIGRATIONS: &[&str] = &["CREATE TABLE mail (
+        id TEXT PRIMARY KEY,
+        stored_at TEXT NOT NULL,
+        envelope_from TEXT NOT NULL,
+        envelope_to TEXT NOT NULL,
+        header_from TEXT,
+        subject TEXT,
+        message TEXT NOT NULL,
+        raw BLOB NOT NULL
+    );
+    CREATE INDEX mail_stored_at ON mail (stored_at);"];
+
+/// Columns read into a [`Row`]
+const COLUMNS: &str = "id, stored_at, envelope_from, envelope_to, message, raw";
+
+/// A mail as read from the database, its JSON columns not parsed yet
+struct Row {
+    id: String,
+    stored_at: DateTime<Utc>,
+    from: String,
+    to: String,
+    message: String,
+    data: Vec<u8>,
+}
+
+impl Row {
+    fn read(row: &rusqlite::Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            id: row.get(0)?,
+            stored_at: row.get(1)?,
+            from: row.get(2)?,
+            to: row.get(3)?,
+            message: row.get(4)?,
+            data: row.get(5)?,
+        })
+    }
+
+    fn stored(self) -> Result<StoredMail> {
+        Ok(StoredMail {
+            id: serde_json::from_value(self.id.into())?,
+            stored_at: self.stored_at,
+            mail: Mail {
+                from: self.from,
+                to: serde_json::from_str(&self.to)?,
+                data: self.data,
+            },
+            message: serde_json::from_str(&self.message)?,
+        })
+    }
+}
+
+/// A mail as stored, with its envelope, raw data and parsed message
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StoredMail {
+    pub id: QueueId,
+    pub stored_at: DateTime<Utc>,
+    pub mail: Mail,
+    pub message: Message,
+}
+
+/// SQLite database the received mail is stored in. The sender, subject and
+/// storage time have columns of their own, to be queried on.
+#[derive(Clone)]
+pub struct Database {
+    path: PathBuf,
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl Database {
+    /// Opens the database at `path`, creating it if needed and migrating its schema
+    pub async fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let open = path.clone();
+        let connection = tokio::task::spawn_blocking(move || {
+            let mut connection = Connection::open(&open)?;
+            connection.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))?;
+            migrate(&mut connection)?;
+            anyhow::Ok(connection)
+        })
+        .await?
+        .with_context(|| format!("cannot open database {}", path.display()))?;
+        Ok(Self {
+            path,
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    /// Stores a mail with its message. Storing a mail again, e.g. when its
+    /// delivery is retried, keeps the first copy.
+    pub async fn insert(&self, id: &QueueId, mail: &Mail, message: &Message) -> Result<()> {
+        let id = id.clone();
+        let envelope_to = serde_json::to_string(&mail.to)?;
+        let header_from = message.from.email.clone();
+        let subject = message.subject.clone();
+        let json = serde_json::to_string(message)?;
+        let from = mail.from.clone();
+        let raw = mail.data.clone();
+        self.run(move |connection| {
+            connection.execute(
+                "INSERT OR IGNORE INTO mail
+                    (id, stored_at, envelope_from, envelope_to, header_from, subject, message, raw)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                params![
+                    id.as_str(),
+                    Utc::now(),
+                    from,
+                    envelope_to,
+                    header_from,
+                    subject,
+                    json,
+                    raw
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+        .with_context(|| format!("cannot store mail in {}", self.path.display()))
+    }
+
+    /// The stored mail with the queue id, if any
+    pub async fn get(&self, id: &str) -> Result<Option<StoredMail>> {
+        let id = id.to_string();
+        self.run(move |connection| {
+            let row = connection
+                .query_row(
+                    &format!("SELECT {COLUMNS} FROM mail WHERE id = ?1"),
+                    [&id],
+                    Row::read,
+                )
+                .optional()?;
+            row.map(Row::stored).transpose()
+        })
+        .await
+    }
+
+    /// The mail stored from `since` and before `until`, oldest first
+    pub async fn list(
+        &self,
+        since: Option<DateTime<Utc>>,
+        until: Option<DateTime<Utc>>,
+    ) -> Result<Vec<StoredMail>> {
+        self.run(move |connection| {
+            let mut statement = connection.prepare(&format!(
+                "SELECT {COLUMNS} FROM mail
+                WHERE (?1 IS NULL OR stored_at >= ?1) AND (?2 IS NULL OR stored_at < ?2)
+                ORDER BY stored_at"
+            ))?;
+            let rows = statement.query_map(params![since, until], Row::read)?;
+            rows.map(|row| row?.stored()).collect()
+        })
+        .await
+    }
+
+    /// Deletes the mail stored before `before` that `expired` says is past its
+    /// retention, given its envelope and storage time, returning how many
+    pub async fn remove_expired(
+        &self,
+        before: DateTime<Utc>,
+        expired: impl Fn(&Mail, DateTime<Utc>) -> bool + Send + 'static,
+    ) -> Result<usize> {
+        self.run(move |connection| {
+            let transaction = connection.transaction()?;
+            let mut ids = Vec::new();
+            {
+                let mut statement = transaction.prepare(
+                    "SELECT id, envelope_from, envelope_to, stored_at FROM mail WHERE stored_at < ?1",
+                )?;
+                let mut rows = statement.query([before])?;
+                while let Some(row) = rows.next()? {
+                    let mail = Mail {
+                        from: row.get(1)?,
+                        to: serde_json::from_str(&row.get::<_, String>(2)?)?,
+                        data: Vec::new(),
+                    };
+                    if expired(&mail, row.get(3)?) {
+                        ids.push(row.get::<_, String>(0)?);
+                    }
+                }
+            }
+            for id in &ids {
+                transaction.execute("DELETE FROM mail WHERE id = ?1", [id])?;
+            }
+            transaction.commit()?;
+            Ok(ids.len())
+        })
+        .await
+        .with_context(|| format!("cannot clean up {}", self.path.display()))
+    }
+
+    /// Runs queries on a blocking thread, SQLite calls blocking the caller
+    async fn run<T: Send + 'static>(
+        &self,
+        query: impl FnOnce(&mut Connection) -> Result<T> + Send + 'static,
+    ) -> Result<T> {
+        let connection = self.connection.clone();
+        tokio::task::spawn_blocking(move || query(&mut connection.lock().unwrap())).await?
+    }
+}
+
+impl MailSink for Database {
+    fn endpoint(&self) -> String {
+        format!("sqlite://{}", self.path.display())
+    }
+
+    async fn deliver(&self, id: &QueueId, mail: &Mail, message: &Message) -> DeliveryResult {
+        self.insert(id, mail, message).await
+    }
+}
+
+/// Applies the migrations the database lacks, each in a transaction
+fn migrate(connection: &mut Connection) -> Result<()> {
+    let version: usize = connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    anyhow::ensure!(
+        version <= MIGRATIONS.len(),
+        "the database schema is at version {version}, newer than this server's {}",
+        MIGRATIONS.len()
+    );
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let transaction = connection.transaction()?;
+        transaction.execute_batch(migration)?;
+        transaction.pragma_update(None, "user_version", index + 1)?;
+        transaction.commit()?;
+        tracing::info!("Migrated the database schema to version {}", index + 1);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::payload;
+
+    #[tokio::test]
+    async fn test_store() {
+        let path = std::env::temp_dir().join(format!("database-test-{}", QueueId::generate()));
+        let mail = Mail {
+            from: "<a@example.com>".into(),
+            to: vec!["<b@example.com>".into()],
+            data: b"From: a@example.com\r\nSubject: hi\r\n\r\nhello\r\n".to_vec(),
+        };
+        let message = payload::build(&mail.data, &payload::Options::default()).unwrap();
+        let id = QueueId::generate();
+        {
+            let database = Database::open(&path).await.unwrap();
+            database.insert(&id, &mail, &message).await.unwrap();
+            // Retried deliveries keep the first copy
+            database.insert(&id, &mail, &message).await.unwrap();
+            assert!(database.get("unknown").await.unwrap().is_none());
+        }
+        // Reopening leaves the migrated schema as is
+        let database = Database::open(&path).await.unwrap();
+        let stored = database.get(id.as_str()).await.unwrap().unwrap();
+        assert_eq!(stored.id, id);
+        assert_eq!(stored.mail, mail);
+        assert_eq!(stored.message.subject.as_deref(), Some("hi"));
+        let listed = database.list(Some(stored.stored_at), None).await.unwrap();
+        assert_eq!(listed.len(), 1);
+        assert!(database
+            .list(None, Some(stored.stored_at))
+            .await
+            .unwrap()
+            .is_empty());
+        // Only what the retention says expired is removed
+        let later = Utc::now() + chrono::Duration::hours(1);
+        let removed = database.remove_expired(later, |_, _| false).await.unwrap();
+        assert_eq!(removed, 0);
+        let removed = database
+            .remove_expired(later, |mail, _| mail.to == ["<b@example.com>"])
+            .await
+            .unwrap();
+        assert_eq!(removed, 1);
+        assert!(database.get(id.as_str()).await.unwrap().is_none());
+        drop(database);
+        for suffix in ["", "-wal", "-shm"] {
+            std::fs::remove_file(format!("{}{suffix}", path.display())).ok();
+        }
+    }
+}
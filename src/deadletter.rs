@@ -0,0 +1,424 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::forward::Destination;
+use crate::priority::Priority;
+use crate::protocol::Mail;
+use crate::queue::{QueueId, QueuedMail};
+use crate::schema::Message;
+use crate::tenant::Tenant;
+
+/// A message given up on, kept with everything needed to look into it
+/// and deliver it again
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeadLetter {
+    pub id: QueueId,
+    pub failed_at: DateTime<Utc>,
+    pub attempts: u32,
+    /// Why the delivery was given up on
+    pub reason: String,
+    pub mail: Mail,
+    pub priority: Priority,
+    pub tenant: Option<String>,
+    /// The payload posted to the endpoint, none when the message cannot be parsed
+    pub payload: Option<Message>,
+    /// Destinations and sinks that had not taken the message when it was given up
+    /// on, those of the fanout that had being left out. Empty for all of them.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub endpoints: Vec<String>,
+}
+
+impl DeadLetter {
+    /// The mail to queue again, its attempts starting over
+    pub fn queued(self) -> QueuedMail {
+        QueuedMail {
+            id: self.id,
+            mail: self.mail,
+            priority: self.priority,
+            not_before: None,
+            tenant: self.tenant,
+            attempts: 0,
+            duplicate_of: None,
+        }
+    }
+}
+
+/// Directory of dead letters, one JSON file each, named after the queue id
+#[derive(Clone, Debug)]
+pub struct DeadLetters {
+    dir: PathBuf,
+}
+
+impl DeadLetters {
+    /// Opens the directory at `dir`, creating it if needed
+    pub async fn open(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        tokio::fs::create_dir_all(&dir)
+            .await
+            .with_context(|| format!("cannot create dead letter directory {}", dir.display()))?;
+        Ok(Self { dir })
+    }
+
+    fn path(&self, id: &str) -> Result<PathBuf> {
+        // Ids come from API clients too, they must not escape the directory
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric()) {
+            bail!("invalid queue id {id:?}");
+        }
+        Ok(self.dir.join(format!("{id}.json")))
+    }
+
+    /// Stores a dead letter, waiting for it to reach the disk
+    pub async fn add(&self, letter: &DeadLetter) -> Result<()> {
+        let path = self.path(letter.id.as_str())?;
+        let tmp = path.with_extension("tmp");
+        let json = serde_json::to_vec_pretty(letter)?;
+        let file = async {
+            let mut file = tokio::fs::File::create(&tmp).await?;
+            tokio::io::AsyncWriteExt::write_all(&mut file, &json).await?;
+            file.sync_all().await?;
+            tokio::fs::rename(&tmp, &path).await
+        };
+        file.await
+            .with_context(|| format!("cannot write dead letter {}", path.display()))
+    }
+
+    pub async fn get(&self, id: &str) -> Result<Option<DeadLetter>> {
+        let path = self.path(id)?;
+        match tokio::fs::read(&path).await {
+            Ok(json) => {
+                Ok(Some(serde_json::from_slice(&json).with_context(|| {
+                    format!("invalid dead letter {}", path.display())
+                })?))
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err).with_context(|| format!("cannot read {}", path.display())),
+        }
+    }
+
+    /// Every dead letter, oldest failure first. Unreadable files are skipped.
+    pub async fn list(&self) -> Result<Vec<DeadLetter>> {
+        let mut letters = Vec::new();
+        let mut entries = tokio::fs::read_dir(&self.dir)
+            .await
+            .with_context(|| format!("cannot read {}", self.dir.display()))?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().is_none_or(|extension| extension != "json") {
+                continue;
+            }
+            let letter = tokio::fs::read(&path)
+                .await
+                .map_err(anyhow::Error::from)
+                .and_then(|json| Ok(serde_json::from_slice(&json)?));
+            match letter {
+                Ok(letter) => letters.push(letter),
+                Err(err) => tracing::warn!("Skipping dead letter {}: {err:#}", path.display()),
+            }
+        }
+        letters.sort_by_key(|letter: &DeadLetter| letter.failed_at);
+        Ok(letters)
+    }
+
+    /// Takes a dead letter out of the store, returning it if it was there
+    pub async fn remove(&self, id: &str) -> Result<Option<DeadLetter>> {
+        let Some(letter) = self.get(id).await? else {
+            return Ok(None);
+        };
+        tokio::fs::remove_file(self.path(id)?).await?;
+        Ok(Some(letter))
+    }
+
+    /// Deletes the dead letters `expired` tells to, given each, returning how many
+    pub async fn remove_expired(&self, expired: impl Fn(&DeadLetter) -> bool) -> Result<usize> {
+        let mut removed = 0;
+        for letter in self.list().await? {
+            if expired(&letter) && self.remove(letter.id.as_str()).await?.is_some() {
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+/// Arguments of the `dead-letters` subcommand
+#[derive(Debug, PartialEq)]
+pub enum Args {
+    List,
+    /// Posts the dead letters to the destination right away, removing the delivered ones
+    Redeliver {
+        ids: Vec<String>,
+    },
+}
+
+impl Args {
+    /// Parses `dead-letters list` or `dead-letters redeliver <id>...`,
+    /// `args` starting after the subcommand name
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self> {
+        let mut args = args.into_iter();
+        match args.next().as_deref() {
+            Some("list") => Ok(Self::List),
+            Some("redeliver") => {
+                let ids = args.collect::<Vec<_>>();
+                if ids.is_empty() {
+                    bail!("usage: dead-letters redeliver <queue id>...");
+                }
+                Ok(Self::Redeliver { ids })
+            }
+            _ => bail!("usage: dead-letters list | dead-letters redeliver <queue id>..."),
+        }
+    }
+}
+
+/// Lists the dead letters in `dir`, or redelivers them as the server routes mail:
+/// to the tenant's destination, or to every one of `destinations` for the mail of
+/// no tenant. A running server can rather queue them again through the admin API,
+/// keeping their delivery history.
+pub async fn run(
+    args: Args,
+    dir: &Path,
+    destinations: Vec<Destination>,
+    tenants: &[Tenant],
+) -> Result<()> {
+    let letters = DeadLetters::open(dir).await?;
+    match args {
+        Args::List => {
+            for letter in letters.list().await? {
+                println!(
+                    "{}\t{}\t{}\t{}\t{}",
+                    letter.id,
+                    letter.failed_at.to_rfc3339(),
+                    letter.mail.from,
+                    letter.mail.to.join(","),
+                    letter.reason
+                );
+            }
+            Ok(())
+        }
+        Args::Redeliver { ids } => {
+            let client = reqwest::Client::new();
+            let mut failed = 0;
+            for id in ids {
+                if let Err(err) = redeliver(&letters, &id, &destinations, tenants, &client).await {
+                    tracing::warn!("Cannot redeliver {id}: {err:#}");
+                    failed += 1;
+                }
+            }
+            if failed > 0 {
+                bail!("{failed} dead letters could not be redelivered");
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Posts a dead letter to the destinations it was given up on for, removing it
+/// once they all took it. When only some did, it is kept, and they get it again
+/// on the next attempt.
+async fn redeliver(
+    letters: &DeadLetters,
+    id: &str,
+    destinations: &[Destination],
+    tenants: &[Tenant],
+    client: &reqwest::Client,
+) -> Result<()> {
+    let letter = letters.get(id).await?.context("no such dead letter")?;
+    let destinations = match &letter.tenant {
+        Some(name) => {
+            let tenant = tenants
+                .iter()
+                .find(|tenant| &tenant.name == name)
+                .with_context(|| format!("unknown tenant {name}"))?;
+            std::slice::from_ref(&tenant.destination)
+        }
+        None if destinations.is_empty() => {
+            bail!("forward-url (FORWARD_URL) is not set, to redeliver to")
+        }
+        None => destinations,
+    };
+    let payload = letter
+        .payload
+        .as_ref()
+        .context("the message could not be parsed")?;
+    let destinations = destinations.iter().filter(|destination| {
+        letter.endpoints.is_empty() || letter.endpoints.contains(&destination.url)
+    });
+    for destination in destinations {
+        destination
+            .forward(client, &letter.id, &letter.mail, payload)
+            .await?;
+    }
+    letters.remove(id).await?;
+    tracing::info!("Redelivered {id}");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_add_list_remove() {
+        let dir = std::env::temp_dir().join(format!("dead-letters-{}", QueueId::generate()));
+        let letters = DeadLetters::open(&dir).await.unwrap();
+        let letter = |reason: &str| DeadLetter {
+            id: QueueId::generate(),
+            failed_at: Utc::now(),
+            attempts: 3,
+            reason: reason.into(),
+            mail: Mail::default(),
+            priority: Priority::Normal,
+            tenant: None,
+            payload: None,
+            endpoints: Vec::new(),
+        };
+        let (first, second) = (letter("404 Not Found"), letter("gave up"));
+        letters.add(&first).await.unwrap();
+        letters.add(&second).await.unwrap();
+        let listed = letters.list().await.unwrap();
+        assert_eq!(
+            listed.iter().map(|letter| &letter.id).collect::<Vec<_>>(),
+            [&first.id, &second.id]
+        );
+        assert_eq!(listed[0].reason, "404 Not Found");
+
+        let removed = letters.remove(first.id.as_str()).await.unwrap().unwrap();
+        assert_eq!(removed.queued().id, first.id);
+        assert!(letters.remove(first.id.as_str()).await.unwrap().is_none());
+        assert!(letters.get("../escape").await.is_err());
+        assert_eq!(letters.list().await.unwrap().len(), 1);
+
+        assert_eq!(letters.remove_expired(|_| false).await.unwrap(), 0);
+        assert_eq!(
+            letters
+                .remove_expired(|letter| letter.id == second.id)
+                .await
+                .unwrap(),
+            1
+        );
+        assert!(letters.list().await.unwrap().is_empty());
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    /// Answers every request with a 200, sending back the paths posted to
+    async fn endpoint() -> (String, tokio::sync::mpsc::UnboundedReceiver<String>) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let (paths, received) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buffer = [0; 4096];
+                // The payload is all in once it ends the JSON object
+                while !request.ends_with(b"}") {
+                    let read = stream.read(&mut buffer).await.unwrap();
+                    request.extend_from_slice(&buffer[..read]);
+                }
+                let request = String::from_utf8_lossy(&request);
+                let path = request.split(' ').nth(1).unwrap_or_default();
+                paths.send(path.to_string()).unwrap();
+                stream
+                    .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n")
+                    .await
+                    .unwrap();
+            }
+        });
+        (url, received)
+    }
+
+    #[tokio::test]
+    async fn test_redeliver_routes() {
+        let dir = std::env::temp_dir().join(format!("dead-letters-{}", QueueId::generate()));
+        let letters = DeadLetters::open(&dir).await.unwrap();
+        let mail = Mail {
+            data: b"From: a@example.com\r\nSubject: hi\r\n\r\nhello\r\n".to_vec(),
+            ..Mail::default()
+        };
+        let letter = |tenant: Option<&str>| DeadLetter {
+            id: QueueId::generate(),
+            failed_at: Utc::now(),
+            attempts: 3,
+            reason: "gave up".into(),
+            mail: mail.clone(),
+            priority: Priority::Normal,
+            tenant: tenant.map(str::to_string),
+            payload: Some(crate::payload::build(&mail.data, &Default::default()).unwrap()),
+            endpoints: Vec::new(),
+        };
+        let (of_no_tenant, of_acme, of_gone) =
+            (letter(None), letter(Some("acme")), letter(Some("gone")));
+        for letter in [&of_no_tenant, &of_acme, &of_gone] {
+            letters.add(letter).await.unwrap();
+        }
+
+        let (url, mut received) = endpoint().await;
+        let destination = |path: &str| Destination::new(format!("{url}{path}")).unwrap();
+        let destinations = vec![destination("/default"), destination("/fanout")];
+        let tenants = [Tenant::new(
+            "acme",
+            vec!["@acme.com".into()],
+            destination("/acme"),
+        )];
+        let ids = [&of_no_tenant, &of_acme, &of_gone].map(|letter| letter.id.to_string());
+        let args = Args::Redeliver { ids: ids.to_vec() };
+        // The letter of a tenant that no longer exists stays
+        assert!(run(args, &dir, destinations, &tenants).await.is_err());
+        let mut paths = Vec::new();
+        while let Ok(path) = received.try_recv() {
+            paths.push(path);
+        }
+        assert_eq!(paths, ["/default", "/fanout", "/acme"]);
+        let left = letters.list().await.unwrap();
+        assert_eq!(
+            left.iter().map(|letter| &letter.id).collect::<Vec<_>>(),
+            [&of_gone.id]
+        );
+
+        // Without a forward URL, only the tenants' letters can be redelivered
+        letters.add(&of_no_tenant).await.unwrap();
+        let err = redeliver(
+            &letters,
+            of_no_tenant.id.as_str(),
+            &[],
+            &tenants,
+            &reqwest::Client::new(),
+        )
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("forward-url"));
+
+        // Given up on for one endpoint of the fanout, only that one gets it again
+        let of_fanout = DeadLetter {
+            endpoints: vec![format!("{url}/fanout")],
+            ..letter(None)
+        };
+        letters.add(&of_fanout).await.unwrap();
+        let args = Args::Redeliver {
+            ids: vec![of_fanout.id.to_string()],
+        };
+        let destinations = vec![destination("/default"), destination("/fanout")];
+        run(args, &dir, destinations, &tenants).await.unwrap();
+        assert_eq!(received.try_recv().unwrap(), "/fanout");
+        assert!(received.try_recv().is_err());
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn test_parse_args() {
+        assert_eq!(Args::parse(["list".to_string()]).unwrap(), Args::List);
+        assert_eq!(
+            Args::parse(["redeliver", "A1", "B2"].map(String::from)).unwrap(),
+            Args::Redeliver {
+                ids: vec!["A1".into(), "B2".into()]
+            }
+        );
+        assert!(Args::parse(["redeliver".to_string()]).is_err());
+        assert!(Args::parse(Vec::new()).is_err());
+    }
+}
@@ -0,0 +1,134 @@
+use std::borrow::Cow;
+
+use base64::engine::{general_purpose, DecodePaddingMode, GeneralPurpose};
+use base64::{alphabet, Engine};
+use mail_parser::decoders::charsets::map::charset_decoder;
+
+/// Base64 engine tolerating missing or superfluous padding,
+/// both of which are common in encoded-words produced in the wild.
+const LENIENT_BASE64: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::STANDARD,
+    general_purpose::PAD.with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+/// Decodes RFC 2047 encoded-words (`=?charset?B|Q?text?=`) left in header text.
+/// mail_parser already handles well-formed headers, but gives up on words
+/// glued to surrounding text or with broken padding, leaking them verbatim.
+/// Words that cannot be decoded are kept as they are.
+pub fn encoded_words(text: &str) -> Cow<'_, str> {
+    if !text.contains("=?") {
+        return Cow::Borrowed(text);
+    }
+    let text = unfold(text);
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text.as_str();
+    // Whitespace separating two adjacent encoded-words is not displayed
+    let mut after_word = false;
+    while let Some(start) = rest.find("=?") {
+        let (before, candidate) = rest.split_at(start);
+        match decode_word(candidate) {
+            Some((word, len)) => {
+                if !(after_word && before.chars().all(char::is_whitespace)) {
+                    decoded.push_str(before);
+                }
+                decoded.push_str(&word);
+                rest = &candidate[len..];
+                after_word = true;
+            }
+            None => {
+                decoded.push_str(before);
+                decoded.push_str("=?");
+                rest = &candidate[2..];
+                after_word = false;
+            }
+        }
+    }
+    decoded.push_str(rest);
+    Cow::Owned(decoded)
+}
+
+/// Joins folded header lines back into a single line
+fn unfold(text: &str) -> String {
+    text.replace("\r\n ", " ")
+        .replace("\r\n\t", " ")
+        .replace("\n ", " ")
+        .replace("\n\t", " ")
+}
+
+/// Decodes a single encoded-word at the start of `text`,
+/// returning the decoded value and the length of the word.
+fn decode_word(text: &str) -> Option<(String, usize)> {
+    let body = text.strip_prefix("=?")?;
+    let (charset, body) = body.split_once('?')?;
+    let (encoding, body) = body.split_once('?')?;
+    let end = body.find("?=")?;
+    let payload = &body[..end];
+    if charset.is_empty() {
+        return None;
+    }
+    // RFC 2231 allows a language suffix: `=?UTF-8*en?Q?...?=`
+    let charset = charset.split('*').next().unwrap_or_default();
+    let bytes = match encoding {
+        "B" | "b" => LENIENT_BASE64.decode(payload.trim_end_matches('=')).ok()?,
+        "Q" | "q" => quoted_printable_word(payload)?,
+        _ => return None,
+    };
+    let word = match charset_decoder(charset.as_bytes()) {
+        Some(decoder) => decoder(&bytes),
+        None => String::from_utf8_lossy(&bytes).into_owned(),
+    };
+    let len = text.len() - body.len() + end + 2;
+    Some((word, len))
+}
+
+/// Decodes the `Q` encoding, which is quoted-printable with `_` standing for a space
+fn quoted_printable_word(payload: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(payload.len());
+    let mut chars = payload.bytes();
+    while let Some(ch) = chars.next() {
+        match ch {
+            b'_' => bytes.push(b' '),
+            b'=' => {
+                let hex = [chars.next()?, chars.next()?];
+                let hex = std::str::from_utf8(&hex).ok()?;
+                bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            }
+            ch => bytes.push(ch),
+        }
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plain_text_untouched() {
+        assert!(matches!(encoded_words("Hello world"), Cow::Borrowed(_)));
+        assert_eq!(encoded_words("a =? b"), "a =? b");
+    }
+
+    #[test]
+    fn test_glued_words() {
+        assert_eq!(encoded_words("Team=?UTF-8?Q?Caf=C3=A9?="), "TeamCafé");
+        assert_eq!(
+            encoded_words("=?UTF-8?B?SGVsbG8g?=\r\n =?utf-8?b?d29ybGQ?="),
+            "Hello world"
+        );
+    }
+
+    #[test]
+    fn test_charsets() {
+        assert_eq!(encoded_words("=?ISO-8859-1?Q?Andr=E9?="), "André");
+        assert_eq!(
+            encoded_words("=?UTF-8*en?Q?Caf=C3=A9_ol=C3=A9?="),
+            "Café olé"
+        );
+    }
+
+    #[test]
+    fn test_missing_padding() {
+        assert_eq!(encoded_words("=?UTF-8?B?SGVsbG8gd29ybGQ?="), "Hello world");
+    }
+}
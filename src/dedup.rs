@@ -0,0 +1,260 @@
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+use crate::format;
+use crate::protocol::Mail;
+use crate::queue::QueueId;
+
+/// What becomes of a mail already accepted once
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Duplicates {
+    /// Acknowledged, so the sender stops retrying, but not delivered
+    Skip,
+    /// Delivered, the payload naming the mail it duplicates
+    Flag,
+}
+
+impl FromStr for Duplicates {
+    type Err = anyhow::Error;
+
+    fn from_str(duplicates: &str) -> Result<Self> {
+        match duplicates {
+            "skip" => Ok(Self::Skip),
+            "flag" => Ok(Self::Flag),
+            _ => anyhow::bail!("unknown duplicate handling {duplicates}, expected skip or flag"),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    key: String,
+    id: QueueId,
+}
+
+struct Seen {
+    order: VecDeque<String>,
+    ids: HashMap<String, QueueId>,
+    file: tokio::fs::File,
+    /// Lines in the file, compacted once it holds twice the capacity
+    lines: usize,
+}
+
+/// Persistent record of the Message-IDs of the latest accepted mail, catching
+/// the copies senders send again when our reply to DATA got lost or came too late.
+/// A Message-ID counts once per set of envelope recipients, as a message sent to
+/// several recipients in separate transactions is no duplicate.
+pub struct MessageIds {
+    path: PathBuf,
+    /// Number of Message-IDs remembered, the oldest being forgotten first
+    capacity: usize,
+    pub duplicates: Duplicates,
+    seen: Mutex<Seen>,
+}
+
+impl MessageIds {
+    pub async fn open(
+        path: impl AsRef<Path>,
+        capacity: usize,
+        duplicates: Duplicates,
+    ) -> Result<Self> {
+        anyhow::ensure!(capacity > 0, "message-id-capacity must be at least 1");
+        let path = path.as_ref().to_path_buf();
+        let entries = match tokio::fs::read_to_string(&path).await {
+            Ok(seen) => seen
+                .lines()
+                .filter_map(|line| serde_json::from_str::<Entry>(line).ok())
+                .collect(),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| format!("cannot read Message-IDs {}", path.display()))
+            }
+        };
+        let mut order = VecDeque::new();
+        let mut ids = HashMap::new();
+        for entry in entries {
+            if ids.insert(entry.key.clone(), entry.id).is_none() {
+                order.push_back(entry.key);
+            }
+        }
+        while order.len() > capacity {
+            if let Some(key) = order.pop_front() {
+                ids.remove(&key);
+            }
+        }
+        let file = compact(&path, &order, &ids).await?;
+        Ok(Self {
+            path,
+            capacity,
+            duplicates,
+            seen: Mutex::new(Seen {
+                lines: order.len(),
+                order,
+                ids,
+                file,
+            }),
+        })
+    }
+
+    /// The id of the mail this one duplicates, if any.
+    /// Mail without a Message-ID is never a duplicate.
+    pub async fn find(&self, mail: &Mail) -> Option<QueueId> {
+        let key = key(mail)?;
+        self.seen.lock().await.ids.get(&key).cloned()
+    }
+
+    /// Remembers the mail accepted as `id`, once it is journaled, for its
+    /// duplicates to be found. A mail remembered already keeps its first id.
+    pub async fn remember(&self, mail: &Mail, id: &QueueId) -> Result<()> {
+        let Some(key) = key(mail) else {
+            return Ok(());
+        };
+        let mut seen = self.seen.lock().await;
+        if seen.ids.contains_key(&key) {
+            return Ok(());
+        }
+        let mut line = serde_json::to_string(&Entry {
+            key: key.clone(),
+            id: id.clone(),
+        })?;
+        line.push('\n');
+        seen.file
+            .write_all(line.as_bytes())
+            .await
+            .with_context(|| format!("cannot write Message-IDs {}", self.path.display()))?;
+        seen.file.sync_data().await?;
+        seen.lines += 1;
+        seen.ids.insert(key.clone(), id.clone());
+        seen.order.push_back(key);
+        if seen.order.len() > self.capacity {
+            if let Some(oldest) = seen.order.pop_front() {
+                seen.ids.remove(&oldest);
+            }
+        }
+        if seen.lines > 2 * self.capacity {
+            seen.file = compact(&self.path, &seen.order, &seen.ids).await?;
+            seen.lines = seen.order.len();
+        }
+        Ok(())
+    }
+
+    /// Forgets the mail remembered as `id`, refused after all, so the copy the
+    /// client sends again isn't taken for a duplicate
+    pub async fn forget(&self, mail: &Mail, id: &QueueId) -> Result<()> {
+        let Some(key) = key(mail) else {
+            return Ok(());
+        };
+        let mut seen = self.seen.lock().await;
+        if seen.ids.get(&key) != Some(id) {
+            return Ok(());
+        }
+        seen.ids.remove(&key);
+        seen.order.retain(|remembered| *remembered != key);
+        seen.file = compact(&self.path, &seen.order, &seen.ids).await?;
+        seen.lines = seen.order.len();
+        Ok(())
+    }
+}
+
+/// Rewrites the file with only the remembered Message-IDs, returning it opened for appending
+async fn compact(
+    path: &Path,
+    order: &VecDeque<String>,
+    ids: &HashMap<String, QueueId>,
+) -> Result<tokio::fs::File> {
+    let mut compacted = String::new();
+    for key in order {
+        compacted += &serde_json::to_string(&Entry {
+            key: key.clone(),
+            id: ids[key].clone(),
+        })?;
+        compacted.push('\n');
+    }
+    let tmp = path.with_extension("compact");
+    tokio::fs::write(&tmp, compacted).await?;
+    tokio::fs::rename(&tmp, path)
+        .await
+        .with_context(|| format!("cannot compact Message-IDs {}", path.display()))?;
+    Ok(tokio::fs::OpenOptions::new()
+        .append(true)
+        .open(path)
+        .await?)
+}
+
+/// The Message-ID and the envelope recipients, in a canonical order
+fn key(mail: &Mail) -> Option<String> {
+    let message_id = format::header_fields(format::headers(mail))
+        .into_iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("Message-ID"))
+        .map(|(_, value)| value)
+        .filter(|value| !value.is_empty())?;
+    let mut recipients = mail
+        .to
+        .iter()
+        .map(|to| to.trim_matches(|c| c == '<' || c == '>').to_lowercase())
+        .collect::<Vec<_>>();
+    recipients.sort();
+    recipients.dedup();
+    Some(format!("{message_id} {}", recipients.join(",")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mail(message_id: &str, to: &str) -> Mail {
+        Mail {
+            from: "<a@example.com>".into(),
+            to: vec![to.into()],
+            data: format!("Message-ID: {message_id}\r\nSubject: hi\r\n\r\nhello\r\n").into(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_duplicates() {
+        let path = std::env::temp_dir().join(format!("message-ids-{}", QueueId::generate()));
+        let (first, second) = (QueueId::generate(), QueueId::generate());
+        {
+            let ids = MessageIds::open(&path, 2, Duplicates::Skip).await.unwrap();
+            let original = mail("<1@example.com>", "<b@example.com>");
+            assert_eq!(ids.find(&original).await, None);
+            ids.remember(&original, &first).await.unwrap();
+            assert_eq!(ids.find(&original).await, Some(first.clone()));
+            // Remembered again, e.g. accepted twice at once, it keeps its first id
+            ids.remember(&original, &second).await.unwrap();
+            assert_eq!(ids.find(&original).await, Some(first.clone()));
+            // Another recipient, or no Message-ID, is no duplicate
+            let other = mail("<1@example.com>", "<C@example.com>");
+            assert_eq!(ids.find(&other).await, None);
+            ids.remember(&Mail::default(), &second).await.unwrap();
+            assert_eq!(ids.find(&Mail::default()).await, None);
+        }
+        let ids = MessageIds::open(&path, 2, Duplicates::Skip).await.unwrap();
+        let original = mail("<1@example.com>", "<b@example.com>");
+        assert_eq!(ids.find(&original).await, Some(first.clone()));
+
+        // Forgotten only by the mail it was remembered as
+        ids.forget(&original, &second).await.unwrap();
+        assert_eq!(ids.find(&original).await, Some(first.clone()));
+        ids.forget(&original, &first).await.unwrap();
+        assert_eq!(ids.find(&original).await, None);
+        let ids = MessageIds::open(&path, 2, Duplicates::Skip).await.unwrap();
+        assert_eq!(ids.find(&original).await, None);
+        ids.remember(&original, &first).await.unwrap();
+
+        // Beyond the capacity, the oldest are forgotten
+        for n in 2..4 {
+            let mail = mail(&format!("<{n}@example.com>"), "<b@example.com>");
+            ids.remember(&mail, &QueueId::generate()).await.unwrap();
+        }
+        assert_eq!(ids.find(&original).await, None);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,597 @@
+#[cfg(feature = "dns")]
+use std::collections::HashMap;
+#[cfg(feature = "dns")]
+use std::fmt;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use rsa::pkcs1::DecodeRsaPrivateKey;
+use rsa::pkcs1v15::SigningKey;
+use rsa::pkcs8::DecodePrivateKey;
+use rsa::signature::{SignatureEncoding, Signer as _};
+use rsa::RsaPrivateKey;
+#[cfg(feature = "dns")]
+use rsa::{
+    pkcs1::DecodeRsaPublicKey, pkcs1v15::Signature, pkcs1v15::VerifyingKey, pkcs8::DecodePublicKey,
+    signature::Verifier, traits::PublicKeyParts, RsaPublicKey,
+};
+use sha2::{Digest, Sha256};
+
+#[cfg(feature = "dns")]
+use crate::dns::{LookupError, Resolver};
+#[cfg(feature = "dns")]
+use crate::header::field_value;
+use crate::header::{field_name, split};
+use crate::protocol;
+
+/// Fields signed when present, as RFC 6376 recommends
+const SIGNED_FIELDS: &[&str] = &[
+    "from",
+    "reply-to",
+    "subject",
+    "date",
+    "to",
+    "cc",
+    "message-id",
+    "in-reply-to",
+    "references",
+    "mime-version",
+    "content-type",
+    "content-transfer-encoding",
+];
+
+/// Signs relayed messages with DKIM, rsa-sha256 and relaxed canonicalization,
+/// so the mail forwarded from our domain passes DKIM at its destination
+pub struct Signer {
+    /// Domain of the signature, `d=`
+    pub domain: String,
+    /// Selector of the public key, published at `<selector>._domainkey.<domain>`
+    pub selector: String,
+    key: SigningKey<Sha256>,
+}
+
+impl Signer {
+    /// Signs with the RSA private key at `path`, PEM-encoded in PKCS#8 or PKCS#1
+    pub fn load(
+        domain: impl Into<String>,
+        selector: impl Into<String>,
+        path: &Path,
+    ) -> Result<Self> {
+        let pem = std::fs::read_to_string(path)
+            .with_context(|| format!("cannot read DKIM private key {}", path.display()))?;
+        let key = RsaPrivateKey::from_pkcs8_pem(&pem)
+            .ok()
+            .or_else(|| RsaPrivateKey::from_pkcs1_pem(&pem).ok())
+            .with_context(|| format!("invalid DKIM private key {}", path.display()))?;
+        Ok(Self::new(domain, selector, key))
+    }
+
+    pub fn new(domain: impl Into<String>, selector: impl Into<String>, key: RsaPrivateKey) -> Self {
+        Self {
+            domain: domain.into(),
+            selector: selector.into(),
+            key: SigningKey::new(key),
+        }
+    }
+
+    /// The message with a `DKIM-Signature` field added on top, signed at `now`
+    pub fn sign(&self, data: &[u8], now: DateTime<Utc>) -> Vec<u8> {
+        let message = to_crlf(protocol::strip_terminator(data));
+        let mut signed = self
+            .signature("DKIM-Signature", "v=1", &message, now)
+            .into_bytes();
+        signed.extend(message);
+        signed
+    }
+
+    /// A signature field of a CRLF message, named `name` and with `tags` first:
+    /// `DKIM-Signature`, or `ARC-Message-Signature` for ARC
+    pub(crate) fn signature(
+        &self,
+        name: &str,
+        tags: &str,
+        message: &[u8],
+        now: DateTime<Utc>,
+    ) -> String {
+        let (fields, body) = split(message);
+        let body_hash =
+            base64::engine::general_purpose::STANDARD.encode(Sha256::digest(relaxed_body(body)));
+
+        // Each instance of a field is signed, bottom-up as RFC 6376 §5.4.2 says
+        let mut names = Vec::new();
+        let mut hashed = Vec::new();
+        for name in SIGNED_FIELDS {
+            for field in fields
+                .iter()
+                .rev()
+                .filter(|field| field_name(field) == *name)
+            {
+                names.push(*name);
+                hashed.extend(relaxed_header(field));
+            }
+        }
+        let signature = format!(
+            "{name}: {tags}; a=rsa-sha256; c=relaxed/relaxed; d={}; s={};\r\n\
+             \tt={}; h={};\r\n\tbh={body_hash};\r\n\tb=",
+            self.domain,
+            self.selector,
+            now.timestamp(),
+            names.join(":"),
+        );
+        self.complete(hashed, signature)
+    }
+
+    /// Completes a field ending with an empty `b=` with the signature of `hashed`
+    /// followed by the field itself, canonicalized without its CRLF
+    pub(crate) fn complete(&self, mut hashed: Vec<u8>, mut field: String) -> String {
+        let mut canonical = relaxed_header(field.as_bytes());
+        canonical.truncate(canonical.len() - 2);
+        hashed.extend(canonical);
+        let b = self.key.sign(&hashed).to_bytes();
+        field.push_str(&base64::engine::general_purpose::STANDARD.encode(b));
+        field.push_str("\r\n");
+        field
+    }
+}
+
+/// Signatures of a message verified at most, so a message can't have us look up keys forever
+#[cfg(feature = "dns")]
+const MAX_SIGNATURES: usize = 5;
+
+/// Smallest RSA key accepted, in bytes (RFC 8301)
+#[cfg(feature = "dns")]
+const MIN_KEY_SIZE: usize = 1024 / 8;
+
+/// Outcome of the verification of a DKIM signature (RFC 8601 §2.7.1)
+#[cfg(feature = "dns")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DkimResult {
+    Pass,
+    /// The signature doesn't match the message, e.g. altered on the way
+    Fail,
+    /// The key couldn't be looked up, the verification may succeed later
+    TempError,
+    /// The signature or its key is broken or unsupported
+    PermError,
+}
+
+#[cfg(feature = "dns")]
+impl DkimResult {
+    /// Name of the result, as in `Authentication-Results`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Pass => "pass",
+            Self::Fail => "fail",
+            Self::TempError => "temperror",
+            Self::PermError => "permerror",
+        }
+    }
+}
+
+/// A verified signature, displayed as in `Authentication-Results`, e.g.
+/// `dkim=pass header.d=example.com header.s=mail header.b=dGhpcyBp`
+#[cfg(feature = "dns")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Verification {
+    pub result: DkimResult,
+    /// Why the signature didn't pass
+    pub reason: Option<&'static str>,
+    /// Signing domain, `d=`
+    pub domain: String,
+    /// Selector of the key, `s=`
+    pub selector: String,
+    /// Start of the signature, telling apart the signatures of a domain
+    pub b: String,
+}
+
+#[cfg(feature = "dns")]
+impl fmt::Display for Verification {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "dkim={}", self.result.as_str())?;
+        if let Some(reason) = self.reason {
+            write!(f, " reason=\"{reason}\"")?;
+        }
+        write!(
+            f,
+            " header.d={} header.s={} header.b={}",
+            self.domain, self.selector, self.b
+        )
+    }
+}
+
+/// Verifies the DKIM signatures of a CRLF message at `now`, looking up their keys
+#[cfg(feature = "dns")]
+pub async fn verify(
+    resolver: &impl Resolver,
+    message: &[u8],
+    now: DateTime<Utc>,
+) -> Vec<Verification> {
+    let (fields, body) = split(message);
+    let mut verifications = Vec::new();
+    for field in fields
+        .iter()
+        .filter(|field| field_name(field) == "dkim-signature")
+        .take(MAX_SIGNATURES)
+    {
+        let value = field_value(field);
+        let outcome = verify_signature(resolver, field, &value, &fields, body, now).await;
+        let (result, reason) = match outcome {
+            Ok(()) => (DkimResult::Pass, None),
+            Err((result, reason)) => (result, Some(reason)),
+        };
+        let b = tag(&value, "b").unwrap_or_default();
+        verifications.push(Verification {
+            result,
+            reason,
+            domain: tag(&value, "d").unwrap_or_default().to_string(),
+            selector: tag(&value, "s").unwrap_or_default().to_string(),
+            b: b.split_whitespace()
+                .collect::<String>()
+                .chars()
+                .take(8)
+                .collect(),
+        });
+    }
+    verifications
+}
+
+/// Verifies a signature field (RFC 6376 §6.1), with `value` its value
+#[cfg(feature = "dns")]
+async fn verify_signature(
+    resolver: &impl Resolver,
+    field: &[u8],
+    value: &str,
+    fields: &[&[u8]],
+    body: &[u8],
+    now: DateTime<Utc>,
+) -> Result<(), (DkimResult, &'static str)> {
+    const SYNTAX: (DkimResult, &str) = (DkimResult::PermError, "signature syntax error");
+    const INVALID_KEY: (DkimResult, &str) = (DkimResult::PermError, "invalid key");
+    let get = |name| tag(value, name);
+    let decode = |value: &str| {
+        base64::engine::general_purpose::STANDARD
+            .decode(value.split_whitespace().collect::<String>())
+    };
+    if get("v") != Some("1") {
+        return Err(SYNTAX);
+    }
+    let domain = get("d").filter(|domain| !domain.is_empty()).ok_or(SYNTAX)?;
+    let selector = get("s")
+        .filter(|selector| !selector.is_empty())
+        .ok_or(SYNTAX)?;
+    let signed = get("h")
+        .ok_or(SYNTAX)?
+        .split(':')
+        .map(|name| name.trim().to_lowercase())
+        .collect::<Vec<_>>();
+    if !signed.iter().any(|name| name == "from") {
+        return Err(SYNTAX);
+    }
+    let b = decode(get("b").ok_or(SYNTAX)?).map_err(|_| SYNTAX)?;
+    let body_hash = decode(get("bh").ok_or(SYNTAX)?).map_err(|_| SYNTAX)?;
+    if !get("a").ok_or(SYNTAX)?.eq_ignore_ascii_case("rsa-sha256") {
+        return Err((DkimResult::PermError, "unsupported algorithm"));
+    }
+    // Canonicalizations of the header and the body, the body one simple when left out
+    let c = get("c").unwrap_or("simple");
+    let (header_c, body_c) = c.split_once('/').unwrap_or((c, "simple"));
+    if ![header_c, body_c]
+        .iter()
+        .all(|c| *c == "simple" || *c == "relaxed")
+    {
+        return Err(SYNTAX);
+    }
+    let (relaxed_headers, relaxed) = (header_c == "relaxed", body_c == "relaxed");
+    // The signing identity must be of the signing domain
+    if let Some(identity) = get("i") {
+        let identity = identity.rsplit_once('@').ok_or(SYNTAX)?.1.to_lowercase();
+        let domain = domain.to_lowercase();
+        if identity != domain && !identity.ends_with(&format!(".{domain}")) {
+            return Err(SYNTAX);
+        }
+    }
+    if let Some(expiry) = get("x") {
+        if expiry.parse::<i64>().map_err(|_| SYNTAX)? < now.timestamp() {
+            return Err((DkimResult::Fail, "signature expired"));
+        }
+    }
+    let length = get("l")
+        .map(|length| length.parse::<usize>())
+        .transpose()
+        .map_err(|_| SYNTAX)?;
+
+    let records = match resolver
+        .txt(&format!("{selector}._domainkey.{domain}"))
+        .await
+    {
+        Ok(records) => records,
+        Err(LookupError::NotFound) => return Err((DkimResult::PermError, "no key")),
+        Err(LookupError::Failed(err)) => {
+            tracing::debug!("Cannot look up the DKIM key {selector} of {domain}: {err}");
+            return Err((DkimResult::TempError, "key unavailable"));
+        }
+    };
+    let record = records.first().ok_or((DkimResult::PermError, "no key"))?;
+    if tag(record, "k").is_some_and(|k| !k.eq_ignore_ascii_case("rsa")) {
+        return Err((DkimResult::PermError, "unsupported key type"));
+    }
+    let der = match tag(record, "p").ok_or(INVALID_KEY)? {
+        "" => return Err((DkimResult::PermError, "key revoked")),
+        key => decode(key).map_err(|_| INVALID_KEY)?,
+    };
+    let key = RsaPublicKey::from_public_key_der(&der)
+        .or_else(|_| RsaPublicKey::from_pkcs1_der(&der))
+        .map_err(|_| INVALID_KEY)?;
+    if key.size() < MIN_KEY_SIZE {
+        return Err((DkimResult::PermError, "key too short"));
+    }
+
+    let canonical_body = match relaxed {
+        true => relaxed_body(body),
+        false => simple_body(body),
+    };
+    let hashed_body = match length {
+        Some(length) => canonical_body
+            .get(..length)
+            .ok_or((DkimResult::Fail, "body shorter than signed"))?,
+        None => &canonical_body,
+    };
+    if Sha256::digest(hashed_body).as_slice() != body_hash {
+        return Err((DkimResult::Fail, "body hash mismatch"));
+    }
+
+    let canonical_header = |field: &[u8]| match relaxed_headers {
+        true => relaxed_header(field),
+        false => [field, b"\r\n"].concat(),
+    };
+    // Each instance of a name takes the next field up, the missing ones nothing
+    let mut taken = HashMap::new();
+    let mut hashed = Vec::new();
+    for name in &signed {
+        let count = taken.entry(name).or_insert(0);
+        let instance = fields
+            .iter()
+            .rev()
+            .filter(|field| field_name(field) == *name)
+            .nth(*count);
+        *count += 1;
+        if let Some(instance) = instance {
+            hashed.extend(canonical_header(instance));
+        }
+    }
+    let mut canonical = canonical_header(without_b(field).as_slice());
+    canonical.truncate(canonical.len() - 2);
+    hashed.extend(canonical);
+    let signature = Signature::try_from(b.as_slice())
+        .map_err(|_| (DkimResult::Fail, "signature did not verify"))?;
+    VerifyingKey::<Sha256>::new(key)
+        .verify(&hashed, &signature)
+        .map_err(|_| (DkimResult::Fail, "signature did not verify"))
+}
+
+/// A signature field with its `b=` tag emptied, as it is hashed
+#[cfg(feature = "dns")]
+fn without_b(field: &[u8]) -> Vec<u8> {
+    let field = String::from_utf8_lossy(field);
+    let Some((name, value)) = field.split_once(':') else {
+        return field.into_owned().into_bytes();
+    };
+    let tags = value
+        .split(';')
+        .map(|tag| match tag.split_once('=') {
+            Some((name, _)) if name.trim() == "b" => format!("{name}="),
+            _ => tag.to_string(),
+        })
+        .collect::<Vec<_>>();
+    format!("{name}:{}", tags.join(";")).into_bytes()
+}
+
+/// The message with bare LF line endings turned into CRLF, as DKIM hashes CRLF lines
+pub(crate) fn to_crlf(data: &[u8]) -> Vec<u8> {
+    let mut crlf = Vec::with_capacity(data.len());
+    for (index, &byte) in data.iter().enumerate() {
+        if byte == b'\n' && (index == 0 || data[index - 1] != b'\r') {
+            crlf.push(b'\r');
+        }
+        crlf.push(byte);
+    }
+    crlf
+}
+
+/// Value of a tag in a `tag=value; ...` list, e.g. of a signature field
+pub(crate) fn tag<'a>(list: &'a str, name: &str) -> Option<&'a str> {
+    list.split(';').find_map(|tag| {
+        let (tag, value) = tag.split_once('=')?;
+        (tag.trim() == name).then(|| value.trim())
+    })
+}
+
+/// A header field in the relaxed canonicalization (RFC 6376 §3.4.2): lowercase
+/// name, unfolded value with runs of whitespace as one space, trimmed, then CRLF
+pub(crate) fn relaxed_header(field: &[u8]) -> Vec<u8> {
+    let colon = field
+        .iter()
+        .position(|&byte| byte == b':')
+        .unwrap_or(field.len());
+    let mut canonical = field_name(field).into_bytes();
+    canonical.push(b':');
+    let mut space = false;
+    let mut value = false;
+    for &byte in field.get(colon + 1..).unwrap_or_default() {
+        match byte {
+            b'\r' | b'\n' => {}
+            b' ' | b'\t' => space = true,
+            byte => {
+                if space && value {
+                    canonical.push(b' ');
+                }
+                space = false;
+                value = true;
+                canonical.push(byte);
+            }
+        }
+    }
+    canonical.extend_from_slice(b"\r\n");
+    canonical
+}
+
+/// A body in the simple canonicalization (RFC 6376 §3.4.3): as it is,
+/// without empty lines at the end, ending with a CRLF
+#[cfg(feature = "dns")]
+pub(crate) fn simple_body(body: &[u8]) -> Vec<u8> {
+    let mut end = body.len();
+    while body[..end].ends_with(b"\r\n") {
+        end -= 2;
+    }
+    let mut canonical = body[..end].to_vec();
+    canonical.extend_from_slice(b"\r\n");
+    canonical
+}
+
+/// A body in the relaxed canonicalization (RFC 6376 §3.4.4): runs of whitespace
+/// as one space, none at the end of lines, no empty lines at the end
+pub(crate) fn relaxed_body(body: &[u8]) -> Vec<u8> {
+    let mut canonical = Vec::with_capacity(body.len());
+    let mut empty_lines = 0;
+    for line in body.split(|&byte| byte == b'\n') {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let mut relaxed = Vec::with_capacity(line.len());
+        let mut space = false;
+        for &byte in line {
+            match byte {
+                b' ' | b'\t' => space = true,
+                byte => {
+                    if space {
+                        relaxed.push(b' ');
+                    }
+                    space = false;
+                    relaxed.push(byte);
+                }
+            }
+        }
+        if relaxed.is_empty() {
+            empty_lines += 1;
+            continue;
+        }
+        for _ in 0..empty_lines {
+            canonical.extend_from_slice(b"\r\n");
+        }
+        empty_lines = 0;
+        canonical.extend(relaxed);
+        canonical.extend_from_slice(b"\r\n");
+    }
+    canonical
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_relaxed() {
+        // The example of RFC 6376 §3.4.5, with a value holding a colon
+        let message =
+            b"A: X\r\nB : Y\t\r\n\tZ  \r\nSubject: Re:  hi\r\n\r\n C \r\nD \t E\r\n\r\n\r\n";
+        let (fields, body) = split(message);
+        assert_eq!(
+            fields
+                .iter()
+                .flat_map(|field| relaxed_header(field))
+                .collect::<Vec<_>>(),
+            b"a:X\r\nb:Y Z\r\nsubject:Re: hi\r\n"
+        );
+        assert_eq!(relaxed_body(body), b" C\r\nD E\r\n");
+        assert_eq!(relaxed_body(b"\r\n\r\n"), b"");
+        assert_eq!(to_crlf(b"a\nb\r\n"), b"a\r\nb\r\n");
+    }
+
+    // Keys are generated with rand, which comes with the relay
+    #[cfg(feature = "relay")]
+    #[test]
+    fn test_sign() {
+        use rsa::pkcs1v15::VerifyingKey;
+        use rsa::signature::Verifier;
+
+        let key = RsaPrivateKey::new(&mut rand::thread_rng(), 1024).unwrap();
+        let verifying_key = VerifyingKey::<Sha256>::new(key.to_public_key());
+        let signer = Signer::new("example.com", "mail", key);
+        let signed = signer.sign(
+            b"From: a@example.com\r\nSubject: hi\r\nX-Other: no\r\n\r\nhello\r\n",
+            Utc::now(),
+        );
+        let (fields, body) = split(&signed);
+        assert_eq!(body, b"hello\r\n");
+        let signature = String::from_utf8(fields[0].to_vec()).unwrap();
+        assert!(signature.contains("d=example.com; s=mail;"));
+        assert!(signature.contains("h=from:subject;"));
+
+        let (unsigned, b) = signature.split_once("\tb=").unwrap();
+        let mut hashed = relaxed_header(fields[1]);
+        hashed.extend(relaxed_header(fields[2]));
+        let mut canonical = relaxed_header(format!("{unsigned}\tb=").as_bytes());
+        canonical.truncate(canonical.len() - 2);
+        hashed.extend(canonical);
+        let b = base64::engine::general_purpose::STANDARD
+            .decode(b.trim())
+            .unwrap();
+        verifying_key
+            .verify(&hashed, &b.as_slice().try_into().unwrap())
+            .unwrap();
+    }
+
+    #[cfg(all(feature = "dns", feature = "relay"))]
+    #[tokio::test]
+    async fn test_verify() {
+        use rsa::pkcs8::EncodePublicKey;
+
+        use crate::dns::Zone;
+
+        let key = RsaPrivateKey::new(&mut rand::thread_rng(), 1024).unwrap();
+        let public = key.to_public_key().to_public_key_der().unwrap();
+        let record = format!(
+            "v=DKIM1; k=rsa; p={}",
+            base64::engine::general_purpose::STANDARD.encode(public.as_bytes())
+        );
+        let zone = Zone::default().with("txt", "mail._domainkey.example.com", &record);
+        let signer = Signer::new("example.com", "mail", key);
+        let now = Utc::now();
+        let signed = signer.sign(
+            b"Received: by mx.example.com\r\nFrom: a@example.com\r\nSubject: hi\r\n\r\nhello\r\n",
+            now,
+        );
+
+        let verifications = verify(&zone, &signed, now).await;
+        assert_eq!(verifications.len(), 1);
+        assert_eq!(verifications[0].result, DkimResult::Pass);
+        assert_eq!(verifications[0].reason, None);
+        assert_eq!(verifications[0].domain, "example.com");
+        assert_eq!(verifications[0].selector, "mail");
+        assert!(verifications[0]
+            .to_string()
+            .starts_with("dkim=pass header.d=example.com header.s=mail header.b="));
+        // Fields added on the way don't break the signature, a changed body does
+        let relayed = [b"X-Added: yes\r\n".as_slice(), &signed].concat();
+        assert_eq!(
+            verify(&zone, &relayed, now).await[0].result,
+            DkimResult::Pass
+        );
+        let altered = [&signed[..signed.len() - 7], b"bye\r\n".as_slice()].concat();
+        let verification = &verify(&zone, &altered, now).await[0];
+        assert_eq!(verification.result, DkimResult::Fail);
+        assert_eq!(verification.reason, Some("body hash mismatch"));
+
+        let verification = &verify(&Zone::default(), &signed, now).await[0];
+        assert_eq!(verification.result, DkimResult::PermError);
+        let failing = Zone::default().failing("mail._domainkey.example.com");
+        assert_eq!(
+            verify(&failing, &signed, now).await[0].result,
+            DkimResult::TempError
+        );
+        assert_eq!(
+            verify(&zone, b"From: a@example.com\r\n\r\nhi\r\n", now).await,
+            []
+        );
+    }
+}
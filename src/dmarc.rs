@@ -0,0 +1,380 @@
+use std::fmt;
+use std::str::FromStr;
+
+use rand::Rng;
+
+use crate::dkim::{self, DkimResult, Verification};
+use crate::dns::{LookupError, Resolver};
+use crate::header::{field_name, field_value, split};
+use crate::spf::SpfResult;
+
+/// What a domain asks receivers to do of its mail failing DMARC, or
+/// what we do of it (RFC 7489 §6.3)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Policy {
+    None,
+    /// Accepted, but flagged as suspicious in the payload
+    Quarantine,
+    Reject,
+}
+
+impl Policy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::Quarantine => "quarantine",
+            Self::Reject => "reject",
+        }
+    }
+
+    /// The next lower policy, for the mail left out of a partial policy
+    fn lower(self) -> Self {
+        match self {
+            Self::Reject => Self::Quarantine,
+            _ => Self::None,
+        }
+    }
+}
+
+impl FromStr for Policy {
+    type Err = anyhow::Error;
+
+    fn from_str(policy: &str) -> anyhow::Result<Self> {
+        match policy {
+            "none" => Ok(Self::None),
+            "quarantine" => Ok(Self::Quarantine),
+            "reject" => Ok(Self::Reject),
+            _ => {
+                anyhow::bail!("unknown DMARC policy {policy}, expected none, quarantine or reject")
+            }
+        }
+    }
+}
+
+impl fmt::Display for Policy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Outcome of a DMARC evaluation (RFC 7489 §11.2)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DmarcResult {
+    /// The From domain publishes no DMARC record
+    None,
+    /// An SPF or DKIM pass is aligned with the From domain
+    Pass,
+    Fail,
+    /// The record couldn't be looked up, the evaluation may succeed later
+    TempError,
+    /// The message has no single From domain to evaluate
+    PermError,
+}
+
+impl DmarcResult {
+    /// Name of the result, as in `Authentication-Results`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::Pass => "pass",
+            Self::Fail => "fail",
+            Self::TempError => "temperror",
+            Self::PermError => "permerror",
+        }
+    }
+}
+
+/// A DMARC evaluation, displayed as in `Authentication-Results`, e.g.
+/// `dmarc=fail policy.dmarc=reject header.from=example.com`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Evaluation {
+    pub result: DmarcResult,
+    /// Domain of the From field
+    pub domain: String,
+    /// Policy of the domain for its failing mail, none without a record
+    pub policy: Option<Policy>,
+}
+
+impl Evaluation {
+    /// What becomes of the mail, failing mail getting the policy
+    /// of its domain, up to `enforcement`
+    pub fn disposition(&self, enforcement: Policy) -> Policy {
+        match (self.result, self.policy) {
+            (DmarcResult::Fail, Some(policy)) => policy.min(enforcement),
+            _ => Policy::None,
+        }
+    }
+}
+
+impl fmt::Display for Evaluation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "dmarc={}", self.result.as_str())?;
+        if let Some(policy) = self.policy {
+            write!(f, " policy.dmarc={policy}")?;
+        }
+        write!(f, " header.from={}", self.domain)
+    }
+}
+
+/// A DMARC record (RFC 7489 §6.3)
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Record {
+    policy: Policy,
+    /// Policy of the subdomains, `sp=`, the domain's by default
+    subdomain_policy: Option<Policy>,
+    strict_dkim: bool,
+    strict_spf: bool,
+    /// Share of the failing mail the policy applies to, `pct=`
+    percent: u8,
+}
+
+/// The domain of the From field of a message, none unless it has exactly one
+/// with a single address, as DMARC can't tell which author to check otherwise
+/// (RFC 7489 §6.6.1)
+pub fn from_domain(message: &[u8]) -> Option<String> {
+    let (fields, _) = split(message);
+    let mut from = fields.iter().filter(|field| field_name(field) == "from");
+    let (Some(field), None) = (from.next(), from.next()) else {
+        return None;
+    };
+    let value = field_value(field);
+    let [value] = addresses(&value)[..] else {
+        return None;
+    };
+    let address = match value.rsplit_once('<') {
+        Some((_, address)) => address.split('>').next().unwrap_or_default(),
+        None => value.trim(),
+    };
+    let (_, domain) = address.rsplit_once('@')?;
+    let domain = domain.trim().trim_end_matches('.').to_lowercase();
+    (!domain.is_empty()).then_some(domain)
+}
+
+/// The addresses of an address list, split on the commas out of quoted strings,
+/// comments and angle brackets
+fn addresses(list: &str) -> Vec<&str> {
+    let mut addresses = Vec::new();
+    let (mut quoted, mut comments, mut angle, mut start) = (false, 0, false, 0);
+    let mut chars = list.char_indices();
+    while let Some((index, c)) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '"' if comments == 0 => quoted = !quoted,
+            '(' if !quoted => comments += 1,
+            ')' if !quoted && comments > 0 => comments -= 1,
+            '<' if !quoted && comments == 0 => angle = true,
+            '>' if !quoted && comments == 0 => angle = false,
+            ',' if !quoted && comments == 0 && !angle => {
+                addresses.push(&list[start..index]);
+                start = index + 1;
+            }
+            _ => {}
+        }
+    }
+    addresses.push(&list[start..]);
+    addresses.retain(|address| !address.trim().is_empty());
+    addresses
+}
+
+/// Evaluates DMARC for mail from the `from` domain, with the SPF result of the
+/// domain checked, when checked, and the DKIM verifications of the message
+pub async fn check(
+    resolver: &impl Resolver,
+    from: &str,
+    spf: Option<(SpfResult, &str)>,
+    dkim: &[Verification],
+) -> Evaluation {
+    let evaluation = |result, policy| Evaluation {
+        result,
+        domain: from.to_string(),
+        policy,
+    };
+    // The record of the domain, or else of its organization (RFC 7489 §6.6.3)
+    let organization = organizational(from);
+    let (policy, record) = match lookup(resolver, from).await {
+        Ok(Some(record)) => (record.policy, record),
+        Ok(None) if organization != from => match lookup(resolver, organization).await {
+            Ok(Some(record)) => (record.subdomain_policy.unwrap_or(record.policy), record),
+            Ok(None) => return evaluation(DmarcResult::None, None),
+            Err(result) => return evaluation(result, None),
+        },
+        Ok(None) => return evaluation(DmarcResult::None, None),
+        Err(result) => return evaluation(result, None),
+    };
+
+    let spf_aligned = spf.is_some_and(|(result, domain)| {
+        result == SpfResult::Pass && aligned(domain, from, record.strict_spf)
+    });
+    let dkim_aligned = dkim.iter().any(|verification| {
+        verification.result == DkimResult::Pass
+            && aligned(&verification.domain, from, record.strict_dkim)
+    });
+    if spf_aligned || dkim_aligned {
+        return evaluation(DmarcResult::Pass, Some(policy));
+    }
+    // Mail left out of a partial policy gets the next lower one (RFC 7489 §6.6.4)
+    let policy = match rand::thread_rng().gen_range(0..100) < record.percent {
+        true => policy,
+        false => policy.lower(),
+    };
+    evaluation(DmarcResult::Fail, Some(policy))
+}
+
+/// Looks up the DMARC record of a domain, none unless it has exactly one valid
+async fn lookup(resolver: &impl Resolver, domain: &str) -> Result<Option<Record>, DmarcResult> {
+    let records = match resolver.txt(&format!("_dmarc.{domain}")).await {
+        Ok(records) => records,
+        Err(LookupError::NotFound) => return Ok(None),
+        Err(LookupError::Failed(err)) => {
+            tracing::debug!("Cannot look up the DMARC record of {domain}: {err}");
+            return Err(DmarcResult::TempError);
+        }
+    };
+    let mut records = records
+        .iter()
+        .filter(|record| dkim::tag(record, "v") == Some("DMARC1"))
+        .filter_map(|record| parse(record));
+    match (records.next(), records.next()) {
+        (Some(record), None) => Ok(Some(record)),
+        _ => Ok(None),
+    }
+}
+
+fn parse(record: &str) -> Option<Record> {
+    let strict = |name| dkim::tag(record, name).is_some_and(|mode| mode == "s");
+    Some(Record {
+        policy: dkim::tag(record, "p")?.parse().ok()?,
+        subdomain_policy: dkim::tag(record, "sp").and_then(|policy| policy.parse().ok()),
+        strict_dkim: strict("adkim"),
+        strict_spf: strict("aspf"),
+        percent: dkim::tag(record, "pct")
+            .and_then(|percent| percent.parse().ok())
+            .unwrap_or(100)
+            .min(100),
+    })
+}
+
+/// Whether an authenticated domain is aligned with the From domain: the same
+/// in strict mode, of the same organization in relaxed mode
+fn aligned(domain: &str, from: &str, strict: bool) -> bool {
+    let domain = domain.trim_end_matches('.').to_lowercase();
+    match strict {
+        true => domain == from,
+        false => organizational(&domain) == organizational(from),
+    }
+}
+
+/// The organizational domain of a domain, the one registered under a public
+/// suffix, the domain itself when it is no registered domain
+fn organizational(domain: &str) -> &str {
+    psl::domain_str(domain).unwrap_or(domain)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dns::Zone;
+
+    use super::*;
+
+    fn dkim(result: DkimResult, domain: &str) -> Verification {
+        Verification {
+            result,
+            reason: None,
+            domain: domain.to_string(),
+            selector: "mail".to_string(),
+            b: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_from_domain() {
+        let message = b"From: Alice <alice@Example.COM>\r\nTo: b@example.org\r\n\r\nhi\r\n";
+        assert_eq!(from_domain(message).as_deref(), Some("example.com"));
+        assert_eq!(
+            from_domain(b"From: alice@example.com\r\n\r\n").as_deref(),
+            Some("example.com")
+        );
+        assert_eq!(
+            from_domain(b"From: a@a.example\r\nFrom: b@b.example\r\n\r\n"),
+            None
+        );
+        assert_eq!(from_domain(b"Subject: hi\r\n\r\n"), None);
+        // Several authors, which DMARC can't pick from
+        assert_eq!(
+            from_domain(b"From: a@a.example, Bob <b@b.example>\r\n\r\n"),
+            None
+        );
+        assert_eq!(
+            from_domain(b"From: \"Doe, Jane (Sales)\" <jane@Example.com> (Jane, at work)\r\n\r\n")
+                .as_deref(),
+            Some("example.com")
+        );
+
+        assert_eq!(organizational("mail.example.com"), "example.com");
+        assert_eq!(organizational("mail.example.co.uk"), "example.co.uk");
+        assert_eq!(organizational("example.github.io"), "example.github.io");
+        assert_eq!(organizational("example"), "example");
+    }
+
+    #[tokio::test]
+    async fn test_check() {
+        let zone = Zone::default()
+            .with(
+                "txt",
+                "_dmarc.example.com",
+                "v=DMARC1; p=reject; sp=quarantine; adkim=s",
+            )
+            .with("txt", "_dmarc.example.org", "v=spf1 -all")
+            .failing("_dmarc.example.net");
+        let spf = Some((SpfResult::Pass, "bounces.example.com"));
+
+        // Relaxed SPF alignment
+        let evaluation = check(&zone, "example.com", spf, &[]).await;
+        assert_eq!(evaluation.result, DmarcResult::Pass);
+        assert_eq!(evaluation.disposition(Policy::Reject), Policy::None);
+        // Strict DKIM alignment
+        let signatures = [dkim(DkimResult::Pass, "mail.example.com")];
+        let evaluation = check(&zone, "example.com", None, &signatures).await;
+        assert_eq!(
+            evaluation.to_string(),
+            "dmarc=fail policy.dmarc=reject header.from=example.com"
+        );
+        assert_eq!(evaluation.disposition(Policy::Reject), Policy::Reject);
+        assert_eq!(
+            evaluation.disposition(Policy::Quarantine),
+            Policy::Quarantine
+        );
+        assert_eq!(evaluation.disposition(Policy::None), Policy::None);
+        let signatures = [dkim(DkimResult::Fail, "example.com")];
+        let evaluation = check(&zone, "example.com", None, &signatures).await;
+        assert_eq!(evaluation.result, DmarcResult::Fail);
+
+        // Subdomains get the policy of their organization
+        let evaluation = check(&zone, "news.example.com", None, &[]).await;
+        assert_eq!(evaluation.result, DmarcResult::Fail);
+        assert_eq!(evaluation.policy, Some(Policy::Quarantine));
+
+        let evaluation = check(&zone, "example.org", None, &[]).await;
+        assert_eq!(evaluation.to_string(), "dmarc=none header.from=example.org");
+        let evaluation = check(&zone, "example.net", spf, &[]).await;
+        assert_eq!(evaluation.result, DmarcResult::TempError);
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            parse("v=DMARC1; p=quarantine; aspf=s; pct=250"),
+            Some(Record {
+                policy: Policy::Quarantine,
+                subdomain_policy: None,
+                strict_dkim: false,
+                strict_spf: true,
+                percent: 100,
+            })
+        );
+        assert_eq!(parse("v=DMARC1; p=drop"), None);
+        assert_eq!(parse("v=DMARC1; rua=mailto:dmarc@example.com"), None);
+    }
+}
@@ -0,0 +1,166 @@
+use std::future::Future;
+use std::net::IpAddr;
+
+use hickory_resolver::error::{ResolveError, ResolveErrorKind};
+use hickory_resolver::proto::op::ResponseCode;
+use hickory_resolver::TokioAsyncResolver;
+
+/// Why a DNS lookup gave no records
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum LookupError {
+    /// The name doesn't exist or has no records of the type, a void lookup for SPF
+    #[error("no records found")]
+    NotFound,
+    /// The lookup failed, e.g. timed out, and may succeed later
+    #[error("DNS lookup failed: {0}")]
+    Failed(String),
+}
+
+/// DNS lookups of the checks run on inbound mail, so they can run
+/// against fixed records in tests
+pub trait Resolver: Send + Sync {
+    /// Text records of a name, the strings of each joined together
+    fn txt(&self, name: &str) -> impl Future<Output = Result<Vec<String>, LookupError>> + Send;
+
+    /// IPv4 and IPv6 addresses of a name
+    fn ips(&self, name: &str) -> impl Future<Output = Result<Vec<IpAddr>, LookupError>> + Send;
+
+    /// Mail exchangers of a name, the most preferred first
+    fn mx(&self, name: &str) -> impl Future<Output = Result<Vec<String>, LookupError>> + Send;
+
+    /// Names an address points back to
+    fn ptr(&self, ip: IpAddr) -> impl Future<Output = Result<Vec<String>, LookupError>> + Send;
+}
+
+impl Resolver for TokioAsyncResolver {
+    async fn txt(&self, name: &str) -> Result<Vec<String>, LookupError> {
+        let lookup = self.txt_lookup(fqdn(name)).await.map_err(lookup_error)?;
+        Ok(lookup
+            .iter()
+            .map(|txt| {
+                txt.txt_data()
+                    .iter()
+                    .map(|data| String::from_utf8_lossy(data))
+                    .collect()
+            })
+            .collect())
+    }
+
+    async fn ips(&self, name: &str) -> Result<Vec<IpAddr>, LookupError> {
+        let name = fqdn(name);
+        let (v4, v6) = tokio::join!(
+            self.ipv4_lookup(name.as_str()),
+            self.ipv6_lookup(name.as_str())
+        );
+        let mut ips = Vec::new();
+        let mut error = LookupError::NotFound;
+        match v4 {
+            Ok(lookup) => ips.extend(lookup.iter().map(|a| IpAddr::V4(a.0))),
+            Err(err) => error = lookup_error(err),
+        }
+        match v6 {
+            Ok(lookup) => ips.extend(lookup.iter().map(|aaaa| IpAddr::V6(aaaa.0))),
+            Err(err) if error == LookupError::NotFound => error = lookup_error(err),
+            Err(_) => {}
+        }
+        match ips.is_empty() {
+            true => Err(error),
+            false => Ok(ips),
+        }
+    }
+
+    async fn mx(&self, name: &str) -> Result<Vec<String>, LookupError> {
+        let lookup = self.mx_lookup(fqdn(name)).await.map_err(lookup_error)?;
+        let mut records = lookup
+            .iter()
+            .map(|mx| (mx.preference(), mx.exchange().to_ascii()))
+            .collect::<Vec<_>>();
+        records.sort();
+        Ok(records
+            .into_iter()
+            .map(|(_, host)| host.trim_end_matches('.').to_string())
+            .collect())
+    }
+
+    async fn ptr(&self, ip: IpAddr) -> Result<Vec<String>, LookupError> {
+        let lookup = self.reverse_lookup(ip).await.map_err(lookup_error)?;
+        Ok(lookup
+            .iter()
+            .map(|name| name.to_string().trim_end_matches('.').to_string())
+            .collect())
+    }
+}
+
+/// The name as fully qualified, so the search domains are not tried
+fn fqdn(name: &str) -> String {
+    format!("{}.", name.trim_end_matches('.'))
+}
+
+fn lookup_error(err: ResolveError) -> LookupError {
+    match err.kind() {
+        ResolveErrorKind::NoRecordsFound {
+            response_code: ResponseCode::NoError | ResponseCode::NXDomain,
+            ..
+        } => LookupError::NotFound,
+        _ => LookupError::Failed(err.to_string()),
+    }
+}
+
+/// Records served from memory, for the tests of the checks
+#[cfg(test)]
+#[derive(Default)]
+pub(crate) struct Zone {
+    records: std::collections::HashMap<(&'static str, String), Vec<String>>,
+}
+
+#[cfg(test)]
+impl Zone {
+    /// Adds a record of `kind`, `txt`, `ip`, `mx` or `ptr`, to a name or,
+    /// for `ptr`, to an address
+    pub(crate) fn with(mut self, kind: &'static str, name: &str, value: &str) -> Self {
+        self.records
+            .entry((kind, name.to_lowercase()))
+            .or_default()
+            .push(value.to_string());
+        self
+    }
+
+    /// Makes lookups of the name fail, as when its servers don't answer
+    pub(crate) fn failing(self, name: &str) -> Self {
+        self.with("fail", name, "")
+    }
+
+    fn lookup(&self, kind: &'static str, name: &str) -> Result<Vec<String>, LookupError> {
+        let name = name.trim_end_matches('.').to_lowercase();
+        if self.records.contains_key(&("fail", name.clone())) {
+            return Err(LookupError::Failed(format!("{name} timed out")));
+        }
+        self.records
+            .get(&(kind, name))
+            .cloned()
+            .ok_or(LookupError::NotFound)
+    }
+}
+
+#[cfg(test)]
+impl Resolver for Zone {
+    async fn txt(&self, name: &str) -> Result<Vec<String>, LookupError> {
+        self.lookup("txt", name)
+    }
+
+    async fn ips(&self, name: &str) -> Result<Vec<IpAddr>, LookupError> {
+        Ok(self
+            .lookup("ip", name)?
+            .iter()
+            .map(|ip| ip.parse().expect("test addresses are valid"))
+            .collect())
+    }
+
+    async fn mx(&self, name: &str) -> Result<Vec<String>, LookupError> {
+        self.lookup("mx", name)
+    }
+
+    async fn ptr(&self, ip: IpAddr) -> Result<Vec<String>, LookupError> {
+        self.lookup("ptr", &ip.to_string())
+    }
+}
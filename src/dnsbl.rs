@@ -0,0 +1,146 @@
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use anyhow::Context;
+
+use crate::dns::{LookupError, Resolver};
+
+/// A DNS blocklist, listing client addresses known to send spam, e.g. `zen.spamhaus.org`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Blocklist {
+    pub zone: String,
+    /// Added to the score of the clients it lists
+    pub weight: u32,
+}
+
+impl FromStr for Blocklist {
+    type Err = anyhow::Error;
+
+    /// Parses `<zone>`, of weight 1, or `<zone>*<weight>`
+    fn from_str(blocklist: &str) -> anyhow::Result<Self> {
+        let (zone, weight) = match blocklist.split_once('*') {
+            Some((zone, weight)) => (
+                zone,
+                weight
+                    .trim()
+                    .parse()
+                    .with_context(|| format!("invalid weight of DNS blocklist {zone}"))?,
+            ),
+            None => (blocklist, 1),
+        };
+        let zone = zone.trim().trim_end_matches('.');
+        if zone.is_empty() {
+            anyhow::bail!("empty DNS blocklist zone in {blocklist:?}");
+        }
+        Ok(Self {
+            zone: zone.to_lowercase(),
+            weight,
+        })
+    }
+}
+
+/// What the blocklists say of a client
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Listing {
+    /// Sum of the weights of the blocklists listing the client
+    pub score: u32,
+    /// Zones of the blocklists listing the client
+    pub zones: Vec<String>,
+}
+
+/// Looks the client at `ip` up in the blocklists. A blocklist failing
+/// to answer doesn't list the client.
+pub async fn check(resolver: &impl Resolver, blocklists: &[Blocklist], ip: IpAddr) -> Listing {
+    let reversed = reversed(ip);
+    let mut listing = Listing::default();
+    for blocklist in blocklists {
+        let name = format!("{reversed}.{}", blocklist.zone);
+        match resolver.ips(&name).await {
+            Ok(ips) if ips.iter().any(listed) => {
+                listing.score += blocklist.weight;
+                listing.zones.push(blocklist.zone.clone());
+            }
+            Ok(ips) => tracing::warn!("DNS blocklist {} answered {ips:?}", blocklist.zone),
+            Err(LookupError::NotFound) => {}
+            Err(LookupError::Failed(err)) => {
+                tracing::warn!("Cannot look {ip} up in {}: {err}", blocklist.zone)
+            }
+        }
+    }
+    listing
+}
+
+/// Whether a blocklist answer lists the client: an address of 127.0.0.0/8,
+/// but for 127.255.255.0/24 telling of errors, e.g. queries over a public resolver
+fn listed(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [first, second, third, _] = ip.octets();
+            first == 127 && (second, third) != (255, 255)
+        }
+        IpAddr::V6(_) => false,
+    }
+}
+
+/// The name of an address in a blocklist zone: its octets, or the nibbles
+/// of IPv6 addresses, in reverse order (RFC 5782 §2.1 and §2.4)
+fn reversed(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, d] = ip.octets();
+            format!("{d}.{c}.{b}.{a}")
+        }
+        IpAddr::V6(ip) => ip
+            .octets()
+            .iter()
+            .rev()
+            .flat_map(|byte| [byte & 0xf, byte >> 4])
+            .map(|nibble| format!("{nibble:x}"))
+            .collect::<Vec<_>>()
+            .join("."),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dns::Zone;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_and_reverse() {
+        let blocklist: Blocklist = "zen.spamhaus.org*3".parse().unwrap();
+        assert_eq!(blocklist.zone, "zen.spamhaus.org");
+        assert_eq!(blocklist.weight, 3);
+        assert_eq!("bl.spamcop.net".parse::<Blocklist>().unwrap().weight, 1);
+        assert!("bl.spamcop.net*high".parse::<Blocklist>().is_err());
+
+        assert_eq!(reversed("192.0.2.99".parse().unwrap()), "99.2.0.192");
+        assert_eq!(
+            reversed("2001:db8::1:2".parse().unwrap()),
+            "2.0.0.0.1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_check() {
+        let zone = Zone::default()
+            .with("ip", "2.2.0.192.zen.example", "127.0.0.2")
+            .with("ip", "2.2.0.192.cop.example", "127.0.0.2")
+            .with("ip", "2.2.0.192.open.example", "127.255.255.254")
+            .failing("2.2.0.192.down.example");
+        let blocklists = [
+            "zen.example*2",
+            "cop.example",
+            "open.example",
+            "down.example",
+        ]
+        .map(|blocklist| blocklist.parse::<Blocklist>().unwrap());
+
+        let listing = check(&zone, &blocklists, "192.0.2.2".parse().unwrap()).await;
+        assert_eq!(listing.score, 3);
+        assert_eq!(listing.zones, ["zen.example", "cop.example"]);
+        let listing = check(&zone, &blocklists, "192.0.2.3".parse().unwrap()).await;
+        assert_eq!(listing, Listing::default());
+    }
+}
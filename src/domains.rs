@@ -0,0 +1,61 @@
+use std::collections::HashSet;
+
+/// Domains the server takes mail for, refusing the recipients of others
+/// so it isn't an open relay
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Domains {
+    /// Domains whose mail is ours
+    local: HashSet<String>,
+    /// Domains we accept mail for on behalf of others, e.g. as a backup MX
+    relay: HashSet<String>,
+}
+
+impl Domains {
+    /// Domains given as comma-separated lists, local and relay
+    pub fn new(local: &str, relay: &str) -> Self {
+        let parse = |domains: &str| {
+            domains
+                .split(',')
+                .map(normalize)
+                .filter(|domain| !domain.is_empty())
+                .collect()
+        };
+        Self {
+            local: parse(local),
+            relay: parse(relay),
+        }
+    }
+
+    /// Whether the mail of a recipient, as in RCPT, is ours to take.
+    /// Addresses without a domain, e.g. `<postmaster>`, are local.
+    pub fn accepts(&self, recipient: &str) -> bool {
+        let address = recipient.trim_start_matches('<').trim_end_matches('>');
+        let Some((_, domain)) = address.rsplit_once('@') else {
+            return true;
+        };
+        let domain = normalize(domain);
+        self.local.contains(&domain) || self.relay.contains(&domain)
+    }
+}
+
+fn normalize(domain: &str) -> String {
+    domain.trim().trim_end_matches('.').to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_accepts() {
+        let domains = Domains::new("Example.com, mail.example.org.", "backup.example.net");
+        assert!(domains.accepts("<alice@example.com>"));
+        assert!(domains.accepts("<bob@EXAMPLE.COM.>"));
+        assert!(domains.accepts("<carol@mail.example.org>"));
+        assert!(domains.accepts("<dave@backup.example.net>"));
+        assert!(domains.accepts("<postmaster>"));
+        assert!(!domains.accepts("<eve@example.net>"));
+        assert!(!domains.accepts("<eve@sub.example.com>"));
+        assert_eq!(Domains::new(" ,", ""), Domains::default());
+    }
+}
@@ -0,0 +1,118 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use tokio::io::AsyncWriteExt;
+
+use crate::protocol::Mail;
+use crate::queue::QueueId;
+use crate::schema::Message;
+use crate::sink::{DeliveryResult, MailSink};
+
+/// Directory the messages are dumped to as received, one `.eml` file each,
+/// e.g. to look into what a sender really sent
+#[derive(Clone, Debug)]
+pub struct EmlDir {
+    dir: PathBuf,
+}
+
+impl EmlDir {
+    /// Opens the directory at `dir`, creating it if needed
+    pub async fn open(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        tokio::fs::create_dir_all(&dir)
+            .await
+            .with_context(|| format!("cannot create .eml directory {}", dir.display()))?;
+        Ok(Self { dir })
+    }
+
+    /// Writes a message to `<queue id>.eml`, the queue id starting with the time
+    /// the mail was accepted so the files sort by it. The file is written under
+    /// a temporary name then renamed, so it is never seen half-written, and a
+    /// retried delivery replaces it rather than adding a copy.
+    pub async fn write(&self, id: &QueueId, mail: &Mail) -> Result<PathBuf> {
+        let path = self.dir.join(format!("{id}.eml"));
+        let tmp = self.dir.join(format!(".{id}.eml.tmp"));
+        let write = async {
+            let mut file = tokio::fs::File::create(&tmp).await?;
+            file.write_all(&mail.data).await?;
+            file.sync_all().await?;
+            tokio::fs::rename(&tmp, &path).await
+        };
+        if let Err(err) = write.await {
+            tokio::fs::remove_file(&tmp).await.ok();
+            return Err(err).with_context(|| format!("cannot write {}", path.display()));
+        }
+        Ok(path)
+    }
+
+    /// Deletes the `.eml` files last written before `before`, returning how many
+    pub async fn remove_older(&self, before: DateTime<Utc>) -> Result<usize> {
+        let mut removed = 0;
+        let mut entries = tokio::fs::read_dir(&self.dir)
+            .await
+            .with_context(|| format!("cannot read {}", self.dir.display()))?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().is_none_or(|extension| extension != "eml") {
+                continue;
+            }
+            let modified = DateTime::<Utc>::from(entry.metadata().await?.modified()?);
+            if modified < before {
+                tokio::fs::remove_file(&path)
+                    .await
+                    .with_context(|| format!("cannot remove {}", path.display()))?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+impl MailSink for EmlDir {
+    fn endpoint(&self) -> String {
+        format!("eml://{}", self.dir.display())
+    }
+
+    async fn deliver(&self, id: &QueueId, mail: &Mail, _: &Message) -> DeliveryResult {
+        self.write(id, mail).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_write() {
+        let dir = std::env::temp_dir().join(format!("eml-test-{}", QueueId::generate()));
+        let eml = EmlDir::open(&dir).await.unwrap();
+        let mail = Mail {
+            data: b"Subject: hi\r\n\r\nhello\r\n".to_vec(),
+            ..Mail::default()
+        };
+        let id = QueueId::generate();
+        let path = eml.write(&id, &mail).await.unwrap();
+        // Written again on a retry, without a copy
+        assert_eq!(eml.write(&id, &mail).await.unwrap(), path);
+        assert_eq!(path, dir.join(format!("{id}.eml")));
+        assert_eq!(std::fs::read(&path).unwrap(), mail.data);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+        assert_eq!(
+            eml.remove_older(Utc::now() - chrono::Duration::hours(1))
+                .await
+                .unwrap(),
+            0
+        );
+        assert_eq!(
+            eml.remove_older(Utc::now() + chrono::Duration::hours(1))
+                .await
+                .unwrap(),
+            1
+        );
+        assert!(!path.exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
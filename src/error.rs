@@ -0,0 +1,62 @@
+use thiserror::Error;
+
+use crate::protocol::{Reply, StateMachine};
+
+/// Errors of an SMTP session, each kind mapping to the reply code
+/// a server reports it with.
+#[derive(Debug, Error)]
+pub enum SmtpError {
+    /// The client sent a command that is not allowed at this point of the session
+    #[error("protocol violation: {0}")]
+    ProtocolViolation(String),
+    /// The client sent a command the server doesn't know
+    #[error("unknown command: {0}")]
+    UnknownCommand(String),
+    /// The client made too many mistakes to keep talking to it
+    #[error("too many errors: {0}")]
+    TooManyErrors(usize),
+    /// The client did not send anything for too long
+    #[error("timed out waiting for the client")]
+    Timeout,
+    /// The message is larger than the server accepts
+    #[error("message too large: {size} bytes, limit is {limit}")]
+    TooLarge { size: usize, limit: usize },
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    /// A command or the message could not be parsed
+    #[error("parse error: {0}")]
+    Parse(String),
+    /// The mail was refused for good, e.g. by the endpoint it was delivered to
+    #[error("rejected: {0}")]
+    Rejected(String),
+    /// The mail could not be handed over for delivery
+    #[error("delivery failed: {0:#}")]
+    Delivery(anyhow::Error),
+}
+
+impl SmtpError {
+    /// Reply reporting the error to the client
+    pub fn reply(&self) -> Reply {
+        match self {
+            Self::ProtocolViolation(_) => StateMachine::NOT_NOW,
+            Self::UnknownCommand(_) => StateMachine::WHAT_IS_THAT,
+            Self::TooManyErrors(_) => StateMachine::GO_AWAY,
+            Self::Timeout => StateMachine::TOO_SLOW,
+            Self::TooLarge { .. } => StateMachine::TOO_BIG,
+            Self::Io(_) | Self::Delivery(_) => StateMachine::UH_OH,
+            Self::Parse(_) => StateMachine::SAY_AGAIN,
+            Self::Rejected(_) => StateMachine::NO_THANKS,
+        }
+    }
+
+    /// SMTP reply code reporting the error to the client
+    pub fn reply_code(&self) -> u16 {
+        self.reply().code
+    }
+}
+
+impl From<std::str::Utf8Error> for SmtpError {
+    fn from(err: std::str::Utf8Error) -> Self {
+        Self::Parse(err.to_string())
+    }
+}
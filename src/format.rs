@@ -0,0 +1,630 @@
+use std::fmt::Write;
+use std::str::FromStr;
+
+use anyhow::Result;
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use serde_json::json;
+use sha2::Sha256;
+
+use crate::proto;
+use crate::protocol::{self, Mail};
+use crate::queue::QueueId;
+use crate::schema::{Contact, Message};
+
+/// How messages are posted to a destination
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// The [`Message`] as JSON
+    #[default]
+    Json,
+    /// `multipart/form-data` with the fields of SendGrid's Inbound Parse webhook,
+    /// for applications written against it
+    SendGrid,
+    /// The fields Mailgun Routes forward messages with: form-encoded,
+    /// or `multipart/form-data` when there are attachments
+    Mailgun,
+    /// The JSON wrapped in a CloudEvents 1.0 envelope (structured mode),
+    /// for event routers like Knative or EventBridge
+    CloudEvents,
+    /// The [`Message`] as MessagePack, a map with the same fields as the JSON
+    #[serde(rename = "msgpack")]
+    MessagePack,
+    /// The [`Message`] as Protobuf, with the schema of `proto/edgemail.proto`
+    Protobuf,
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(format: &str) -> Result<Self> {
+        match format {
+            "json" => Ok(Self::Json),
+            "sendgrid" => Ok(Self::SendGrid),
+            "mailgun" => Ok(Self::Mailgun),
+            "cloudevents" => Ok(Self::CloudEvents),
+            "msgpack" => Ok(Self::MessagePack),
+            "protobuf" => Ok(Self::Protobuf),
+            _ => anyhow::bail!(
+                "unknown format {format}, expected json, sendgrid, mailgun, cloudevents, msgpack or protobuf"
+            ),
+        }
+    }
+}
+
+impl Format {
+    /// Whether attachments are posted as files, with no field for the link of one
+    /// uploaded to the object store: offloaded, they would arrive empty
+    pub fn needs_attachment_content(self) -> bool {
+        matches!(self, Self::SendGrid | Self::Mailgun)
+    }
+}
+
+/// `Content-Encoding` request bodies may be compressed with
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Gzip,
+    /// zlib, as HTTP means by `deflate`
+    Deflate,
+}
+
+impl FromStr for Compression {
+    type Err = anyhow::Error;
+
+    fn from_str(compression: &str) -> Result<Self> {
+        match compression {
+            "gzip" => Ok(Self::Gzip),
+            "deflate" => Ok(Self::Deflate),
+            _ => anyhow::bail!("unknown compression {compression}, expected gzip or deflate"),
+        }
+    }
+}
+
+impl Compression {
+    /// Value of the `Content-Encoding` header
+    pub fn encoding(self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            Self::Deflate => "deflate",
+        }
+    }
+
+    pub fn compress(self, bytes: &[u8]) -> Result<Vec<u8>> {
+        let level = flate2::Compression::default();
+        Ok(match self {
+            Self::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(Vec::new(), level);
+                std::io::Write::write_all(&mut encoder, bytes)?;
+                encoder.finish()?
+            }
+            Self::Deflate => {
+                let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), level);
+                std::io::Write::write_all(&mut encoder, bytes)?;
+                encoder.finish()?
+            }
+        })
+    }
+}
+
+/// A request body, with its content type
+#[derive(Clone, Debug)]
+pub struct Body {
+    pub content_type: String,
+    pub bytes: Vec<u8>,
+}
+
+impl Format {
+    /// Encodes the message with queue id `id`, `mail` being what it was built from.
+    /// Formats with their own signature fields sign with `signing_secret`.
+    pub fn encode(
+        self,
+        id: &QueueId,
+        mail: &Mail,
+        message: &Message,
+        signing_secret: Option<&str>,
+    ) -> Result<Body> {
+        match self {
+            Self::Json => Ok(Body {
+                content_type: "application/json".into(),
+                bytes: serde_json::to_vec(message)?,
+            }),
+            Self::SendGrid => Ok(sendgrid(mail, message)),
+            Self::Mailgun => Ok(mailgun(mail, message, signing_secret)),
+            Self::CloudEvents => Ok(Body {
+                content_type: "application/cloudevents+json".into(),
+                bytes: serde_json::to_vec(&cloud_event(id, mail, message))?,
+            }),
+            Self::MessagePack => Ok(Body {
+                content_type: "application/msgpack".into(),
+                bytes: rmp_serde::to_vec_named(message)?,
+            }),
+            Self::Protobuf => Ok(Body {
+                content_type: "application/x-protobuf".into(),
+                bytes: prost::Message::encode_to_vec(&proto::Message::from(message)),
+            }),
+        }
+    }
+}
+
+/// Type of the CloudEvents carrying received messages
+pub const CLOUD_EVENT_TYPE: &str = "in.deepwith.email.received";
+
+/// CloudEvents 1.0 envelope of a message. The queue id makes the event id, so
+/// retries of a delivery are recognized as the same event, and the envelope
+/// recipients its subject.
+fn cloud_event(id: &QueueId, mail: &Mail, message: &Message) -> serde_json::Value {
+    json!({
+        "specversion": "1.0",
+        "id": id,
+        "source": "/edgemail",
+        "type": CLOUD_EVENT_TYPE,
+        "subject": mail.to.iter().map(|to| unbracket(to)).collect::<Vec<_>>().join(","),
+        "time": chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+        "datacontenttype": "application/json",
+        "data": message,
+    })
+}
+
+/// Fields of SendGrid's Inbound Parse webhook, in its default (parsed) mode
+fn sendgrid(mail: &Mail, message: &Message) -> Body {
+    let mut form = Multipart::new();
+    form.text("headers", &String::from_utf8_lossy(headers(mail)));
+    form.text("from", &address(&message.from));
+    form.text("to", &addresses(&message.to));
+    if !message.cc.is_empty() {
+        form.text("cc", &addresses(&message.cc));
+    }
+    form.text("subject", message.subject.as_deref().unwrap_or_default());
+    for (field, mime) in [("text", "text/plain"), ("html", "text/html")] {
+        if let Some(value) = body(message, mime) {
+            form.text(field, value);
+        }
+    }
+    let envelope = json!({
+        "from": unbracket(&mail.from),
+        "to": mail.to.iter().map(|to| unbracket(to)).collect::<Vec<_>>(),
+    });
+    form.text("envelope", &envelope.to_string());
+    // Everything is decoded to UTF-8 before it gets here
+    let charsets = json!({"to": "UTF-8", "from": "UTF-8", "subject": "UTF-8", "text": "UTF-8", "html": "UTF-8"});
+    form.text("charsets", &charsets.to_string());
+    form.text("attachments", &message.attachments.len().to_string());
+    if !message.attachments.is_empty() {
+        let mut info = serde_json::Map::new();
+        let mut content_ids = serde_json::Map::new();
+        for (index, attachment) in message.attachments.iter().enumerate() {
+            let field = format!("attachment{}", index + 1);
+            let content_type = attachment
+                .content_type
+                .as_deref()
+                .unwrap_or("application/octet-stream");
+            info.insert(
+                field.clone(),
+                json!({
+                    "filename": attachment.filename,
+                    "name": attachment.filename,
+                    "type": content_type,
+                    "content-id": attachment.content_id,
+                }),
+            );
+            if let Some(content_id) = &attachment.content_id {
+                content_ids.insert(content_id.clone(), field.clone().into());
+            }
+            form.file(
+                &field,
+                &attachment.filename,
+                content_type,
+                &attachment.content,
+            );
+        }
+        form.text(
+            "attachment-info",
+            &serde_json::Value::Object(info).to_string(),
+        );
+        form.text(
+            "content-ids",
+            &serde_json::Value::Object(content_ids).to_string(),
+        );
+    }
+    form.finish()
+}
+
+/// Fields of a message forwarded by a Mailgun Route, signed with the destination's
+/// secret as Mailgun does with its signing key. The `stripped-*` fields are left out,
+/// as quotes and signatures are not told apart from the rest of the body.
+fn mailgun(mail: &Mail, message: &Message, signing_secret: Option<&str>) -> Body {
+    let mut fields = vec![
+        (
+            "recipient",
+            mail.to
+                .iter()
+                .map(|to| unbracket(to))
+                .collect::<Vec<_>>()
+                .join(","),
+        ),
+        ("sender", unbracket(&mail.from).to_string()),
+        ("from", address(&message.from)),
+        ("subject", message.subject.clone().unwrap_or_default()),
+    ];
+    for (field, mime) in [("body-plain", "text/plain"), ("body-html", "text/html")] {
+        if let Some(value) = body(message, mime) {
+            fields.push((field, value.to_string()));
+        }
+    }
+    let headers = header_fields(headers(mail))
+        .into_iter()
+        .map(|(name, value)| [name, value])
+        .collect::<Vec<_>>();
+    fields.push(("message-headers", json!(headers).to_string()));
+    // Receivers check `signature` is the HMAC of `timestamp` and `token` with their
+    // signing key, and may refuse a token they have seen already
+    let timestamp = chrono::Utc::now().timestamp().to_string();
+    let token = (0..25)
+        .map(|_| format!("{:02x}", rand::random::<u8>()))
+        .collect::<String>();
+    if let Some(secret) = signing_secret {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+        mac.update(timestamp.as_bytes());
+        mac.update(token.as_bytes());
+        let signature = mac.finalize().into_bytes();
+        fields.push((
+            "signature",
+            signature.iter().map(|byte| format!("{byte:02x}")).collect(),
+        ));
+    }
+    fields.push(("timestamp", timestamp));
+    fields.push(("token", token));
+    if message.attachments.is_empty() {
+        let mut form = url::form_urlencoded::Serializer::new(String::new());
+        form.extend_pairs(&fields);
+        return Body {
+            content_type: "application/x-www-form-urlencoded".into(),
+            bytes: form.finish().into_bytes(),
+        };
+    }
+    let mut form = Multipart::new();
+    for (name, value) in &fields {
+        form.text(name, value);
+    }
+    form.text("attachment-count", &message.attachments.len().to_string());
+    let mut content_ids = serde_json::Map::new();
+    for (index, attachment) in message.attachments.iter().enumerate() {
+        let field = format!("attachment-{}", index + 1);
+        if let Some(content_id) = &attachment.content_id {
+            content_ids.insert(format!("<{content_id}>"), field.clone().into());
+        }
+        let content_type = attachment
+            .content_type
+            .as_deref()
+            .unwrap_or("application/octet-stream");
+        form.file(
+            &field,
+            &attachment.filename,
+            content_type,
+            &attachment.content,
+        );
+    }
+    form.text(
+        "content-id-map",
+        &serde_json::Value::Object(content_ids).to_string(),
+    );
+    form.finish()
+}
+
+/// First body of the given type, parts without a type being plain text
+fn body<'a>(message: &'a Message, mime: &str) -> Option<&'a str> {
+    message
+        .content
+        .iter()
+        .find(|content| content.mime.as_deref().unwrap_or("text/plain") == mime)
+        .and_then(|content| content.value.as_deref())
+}
+
+fn unbracket(address: &str) -> &str {
+    address.trim_matches(|c| c == '<' || c == '>')
+}
+
+/// Names and unfolded values of the fields of a header section
+pub fn header_fields(headers: &[u8]) -> Vec<(String, String)> {
+    let mut fields: Vec<(String, String)> = Vec::new();
+    for line in String::from_utf8_lossy(headers).lines() {
+        if line.starts_with([' ', '\t']) {
+            if let Some((_, value)) = fields.last_mut() {
+                value.push(' ');
+                value.push_str(line.trim());
+            }
+        } else if let Some((name, value)) = line.split_once(':') {
+            fields.push((name.trim().to_string(), value.trim().to_string()));
+        }
+    }
+    fields
+}
+
+/// Header section of the message, without the blank line ending it
+pub fn headers(mail: &Mail) -> &[u8] {
+    let data = protocol::strip_terminator(&mail.data);
+    // Files replayed from disk may have bare LF line endings
+    let end = (0..data.len()).find(|&index| {
+        data[index..].starts_with(b"\r\n\r\n") || data[index..].starts_with(b"\n\n")
+    });
+    end.map_or(data, |end| &data[..end])
+}
+
+fn address(contact: &Contact) -> String {
+    let email = contact.email.as_deref().unwrap_or_default();
+    match &contact.name {
+        Some(name) => format!("{name} <{email}>"),
+        None => email.to_string(),
+    }
+}
+
+fn addresses(contacts: &[Contact]) -> String {
+    contacts.iter().map(address).collect::<Vec<_>>().join(", ")
+}
+
+/// Builds a `multipart/form-data` body (RFC 7578)
+pub struct Multipart {
+    boundary: String,
+    bytes: Vec<u8>,
+}
+
+impl Multipart {
+    pub fn new() -> Self {
+        Self {
+            boundary: format!("edgemail-{:032x}", rand::random::<u128>()),
+            bytes: Vec::new(),
+        }
+    }
+
+    pub fn text(&mut self, name: &str, value: &str) {
+        self.part(name, None, "text/plain; charset=utf-8", value.as_bytes());
+    }
+
+    pub fn file(&mut self, name: &str, filename: &str, content_type: &str, content: &[u8]) {
+        self.part(name, Some(filename), content_type, content);
+    }
+
+    fn part(&mut self, name: &str, filename: Option<&str>, content_type: &str, content: &[u8]) {
+        let quote = |value: &str| value.replace('\\', "\\\\").replace('"', "\\\"");
+        let mut head = format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"{}\"",
+            self.boundary,
+            quote(name)
+        );
+        if let Some(filename) = filename {
+            // Line breaks would end the header early
+            let filename = filename.replace(['\r', '\n'], " ");
+            write!(head, "; filename=\"{}\"", quote(&filename)).unwrap();
+        }
+        write!(head, "\r\nContent-Type: {content_type}\r\n\r\n").unwrap();
+        self.bytes.extend_from_slice(head.as_bytes());
+        self.bytes.extend_from_slice(content);
+        self.bytes.extend_from_slice(b"\r\n");
+    }
+
+    pub fn finish(mut self) -> Body {
+        self.bytes
+            .extend_from_slice(format!("--{}--\r\n", self.boundary).as_bytes());
+        Body {
+            content_type: format!("multipart/form-data; boundary={}", self.boundary),
+            bytes: self.bytes,
+        }
+    }
+}
+
+impl Default for Multipart {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::payload;
+
+    #[test]
+    fn test_needs_attachment_content() {
+        // The formats posting attachments as files can't take offloaded ones
+        for format in [Format::SendGrid, Format::Mailgun] {
+            assert!(format.needs_attachment_content());
+        }
+        for format in [
+            Format::Json,
+            Format::CloudEvents,
+            Format::MessagePack,
+            Format::Protobuf,
+        ] {
+            assert!(!format.needs_attachment_content());
+        }
+    }
+
+    #[test]
+    fn test_sendgrid() {
+        let mail = Mail {
+            from: "<bounce@example.com>".into(),
+            to: vec!["<inbox@example.org>".into()],
+            data: "From: Ann <ann@example.com>\r\n\
+                   To: inbox@example.org\r\n\
+                   Subject: Report\r\n\
+                   Content-Type: multipart/mixed; boundary=b\r\n\
+                   \r\n\
+                   --b\r\n\
+                   Content-Type: text/plain\r\n\
+                   \r\n\
+                   See attached\r\n\
+                   --b\r\n\
+                   Content-Type: text/csv\r\n\
+                   Content-Disposition: attachment; filename=report.csv\r\n\
+                   \r\n\
+                   a,b\r\n\
+                   --b--\r\n\
+                   .\r\n"
+                .into(),
+        };
+        let message = payload::build(&mail.data, &payload::Options::default()).unwrap();
+        let body = Format::SendGrid
+            .encode(&QueueId::generate(), &mail, &message, None)
+            .unwrap();
+        let boundary = body
+            .content_type
+            .strip_prefix("multipart/form-data; boundary=")
+            .unwrap();
+        let text = String::from_utf8(body.bytes).unwrap();
+        let field = |name: &str| {
+            let start = format!("name=\"{name}\"");
+            let part = &text[text.find(&start).unwrap()..];
+            let value = &part[part.find("\r\n\r\n").unwrap() + 4..];
+            value[..value.find(&format!("\r\n--{boundary}")).unwrap()].to_string()
+        };
+        assert_eq!(field("from"), "Ann <ann@example.com>");
+        assert_eq!(field("subject"), "Report");
+        assert_eq!(field("text"), "See attached");
+        assert!(field("headers").ends_with("boundary=b"));
+        assert_eq!(
+            field("envelope"),
+            r#"{"from":"bounce@example.com","to":["inbox@example.org"]}"#
+        );
+        assert_eq!(field("attachments"), "1");
+        assert_eq!(field("attachment1"), "a,b");
+        assert!(text.contains("filename=\"report.csv\"\r\nContent-Type: text/csv"));
+        assert!(text.ends_with(&format!("--{boundary}--\r\n")));
+    }
+
+    #[test]
+    fn test_mailgun() {
+        let mail = Mail {
+            from: "<bounce@example.com>".into(),
+            to: vec!["<inbox@example.org>".into(), "<team@example.org>".into()],
+            data: "From: Ann <ann@example.com>\r\n\
+                   Subject: Hello\r\n\
+                   X-Long: first\r\n second\r\n\
+                   \r\n\
+                   Hi there\r\n"
+                .into(),
+        };
+        let message = payload::build(&mail.data, &payload::Options::default()).unwrap();
+        let body = Format::Mailgun
+            .encode(&QueueId::generate(), &mail, &message, Some("key"))
+            .unwrap();
+        assert_eq!(body.content_type, "application/x-www-form-urlencoded");
+        let fields = url::form_urlencoded::parse(&body.bytes)
+            .into_owned()
+            .collect::<std::collections::HashMap<_, _>>();
+        assert_eq!(fields["recipient"], "inbox@example.org,team@example.org");
+        assert_eq!(fields["sender"], "bounce@example.com");
+        assert_eq!(fields["from"], "Ann <ann@example.com>");
+        assert_eq!(fields["subject"], "Hello");
+        assert_eq!(fields["body-plain"], "Hi there\r\n");
+        assert_eq!(
+            fields["message-headers"],
+            r#"[["From","Ann <ann@example.com>"],["Subject","Hello"],["X-Long","first second"]]"#
+        );
+        assert_eq!(fields["token"].len(), 50);
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"key").unwrap();
+        mac.update(format!("{}{}", fields["timestamp"], fields["token"]).as_bytes());
+        let signature = mac
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<String>();
+        assert_eq!(fields["signature"], signature);
+    }
+
+    #[test]
+    fn test_cloud_event() {
+        let mail = Mail {
+            from: "<bounce@example.com>".into(),
+            to: vec!["<inbox@example.org>".into()],
+            data: "From: ann@example.com\r\nSubject: Hello\r\n\r\nHi\r\n".into(),
+        };
+        let message = payload::build(&mail.data, &payload::Options::default()).unwrap();
+        let id = QueueId::generate();
+        let body = Format::CloudEvents
+            .encode(&id, &mail, &message, None)
+            .unwrap();
+        assert_eq!(body.content_type, "application/cloudevents+json");
+        let event: serde_json::Value = serde_json::from_slice(&body.bytes).unwrap();
+        assert_eq!(event["specversion"], "1.0");
+        assert_eq!(event["id"], id.as_str());
+        assert_eq!(event["type"], CLOUD_EVENT_TYPE);
+        assert_eq!(event["subject"], "inbox@example.org");
+        assert_eq!(event["datacontenttype"], "application/json");
+        assert_eq!(event["data"]["subject"], "Hello");
+    }
+
+    #[test]
+    fn test_binary_formats() {
+        let mail = Mail {
+            from: "<bounce@example.com>".into(),
+            to: vec!["<inbox@example.org>".into()],
+            data: "From: ann@example.com\r\n\
+                   Subject: Hello\r\n\
+                   Content-Type: multipart/mixed; boundary=b\r\n\
+                   \r\n\
+                   --b\r\n\
+                   Content-Type: text/plain\r\n\
+                   \r\n\
+                   Hi\r\n\
+                   --b\r\n\
+                   Content-Type: application/octet-stream\r\n\
+                   Content-Disposition: attachment; filename=a.bin\r\n\
+                   \r\n\
+                   abc\r\n\
+                   --b--\r\n"
+                .into(),
+        };
+        let message = payload::build(&mail.data, &payload::Options::default()).unwrap();
+        let id = QueueId::generate();
+
+        let body = Format::MessagePack
+            .encode(&id, &mail, &message, None)
+            .unwrap();
+        let decoded: Message = rmp_serde::from_slice(&body.bytes).unwrap();
+        assert_eq!(decoded.subject.as_deref(), Some("Hello"));
+        assert_eq!(decoded.attachments[0].content, b"abc");
+
+        let body = Format::Protobuf.encode(&id, &mail, &message, None).unwrap();
+        assert_eq!(body.content_type, "application/x-protobuf");
+        let decoded = <proto::Message as prost::Message>::decode(body.bytes.as_slice()).unwrap();
+        assert_eq!(
+            decoded.from.unwrap().email.as_deref(),
+            Some("ann@example.com")
+        );
+        assert_eq!(decoded.subject.as_deref(), Some("Hello"));
+        assert_eq!(decoded.attachments[0].filename, "a.bin");
+        assert_eq!(decoded.attachments[0].content, b"abc");
+    }
+
+    #[test]
+    fn test_compression() {
+        let bytes = "<p>Hello</p>".repeat(100).into_bytes();
+        let gzip = Compression::Gzip.compress(&bytes).unwrap();
+        assert!(gzip.len() < bytes.len());
+        let mut decoded = Vec::new();
+        std::io::Read::read_to_end(
+            &mut flate2::read::GzDecoder::new(gzip.as_slice()),
+            &mut decoded,
+        )
+        .unwrap();
+        assert_eq!(decoded, bytes);
+
+        let zlib = Compression::Deflate.compress(&bytes).unwrap();
+        let mut decoded = Vec::new();
+        std::io::Read::read_to_end(
+            &mut flate2::read::ZlibDecoder::new(zlib.as_slice()),
+            &mut decoded,
+        )
+        .unwrap();
+        assert_eq!(decoded, bytes);
+        assert_eq!(
+            "deflate".parse::<Compression>().unwrap().encoding(),
+            "deflate"
+        );
+        assert!("br".parse::<Compression>().is_err());
+    }
+}
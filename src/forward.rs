@@ -0,0 +1,1620 @@
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+use tokio::sync::{broadcast, watch};
+
+use crate::batch::Batching;
+use crate::chaos::Faults;
+use crate::deadletter::{DeadLetter, DeadLetters};
+use crate::dedup::{Duplicates, MessageIds};
+use crate::error::SmtpError;
+use crate::format::{Body, Compression, Format};
+use crate::ledger::Ledger;
+use crate::payload;
+use crate::priority;
+use crate::protocol::{Acceptor, Mail};
+use crate::queue::{QueueId, QueuedMail, ShardedQueue};
+use crate::ratelimit::RateLimiter;
+use crate::retry::{self, RetryPolicy};
+use crate::rewrite::UrlRewrite;
+use crate::schedule;
+use crate::schema::Message;
+use crate::senders::Senders;
+use crate::sink::{DeliveryResult, DynSink, MailSink, SinkRegistry};
+#[cfg(feature = "sqlite")]
+use crate::spool::Spool;
+use crate::storage::ObjectStore;
+use crate::tenant::{self, Tenant};
+use crate::usage::Meter;
+use crate::wal::{Record, Wal};
+
+/// Endpoint that receives the forwarded messages
+#[derive(Clone, Debug)]
+pub struct Destination {
+    pub url: String,
+    /// Tried in order when the URL is down or fails with a 5xx,
+    /// the message counting as delivered to the destination all the same
+    pub fallback_urls: Vec<String>,
+    /// Sent as the `Authorization` header
+    pub token: String,
+    /// Rewriting applied to URLs in the bodies before forwarding
+    pub url_rewrite: Option<UrlRewrite>,
+    /// Number of deliveries to the destination that may run at once,
+    /// mail for a recipient domain being delivered one message at a time
+    pub concurrency: usize,
+    /// Maximum number of requests per second sent to the destination,
+    /// mail above the rate waits in the queue
+    pub rate_limit: Option<f64>,
+    /// Secret shared with the receiver, the requests being signed with it
+    /// so it can check they come from this server, see [`signature`]
+    pub signing_secret: Option<String>,
+    /// How the messages are encoded, JSON unless the receiver expects
+    /// what another provider sends
+    pub format: Format,
+    /// Bodies larger than `compression_threshold` bytes are compressed with it
+    pub compression: Option<Compression>,
+    pub compression_threshold: usize,
+}
+
+/// Size in bytes above which bodies are compressed, when compression is on
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 1024;
+
+impl Destination {
+    /// Posts to `url`, unauthenticated, unsigned and uncompressed, in JSON,
+    /// four deliveries at a time
+    pub fn new(url: impl Into<String>) -> Result<Self> {
+        let url = url.into();
+        check_url(&url)?;
+        Ok(Self {
+            url,
+            fallback_urls: Vec::new(),
+            token: String::new(),
+            url_rewrite: None,
+            concurrency: 4,
+            rate_limit: None,
+            signing_secret: None,
+            format: Format::default(),
+            compression: None,
+            compression_threshold: DEFAULT_COMPRESSION_THRESHOLD,
+        })
+    }
+
+    /// The URL then the fallback ones, in the order they are tried
+    pub fn endpoints(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(&self.url)
+            .chain(&self.fallback_urls)
+            .map(String::as_str)
+    }
+
+    /// Posts the message to the destination, failing over to the fallback URLs,
+    /// returning the endpoint that took it and the response body
+    pub async fn forward(
+        &self,
+        client: &reqwest::Client,
+        id: &QueueId,
+        mail: &Mail,
+        message: &Message,
+    ) -> Result<(&str, String)> {
+        self.fail_over(|endpoint| self.post(client, endpoint, id, mail, message))
+            .await
+    }
+
+    /// Runs `post` on the URL, then on the fallback URLs while it fails transiently,
+    /// returning the endpoint it succeeded on with its result, or the last error
+    pub async fn fail_over<'a, T, F>(
+        &'a self,
+        mut post: impl FnMut(&'a str) -> F,
+    ) -> Result<(&'a str, T)>
+    where
+        F: Future<Output = Result<T>>,
+    {
+        let mut endpoints = self.endpoints().peekable();
+        while let Some(endpoint) = endpoints.next() {
+            match post(endpoint).await {
+                Ok(result) => return Ok((endpoint, result)),
+                Err(err) if endpoints.peek().is_some() && retry::is_transient(&err) => {
+                    tracing::warn!("Cannot post to {endpoint}, failing over: {err:#}");
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        unreachable!("a destination has at least its URL")
+    }
+
+    /// Posts the message to one of the destination's endpoints, in the destination's format,
+    /// returning the response body. `mail` is what the message was built from.
+    /// The queue id is sent as `Idempotency-Key`, identical across retries
+    /// of the same message so the receiver can drop duplicates.
+    pub async fn post(
+        &self,
+        client: &reqwest::Client,
+        endpoint: &str,
+        id: &QueueId,
+        mail: &Mail,
+        message: &Message,
+    ) -> Result<String> {
+        let body = match &self.url_rewrite {
+            Some(rewrite) => {
+                let mut message = message.clone();
+                rewrite.apply(&mut message);
+                self.format
+                    .encode(id, mail, &message, self.signing_secret.as_deref())?
+            }
+            None => self
+                .format
+                .encode(id, mail, message, self.signing_secret.as_deref())?,
+        };
+        self.send(client, endpoint, id.as_str(), body).await
+    }
+
+    /// Posts messages to one of the destination's endpoints as a JSON array,
+    /// returning the response body. Their queue ids, comma-separated and in the
+    /// same order, are sent as `Idempotency-Key`.
+    pub async fn post_batch(
+        &self,
+        client: &reqwest::Client,
+        endpoint: &str,
+        ids: &[&QueueId],
+        messages: &[&Message],
+    ) -> Result<String> {
+        let bytes = match &self.url_rewrite {
+            Some(rewrite) => {
+                let messages = messages
+                    .iter()
+                    .map(|message| {
+                        let mut message = (*message).clone();
+                        rewrite.apply(&mut message);
+                        message
+                    })
+                    .collect::<Vec<_>>();
+                serde_json::to_vec(&messages)?
+            }
+            None => serde_json::to_vec(messages)?,
+        };
+        let body = Body {
+            content_type: "application/json".into(),
+            bytes,
+        };
+        let key = ids
+            .iter()
+            .map(|id| id.as_str())
+            .collect::<Vec<_>>()
+            .join(",");
+        self.send(client, endpoint, &key, body).await
+    }
+
+    /// Sends a request body, signed and compressed as the destination wants
+    async fn send(
+        &self,
+        client: &reqwest::Client,
+        endpoint: &str,
+        idempotency_key: &str,
+        body: Body,
+    ) -> Result<String> {
+        tracing::trace!(
+            "Sending {} bytes of {}",
+            body.bytes.len(),
+            body.content_type
+        );
+        let mut request = client
+            .post(endpoint)
+            .header("Content-Type", &body.content_type)
+            .header("Authorization", &self.token)
+            .header("Idempotency-Key", idempotency_key);
+        if let Some(secret) = &self.signing_secret {
+            let timestamp = Utc::now().timestamp();
+            request = request.header("X-Edgemail-Timestamp", timestamp).header(
+                "X-Edgemail-Signature",
+                signature(secret, timestamp, &body.bytes),
+            );
+        }
+        // Compressed after signing, receivers checking the body they decompressed
+        let bytes = match self.compression {
+            Some(compression) if body.bytes.len() > self.compression_threshold => {
+                request = request.header("Content-Encoding", compression.encoding());
+                compression.compress(&body.bytes)?
+            }
+            _ => body.bytes,
+        };
+        let resp = request.body(bytes).send().await?.error_for_status()?;
+        Ok(resp.text().await.unwrap_or_default())
+    }
+}
+
+/// A destination as a [`MailSink`], posting with failover but without
+/// the rate limiting and batching of the forwarder's own destinations
+pub struct Webhook {
+    client: reqwest::Client,
+    pub destination: Destination,
+}
+
+impl Webhook {
+    pub fn new(destination: Destination) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            destination,
+        }
+    }
+}
+
+impl MailSink for Webhook {
+    fn endpoint(&self) -> String {
+        self.destination.url.clone()
+    }
+
+    async fn deliver(&self, id: &QueueId, mail: &Mail, message: &Message) -> DeliveryResult {
+        let (endpoint, resp) = self
+            .destination
+            .forward(&self.client, id, mail, message)
+            .await?;
+        tracing::debug!("Delivered mail {id} to {endpoint}: {resp}");
+        Ok(())
+    }
+
+    fn needs_attachment_content(&self) -> bool {
+        self.destination.format.needs_attachment_content()
+    }
+}
+
+/// Mirrors a WAL record in the spool: accepted mail is added, retries update
+/// it, and it is removed once delivered or given up on
+#[cfg(feature = "sqlite")]
+async fn spool_record(spool: &Spool, record: &Record) -> Result<()> {
+    match record {
+        Record::Accepted {
+            id,
+            at,
+            mail,
+            priority,
+            not_before,
+            tenant,
+            duplicate_of,
+        } => {
+            let entry = QueuedMail {
+                id: id.clone(),
+                mail: mail.clone(),
+                priority: *priority,
+                not_before: *not_before,
+                tenant: tenant.clone(),
+                attempts: 0,
+                duplicate_of: duplicate_of.clone(),
+            };
+            spool.add(&entry, *at).await
+        }
+        Record::Deferred {
+            id,
+            attempts,
+            until,
+            ..
+        } => spool.defer(id, *attempts, *until).await,
+        Record::Delivered { id, .. } | Record::Dropped { id, .. } => spool.remove(id).await,
+        _ => Ok(()),
+    }
+}
+
+/// Signature of a request body sent at `timestamp`, in Unix seconds, as put in
+/// the `X-Edgemail-Signature` header: `sha256=` and the hex HMAC-SHA256 of
+/// `<timestamp>.<body>`. The timestamp is sent along in `X-Edgemail-Timestamp`,
+/// so receivers can reject old requests replayed with their signature.
+pub fn signature(secret: &str, timestamp: i64, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(format!("{timestamp}.").as_bytes());
+    mac.update(body);
+    let hex = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect::<String>();
+    format!("sha256={hex}")
+}
+
+/// Checks a destination is an HTTP(S) URL, so a typo fails at startup
+/// rather than every delivery
+pub fn check_url(url: &str) -> Result<()> {
+    let parsed = url::Url::parse(url).with_context(|| format!("invalid forward URL {url}"))?;
+    anyhow::ensure!(
+        matches!(parsed.scheme(), "http" | "https"),
+        "forward URL {url} is not HTTP(S)"
+    );
+    Ok(())
+}
+
+/// Number of accepted mails a slow subscriber may lag behind before missing some
+const SUBSCRIBER_BACKLOG: usize = 64;
+
+/// A mail as it is accepted, pushed to the subscribers of [`Forwarder::subscribe`]
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AcceptedMail {
+    pub id: QueueId,
+    pub accepted_at: DateTime<Utc>,
+    /// Envelope sender
+    pub from: String,
+    /// Envelope recipients
+    pub to: Vec<String>,
+    /// The payload that will be forwarded to the destination
+    pub message: Message,
+}
+
+/// Takes accepted mail, journals and queues it,
+/// and runs the workers forwarding it to the destinations
+pub struct Forwarder {
+    client: reqwest::Client,
+    queue: ShardedQueue,
+    /// Destination of the mail of no tenant, the fanout endpoints being sinks
+    destination: Destination,
+    /// Rate limiters of the default destination and the tenants' ones, by tenant name
+    rate_limiters: HashMap<Option<String>, RateLimiter>,
+    payload_options: payload::Options,
+    tenants: Vec<Tenant>,
+    priority_rules: priority::Rules,
+    schedule: Vec<schedule::Rule>,
+    retry: RetryPolicy,
+    /// Where mail given up on is kept, when it isn't just dropped
+    dead_letters: Option<DeadLetters>,
+    /// Whether mail is delivered before it is acknowledged, rather than queued
+    sync_delivery: bool,
+    /// Where large attachments are uploaded instead of being inlined
+    object_store: Option<ObjectStore>,
+    /// Message-IDs of the latest mail, when duplicates are looked for
+    message_ids: Option<MessageIds>,
+    /// Whether mail is delivered in batches rather than one request each
+    batching: Option<Batching>,
+    /// Sinks the mail of no tenant is delivered to, besides its destinations
+    sinks: SinkRegistry,
+    wal: Wal,
+    /// Where the accepted mail is kept until it is delivered, besides the WAL
+    #[cfg(feature = "sqlite")]
+    spool: Option<Spool>,
+    ledger: Ledger,
+    faults: Faults,
+    accepted: broadcast::Sender<Arc<AcceptedMail>>,
+    usage: Arc<Meter>,
+    /// Quotas of the sender domains, counted by the SMTP sessions
+    senders: Option<Arc<Senders>>,
+    /// Whether this instance delivers mail, as the only one or the leader of a pair
+    leader: watch::Sender<bool>,
+    /// Ids of the mail queued by this instance and not finished yet
+    known: Mutex<HashSet<QueueId>>,
+    /// Held while a dead letter is queued again, for it to be queued once
+    requeuing: tokio::sync::Mutex<()>,
+}
+
+impl Forwarder {
+    pub fn new(
+        destination: Destination,
+        payload_options: payload::Options,
+        priority_rules: priority::Rules,
+        schedule: Vec<schedule::Rule>,
+        wal: Wal,
+        ledger: Ledger,
+        faults: Faults,
+    ) -> Result<Self> {
+        Ok(Self {
+            client: reqwest::Client::new(),
+            queue: ShardedQueue::new(destination.concurrency),
+            rate_limiters: destination
+                .rate_limit
+                .map(|rate| Ok((None, RateLimiter::new(rate)?)))
+                .into_iter()
+                .collect::<Result<_>>()?,
+            destination,
+            payload_options,
+            tenants: Vec::new(),
+            priority_rules,
+            schedule,
+            retry: RetryPolicy::default(),
+            dead_letters: None,
+            sync_delivery: false,
+            object_store: None,
+            message_ids: None,
+            batching: None,
+            sinks: SinkRegistry::default(),
+            wal,
+            #[cfg(feature = "sqlite")]
+            spool: None,
+            ledger,
+            faults,
+            accepted: broadcast::channel(SUBSCRIBER_BACKLOG).0,
+            usage: Arc::default(),
+            senders: None,
+            leader: watch::channel(true).0,
+            known: Mutex::default(),
+            requeuing: tokio::sync::Mutex::default(),
+        })
+    }
+
+    /// Receives every mail accepted from now on, e.g. to show it live on a dashboard
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<AcceptedMail>> {
+        self.accepted.subscribe()
+    }
+
+    /// Meters an accepted mail, and pushes it to the subscribers if there are any.
+    /// The payload is only built for the subscribers, the client waiting meanwhile.
+    fn publish(
+        &self,
+        id: &QueueId,
+        at: DateTime<Utc>,
+        mail: &Mail,
+        tenant: Option<&Tenant>,
+        options: &payload::Options,
+    ) {
+        let subscribed = self.accepted.receiver_count() > 0;
+        let message = subscribed.then(|| {
+            payload::build(&mail.data, options).map(|mut message| {
+                message.internationalized_addresses |= mail.internationalized();
+                message
+            })
+        });
+        let attachment_bytes = match &message {
+            Some(Ok(message)) => message
+                .attachments
+                .iter()
+                .map(|attachment| attachment.content.len())
+                .sum(),
+            Some(Err(_)) => 0,
+            None => payload::attachment_bytes(&mail.data),
+        };
+        self.usage.record(
+            tenant.map(|tenant| tenant.name.as_str()),
+            mail.data.len(),
+            attachment_bytes,
+        );
+        let Some(message) = message else {
+            return;
+        };
+        match message {
+            Ok(message) => {
+                self.accepted
+                    .send(Arc::new(AcceptedMail {
+                        id: id.clone(),
+                        accepted_at: at,
+                        from: mail.from.clone(),
+                        to: mail.to.clone(),
+                        message,
+                    }))
+                    .ok();
+            }
+            Err(err) => tracing::debug!("Not publishing mail {id}: {err:#}"),
+        }
+    }
+
+    /// Routes the mail of the tenants' recipients to their own destinations,
+    /// the default destination getting the mail of everyone else
+    pub fn with_tenants(mut self, tenants: Vec<Tenant>) -> Result<Self> {
+        for tenant in &tenants {
+            if let Some(rate) = tenant.destination.rate_limit {
+                let limiter = RateLimiter::new(rate)
+                    .with_context(|| format!("invalid rate limit of tenant {}", tenant.name))?;
+                self.rate_limiters
+                    .insert(Some(tenant.name.clone()), limiter);
+            }
+        }
+        self.tenants = tenants;
+        Ok(self)
+    }
+
+    /// Retries failed deliveries on this schedule instead of the default one
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Keeps the mail given up on in `dead_letters`, to be looked into and queued again
+    pub fn with_dead_letters(mut self, dead_letters: DeadLetters) -> Self {
+        self.dead_letters = Some(dead_letters);
+        self
+    }
+
+    /// Delivers each mail during the DATA phase instead of queueing it, the reply
+    /// of its endpoint deciding the SMTP reply, so the application behind it can
+    /// turn down unknown recipients or spam while the client is still connected
+    pub fn with_sync_delivery(mut self, sync_delivery: bool) -> Self {
+        self.sync_delivery = sync_delivery;
+        self
+    }
+
+    /// Uploads the attachments above the store's threshold to it,
+    /// the payload linking to them rather than inlining them
+    pub fn with_object_store(mut self, object_store: Option<ObjectStore>) -> Self {
+        self.object_store = object_store;
+        self
+    }
+
+    /// Looks for mail sent again with the same Message-ID and recipients,
+    /// skipping or flagging it
+    pub fn with_message_ids(mut self, message_ids: Option<MessageIds>) -> Self {
+        self.message_ids = message_ids;
+        self
+    }
+
+    /// Keeps the accepted mail in a spool until it is delivered or given up on
+    #[cfg(feature = "sqlite")]
+    pub fn with_spool(mut self, spool: Option<Spool>) -> Self {
+        self.spool = spool;
+        self
+    }
+
+    /// Posts the mail taken from the queue within the batching window together,
+    /// as a JSON array, rather than one request each
+    pub fn with_batching(mut self, batching: Option<Batching>) -> Self {
+        self.batching = batching;
+        self
+    }
+
+    /// Delivers the mail of no tenant to the sinks too, e.g. Kafka, SQS or Redis,
+    /// each recorded in the ledger like a destination so retries only deliver it once
+    pub fn with_sinks(mut self, sinks: SinkRegistry) -> Self {
+        self.sinks = sinks;
+        self
+    }
+
+    /// Delivers the mail of no tenant to one more sink, after the registered ones
+    pub fn with_sink(mut self, sink: impl MailSink + 'static) -> Result<Self> {
+        self.sinks.register(sink)?;
+        Ok(self)
+    }
+
+    /// Mail given up on, if it is kept
+    pub fn dead_letters(&self) -> Option<&DeadLetters> {
+        self.dead_letters.as_ref()
+    }
+
+    /// Journals a dead letter again and queues it, returning whether there was one.
+    /// Requeuing it twice queues it once: a letter already pending in the WAL, e.g.
+    /// when an earlier request was cut short before removing it, is only removed.
+    pub async fn requeue_dead_letter(&self, id: &str) -> Result<bool> {
+        let dead_letters = self
+            .dead_letters
+            .as_ref()
+            .context("dead letters are not kept")?;
+        let _requeuing = self.requeuing.lock().await;
+        let Some(letter) = dead_letters.get(id).await? else {
+            return Ok(false);
+        };
+        let entry = letter.queued();
+        let pending = self.wal.pending().await?;
+        if pending.iter().any(|pending| pending.id == entry.id) {
+            dead_letters.remove(id).await?;
+            tracing::info!("Dead letter {id} is queued already");
+            return Ok(true);
+        }
+        self.journal(&Record::Accepted {
+            id: entry.id.clone(),
+            at: Utc::now(),
+            mail: entry.mail.clone(),
+            priority: entry.priority,
+            not_before: None,
+            tenant: entry.tenant.clone(),
+            duplicate_of: entry.duplicate_of.clone(),
+        })
+        .await?;
+        dead_letters.remove(id).await?;
+        tracing::info!("Queued dead letter {id} again");
+        if *self.leader.borrow() {
+            self.requeue(entry);
+        }
+        Ok(true)
+    }
+
+    /// Shares the quotas of the sender domains with the SMTP sessions,
+    /// reporting what the mail of each domain amounted to in the admin API
+    pub fn with_senders(mut self, senders: Option<Arc<Senders>>) -> Self {
+        self.senders = senders;
+        self
+    }
+
+    /// Quotas of the sender domains, and what their mail amounted to
+    pub fn senders(&self) -> Option<&Arc<Senders>> {
+        self.senders.as_ref()
+    }
+
+    /// Usage of each tenant since it was last reported
+    pub fn usage(&self) -> &Arc<Meter> {
+        &self.usage
+    }
+
+    /// Mail waiting for delivery
+    pub fn queue(&self) -> &ShardedQueue {
+        &self.queue
+    }
+
+    /// Journal of the accepted mail and its deliveries
+    pub fn wal(&self) -> &Wal {
+        &self.wal
+    }
+
+    /// Where mail for the tenant is delivered, and how its payload is built
+    fn route(&self, tenant: Option<&str>) -> Result<(&[Destination], &payload::Options)> {
+        let Some(name) = tenant else {
+            return Ok((
+                std::slice::from_ref(&self.destination),
+                &self.payload_options,
+            ));
+        };
+        self.tenants
+            .iter()
+            .find(|tenant| tenant.name == name)
+            .map(|tenant| {
+                (
+                    std::slice::from_ref(&tenant.destination),
+                    &tenant.payload_options,
+                )
+            })
+            .with_context(|| format!("unknown tenant {name}"))
+    }
+
+    /// Journals the part of an accepted mail going to a tenant
+    async fn journal_part(
+        &self,
+        mail: Mail,
+        tenant: Option<&Tenant>,
+        now: DateTime<Utc>,
+    ) -> Result<QueuedMail> {
+        let id = QueueId::generate();
+        let priority = self.priority_rules.classify(&mail);
+        let not_before = schedule::not_before(&self.schedule, &mail, priority, now);
+        let tenant_name = tenant.map(|tenant| tenant.name.clone());
+        let duplicate_of = match &self.message_ids {
+            Some(message_ids) => message_ids.find(&mail).await,
+            None => None,
+        };
+        self.journal(&Record::Accepted {
+            id: id.clone(),
+            at: now,
+            mail: mail.clone(),
+            priority,
+            not_before,
+            tenant: tenant_name.clone(),
+            duplicate_of: duplicate_of.clone(),
+        })
+        .await?;
+        Ok(QueuedMail {
+            id,
+            mail,
+            priority,
+            not_before,
+            tenant: tenant_name,
+            attempts: 0,
+            duplicate_of,
+        })
+    }
+
+    /// Gives up on the journaled parts of a mail refused as a whole, the client
+    /// sending it again: they are dropped, the quotas taken for the mail returned,
+    /// and their Message-IDs forgotten, so the copy sent again is neither over
+    /// the quotas nor a duplicate
+    async fn abandon(
+        &self,
+        entries: &[QueuedMail],
+        tenants: &[&Tenant],
+        now: DateTime<Utc>,
+        reason: &str,
+    ) {
+        for tenant in tenants {
+            tenant.return_quota(now);
+        }
+        for entry in entries {
+            let dropped = Record::Dropped {
+                id: entry.id.clone(),
+                at: now,
+                reason: reason.into(),
+            };
+            if let Err(err) = self.journal(&dropped).await {
+                tracing::error!("Cannot drop mail {}: {err:#}", entry.id);
+            }
+            let Some(message_ids) = &self.message_ids else {
+                continue;
+            };
+            if let Err(err) = message_ids.forget(&entry.mail, &entry.id).await {
+                tracing::error!("Cannot forget the Message-ID of mail {}: {err:#}", entry.id);
+            }
+        }
+    }
+
+    /// Queues the journaled part of an accepted mail going to a tenant, or delivers
+    /// it right away with synchronous delivery, by the client's `deadline`.
+    /// `partial` tells an earlier part of the mail was delivered already.
+    async fn queue_part(
+        &self,
+        entry: QueuedMail,
+        tenant: Option<&Tenant>,
+        now: DateTime<Utc>,
+        deadline: Instant,
+        partial: bool,
+    ) -> Result<()> {
+        let QueuedMail {
+            id,
+            priority,
+            not_before,
+            ..
+        } = &entry;
+        if let (Some(original), Some(message_ids)) = (&entry.duplicate_of, &self.message_ids) {
+            if message_ids.duplicates == Duplicates::Skip {
+                tracing::info!("Accepted mail {id}, a duplicate of {original}, not delivering it");
+                return self
+                    .journal(&Record::Dropped {
+                        id: id.clone(),
+                        at: now,
+                        reason: format!("duplicate of {original}"),
+                    })
+                    .await;
+            }
+        }
+        let for_tenant = tenant
+            .map(|tenant| format!(" for tenant {}", tenant.name))
+            .unwrap_or_default();
+        match not_before {
+            Some(not_before) => tracing::info!(
+                "Accepted mail {id}{for_tenant} with {priority:?} priority, deferred until {not_before}"
+            ),
+            None => tracing::info!("Accepted mail {id}{for_tenant} with {priority:?} priority"),
+        }
+        let payload_options = match tenant {
+            Some(tenant) => &tenant.payload_options,
+            None => &self.payload_options,
+        };
+        self.publish(id, now, &entry.mail, tenant, payload_options);
+        if self.sync_delivery {
+            self.deliver_now(entry, deadline, partial).await?;
+        } else if *self.leader.borrow() {
+            // Without the lead, the mail waits in the WAL for the leader to adopt it
+            self.requeue(entry);
+        }
+        Ok(())
+    }
+
+    /// Delivers a mail while the client waits, by its `deadline`, the endpoint's
+    /// verdict deciding the reply: a 403 or 410 rejects the mail for good, as does
+    /// a message that cannot be parsed, and any other failure or running out of
+    /// time asks the client to try again later. Either way the mail stays the
+    /// client's problem, so it is dropped rather than retried or kept as a dead letter. Once an endpoint took the mail,
+    /// or the client's other recipients, it is ours though: it is accepted, and
+    /// retried for the other endpoints, the ledger telling which have it.
+    async fn deliver_now(&self, entry: QueuedMail, deadline: Instant, partial: bool) -> Result<()> {
+        let deadline = tokio::time::Instant::from_std(deadline);
+        let err = match tokio::time::timeout_at(deadline, self.try_deliver(&entry, partial)).await {
+            Ok(Ok(true)) => return Ok(()),
+            Ok(Ok(false)) if partial => return Ok(()),
+            Ok(Ok(false)) => {
+                self.journal(&Record::Dropped {
+                    id: entry.id.clone(),
+                    at: Utc::now(),
+                    reason: "refused while the client waited: the message cannot be parsed".into(),
+                })
+                .await?;
+                return Err(SmtpError::Rejected("the message cannot be parsed".into()).into());
+            }
+            Ok(Err(err)) => err,
+            Err(_) => anyhow::anyhow!("not delivered before the client's deadline"),
+        };
+        let taken = self.endpoints(&entry).await;
+        if partial || taken.iter().any(|(_, taken)| *taken) {
+            tracing::info!(
+                "Mail {} was partly delivered while the client waited, retrying the rest: {err:#}",
+                entry.id
+            );
+            if *self.leader.borrow() {
+                self.known.lock().unwrap().insert(entry.id.clone());
+                self.retry(entry, &err).await;
+            }
+            return Ok(());
+        }
+        self.journal(&Record::Dropped {
+            id: entry.id.clone(),
+            at: Utc::now(),
+            reason: format!("refused while the client waited: {err:#}"),
+        })
+        .await?;
+        let status = err
+            .downcast_ref::<reqwest::Error>()
+            .and_then(reqwest::Error::status);
+        match status {
+            Some(reqwest::StatusCode::FORBIDDEN | reqwest::StatusCode::GONE) => {
+                Err(SmtpError::Rejected(format!("{err:#}")).into())
+            }
+            _ => Err(err),
+        }
+    }
+
+    /// Appends a record to the WAL, and keeps the spool in step with it
+    async fn journal(&self, record: &Record) -> Result<()> {
+        self.faults.storage()?;
+        self.wal.append(record).await?;
+        #[cfg(feature = "sqlite")]
+        if let Some(spool) = &self.spool {
+            spool_record(spool, record).await?;
+        }
+        if let Record::Delivered { id, .. } | Record::Dropped { id, .. } = record {
+            self.known.lock().unwrap().remove(id);
+        }
+        Ok(())
+    }
+
+    /// Queues mail that was journaled before, e.g. replayed from the WAL
+    pub fn requeue(&self, entry: QueuedMail) {
+        self.known.lock().unwrap().insert(entry.id.clone());
+        self.queue.push(entry);
+    }
+
+    /// Queues the spooled mail not queued yet, e.g. when the WAL was lost
+    /// or compacted away while the spool kept it
+    #[cfg(feature = "sqlite")]
+    pub async fn requeue_spooled(&self) -> Result<()> {
+        let Some(spool) = &self.spool else {
+            return Ok(());
+        };
+        for entry in spool.pending().await? {
+            if self.known.lock().unwrap().insert(entry.id.clone()) {
+                tracing::info!("Requeuing spooled mail {}", entry.id);
+                self.queue.push(entry);
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes this instance deliver mail or stop doing so, as it gains or loses
+    /// the lead of a high-availability pair. On losing it the queue is dropped,
+    /// the new leader picking the pending mail up from the shared WAL.
+    pub fn set_leader(&self, leader: bool) {
+        let changed = self
+            .leader
+            .send_if_modified(|current| std::mem::replace(current, leader) != leader);
+        if !changed {
+            return;
+        }
+        if leader {
+            tracing::info!("Became the leader, delivering mail");
+        } else {
+            tracing::info!("Not the leader, leaving delivery to the peer");
+            self.queue.clear();
+            self.known.lock().unwrap().clear();
+        }
+    }
+
+    /// Queues the pending mail of the shared WAL not queued yet,
+    /// i.e. accepted by the peer or before the leadership changed
+    pub async fn adopt_pending(&self) -> Result<()> {
+        for entry in self.wal.pending().await? {
+            if self.known.lock().unwrap().insert(entry.id.clone()) {
+                tracing::debug!("Adopting pending mail {}", entry.id);
+                self.queue.push(entry);
+            }
+        }
+        Ok(())
+    }
+
+    /// Spawns the delivery workers, one per shard of the queue,
+    /// as many as the destination's concurrency
+    pub fn spawn_workers(self: &Arc<Self>) {
+        for worker in 0..self.queue.shards() {
+            let forwarder = self.clone();
+            tokio::spawn(async move {
+                tracing::debug!("Delivery worker {worker} started");
+                let mut leader = forwarder.leader.subscribe();
+                loop {
+                    leader.wait_for(|leader| *leader).await.ok();
+                    let entry = forwarder.queue.pop(worker).await;
+                    if !*leader.borrow() {
+                        // Stepped down while waiting, the new leader delivers it
+                        continue;
+                    }
+                    let Some(batching) = forwarder.batching else {
+                        if let Err(err) = forwarder.deliver(&entry).await {
+                            forwarder.retry(entry, &err).await;
+                        }
+                        continue;
+                    };
+                    let batch = batching.collect(&forwarder.queue, worker, entry).await;
+                    for (entry, err) in forwarder.deliver_batch(batch).await {
+                        forwarder.retry(entry, &err).await;
+                    }
+                }
+            });
+        }
+    }
+
+    /// Queues a mail again after a failed delivery, once the retry policy's delay
+    /// is over, or gives up on it when the failure is permanent or the attempts
+    /// are exhausted. Mail given up on is dropped from the WAL, and kept as a dead letter.
+    async fn retry(&self, mut entry: QueuedMail, err: &anyhow::Error) {
+        entry.attempts += 1;
+        let delay = match retry::is_transient(err) {
+            true => self.retry.delay(entry.attempts),
+            false => None,
+        };
+        match delay {
+            Some(delay) => {
+                tracing::info!(
+                    "Retrying mail {} in {}s, after {} failed attempts",
+                    entry.id,
+                    delay.as_secs(),
+                    entry.attempts
+                );
+                let until = Utc::now()
+                    + chrono::Duration::from_std(delay).unwrap_or(chrono::Duration::zero());
+                entry.not_before = Some(until);
+                // For the peer to keep to the schedule, should it take the mail over
+                let deferred = Record::Deferred {
+                    id: entry.id.clone(),
+                    at: Utc::now(),
+                    attempts: entry.attempts,
+                    until,
+                };
+                if let Err(err) = self.journal(&deferred).await {
+                    tracing::error!("Cannot journal the retry of mail {}: {err:#}", entry.id);
+                }
+                self.queue.push(entry);
+            }
+            None => {
+                tracing::warn!(
+                    "Giving up on mail {} after {} attempts: {err:#}",
+                    entry.id,
+                    entry.attempts
+                );
+                let reason = format!("gave up after {} attempts: {err:#}", entry.attempts);
+                if let Err(err) = self.bury(&entry, reason).await {
+                    // Left pending in the WAL, to be adopted again or replayed on restart
+                    tracing::error!("Cannot drop mail {}: {err:#}", entry.id);
+                    self.known.lock().unwrap().remove(&entry.id);
+                }
+            }
+        }
+    }
+
+    /// The destinations and sinks of a mail, with whether the ledger says they took it
+    async fn endpoints(&self, entry: &QueuedMail) -> Vec<(String, bool)> {
+        let QueuedMail { id, tenant, .. } = entry;
+        let mut urls = match self.route(tenant.as_deref()) {
+            Ok((destinations, _)) => destinations
+                .iter()
+                .map(|destination| destination.url.clone())
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+        if tenant.is_none() {
+            urls.extend(self.sinks.iter().map(|sink| sink.endpoint()));
+        }
+        let mut endpoints = Vec::new();
+        for url in urls {
+            let taken = self.ledger.contains(tenant.as_deref(), &url, id).await;
+            endpoints.push((url, taken));
+        }
+        endpoints
+    }
+
+    /// Gives up on a mail, journaling it as dropped once it is stored
+    /// as a dead letter, if they are kept, for the endpoints that didn't take it
+    async fn bury(&self, entry: &QueuedMail, reason: String) -> Result<()> {
+        if let Some(dead_letters) = &self.dead_letters {
+            let options = self
+                .route(entry.tenant.as_deref())
+                .map(|(_, options)| options)
+                .unwrap_or(&self.payload_options);
+            let endpoints = self
+                .endpoints(entry)
+                .await
+                .into_iter()
+                .filter_map(|(endpoint, taken)| (!taken).then_some(endpoint))
+                .collect();
+            dead_letters
+                .add(&DeadLetter {
+                    id: entry.id.clone(),
+                    failed_at: Utc::now(),
+                    attempts: entry.attempts,
+                    reason: reason.clone(),
+                    mail: entry.mail.clone(),
+                    priority: entry.priority,
+                    tenant: entry.tenant.clone(),
+                    payload: payload::build(&entry.mail.data, options).ok(),
+                    endpoints,
+                })
+                .await?;
+        }
+        self.journal(&Record::Dropped {
+            id: entry.id.clone(),
+            at: Utc::now(),
+            reason,
+        })
+        .await
+    }
+
+    /// Forwards an accepted mail to each of its destinations, journaling the outcome.
+    /// The mail is delivered once every destination got it, the ones that failed
+    /// being tried again by the next attempt.
+    /// Messages that cannot be parsed are dropped, as retrying would not help.
+    pub async fn deliver(&self, entry: &QueuedMail) -> Result<()> {
+        self.try_deliver(entry, true).await.map(|_| ())
+    }
+
+    /// Delivers a mail as [`Forwarder::deliver`] does, returning whether it was
+    /// rather than given up on as unparseable. Unparseable mail is only kept as a
+    /// dead letter with `bury`, when it is ours rather than refused to the client.
+    async fn try_deliver(&self, entry: &QueuedMail, bury: bool) -> Result<bool> {
+        let QueuedMail {
+            id, mail, tenant, ..
+        } = entry;
+        tracing::info!("Sending mail {id}");
+        tracing::info!("{mail:?}");
+        let (destinations, payload_options) = match self.route(tenant.as_deref()) {
+            Ok(route) => route,
+            Err(err) => {
+                // Kept pending, in case the tenant comes back in the configuration
+                tracing::warn!("Cannot deliver mail {id}: {err:#}");
+                self.journal(&Record::Failed {
+                    id: id.clone(),
+                    at: Utc::now(),
+                    error: format!("{err:#}"),
+                    endpoint: None,
+                })
+                .await?;
+                return Err(err);
+            }
+        };
+        let Some(message) = self
+            .message(entry, destinations, payload_options, bury)
+            .await?
+        else {
+            return Ok(false);
+        };
+        tracing::trace!("Sending {message:?}");
+        let mut failures = Vec::new();
+        for destination in destinations {
+            if let Err(err) = self.deliver_to(destination, entry, &message).await {
+                failures.push(err);
+            }
+        }
+        for sink in self.sinks.iter() {
+            if let Err(err) = self.deliver_to_sink(sink, entry, &message).await {
+                failures.push(err);
+            }
+        }
+        // A transient failure is reported first, so the message is retried
+        // for the destinations that may still take it
+        if let Some(index) = failures.iter().position(retry::is_transient) {
+            return Err(failures.swap_remove(index));
+        }
+        if let Some(err) = failures.into_iter().next() {
+            return Err(err);
+        }
+        self.journal(&Record::Delivered {
+            id: id.clone(),
+            at: Utc::now(),
+        })
+        .await?;
+        Ok(true)
+    }
+
+    /// Builds the payload of a mail, uploading its large attachments unless one of
+    /// its destinations needs their content. Mail that cannot be parsed is given up
+    /// on, returning none, and kept as a dead letter with `bury`.
+    async fn message(
+        &self,
+        entry: &QueuedMail,
+        destinations: &[Destination],
+        payload_options: &payload::Options,
+        bury: bool,
+    ) -> Result<Option<Message>> {
+        let QueuedMail { id, mail, .. } = entry;
+        let mut message = match payload::build(&mail.data, payload_options) {
+            Ok(mut message) => {
+                message.internationalized_addresses |= mail.internationalized();
+                message.duplicate_of = entry.duplicate_of.as_ref().map(QueueId::to_string);
+                message
+            }
+            Err(err) if bury => {
+                tracing::warn!("Cant parse message, discarding: {err:#}");
+                self.bury(entry, format!("{err:#}")).await?;
+                return Ok(None);
+            }
+            Err(err) => {
+                tracing::info!("Cant parse message {id}, refusing it: {err:#}");
+                return Ok(None);
+            }
+        };
+        // Not for destinations or sinks taking the attachments as files, they would get them empty
+        let sinks = match entry.tenant {
+            Some(_) => None,
+            None => Some(self.sinks.iter()),
+        };
+        let object_store = self.object_store.as_ref().filter(|_| {
+            destinations
+                .iter()
+                .all(|destination| !destination.format.needs_attachment_content())
+                && sinks
+                    .into_iter()
+                    .flatten()
+                    .all(|sink| !sink.needs_attachment_content())
+        });
+        if let Some(object_store) = object_store {
+            if let Err(err) = object_store.offload(&self.client, id, &mut message).await {
+                tracing::warn!("Cannot offload the attachments of mail {id}: {err:#}");
+                self.journal(&Record::Failed {
+                    id: id.clone(),
+                    at: Utc::now(),
+                    error: format!("{err:#}"),
+                    endpoint: None,
+                })
+                .await?;
+                return Err(err);
+            }
+        }
+        Ok(Some(message))
+    }
+
+    /// Delivers mail taken from the queue together, returning the mail that failed
+    /// with its error. Mail is batched per tenant, for destinations taking JSON:
+    /// for the others, and for a lone mail, it is delivered one at a time.
+    pub async fn deliver_batch(
+        &self,
+        entries: Vec<QueuedMail>,
+    ) -> Vec<(QueuedMail, Arc<anyhow::Error>)> {
+        let mut failures = Vec::new();
+        let mut groups: Vec<(Option<String>, Vec<QueuedMail>)> = Vec::new();
+        for entry in entries {
+            match groups
+                .iter_mut()
+                .find(|(tenant, _)| *tenant == entry.tenant)
+            {
+                Some((_, group)) => group.push(entry),
+                None => groups.push((entry.tenant.clone(), vec![entry])),
+            }
+        }
+        for (tenant, group) in groups {
+            let route = self
+                .route(tenant.as_deref())
+                .ok()
+                .filter(|(destinations, _)| {
+                    group.len() > 1
+                        && destinations
+                            .iter()
+                            .all(|destination| destination.format == Format::Json)
+                });
+            let Some((destinations, payload_options)) = route else {
+                for entry in group {
+                    if let Err(err) = self.deliver(&entry).await {
+                        failures.push((entry, Arc::new(err)));
+                    }
+                }
+                continue;
+            };
+            tracing::info!("Sending {} mails for {tenant:?} in a batch", group.len());
+            let mut batch = Vec::new();
+            for entry in group {
+                match self
+                    .message(&entry, destinations, payload_options, true)
+                    .await
+                {
+                    Ok(Some(message)) => batch.push((entry, message)),
+                    Ok(None) => {}
+                    Err(err) => failures.push((entry, Arc::new(err))),
+                }
+            }
+            let mut errors = vec![Vec::new(); batch.len()];
+            for destination in destinations {
+                for (index, err) in self.deliver_batch_to(destination, &tenant, &batch).await {
+                    errors[index].push(err);
+                }
+            }
+            for ((entry, message), errors) in batch.iter().zip(&mut errors) {
+                for sink in self.sinks.iter() {
+                    if let Err(err) = self.deliver_to_sink(sink, entry, message).await {
+                        errors.push(Arc::new(err));
+                    }
+                }
+            }
+            for ((entry, _), mut errors) in batch.into_iter().zip(errors) {
+                // As for a single mail, a transient failure is reported first
+                if let Some(index) = errors.iter().position(|err| retry::is_transient(err)) {
+                    failures.push((entry, errors.swap_remove(index)));
+                    continue;
+                }
+                if let Some(err) = errors.into_iter().next() {
+                    failures.push((entry, err));
+                    continue;
+                }
+                let delivered = Record::Delivered {
+                    id: entry.id.clone(),
+                    at: Utc::now(),
+                };
+                if let Err(err) = self.journal(&delivered).await {
+                    failures.push((entry, Arc::new(err)));
+                }
+            }
+        }
+        failures
+    }
+
+    /// Posts a batch to one of its destinations, leaving out the mail the ledger says
+    /// it got already, returning the indices of the mail it failed for with the error.
+    /// A batch the destination answers with an error status is posted again one mail
+    /// at a time, for one bad mail not to fail the others: each gets its own verdict,
+    /// a 4xx giving up on it and a 5xx retrying it. Mail that could not be posted at
+    /// all, e.g. as the destination is down, is retried together.
+    async fn deliver_batch_to(
+        &self,
+        destination: &Destination,
+        tenant: &Option<String>,
+        batch: &[(QueuedMail, Message)],
+    ) -> Vec<(usize, Arc<anyhow::Error>)> {
+        let url = &destination.url;
+        let mut pending = Vec::new();
+        for (index, (entry, _)) in batch.iter().enumerate() {
+            if !self
+                .ledger
+                .contains(tenant.as_deref(), url, &entry.id)
+                .await
+            {
+                pending.push(index);
+            }
+        }
+        if pending.is_empty() {
+            return Vec::new();
+        }
+        let ids = pending
+            .iter()
+            .map(|&index| &batch[index].0.id)
+            .collect::<Vec<_>>();
+        let messages = pending
+            .iter()
+            .map(|&index| &batch[index].1)
+            .collect::<Vec<_>>();
+        let Err(err) = self.post_batch(destination, tenant, &ids, &messages).await else {
+            return Vec::new();
+        };
+        let status = err
+            .downcast_ref::<reqwest::Error>()
+            .and_then(reqwest::Error::status);
+        if status.is_none() || pending.len() == 1 {
+            let err = Arc::new(err);
+            return pending
+                .into_iter()
+                .map(|index| (index, err.clone()))
+                .collect();
+        }
+        tracing::info!(
+            "{url} refused a batch of {} mails, posting them one at a time: {err:#}",
+            pending.len()
+        );
+        let mut failures = Vec::new();
+        for index in pending {
+            let (entry, message) = &batch[index];
+            if let Err(err) = self.deliver_to(destination, entry, message).await {
+                failures.push((index, Arc::new(err)));
+            }
+        }
+        failures
+    }
+
+    /// Posts the messages of a batch, failing over to the fallback URLs
+    /// while they fail transiently, journaling each mail as for a single one
+    async fn post_batch(
+        &self,
+        destination: &Destination,
+        tenant: &Option<String>,
+        ids: &[&QueueId],
+        messages: &[&Message],
+    ) -> Result<()> {
+        if let Some(rate_limiter) = self.rate_limiters.get(tenant) {
+            rate_limiter.acquire().await;
+        }
+        let (endpoint, resp) = destination
+            .fail_over(|endpoint| async move {
+                for id in ids {
+                    self.journal(&Record::Attempt {
+                        id: (*id).clone(),
+                        at: Utc::now(),
+                        endpoint: Some(endpoint.to_string()),
+                    })
+                    .await?;
+                }
+                let result = match self.faults.sink().await {
+                    Ok(()) => {
+                        destination
+                            .post_batch(&self.client, endpoint, ids, messages)
+                            .await
+                    }
+                    Err(err) => Err(err),
+                };
+                if let Err(err) = &result {
+                    tracing::warn!("SEND ERROR to {endpoint}: {err:?}");
+                    for id in ids {
+                        self.journal(&Record::Failed {
+                            id: (*id).clone(),
+                            at: Utc::now(),
+                            error: format!("{err:#}"),
+                            endpoint: Some(endpoint.to_string()),
+                        })
+                        .await?;
+                    }
+                }
+                result
+            })
+            .await?;
+        tracing::debug!("RECEIVED SEND Response {resp}");
+        self.faults.storage()?;
+        for id in ids {
+            self.ledger
+                .record(tenant.as_deref(), &destination.url, id)
+                .await?;
+            self.journal(&Record::Forwarded {
+                id: (*id).clone(),
+                at: Utc::now(),
+                endpoint: endpoint.to_string(),
+            })
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Delivers the message of a mail of no tenant to a sink, unless the ledger
+    /// says it was already. A sink taking it in parts gets those the ledger
+    /// doesn't have, each recorded as `{endpoint}#{part}` once it took the message.
+    async fn deliver_to_sink(
+        &self,
+        sink: &dyn DynSink,
+        entry: &QueuedMail,
+        message: &Message,
+    ) -> Result<()> {
+        if entry.tenant.is_some() {
+            return Ok(());
+        }
+        let QueuedMail { id, mail, .. } = entry;
+        let endpoint = sink.endpoint();
+        if self.ledger.contains(None, &endpoint, id).await {
+            tracing::info!("Mail {id} was already delivered to {endpoint}, skipping");
+            return Ok(());
+        }
+        let parts = sink.parts(id, mail);
+        if parts.is_empty() {
+            return self
+                .hand_over(id, &endpoint, sink.deliver(id, mail, message))
+                .await;
+        }
+        let mut failure = None;
+        for part in parts {
+            let part_endpoint = format!("{endpoint}#{part}");
+            if self.ledger.contains(None, &part_endpoint, id).await {
+                tracing::info!("Mail {id} was already delivered to {part_endpoint}, skipping");
+                continue;
+            }
+            let delivered = sink.deliver_part(id, mail, message, &part);
+            if let Err(err) = self.hand_over(id, &part_endpoint, delivered).await {
+                // A transient failure is kept over a permanent one, so the mail is retried
+                if failure
+                    .as_ref()
+                    .is_none_or(|failure| !retry::is_transient(failure))
+                {
+                    failure = Some(err);
+                }
+            }
+        }
+        match failure {
+            Some(err) => Err(err),
+            // Every part took it
+            None => self.ledger.record(None, &endpoint, id).await,
+        }
+    }
+
+    /// Journals the attempt to hand a mail over to a sink, and its outcome,
+    /// recording it in the ledger once it took the mail
+    async fn hand_over(
+        &self,
+        id: &QueueId,
+        endpoint: &str,
+        deliver: impl Future<Output = DeliveryResult>,
+    ) -> Result<()> {
+        self.journal(&Record::Attempt {
+            id: id.clone(),
+            at: Utc::now(),
+            endpoint: Some(endpoint.to_string()),
+        })
+        .await?;
+        if let Err(err) = deliver.await {
+            tracing::warn!("Cannot deliver mail {id} to {endpoint}: {err:#}");
+            self.journal(&Record::Failed {
+                id: id.clone(),
+                at: Utc::now(),
+                error: format!("{err:#}"),
+                endpoint: Some(endpoint.to_string()),
+            })
+            .await?;
+            return Err(err);
+        }
+        self.ledger.record(None, endpoint, id).await?;
+        self.journal(&Record::Forwarded {
+            id: id.clone(),
+            at: Utc::now(),
+            endpoint: endpoint.to_string(),
+        })
+        .await
+    }
+
+    /// Posts a message to one of its destinations, unless the ledger says it got it
+    /// already, failing over to its fallback URLs while they fail transiently
+    async fn deliver_to(
+        &self,
+        destination: &Destination,
+        entry: &QueuedMail,
+        message: &Message,
+    ) -> Result<()> {
+        let QueuedMail { id, tenant, .. } = entry;
+        let url = &destination.url;
+        if self.ledger.contains(tenant.as_deref(), url, id).await {
+            tracing::info!("Mail {id} was already delivered to {url}, skipping");
+            return Ok(());
+        }
+        if let Some(rate_limiter) = self.rate_limiters.get(tenant) {
+            rate_limiter.acquire().await;
+        }
+        let (endpoint, resp) = destination
+            .fail_over(|endpoint| async move {
+                self.journal(&Record::Attempt {
+                    id: id.clone(),
+                    at: Utc::now(),
+                    endpoint: Some(endpoint.to_string()),
+                })
+                .await?;
+                let result = match self.faults.sink().await {
+                    Ok(()) => {
+                        destination
+                            .post(&self.client, endpoint, id, &entry.mail, message)
+                            .await
+                    }
+                    Err(err) => Err(err),
+                };
+                if let Err(err) = &result {
+                    tracing::warn!("SEND ERROR to {endpoint}: {err:?}");
+                    self.journal(&Record::Failed {
+                        id: id.clone(),
+                        at: Utc::now(),
+                        error: format!("{err:#}"),
+                        endpoint: Some(endpoint.to_string()),
+                    })
+                    .await?;
+                }
+                result
+            })
+            .await?;
+        tracing::debug!("RECEIVED SEND Response {resp}");
+        self.faults.storage()?;
+        // Recorded under the destination's URL, whichever endpoint took it
+        self.ledger.record(tenant.as_deref(), url, id).await?;
+        self.journal(&Record::Forwarded {
+            id: id.clone(),
+            at: Utc::now(),
+            endpoint: endpoint.to_string(),
+        })
+        .await
+    }
+}
+
+impl Acceptor for Forwarder {
+    /// Journals a received mail and queues it for delivery, returning its queue ids.
+    /// Only once this returns may the mail be acknowledged to the client.
+    /// Recipients of different tenants are split into separate mails, each with
+    /// its own queue id, so a tenant never sees the others' recipients.
+    async fn accept(&self, mail: &Mail, deadline: Instant) -> Result<Vec<QueueId>> {
+        let now = Utc::now();
+        let mut parts: Vec<(Option<&Tenant>, Mail)> = Vec::new();
+        for to in &mail.to {
+            let tenant = tenant::find(&self.tenants, to);
+            let name = tenant.map(|tenant| &tenant.name);
+            match parts
+                .iter_mut()
+                .find(|(other, _)| other.map(|other| &other.name) == name)
+            {
+                Some((_, part)) => part.to.push(to.clone()),
+                None => parts.push((
+                    tenant,
+                    Mail {
+                        to: vec![to.clone()],
+                        ..mail.clone()
+                    },
+                )),
+            }
+        }
+        if parts.is_empty() {
+            parts.push((None, mail.clone()));
+        }
+        let tenants = parts
+            .iter()
+            .filter_map(|(tenant, _)| *tenant)
+            .collect::<Vec<_>>();
+        for (i, tenant) in tenants.iter().enumerate() {
+            if !tenant.take_quota(now) {
+                for counted in &tenants[..i] {
+                    counted.return_quota(now);
+                }
+                anyhow::bail!("tenant {} is over its daily quota", tenant.name);
+            }
+        }
+
+        // Every part is journaled before any is queued: should one fail, the client
+        // sends the whole mail again, so none of them may be delivered
+        let mut entries = Vec::new();
+        for (tenant, mail) in &parts {
+            match self.journal_part(mail.clone(), *tenant, now).await {
+                Ok(entry) => entries.push(entry),
+                Err(err) => {
+                    let reason = "another part of the mail could not be journaled";
+                    self.abandon(&entries, &tenants, now, reason).await;
+                    return Err(err);
+                }
+            }
+        }
+        // Only now that the whole mail is ours may its duplicates be looked for
+        if let Some(message_ids) = &self.message_ids {
+            for entry in entries.iter().filter(|entry| entry.duplicate_of.is_none()) {
+                if let Err(err) = message_ids.remember(&entry.mail, &entry.id).await {
+                    tracing::error!(
+                        "Cannot remember the Message-ID of mail {}: {err:#}",
+                        entry.id
+                    );
+                }
+            }
+        }
+        let ids = entries
+            .iter()
+            .map(|entry| entry.id.clone())
+            .collect::<Vec<_>>();
+        let mut partial = false;
+        let mut left = entries.into_iter();
+        for (tenant, _) in &parts {
+            let Some(entry) = left.next() else {
+                break;
+            };
+            // Kept to be forgotten should the mail be refused
+            let remembered = self
+                .message_ids
+                .as_ref()
+                .map(|_| (entry.mail.clone(), entry.id.clone()));
+            if let Err(err) = self
+                .queue_part(entry, *tenant, now, deadline, partial)
+                .await
+            {
+                // Refused, the client sends the whole mail again: none of it may be delivered
+                if !partial {
+                    let rest = left.collect::<Vec<_>>();
+                    let reason = "another part of the mail was refused";
+                    self.abandon(&rest, &tenants, now, reason).await;
+                    if let (Some(message_ids), Some((mail, id))) = (&self.message_ids, remembered) {
+                        if let Err(err) = message_ids.forget(&mail, &id).await {
+                            tracing::error!("Cannot forget the Message-ID of mail {id}: {err:#}");
+                        }
+                    }
+                }
+                return Err(err);
+            }
+            // Delivered already, the rest of the mail can no longer be refused
+            partial = self.sync_delivery;
+        }
+        Ok(ids)
+    }
+
+    fn disconnect(&self) -> bool {
+        self.faults.disconnect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signature() {
+        assert_eq!(
+            signature("secret", 1700000000, br#"{"subject":"hi"}"#),
+            "sha256=286ae9005bd9b25b296b4c5d80625b9496a91e395a9778562c9eddae320ea4df"
+        );
+    }
+}
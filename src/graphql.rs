@@ -0,0 +1,550 @@
+use std::collections::HashMap;
+use std::sync::{Arc, OnceLock};
+
+use async_graphql::connection::{query, Connection, Edge};
+use async_graphql::{
+    ComplexObject, Context, EmptySubscription, Enum, InputObject, Object, Schema, SimpleObject,
+};
+use chrono::{DateTime, Utc};
+
+use crate::deadletter;
+use crate::forward::Forwarder;
+use crate::payload;
+use crate::priority::Priority;
+use crate::protocol::Mail;
+use crate::queue::{QueueId, QueuedMail};
+use crate::schema;
+use crate::senders::SenderStats;
+use crate::wal::Record;
+
+pub type AdminSchema = Schema<Query, Mutation, EmptySubscription>;
+
+/// Builds the schema of the admin API, served by [`crate::admin`]
+pub fn schema(forwarder: Arc<Forwarder>) -> AdminSchema {
+    Schema::build(Query, Mutation, EmptySubscription)
+        .data(forwarder)
+        .finish()
+}
+
+/// Page size when the client doesn't ask for one
+const DEFAULT_PAGE_SIZE: usize = 20;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Enum)]
+pub enum Status {
+    /// Waiting for delivery, or for another attempt
+    Pending,
+    Delivered,
+    /// Given up on, e.g. because it could not be parsed
+    Dropped,
+}
+
+/// A delivery attempt and how it ended
+#[derive(Clone, Debug, SimpleObject)]
+pub struct Attempt {
+    /// URL of the destination posted to
+    pub endpoint: Option<String>,
+    pub started_at: DateTime<Utc>,
+    /// None while the attempt is in progress
+    pub finished_at: Option<DateTime<Utc>>,
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Debug, SimpleObject)]
+pub struct Attachment {
+    pub filename: String,
+    /// Size in bytes
+    pub size: usize,
+}
+
+/// A message in the journal, with its delivery history
+#[derive(Clone, Debug, SimpleObject)]
+#[graphql(complex)]
+pub struct Message {
+    #[graphql(skip)]
+    pub id: QueueId,
+    pub accepted_at: DateTime<Utc>,
+    /// Envelope sender
+    pub from: String,
+    /// Envelope recipients
+    pub to: Vec<String>,
+    pub priority: Priority,
+    pub not_before: Option<DateTime<Utc>>,
+    /// Tenant the recipients belong to, none for the default destination
+    pub tenant: Option<String>,
+    pub status: Status,
+    /// Why the message was dropped
+    pub reason: Option<String>,
+    pub attempts: Vec<Attempt>,
+    /// Endpoints that took the message, fallback ones included
+    pub delivered_to: Vec<String>,
+    #[graphql(skip)]
+    pub mail: Mail,
+    /// The payload of the mail, parsed by the first field needing it
+    #[graphql(skip)]
+    pub parsed: OnceLock<Option<schema::Message>>,
+}
+
+impl Message {
+    /// The payload of the mail, none when it can't be parsed
+    fn payload(&self) -> Option<&schema::Message> {
+        self.parsed
+            .get_or_init(|| payload::build(&self.mail.data, &payload::Options::default()).ok())
+            .as_ref()
+    }
+}
+
+#[ComplexObject]
+impl Message {
+    async fn id(&self) -> &str {
+        self.id.as_str()
+    }
+
+    async fn subject(&self) -> Option<String> {
+        self.payload()?.subject.clone()
+    }
+
+    async fn attachments(&self) -> Vec<Attachment> {
+        self.payload()
+            .map(|message| {
+                message
+                    .attachments
+                    .iter()
+                    .map(|attachment| Attachment {
+                        filename: attachment.filename.clone(),
+                        size: attachment.content.len(),
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// The message as received, in its original MIME form
+    async fn raw(&self) -> String {
+        String::from_utf8_lossy(&self.mail.data).into_owned()
+    }
+}
+
+/// A mail waiting in the delivery queue
+#[derive(Clone, Debug, SimpleObject)]
+pub struct QueueEntry {
+    pub id: String,
+    pub from: String,
+    pub to: Vec<String>,
+    pub priority: Priority,
+    pub not_before: Option<DateTime<Utc>>,
+    pub tenant: Option<String>,
+}
+
+impl From<QueuedMail> for QueueEntry {
+    fn from(entry: QueuedMail) -> Self {
+        Self {
+            id: entry.id.to_string(),
+            from: entry.mail.from,
+            to: entry.mail.to,
+            priority: entry.priority,
+            not_before: entry.not_before,
+            tenant: entry.tenant,
+        }
+    }
+}
+
+/// What the mail of a sender domain amounted to since the server started
+#[derive(Clone, Debug, SimpleObject)]
+pub struct Sender {
+    pub domain: String,
+    /// Mails accepted, counted against the quota of the domain
+    pub messages: u64,
+    /// Mails deferred for being over the quota of the domain
+    pub deferred: u64,
+    pub last_seen: DateTime<Utc>,
+}
+
+impl From<SenderStats> for Sender {
+    fn from(stats: SenderStats) -> Self {
+        Self {
+            domain: stats.domain,
+            messages: stats.messages,
+            deferred: stats.deferred,
+            last_seen: stats.last_seen,
+        }
+    }
+}
+
+/// A mail given up on, kept until it is queued again
+#[derive(Clone, Debug, SimpleObject)]
+pub struct DeadLetter {
+    pub id: String,
+    pub failed_at: DateTime<Utc>,
+    pub attempts: u32,
+    pub reason: String,
+    pub from: String,
+    pub to: Vec<String>,
+    pub tenant: Option<String>,
+    /// The payload posted to the endpoint as JSON, none when the message cannot be parsed
+    pub payload: Option<String>,
+    /// The message as received, in its original MIME form
+    pub raw: String,
+    /// Endpoints that had not taken the message when it was given up on, empty for all
+    pub endpoints: Vec<String>,
+}
+
+impl From<deadletter::DeadLetter> for DeadLetter {
+    fn from(letter: deadletter::DeadLetter) -> Self {
+        Self {
+            id: letter.id.to_string(),
+            failed_at: letter.failed_at,
+            attempts: letter.attempts,
+            reason: letter.reason,
+            payload: letter
+                .payload
+                .and_then(|payload| serde_json::to_string(&payload).ok()),
+            raw: String::from_utf8_lossy(&letter.mail.data).into_owned(),
+            from: letter.mail.from,
+            to: letter.mail.to,
+            tenant: letter.tenant,
+            endpoints: letter.endpoints,
+        }
+    }
+}
+
+/// Narrows down the listed messages, all conditions having to match
+#[derive(Debug, Default, InputObject)]
+pub struct MessageFilter {
+    pub status: Option<Status>,
+    /// Part of the envelope sender, ignoring case
+    pub from: Option<String>,
+    /// Part of any envelope recipient, ignoring case
+    pub to: Option<String>,
+    pub accepted_after: Option<DateTime<Utc>>,
+    pub accepted_before: Option<DateTime<Utc>>,
+}
+
+impl MessageFilter {
+    fn matches(&self, message: &Message) -> bool {
+        let contains =
+            |haystack: &str, needle: &str| haystack.to_lowercase().contains(&needle.to_lowercase());
+        self.status.is_none_or(|status| message.status == status)
+            && self
+                .from
+                .as_deref()
+                .is_none_or(|from| contains(&message.from, from))
+            && self
+                .to
+                .as_deref()
+                .is_none_or(|to| message.to.iter().any(|recipient| contains(recipient, to)))
+            && self
+                .accepted_after
+                .is_none_or(|after| message.accepted_at > after)
+            && self
+                .accepted_before
+                .is_none_or(|before| message.accepted_at < before)
+    }
+}
+
+/// Folds the journal into the messages it tells about, in acceptance order
+pub fn messages(records: Vec<Record>) -> Vec<Message> {
+    let mut order = Vec::new();
+    let mut messages = HashMap::new();
+    for record in records {
+        match record {
+            Record::Accepted {
+                id,
+                at,
+                mail,
+                priority,
+                not_before,
+                tenant,
+                ..
+            } => {
+                // Accepted again when the history was written twice, by a crash
+                // after the log was moved there but before it was compacted
+                if messages.contains_key(&id) {
+                    continue;
+                }
+                order.push(id.clone());
+                messages.insert(
+                    id.clone(),
+                    Message {
+                        id,
+                        accepted_at: at,
+                        from: mail.from.clone(),
+                        to: mail.to.clone(),
+                        priority,
+                        not_before,
+                        tenant,
+                        status: Status::Pending,
+                        reason: None,
+                        attempts: Vec::new(),
+                        delivered_to: Vec::new(),
+                        mail,
+                        parsed: OnceLock::new(),
+                    },
+                );
+            }
+            Record::Attempt { id, at, endpoint } => {
+                if let Some(message) = messages.get_mut(&id) {
+                    message.attempts.push(Attempt {
+                        endpoint,
+                        started_at: at,
+                        finished_at: None,
+                        error: None,
+                    });
+                }
+            }
+            Record::Failed {
+                id,
+                at,
+                error,
+                endpoint,
+            } => {
+                // The latest attempt to the same destination
+                if let Some(attempt) = messages.get_mut(&id).and_then(|message| {
+                    message
+                        .attempts
+                        .iter_mut()
+                        .rev()
+                        .find(|attempt| endpoint.is_none() || attempt.endpoint == endpoint)
+                }) {
+                    attempt.finished_at = Some(at);
+                    attempt.error = Some(error);
+                }
+            }
+            Record::Deferred { id, until, .. } => {
+                if let Some(message) = messages.get_mut(&id) {
+                    message.not_before = Some(until);
+                }
+            }
+            Record::Forwarded { id, at, endpoint } => {
+                if let Some(message) = messages.get_mut(&id) {
+                    if let Some(attempt) = message
+                        .attempts
+                        .iter_mut()
+                        .rev()
+                        .find(|attempt| attempt.endpoint.as_ref() == Some(&endpoint))
+                    {
+                        attempt.finished_at = Some(at);
+                    }
+                    message.delivered_to.push(endpoint);
+                }
+            }
+            Record::Delivered { id, at } => {
+                if let Some(message) = messages.get_mut(&id) {
+                    message.status = Status::Delivered;
+                    for attempt in &mut message.attempts {
+                        attempt.finished_at.get_or_insert(at);
+                    }
+                }
+            }
+            Record::Dropped { id, reason, .. } => {
+                if let Some(message) = messages.get_mut(&id) {
+                    message.status = Status::Dropped;
+                    message.reason = Some(reason);
+                }
+            }
+        }
+    }
+    order
+        .into_iter()
+        .filter_map(|id| messages.remove(&id))
+        .collect()
+}
+
+/// Cuts a page out of `items`, cursors being positions in the list
+async fn paginate<T: async_graphql::OutputType>(
+    items: Vec<T>,
+    after: Option<String>,
+    first: Option<i32>,
+) -> async_graphql::Result<Connection<usize, T>> {
+    query(
+        after,
+        None,
+        first,
+        None,
+        |after: Option<usize>, _: Option<usize>, first, _| async move {
+            let start = after.map_or(0, |after| after + 1).min(items.len());
+            let end = (start + first.unwrap_or(DEFAULT_PAGE_SIZE)).min(items.len());
+            let mut connection = Connection::new(start > 0, end < items.len());
+            connection.edges.extend(
+                items
+                    .into_iter()
+                    .enumerate()
+                    .skip(start)
+                    .take(end - start)
+                    .map(|(cursor, item)| Edge::new(cursor, item)),
+            );
+            Ok::<_, async_graphql::Error>(connection)
+        },
+    )
+    .await
+}
+
+pub struct Query;
+
+#[Object]
+impl Query {
+    /// Messages in the journal and its history, oldest first
+    async fn messages(
+        &self,
+        ctx: &Context<'_>,
+        filter: Option<MessageFilter>,
+        after: Option<String>,
+        first: Option<i32>,
+    ) -> async_graphql::Result<Connection<usize, Message>> {
+        let forwarder = ctx.data::<Arc<Forwarder>>()?;
+        let filter = filter.unwrap_or_default();
+        let messages = messages(forwarder.wal().records().await?)
+            .into_iter()
+            .filter(|message| filter.matches(message))
+            .collect();
+        paginate(messages, after, first).await
+    }
+
+    async fn message(
+        &self,
+        ctx: &Context<'_>,
+        id: String,
+    ) -> async_graphql::Result<Option<Message>> {
+        let forwarder = ctx.data::<Arc<Forwarder>>()?;
+        Ok(messages(forwarder.wal().records().await?)
+            .into_iter()
+            .find(|message| message.id.as_str() == id))
+    }
+
+    /// Mail waiting for delivery, in the order it will be delivered
+    async fn queue(
+        &self,
+        ctx: &Context<'_>,
+        after: Option<String>,
+        first: Option<i32>,
+    ) -> async_graphql::Result<Connection<usize, QueueEntry>> {
+        let forwarder = ctx.data::<Arc<Forwarder>>()?;
+        let entries = forwarder
+            .queue()
+            .snapshot()
+            .into_iter()
+            .map(Into::into)
+            .collect();
+        paginate(entries, after, first).await
+    }
+
+    /// Mail given up on, oldest failure first
+    async fn dead_letters(
+        &self,
+        ctx: &Context<'_>,
+        after: Option<String>,
+        first: Option<i32>,
+    ) -> async_graphql::Result<Connection<usize, DeadLetter>> {
+        let forwarder = ctx.data::<Arc<Forwarder>>()?;
+        let letters = match forwarder.dead_letters() {
+            Some(dead_letters) => dead_letters.list().await?,
+            None => Vec::new(),
+        };
+        paginate(letters.into_iter().map(Into::into).collect(), after, first).await
+    }
+
+    /// Sender domains, busiest first, when their quotas are enforced
+    async fn senders(
+        &self,
+        ctx: &Context<'_>,
+        after: Option<String>,
+        first: Option<i32>,
+    ) -> async_graphql::Result<Connection<usize, Sender>> {
+        let forwarder = ctx.data::<Arc<Forwarder>>()?;
+        let senders = match forwarder.senders() {
+            Some(senders) => senders.stats().into_iter().map(Into::into).collect(),
+            None => Vec::new(),
+        };
+        paginate(senders, after, first).await
+    }
+}
+
+pub struct Mutation;
+
+#[Object]
+impl Mutation {
+    /// Queues a dead letter for delivery again, returning false when there is no such letter
+    async fn requeue_dead_letter(
+        &self,
+        ctx: &Context<'_>,
+        id: String,
+    ) -> async_graphql::Result<bool> {
+        let forwarder = ctx.data::<Arc<Forwarder>>()?;
+        Ok(forwarder.requeue_dead_letter(&id).await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_messages_from_records() {
+        let (first, second) = (QueueId::generate(), QueueId::generate());
+        let at = Utc::now();
+        let accepted = |id: &QueueId, from: &str| Record::Accepted {
+            id: id.clone(),
+            at,
+            mail: Mail {
+                from: from.into(),
+                to: vec!["<b@example.com>".into()],
+                data: "Subject: hi\r\n\r\nhello\r\n".into(),
+            },
+            priority: Priority::Normal,
+            not_before: None,
+            tenant: None,
+            duplicate_of: None,
+        };
+        let messages = messages(vec![
+            accepted(&first, "<a@example.com>"),
+            accepted(&second, "<c@example.org>"),
+            Record::Attempt {
+                id: first.clone(),
+                at,
+                endpoint: Some("https://a.example.com".into()),
+            },
+            Record::Attempt {
+                id: first.clone(),
+                at,
+                endpoint: Some("https://b.example.com".into()),
+            },
+            Record::Failed {
+                id: first.clone(),
+                at,
+                error: "503".into(),
+                endpoint: Some("https://a.example.com".into()),
+            },
+            Record::Attempt {
+                id: first.clone(),
+                at,
+                endpoint: Some("https://a.example.com".into()),
+            },
+            Record::Forwarded {
+                id: first.clone(),
+                at,
+                endpoint: "https://a.example.com".into(),
+            },
+            Record::Delivered {
+                id: first.clone(),
+                at,
+            },
+        ]);
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].status, Status::Delivered);
+        assert_eq!(messages[0].attempts.len(), 3);
+        assert_eq!(messages[0].attempts[0].error.as_deref(), Some("503"));
+        assert_eq!(messages[0].attempts[1].error, None);
+        assert_eq!(messages[0].delivered_to, ["https://a.example.com"]);
+        assert_eq!(messages[1].status, Status::Pending);
+
+        let filter = MessageFilter {
+            from: Some("EXAMPLE.ORG".into()),
+            ..MessageFilter::default()
+        };
+        let matching = messages
+            .iter()
+            .filter(|message| filter.matches(message))
+            .collect::<Vec<_>>();
+        assert_eq!(matching.len(), 1);
+        assert_eq!(matching[0].id, second);
+    }
+}
@@ -0,0 +1,46 @@
+/// The header fields of a CRLF message, each with its folded lines but without
+/// its final CRLF, and the body
+pub(crate) fn split(message: &[u8]) -> (Vec<&[u8]>, &[u8]) {
+    let mut fields: Vec<&[u8]> = Vec::new();
+    let mut start = 0;
+    let mut index = 0;
+    while index < message.len() {
+        let end = message[index..]
+            .windows(2)
+            .position(|pair| pair == b"\r\n")
+            .map_or(message.len(), |end| index + end);
+        let line = &message[index..end];
+        let next = (end + 2).min(message.len());
+        if line.is_empty() {
+            if index > start {
+                fields.push(&message[start..index - 2]);
+            }
+            return (fields, &message[next..]);
+        }
+        if !line.starts_with(b" ") && !line.starts_with(b"\t") && index > start {
+            fields.push(&message[start..index - 2]);
+            start = index;
+        }
+        index = next;
+    }
+    if start < message.len() {
+        let field = &message[start..];
+        fields.push(field.strip_suffix(b"\r\n").unwrap_or(field));
+    }
+    (fields, &[])
+}
+
+/// Value of a header field, after its name and colon
+pub(crate) fn field_value(field: &[u8]) -> String {
+    let value = field
+        .iter()
+        .position(|&byte| byte == b':')
+        .map_or(&[][..], |colon| &field[colon + 1..]);
+    String::from_utf8_lossy(value).trim().to_string()
+}
+
+/// Lowercase name of a header field
+pub(crate) fn field_name(field: &[u8]) -> String {
+    let name = field.split(|&byte| byte == b':').next().unwrap_or_default();
+    String::from_utf8_lossy(name).trim().to_lowercase()
+}
@@ -0,0 +1,67 @@
+use std::net::IpAddr;
+
+/// What names the unauthenticated clients may not introduce themselves
+/// with in HELO/EHLO, their mail being refused
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HeloPolicy {
+    /// Addresses, bare or as address literals, e.g. `[192.0.2.1]`
+    pub reject_ip: bool,
+    /// Our own domain, claimed by clients impersonating us
+    pub reject_own: Option<String>,
+    /// Names without a dot, e.g. `localhost` or `DESKTOP-1234`
+    pub require_fqdn: bool,
+}
+
+impl HeloPolicy {
+    /// Checks the name a client introduced itself with, returning why it is refused
+    pub fn check(&self, helo: Option<&str>) -> Result<(), &'static str> {
+        let Some(helo) = helo.map(|helo| helo.trim_end_matches('.')) else {
+            return Err("no name");
+        };
+        let literal = helo.starts_with('[') && helo.ends_with(']');
+        if literal || helo.parse::<IpAddr>().is_ok() {
+            return match self.reject_ip {
+                true => Err("address"),
+                false => Ok(()),
+            };
+        }
+        if let Some(domain) = &self.reject_own {
+            if helo.eq_ignore_ascii_case(domain.trim_end_matches('.')) {
+                return Err("our own name");
+            }
+        }
+        if self.require_fqdn && !helo.contains('.') {
+            return Err("not fully qualified");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let policy = HeloPolicy {
+            reject_ip: true,
+            reject_own: Some("mx.example.org".into()),
+            require_fqdn: true,
+        };
+        assert_eq!(policy.check(Some("mail.example.com")), Ok(()));
+        assert_eq!(policy.check(Some("mail.example.com.")), Ok(()));
+        assert_eq!(policy.check(Some("[192.0.2.1]")), Err("address"));
+        assert_eq!(policy.check(Some("[IPv6:2001:db8::1]")), Err("address"));
+        assert_eq!(policy.check(Some("192.0.2.1")), Err("address"));
+        assert_eq!(policy.check(Some("MX.example.org")), Err("our own name"));
+        assert_eq!(policy.check(Some("localhost")), Err("not fully qualified"));
+        assert_eq!(policy.check(None), Err("no name"));
+
+        let lenient = HeloPolicy {
+            require_fqdn: true,
+            ..Default::default()
+        };
+        assert_eq!(lenient.check(Some("[192.0.2.1]")), Ok(()));
+        assert_eq!(lenient.check(Some("mx.example.org")), Ok(()));
+    }
+}
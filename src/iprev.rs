@@ -0,0 +1,115 @@
+use std::fmt;
+use std::net::IpAddr;
+
+use crate::dns::{LookupError, Resolver};
+
+/// Names of an address confirmed at most, so a client can't have us look up names forever
+const MAX_NAMES: usize = 10;
+
+/// Outcome of a reverse DNS check (RFC 8601 §3)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IprevResult {
+    /// A name the address points to points back to it
+    Pass,
+    /// The address has no name, or none pointing back to it
+    Fail,
+    /// A lookup failed, the check may succeed later
+    TempError,
+}
+
+impl IprevResult {
+    /// Name of the result, as in `Authentication-Results`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Pass => "pass",
+            Self::Fail => "fail",
+            Self::TempError => "temperror",
+        }
+    }
+}
+
+/// A reverse DNS check of a client, displayed as in `Authentication-Results`,
+/// e.g. `iprev=pass policy.iprev=192.0.2.1 policy.ptr=mail.example.com`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Iprev {
+    pub result: IprevResult,
+    pub ip: IpAddr,
+    /// Name of the address, confirmed when the check passed
+    pub name: Option<String>,
+}
+
+impl fmt::Display for Iprev {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "iprev={} policy.iprev={}", self.result.as_str(), self.ip)?;
+        if let Some(name) = &self.name {
+            write!(f, " policy.ptr={name}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks that the client at `ip` has forward-confirmed reverse DNS (FCrDNS):
+/// a name of its address whose addresses include it
+pub async fn check(resolver: &impl Resolver, ip: IpAddr) -> Iprev {
+    let iprev = |result, name: Option<&String>| Iprev {
+        result,
+        ip,
+        name: name.cloned(),
+    };
+    let names = match resolver.ptr(ip).await {
+        Ok(names) => names,
+        Err(LookupError::NotFound) => return iprev(IprevResult::Fail, None),
+        Err(LookupError::Failed(err)) => {
+            tracing::debug!("Cannot look up the names of {ip}: {err}");
+            return iprev(IprevResult::TempError, None);
+        }
+    };
+    let mut failed = false;
+    for name in names.iter().take(MAX_NAMES) {
+        match resolver.ips(name).await {
+            Ok(ips) if ips.contains(&ip) => return iprev(IprevResult::Pass, Some(name)),
+            Ok(_) | Err(LookupError::NotFound) => {}
+            Err(LookupError::Failed(err)) => {
+                tracing::debug!("Cannot look up the addresses of {name}: {err}");
+                failed = true;
+            }
+        }
+    }
+    match failed {
+        true => iprev(IprevResult::TempError, names.first()),
+        false => iprev(IprevResult::Fail, names.first()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dns::Zone;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_check() {
+        let zone = Zone::default()
+            .with("ptr", "192.0.2.1", "mail.example.com")
+            .with("ip", "mail.example.com", "192.0.2.1")
+            .with("ip", "mail.example.com", "2001:db8::1")
+            .with("ptr", "192.0.2.2", "spoofed.example.com")
+            .with("ptr", "192.0.2.2", "other.example.net")
+            .with("ip", "spoofed.example.com", "192.0.2.99")
+            .with("ptr", "192.0.2.3", "down.example.org")
+            .failing("down.example.org");
+
+        let iprev = check(&zone, "192.0.2.1".parse().unwrap()).await;
+        assert_eq!(
+            iprev.to_string(),
+            "iprev=pass policy.iprev=192.0.2.1 policy.ptr=mail.example.com"
+        );
+        let iprev = check(&zone, "192.0.2.2".parse().unwrap()).await;
+        assert_eq!(iprev.result, IprevResult::Fail);
+        assert_eq!(iprev.name.as_deref(), Some("spoofed.example.com"));
+        let iprev = check(&zone, "192.0.2.3".parse().unwrap()).await;
+        assert_eq!(iprev.result, IprevResult::TempError);
+        let iprev = check(&zone, "192.0.2.5".parse().unwrap()).await;
+        assert_eq!(iprev.to_string(), "iprev=fail policy.iprev=192.0.2.5");
+    }
+}
@@ -0,0 +1,488 @@
+use std::sync::Arc;
+
+use axum::extract::{Path, State};
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+use crate::forward::Forwarder;
+use crate::graphql::{self, Message};
+use crate::payload;
+use crate::received::{ReceivedMail, Session};
+use crate::schema::Contact;
+
+const CORE: &str = "urn:ietf:params:jmap:core";
+const MAIL: &str = "urn:ietf:params:jmap:mail";
+/// The only account, holding all the journaled mail
+pub const ACCOUNT_ID: &str = "mail";
+/// The only mailbox, every message being in it
+const MAILBOX_ID: &str = "inbox";
+/// Length of the body preview of emails, in characters
+const PREVIEW_LENGTH: usize = 256;
+/// Most emails an `Email/get` call may ask for
+const MAX_OBJECTS_IN_GET: usize = 500;
+
+/// A minimal, read-only JMAP Mail server (RFC 8620, RFC 8621) over the journaled mail:
+/// the session resource, `Email/query`, `Email/get` and blob download.
+/// Uploads are refused, as nothing can be created.
+pub fn router() -> Router<Arc<Forwarder>> {
+    Router::new()
+        .route("/.well-known/jmap", get(session))
+        .route("/jmap/api", post(api))
+        .route("/jmap/download/:account/:blob/:name", get(download))
+        .route("/jmap/upload/:account/", post(upload))
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Request {
+    using: Vec<String>,
+    method_calls: Vec<(String, Map<String, Value>, String)>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ApiResponse {
+    method_responses: Vec<(String, Value, String)>,
+    session_state: String,
+}
+
+/// Failure of a method call, answered with an `error` response
+struct MethodError(&'static str);
+
+async fn session(State(forwarder): State<Arc<Forwarder>>) -> Json<Value> {
+    let state = forwarder
+        .wal()
+        .records()
+        .await
+        .map(|records| state(&graphql::messages(records)))
+        .unwrap_or_default();
+    Json(json!({
+        "capabilities": {
+            CORE: {
+                "maxSizeUpload": 0,
+                "maxConcurrentUpload": 1,
+                "maxSizeRequest": 10_000_000,
+                "maxConcurrentRequests": 4,
+                "maxCallsInRequest": 16,
+                "maxObjectsInGet": MAX_OBJECTS_IN_GET,
+                "maxObjectsInSet": 0,
+                "collationAlgorithms": [],
+            },
+            MAIL: {},
+        },
+        "accounts": {
+            ACCOUNT_ID: {
+                "name": ACCOUNT_ID,
+                "isPersonal": false,
+                "isReadOnly": true,
+                "accountCapabilities": { MAIL: {
+                    "maxMailboxesPerEmail": 1,
+                    "maxMailboxDepth": 1,
+                    "maxSizeMailboxName": 64,
+                    "maxSizeAttachmentsPerEmail": 0,
+                    "emailQuerySortOptions": ["receivedAt"],
+                    "mayCreateTopLevelMailbox": false,
+                } },
+            },
+        },
+        "primaryAccounts": { CORE: ACCOUNT_ID, MAIL: ACCOUNT_ID },
+        "username": "admin",
+        "apiUrl": "/jmap/api",
+        "downloadUrl": "/jmap/download/{accountId}/{blobId}/{name}?accept={type}",
+        "uploadUrl": "/jmap/upload/{accountId}/",
+        "eventSourceUrl": "/stream",
+        "state": state,
+    }))
+}
+
+/// Refuses uploads, `maxSizeUpload` being 0 as nothing can be created
+async fn upload(Path(account): Path<String>) -> Response {
+    if account != ACCOUNT_ID {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    (
+        StatusCode::PAYLOAD_TOO_LARGE,
+        Json(json!({
+            "type": "urn:ietf:params:jmap:error:limit",
+            "limit": "maxSizeUpload",
+            "status": 413,
+            "detail": "uploads are not supported",
+        })),
+    )
+        .into_response()
+}
+
+/// State of the emails, counting the changes to them. Emails are only ever
+/// created, as the message is accepted, so that is how many there are.
+fn state(messages: &[Message]) -> String {
+    messages.len().to_string()
+}
+
+async fn api(
+    State(forwarder): State<Arc<Forwarder>>,
+    Json(request): Json<Request>,
+) -> Result<Json<ApiResponse>, (StatusCode, Json<Value>)> {
+    if let Some(unknown) = request
+        .using
+        .iter()
+        .find(|capability| *capability != CORE && *capability != MAIL)
+    {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "type": "urn:ietf:params:jmap:error:unknownCapability",
+                "status": 400,
+                "detail": format!("unsupported capability {unknown}"),
+            })),
+        ));
+    }
+    let records = forwarder.wal().records().await.map_err(|err| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "type": "serverFail", "detail": format!("{err:#}") })),
+        )
+    })?;
+    let messages = graphql::messages(records);
+    let state = state(&messages);
+
+    let mut responses: Vec<(String, Value, String)> = Vec::new();
+    for (name, arguments, call_id) in request.method_calls {
+        let result = resolve_references(arguments, &responses).and_then(|arguments| {
+            if arguments
+                .get("accountId")
+                .is_some_and(|account| account != ACCOUNT_ID)
+            {
+                return Err(MethodError("accountNotFound"));
+            }
+            match name.as_str() {
+                "Email/query" => Ok(query(&messages, &arguments, &state)),
+                "Email/get" => get_emails(&messages, &arguments, &state),
+                _ => Err(MethodError("unknownMethod")),
+            }
+        });
+        responses.push(match result {
+            Ok(response) => (name, response, call_id),
+            Err(MethodError(error)) => ("error".into(), json!({ "type": error }), call_id),
+        });
+    }
+    Ok(Json(ApiResponse {
+        method_responses: responses,
+        session_state: state,
+    }))
+}
+
+/// Replaces `#argument` back-references with the result of an earlier call,
+/// e.g. `"#ids": {"resultOf": "c0", "name": "Email/query", "path": "/ids"}`.
+/// Paths are plain JSON pointers, without the `*` wildcard.
+fn resolve_references(
+    arguments: Map<String, Value>,
+    responses: &[(String, Value, String)],
+) -> Result<Map<String, Value>, MethodError> {
+    arguments
+        .into_iter()
+        .map(|(key, value)| {
+            let Some(key) = key.strip_prefix('#') else {
+                return Ok((key, value));
+            };
+            let reference = |field: &str| value.get(field).and_then(Value::as_str);
+            let resolved = responses
+                .iter()
+                .find(|(name, _, call_id)| {
+                    Some(call_id.as_str()) == reference("resultOf")
+                        && Some(name.as_str()) == reference("name")
+                })
+                .and_then(|(_, response, _)| response.pointer(reference("path")?))
+                .ok_or(MethodError("invalidResultReference"))?;
+            Ok((key.to_string(), resolved.clone()))
+        })
+        .collect()
+}
+
+/// The message as sent by the client, without the DATA terminator
+fn raw(message: &Message) -> Vec<u8> {
+    ReceivedMail::new(vec![message.id.clone()], &message.mail, Session::default()).raw
+}
+
+/// The payload of the message, None when it cannot be parsed
+fn parse(message: &Message) -> Option<crate::schema::Message> {
+    payload::build(&raw(message), &payload::Options::default()).ok()
+}
+
+fn addresses(contacts: &[Contact]) -> Value {
+    contacts
+        .iter()
+        .map(|contact| json!({ "name": contact.name, "email": contact.email }))
+        .collect()
+}
+
+/// The message as a JMAP Email object
+fn email(message: &Message) -> Value {
+    let parsed = parse(message);
+    let envelope =
+        |address: &str| json!([{ "name": null, "email": address.trim_matches(['<', '>']) }]);
+    let preview = parsed
+        .as_ref()
+        .and_then(|parsed| {
+            parsed
+                .content
+                .iter()
+                .find(|content| content.mime.as_deref() != Some("text/html"))
+                .or(parsed.content.first())
+        })
+        .and_then(|content| content.value.as_deref())
+        .map(|text| {
+            text.split_whitespace()
+                .collect::<Vec<_>>()
+                .join(" ")
+                .chars()
+                .take(PREVIEW_LENGTH)
+                .collect::<String>()
+        })
+        .unwrap_or_default();
+    json!({
+        "id": message.id.as_str(),
+        "blobId": message.id.as_str(),
+        "threadId": message.id.as_str(),
+        "mailboxIds": { MAILBOX_ID: true },
+        "keywords": {},
+        "size": raw(message).len(),
+        "receivedAt": message.accepted_at.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+        "from": parsed.as_ref().map_or_else(
+            || envelope(&message.from),
+            |parsed| addresses(std::slice::from_ref(&parsed.from)),
+        ),
+        "to": parsed.as_ref().map_or_else(
+            || message.to.iter().flat_map(|to| envelope(to).as_array().cloned().unwrap_or_default()).collect(),
+            |parsed| addresses(&parsed.to),
+        ),
+        "cc": parsed.as_ref().map(|parsed| addresses(&parsed.cc)),
+        "subject": parsed.as_ref().and_then(|parsed| parsed.subject.clone()),
+        "preview": preview,
+        "hasAttachment": parsed.as_ref().is_some_and(|parsed| !parsed.attachments.is_empty()),
+    })
+}
+
+/// Whether the message matches an `Email/query` filter condition.
+/// Supports `from`, `to`, `subject`, `text`, `after` and `before`.
+fn matches(message: &Message, filter: &Value) -> bool {
+    let condition = |name: &str| filter.get(name).and_then(Value::as_str);
+    let contains =
+        |haystack: &str, needle: &str| haystack.to_lowercase().contains(&needle.to_lowercase());
+    let date = |name: &str| {
+        condition(name).and_then(|date| chrono::DateTime::parse_from_rfc3339(date).ok())
+    };
+    let subject = || {
+        parse(message)
+            .and_then(|parsed| parsed.subject)
+            .unwrap_or_default()
+    };
+    condition("from").is_none_or(|from| contains(&message.from, from))
+        && condition("to").is_none_or(|to| message.to.iter().any(|rcpt| contains(rcpt, to)))
+        && condition("subject").is_none_or(|text| contains(&subject(), text))
+        && condition("text")
+            .is_none_or(|text| contains(&String::from_utf8_lossy(&message.mail.data), text))
+        && date("after").is_none_or(|after| message.accepted_at >= after)
+        && date("before").is_none_or(|before| message.accepted_at < before)
+}
+
+fn query(messages: &[Message], arguments: &Map<String, Value>, state: &str) -> Value {
+    let filter = arguments.get("filter").cloned().unwrap_or(Value::Null);
+    let mut found = messages
+        .iter()
+        .filter(|message| matches(message, &filter))
+        .collect::<Vec<_>>();
+    // Messages are in acceptance order already, which is receivedAt ascending
+    let descending = arguments
+        .get("sort")
+        .and_then(|sort| sort.get(0))
+        .and_then(|comparator| comparator.get("isAscending"))
+        .and_then(Value::as_bool)
+        == Some(false);
+    if descending {
+        found.reverse();
+    }
+    let total = found.len();
+    let position = arguments
+        .get("position")
+        .and_then(Value::as_u64)
+        .map_or(0, |position| position as usize)
+        .min(total);
+    let limit = arguments
+        .get("limit")
+        .and_then(Value::as_u64)
+        .map_or(total, |limit| limit as usize);
+    let ids = found[position..]
+        .iter()
+        .take(limit)
+        .map(|message| message.id.as_str())
+        .collect::<Vec<_>>();
+    json!({
+        "accountId": ACCOUNT_ID,
+        "queryState": state,
+        "canCalculateChanges": false,
+        "position": position,
+        "ids": ids,
+        "total": total,
+    })
+}
+
+/// Answers `Email/get`, refusing to return more than [`MAX_OBJECTS_IN_GET`] emails
+fn get_emails(
+    messages: &[Message],
+    arguments: &Map<String, Value>,
+    state: &str,
+) -> Result<Value, MethodError> {
+    let ids = arguments.get("ids").and_then(Value::as_array);
+    if ids.map_or(messages.len(), Vec::len) > MAX_OBJECTS_IN_GET {
+        return Err(MethodError("requestTooLarge"));
+    }
+    let properties = arguments
+        .get("properties")
+        .and_then(Value::as_array)
+        .map(|properties| {
+            properties
+                .iter()
+                .filter_map(Value::as_str)
+                .collect::<Vec<_>>()
+        });
+    let select = |email: Value| match (&properties, email) {
+        (Some(properties), Value::Object(email)) => Value::Object(
+            email
+                .into_iter()
+                // The id is always returned
+                .filter(|(key, _)| key == "id" || properties.contains(&key.as_str()))
+                .collect(),
+        ),
+        (_, email) => email,
+    };
+    let (list, not_found) = match ids {
+        Some(ids) => {
+            let mut list = Vec::new();
+            let mut not_found = Vec::new();
+            for id in ids.iter().filter_map(Value::as_str) {
+                match messages.iter().find(|message| message.id.as_str() == id) {
+                    Some(message) => list.push(select(email(message))),
+                    None => not_found.push(id),
+                }
+            }
+            (list, not_found)
+        }
+        None => (
+            messages
+                .iter()
+                .map(|message| select(email(message)))
+                .collect(),
+            Vec::new(),
+        ),
+    };
+    Ok(json!({
+        "accountId": ACCOUNT_ID,
+        "state": state,
+        "list": list,
+        "notFound": not_found,
+    }))
+}
+
+/// Downloads a message in its original MIME form, the blob id being the message id
+async fn download(
+    State(forwarder): State<Arc<Forwarder>>,
+    Path((account, blob, _name)): Path<(String, String, String)>,
+) -> Response {
+    if account != ACCOUNT_ID {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    let records = match forwarder.wal().records().await {
+        Ok(records) => records,
+        Err(err) => {
+            tracing::error!("Cannot read the WAL: {err:#}");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    match graphql::messages(records)
+        .iter()
+        .find(|message| message.id.as_str() == blob)
+    {
+        Some(message) => ([(header::CONTENT_TYPE, "message/rfc822")], raw(message)).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::*;
+    use crate::priority::Priority;
+    use crate::protocol::Mail;
+    use crate::queue::QueueId;
+    use crate::wal::Record;
+
+    fn messages() -> Vec<Message> {
+        let record = |subject: &str| {
+            Record::Accepted {
+            id: QueueId::generate(),
+            at: Utc::now(),
+            mail: Mail {
+                from: "<a@example.com>".into(),
+                to: vec!["<b@example.com>".into()],
+                data: format!(
+                    "From: Ann <a@example.com>\r\nTo: b@example.com\r\nSubject: {subject}\r\n\r\nhello  there\r\n.\r\n"
+                )
+                .into(),
+            },
+            priority: Priority::Normal,
+            not_before: None,
+            tenant: None,
+            duplicate_of: None,
+        }
+        };
+        graphql::messages(vec![record("first"), record("second")])
+    }
+
+    #[test]
+    fn test_query_then_get() {
+        let messages = messages();
+        let call = |arguments: Value| match arguments {
+            Value::Object(arguments) => arguments,
+            _ => unreachable!(),
+        };
+        let queried = query(
+            &messages,
+            &call(json!({ "filter": { "subject": "SECOND" } })),
+            "1",
+        );
+        assert_eq!(queried["total"], 1);
+        let responses = vec![("Email/query".to_string(), queried, "c0".to_string())];
+        let arguments = resolve_references(
+            call(json!({
+                "#ids": { "resultOf": "c0", "name": "Email/query", "path": "/ids" },
+                "properties": ["subject", "from", "preview"],
+            })),
+            &responses,
+        )
+        .ok()
+        .unwrap();
+        let got = get_emails(&messages, &arguments, "1").ok().unwrap();
+        let email = &got["list"][0];
+        assert_eq!(email["id"], messages[1].id.as_str());
+        assert_eq!(email["subject"], "second");
+        assert_eq!(email["from"][0]["name"], "Ann");
+        assert_eq!(email["preview"], "hello there");
+        assert!(email.get("size").is_none());
+
+        assert!(resolve_references(
+            call(json!({ "#ids": { "resultOf": "c9", "name": "Email/query", "path": "/ids" } })),
+            &responses,
+        )
+        .is_err());
+
+        let ids = vec![Value::from(messages[0].id.as_str()); MAX_OBJECTS_IN_GET + 1];
+        let too_many = get_emails(&messages, &call(json!({ "ids": ids })), "1");
+        assert!(matches!(too_many, Err(MethodError("requestTooLarge"))));
+        assert_eq!(state(&messages), "2");
+    }
+}
@@ -0,0 +1,87 @@
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use rdkafka::config::ClientConfig;
+use rdkafka::message::{Header, OwnedHeaders};
+use rdkafka::producer::{FutureProducer, FutureRecord};
+
+use crate::format::Format;
+use crate::protocol::Mail;
+use crate::queue::QueueId;
+use crate::schema::Message;
+use crate::sink::{recipient_domain, DeliveryResult, MailSink};
+
+/// Kafka topic the messages are published to, in addition to the webhook
+pub struct KafkaSink {
+    producer: FutureProducer,
+    pub topic: String,
+    /// How the messages are encoded, `json`, `msgpack` or `protobuf`
+    pub format: Format,
+}
+
+impl KafkaSink {
+    /// Publishes to `topic` through `brokers`, the comma-separated bootstrap servers,
+    /// waiting for `acks` acknowledgements, `all`, `1` or `0`
+    pub fn new(
+        brokers: &str,
+        topic: impl Into<String>,
+        acks: &str,
+        format: Format,
+    ) -> Result<Self> {
+        anyhow::ensure!(
+            matches!(acks, "all" | "-1" | "0" | "1"),
+            "invalid kafka-acks {acks}, expected all, 1 or 0"
+        );
+        anyhow::ensure!(
+            matches!(
+                format,
+                Format::Json | Format::MessagePack | Format::Protobuf
+            ),
+            "kafka-format must be json, msgpack or protobuf"
+        );
+        let producer = ClientConfig::new()
+            .set("bootstrap.servers", brokers)
+            .set("acks", acks)
+            // Retried by the delivery workers, with the rest of the mail
+            .set("message.timeout.ms", "30000")
+            .create()
+            .context("cannot create Kafka producer")?;
+        Ok(Self {
+            producer,
+            topic: topic.into(),
+            format,
+        })
+    }
+}
+
+impl MailSink for KafkaSink {
+    fn endpoint(&self) -> String {
+        format!("kafka://{}", self.topic)
+    }
+
+    /// Publishes a message, keyed by its recipient domain so the mail of a domain
+    /// stays in order on one partition. The queue id is sent in the `id` header.
+    async fn deliver(&self, id: &QueueId, mail: &Mail, message: &Message) -> DeliveryResult {
+        let body = self.format.encode(id, mail, message, None)?;
+        let key = recipient_domain(mail);
+        let headers = OwnedHeaders::new()
+            .insert(Header {
+                key: "id",
+                value: Some(id.as_str()),
+            })
+            .insert(Header {
+                key: "content-type",
+                value: Some(body.content_type.as_str()),
+            });
+        let record = FutureRecord::to(&self.topic)
+            .key(&key)
+            .payload(&body.bytes)
+            .headers(headers);
+        self.producer
+            .send(record, Duration::from_secs(5))
+            .await
+            .map_err(|(err, _)| err)
+            .with_context(|| format!("cannot publish to Kafka topic {}", self.topic))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,89 @@
+use crate::schema::Content;
+
+/// Detects the language of the message body, preferring plain text
+/// parts over HTML ones. Returns the ISO 639-3 code of the language,
+/// or None when the text is too short or ambiguous to tell reliably.
+pub fn detect(content: &[Content]) -> Option<String> {
+    let text = body_text(content, "text/plain")
+        .or_else(|| body_text(content, "text/html").map(|html| strip_tags(&html)))?;
+    let info = whatlang::detect(&text)?;
+    if !info.is_reliable() {
+        tracing::debug!("Unreliable language guess {:?}", info.lang());
+        return None;
+    }
+    Some(info.lang().code().to_string())
+}
+
+/// Concatenates the values of all parts of the given mime type
+fn body_text(content: &[Content], mime: &str) -> Option<String> {
+    let text = content
+        .iter()
+        .filter(|part| {
+            part.mime
+                .as_deref()
+                .is_some_and(|part_mime| part_mime.eq_ignore_ascii_case(mime))
+        })
+        .filter_map(|part| part.value.as_deref())
+        .collect::<Vec<_>>()
+        .join("\n");
+    (!text.trim().is_empty()).then_some(text)
+}
+
+/// Crude tag removal, good enough to feed the detector
+fn strip_tags(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut in_tag = false;
+    for ch in html.chars() {
+        match ch {
+            '<' => in_tag = true,
+            '>' => {
+                in_tag = false;
+                text.push(' ');
+            }
+            ch if !in_tag => text.push(ch),
+            _ => {}
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn content(mime: &str, value: &str) -> Content {
+        Content {
+            mime: Some(mime.into()),
+            value: Some(value.into()),
+        }
+    }
+
+    #[test]
+    fn test_detect_plain_text() {
+        let parts = [
+            content(
+                "text/plain",
+                "Bonjour à tous, la réunion de demain est reportée à jeudi matin.",
+            ),
+            content(
+                "text/html",
+                "<p>Hello everyone, tomorrow's meeting is moved to Thursday.</p>",
+            ),
+        ];
+        assert_eq!(detect(&parts).as_deref(), Some("fra"));
+    }
+
+    #[test]
+    fn test_detect_html_only() {
+        let parts = [content(
+            "text/html",
+            "<p>Hallo zusammen, das Treffen morgen wird auf Donnerstag verschoben.</p>",
+        )];
+        assert_eq!(detect(&parts).as_deref(), Some("deu"));
+    }
+
+    #[test]
+    fn test_no_text() {
+        assert_eq!(detect(&[]), None);
+    }
+}
@@ -0,0 +1,128 @@
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::forward::Forwarder;
+
+/// Contents of the lease file
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Holder {
+    holder: String,
+    expires_at: DateTime<Utc>,
+}
+
+/// Lease on the delivery workers of a high-availability pair, kept in a file
+/// on the storage shared by both instances. Both accept mail, but only
+/// the instance holding the lease delivers it. The leader renews the lease
+/// three times per TTL, and the other instance takes over once it expires.
+#[derive(Clone, Debug)]
+pub struct Lease {
+    pub path: PathBuf,
+    /// Identifies this instance in the lease file
+    pub holder: String,
+    pub ttl: Duration,
+}
+
+impl Lease {
+    /// Takes or renews the lease, unless another instance holds it.
+    /// Returns whether this instance is the leader until `now` plus the TTL.
+    pub fn try_acquire(&self, now: DateTime<Utc>) -> Result<bool> {
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&self.path)
+            .with_context(|| format!("cannot open lease {}", self.path.display()))?;
+        // Released when the file is closed
+        file.lock()?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        let current = serde_json::from_str::<Holder>(&contents).ok();
+        let available = current
+            .as_ref()
+            .is_none_or(|current| current.holder == self.holder || current.expires_at <= now);
+        if available {
+            let lease = serde_json::to_string(&Holder {
+                holder: self.holder.clone(),
+                expires_at: now + self.ttl,
+            })?;
+            file.set_len(0)?;
+            file.seek(SeekFrom::Start(0))?;
+            file.write_all(lease.as_bytes())?;
+            file.sync_data()?;
+        }
+        Ok(available)
+    }
+
+    /// Competes for the lease for as long as the server runs, letting
+    /// the forwarder deliver mail only while holding it. Failing to reach
+    /// the lease file counts as losing it, as the peer may have taken over.
+    pub fn spawn(self, forwarder: Arc<Forwarder>) {
+        tokio::spawn(async move {
+            let period = (self.ttl / 3).to_std().unwrap_or_default();
+            let mut ticks = tokio::time::interval(period);
+            loop {
+                ticks.tick().await;
+                let lease = self.clone();
+                let leader = match tokio::task::spawn_blocking(move || {
+                    lease.try_acquire(Utc::now())
+                })
+                .await
+                {
+                    Ok(Ok(leader)) => leader,
+                    Ok(Err(err)) => {
+                        tracing::error!("Cannot renew the lease: {err:#}");
+                        false
+                    }
+                    Err(err) => {
+                        tracing::error!("Cannot renew the lease: {err}");
+                        false
+                    }
+                };
+                forwarder.set_leader(leader);
+                if leader {
+                    // Pick up the mail accepted by the peer
+                    if let Err(err) = forwarder.adopt_pending().await {
+                        tracing::error!("Cannot read pending mail: {err:#}");
+                    }
+                    if let Err(err) = forwarder.wal().compact().await {
+                        tracing::error!("Cannot compact the WAL: {err:#}");
+                    }
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::QueueId;
+
+    #[test]
+    fn test_failover() {
+        let path = std::env::temp_dir().join(format!("lease-test-{}", QueueId::generate()));
+        let lease = |holder: &str| Lease {
+            path: path.clone(),
+            holder: holder.into(),
+            ttl: Duration::seconds(15),
+        };
+        let (a, b) = (lease("a"), lease("b"));
+        let now = Utc::now();
+        assert!(a.try_acquire(now).unwrap());
+        assert!(!b.try_acquire(now).unwrap());
+        // Renewing pushes the expiry back
+        assert!(a.try_acquire(now + Duration::seconds(10)).unwrap());
+        assert!(!b.try_acquire(now + Duration::seconds(20)).unwrap());
+        // a stopped renewing
+        assert!(b.try_acquire(now + Duration::seconds(30)).unwrap());
+        assert!(!a.try_acquire(now + Duration::seconds(31)).unwrap());
+        std::fs::remove_file(path).unwrap();
+    }
+}
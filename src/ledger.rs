@@ -0,0 +1,177 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+use crate::queue::QueueId;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    /// Tenant the mail belongs to, none for the mail of no tenant
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tenant: Option<String>,
+    destination: String,
+    id: QueueId,
+    at: DateTime<Utc>,
+}
+
+/// What a delivery is recorded under: the tenant of the mail, the destination
+/// and the queue id of the mail
+type Key = (Option<String>, String, QueueId);
+
+/// Persistent record of the (tenant, destination, message) triples already delivered.
+/// Consulted before each attempt, so that replaying the WAL after a crash
+/// doesn't deliver a message twice to a destination that already has it.
+/// Tenants sharing a destination have their deliveries recorded apart.
+pub struct Ledger {
+    path: PathBuf,
+    delivered: Mutex<(HashSet<Key>, tokio::fs::File)>,
+}
+
+impl Ledger {
+    /// Opens the ledger at `path`, forgetting entries older than the retention
+    /// of their tenant and destination
+    pub async fn open(
+        path: impl AsRef<Path>,
+        retention: impl Fn(Option<&str>, &str) -> Duration,
+    ) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let entries = match tokio::fs::read_to_string(&path).await {
+            Ok(ledger) => ledger
+                .lines()
+                .filter_map(|line| serde_json::from_str::<Entry>(line).ok())
+                .collect(),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => {
+                return Err(err).with_context(|| format!("cannot read ledger {}", path.display()))
+            }
+        };
+        let now = Utc::now();
+        let entries = entries
+            .into_iter()
+            .filter(|entry| entry.at > now - retention(entry.tenant.as_deref(), &entry.destination))
+            .collect::<Vec<_>>();
+
+        let mut pruned = String::new();
+        for entry in &entries {
+            pruned += &serde_json::to_string(entry)?;
+            pruned.push('\n');
+        }
+        let tmp = path.with_extension("prune");
+        tokio::fs::write(&tmp, pruned).await?;
+        tokio::fs::rename(&tmp, &path)
+            .await
+            .with_context(|| format!("cannot prune ledger {}", path.display()))?;
+
+        let file = tokio::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .await?;
+        let delivered = entries
+            .into_iter()
+            .map(|entry| (entry.tenant, entry.destination, entry.id))
+            .collect();
+        Ok(Self {
+            path,
+            delivered: Mutex::new((delivered, file)),
+        })
+    }
+
+    /// Checks whether the message of the tenant was already delivered to the destination
+    pub async fn contains(&self, tenant: Option<&str>, destination: &str, id: &QueueId) -> bool {
+        self.delivered.lock().await.0.contains(&(
+            tenant.map(str::to_string),
+            destination.to_string(),
+            id.clone(),
+        ))
+    }
+
+    /// Records a successful delivery, waiting for it to reach the disk
+    pub async fn record(
+        &self,
+        tenant: Option<&str>,
+        destination: &str,
+        id: &QueueId,
+    ) -> Result<()> {
+        let entry = Entry {
+            tenant: tenant.map(str::to_string),
+            destination: destination.to_string(),
+            id: id.clone(),
+            at: Utc::now(),
+        };
+        let mut line = serde_json::to_string(&entry)?;
+        line.push('\n');
+        let mut delivered = self.delivered.lock().await;
+        let (delivered, file) = &mut *delivered;
+        file.write_all(line.as_bytes())
+            .await
+            .with_context(|| format!("cannot write ledger {}", self.path.display()))?;
+        file.sync_data().await?;
+        delivered.insert((entry.tenant, entry.destination, entry.id));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_survives_reopen() {
+        let path = std::env::temp_dir().join(format!("ledger-test-{}", QueueId::generate()));
+        let id = QueueId::generate();
+        {
+            let ledger = Ledger::open(&path, |_, _| Duration::days(1)).await.unwrap();
+            assert!(!ledger.contains(None, "https://a.example.com", &id).await);
+            ledger
+                .record(None, "https://a.example.com", &id)
+                .await
+                .unwrap();
+            assert!(ledger.contains(None, "https://a.example.com", &id).await);
+            ledger
+                .record(None, "https://c.example.com", &id)
+                .await
+                .unwrap();
+        }
+        let ledger = Ledger::open(&path, |_, _| Duration::days(1)).await.unwrap();
+        assert!(ledger.contains(None, "https://a.example.com", &id).await);
+        assert!(!ledger.contains(None, "https://b.example.com", &id).await);
+
+        let ledger = Ledger::open(&path, |_, destination| match destination {
+            "https://a.example.com" => Duration::zero(),
+            _ => Duration::days(1),
+        })
+        .await
+        .unwrap();
+        assert!(!ledger.contains(None, "https://a.example.com", &id).await);
+        assert!(ledger.contains(None, "https://c.example.com", &id).await);
+
+        // Tenants sharing a destination are told apart, down to their retention
+        ledger
+            .record(Some("acme"), "https://c.example.com", &id)
+            .await
+            .unwrap();
+        assert!(
+            !ledger
+                .contains(Some("other"), "https://c.example.com", &id)
+                .await
+        );
+        let ledger = Ledger::open(&path, |tenant, _| match tenant {
+            Some("acme") => Duration::zero(),
+            _ => Duration::days(1),
+        })
+        .await
+        .unwrap();
+        assert!(
+            !ledger
+                .contains(Some("acme"), "https://c.example.com", &id)
+                .await
+        );
+        assert!(ledger.contains(None, "https://c.example.com", &id).await);
+        std::fs::remove_file(path).unwrap();
+    }
+}
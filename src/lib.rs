@@ -1,2 +1,124 @@
+#[cfg(feature = "admin")]
+pub mod admin;
+#[cfg(feature = "relay")]
+pub mod alias;
+#[cfg(feature = "relay")]
+pub mod arc;
+#[cfg(feature = "forward")]
+pub mod archive;
+pub mod auth;
+pub mod authentication;
+#[cfg(feature = "aws")]
+pub mod aws;
+#[cfg(feature = "forward")]
+pub mod batch;
+#[cfg(feature = "forward")]
+pub mod chaos;
+#[cfg(feature = "dns")]
+pub mod checks;
+#[cfg(feature = "forward")]
+pub mod cleanup;
+#[cfg(feature = "cli")]
+pub mod config;
+#[cfg(feature = "sqlite")]
+pub mod database;
+#[cfg(feature = "forward")]
+pub mod deadletter;
+pub mod decode;
+#[cfg(feature = "forward")]
+pub mod dedup;
+#[cfg(any(feature = "relay", feature = "dns"))]
+pub mod dkim;
+#[cfg(feature = "dns")]
+pub mod dmarc;
+#[cfg(feature = "dns")]
+pub mod dns;
+#[cfg(feature = "dns")]
+pub mod dnsbl;
+pub mod domains;
+#[cfg(feature = "forward")]
+pub mod eml;
+pub mod error;
+#[cfg(feature = "forward")]
+pub mod format;
+#[cfg(feature = "forward")]
+pub mod forward;
+#[cfg(feature = "admin")]
+pub mod graphql;
+mod header;
+pub mod helo;
+#[cfg(feature = "dns")]
+pub mod iprev;
+#[cfg(feature = "admin")]
+pub mod jmap;
+#[cfg(feature = "kafka")]
+pub mod kafka;
+pub mod language;
+#[cfg(feature = "forward")]
+pub mod leader;
+#[cfg(feature = "forward")]
+pub mod ledger;
+#[cfg(feature = "forward")]
+pub mod loadgen;
+#[cfg(feature = "forward")]
+pub mod maildir;
+#[cfg(feature = "forward")]
+pub mod mbox;
+pub mod payload;
+pub mod priority;
+#[cfg(feature = "forward")]
+pub mod proto;
+pub mod protocol;
+#[cfg(feature = "pubsub")]
+pub mod pubsub;
+pub mod queue;
+#[cfg(feature = "server")]
+pub mod ratelimit;
+pub mod received;
+pub mod recipients;
+#[cfg(feature = "redis")]
+pub mod redis_stream;
+#[cfg(feature = "relay")]
+pub mod relay;
+#[cfg(feature = "forward")]
+pub mod replay;
+#[cfg(feature = "forward")]
+pub mod retry;
+pub mod rewrite;
+#[cfg(feature = "forward")]
+pub mod ring;
+#[cfg(feature = "rspamd")]
+pub mod rspamd;
+#[cfg(feature = "forward")]
+pub mod schedule;
 pub mod schema;
+#[cfg(feature = "server")]
+pub mod senders;
+#[cfg(feature = "forward")]
+pub mod sink;
+#[cfg(feature = "server")]
 pub mod smtp;
+#[cfg(feature = "client")]
+pub mod smtp_client;
+pub mod spam;
+#[cfg(feature = "dns")]
+pub mod spf;
+#[cfg(feature = "sqlite")]
+pub mod spool;
+#[cfg(feature = "relay")]
+pub mod srs;
+#[cfg(feature = "forward")]
+pub mod storage;
+#[cfg(feature = "server")]
+pub mod tarpit;
+#[cfg(feature = "forward")]
+pub mod tenant;
+pub mod thumbnail;
+#[cfg(any(feature = "rustls", feature = "native-tls"))]
+pub mod tls;
+pub mod tracking;
+pub mod urls;
+#[cfg(feature = "forward")]
+pub mod usage;
+#[cfg(feature = "forward")]
+pub mod wal;
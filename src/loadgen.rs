@@ -0,0 +1,248 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, ensure, Context, Result};
+use rand::Rng;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+use crate::ratelimit::RateLimiter;
+
+/// Arguments of the `loadgen` subcommand
+#[derive(Debug, PartialEq)]
+pub struct Args {
+    /// Address of the SMTP server under test
+    pub target: String,
+    /// Number of SMTP sessions open at once
+    pub sessions: usize,
+    /// Total number of messages to send
+    pub messages: usize,
+    /// Messages per second across all sessions, as fast as possible when unset
+    pub rate: Option<f64>,
+    /// Message body sizes in bytes, picked uniformly in this range
+    pub size: (usize, usize),
+}
+
+impl Default for Args {
+    fn default() -> Self {
+        Self {
+            target: "127.0.0.1:25".into(),
+            sessions: 10,
+            messages: 1000,
+            rate: None,
+            size: (1024, 16 * 1024),
+        }
+    }
+}
+
+impl Args {
+    /// Parses `loadgen [--target <host:port>] [--sessions <n>] [--messages <n>]
+    /// [--rate <per second>] [--size <bytes>|<min>-<max>]`,
+    /// `args` starting after the subcommand name
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self> {
+        let mut parsed = Self::default();
+        let mut args = args.into_iter();
+        while let Some(flag) = args.next() {
+            let value = args
+                .next()
+                .with_context(|| format!("{flag} needs a value"))?;
+            let invalid = || format!("invalid {flag} {value:?}");
+            match flag.as_str() {
+                "--target" => parsed.target = value,
+                "--sessions" => parsed.sessions = value.parse().with_context(invalid)?,
+                "--messages" => parsed.messages = value.parse().with_context(invalid)?,
+                "--rate" => parsed.rate = Some(value.parse().with_context(invalid)?),
+                "--size" => {
+                    parsed.size = match value.split_once('-') {
+                        Some((min, max)) => (
+                            min.parse().with_context(invalid)?,
+                            max.parse().with_context(invalid)?,
+                        ),
+                        None => {
+                            let size = value.parse().with_context(invalid)?;
+                            (size, size)
+                        }
+                    }
+                }
+                flag => bail!("unknown loadgen option {flag}"),
+            }
+        }
+        ensure!(parsed.sessions > 0, "--sessions must be at least 1");
+        ensure!(parsed.size.0 <= parsed.size.1, "--size range is reversed");
+        Ok(parsed)
+    }
+}
+
+/// Sends synthetic mail to an SMTP server from concurrent sessions,
+/// then reports the throughput and the latency percentiles of the sessions.
+pub async fn run(args: Args) -> Result<()> {
+    let args = Arc::new(args);
+    let limiter = args
+        .rate
+        .filter(|rate| *rate > 0.0)
+        .map(RateLimiter::new)
+        .transpose()?;
+    let limiter = Arc::new(limiter);
+    let remaining = Arc::new(AtomicUsize::new(args.messages));
+    let latencies = Arc::new(Mutex::new(Vec::with_capacity(args.messages)));
+    let failures = Arc::new(AtomicUsize::new(0));
+
+    let started = Instant::now();
+    let sessions = (0..args.sessions)
+        .map(|_| {
+            let (args, limiter, remaining, latencies, failures) = (
+                args.clone(),
+                limiter.clone(),
+                remaining.clone(),
+                latencies.clone(),
+                failures.clone(),
+            );
+            tokio::spawn(async move {
+                // Take messages until there are none left
+                while remaining
+                    .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+                    .is_ok()
+                {
+                    if let Some(limiter) = &*limiter {
+                        limiter.acquire().await;
+                    }
+                    let size = rand::thread_rng().gen_range(args.size.0..=args.size.1);
+                    let sent = Instant::now();
+                    match send(&args.target, size).await {
+                        Ok(()) => latencies.lock().unwrap().push(sent.elapsed()),
+                        Err(err) => {
+                            tracing::warn!("Session failed: {err:#}");
+                            failures.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for session in sessions {
+        session.await?;
+    }
+    let elapsed = started.elapsed();
+
+    let mut latencies = std::mem::take(&mut *latencies.lock().unwrap());
+    latencies.sort();
+    let failures = failures.load(Ordering::Relaxed);
+    println!(
+        "{} messages sent, {failures} failed in {:.2}s: {:.1} messages/s",
+        latencies.len(),
+        elapsed.as_secs_f64(),
+        latencies.len() as f64 / elapsed.as_secs_f64()
+    );
+    for p in [50, 90, 99] {
+        if let Some(latency) = percentile(&latencies, p) {
+            println!("p{p}: {:.1}ms", latency.as_secs_f64() * 1000.0);
+        }
+    }
+    if let Some(max) = latencies.last() {
+        println!("max: {:.1}ms", max.as_secs_f64() * 1000.0);
+    }
+    Ok(())
+}
+
+/// Nearest-rank percentile of sorted latencies
+fn percentile(sorted: &[Duration], p: usize) -> Option<Duration> {
+    let rank = (sorted.len() * p).div_ceil(100);
+    sorted.get(rank.saturating_sub(1)).copied()
+}
+
+/// Runs a whole SMTP session delivering one message with a body of `size` bytes
+async fn send(target: &str, size: usize) -> Result<()> {
+    let stream = TcpStream::connect(target)
+        .await
+        .with_context(|| format!("cannot connect to {target}"))?;
+    let (read, mut write) = stream.into_split();
+    let mut read = BufReader::new(read);
+
+    expect(&mut read, "220").await?;
+    for (command, reply) in [
+        ("HELO loadgen.localhost\r\n", "250"),
+        ("MAIL FROM:<loadgen@loadgen.localhost>\r\n", "250"),
+        ("RCPT TO:<sink@loadgen.localhost>\r\n", "250"),
+        ("DATA\r\n", "354"),
+    ] {
+        write.write_all(command.as_bytes()).await?;
+        expect(&mut read, reply).await?;
+    }
+    write.write_all(message(size).as_bytes()).await?;
+    expect(&mut read, "250").await?;
+    write.write_all(b"QUIT\r\n").await?;
+    expect(&mut read, "221").await?;
+    Ok(())
+}
+
+/// Reads a possibly multiline reply, checking its code
+async fn expect(read: &mut BufReader<tokio::net::tcp::OwnedReadHalf>, code: &str) -> Result<()> {
+    loop {
+        let mut line = String::new();
+        ensure!(
+            read.read_line(&mut line).await? > 0,
+            "connection closed while waiting for {code}"
+        );
+        ensure!(line.starts_with(code), "expected {code}, got {line:?}");
+        // `250-` continues a multiline reply, `250 ` ends it
+        if line.as_bytes().get(3) != Some(&b'-') {
+            return Ok(());
+        }
+    }
+}
+
+/// Synthetic message with a body of `size` bytes, terminated for DATA
+fn message(size: usize) -> String {
+    let mut message = String::from(
+        "From: Load Generator <loadgen@loadgen.localhost>\r\n\
+         To: <sink@loadgen.localhost>\r\n\
+         Subject: Load test\r\n\r\n",
+    );
+    let line = "The quick brown fox jumps over the lazy dog. ";
+    let mut body = line.repeat(size / line.len() + 1);
+    body.truncate(size);
+    for chunk in body.as_bytes().chunks(76) {
+        let chunk = std::str::from_utf8(chunk).unwrap();
+        // Dot-stuffing, so that a line can't end the data early
+        if chunk.starts_with('.') {
+            message.push('.');
+        }
+        message += chunk;
+        message += "\r\n";
+    }
+    message += ".\r\n";
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_args() {
+        let args = Args::parse(
+            ["--sessions", "50", "--rate", "200", "--size", "100-2000"].map(String::from),
+        )
+        .unwrap();
+        assert_eq!(
+            args,
+            Args {
+                sessions: 50,
+                rate: Some(200.0),
+                size: (100, 2000),
+                ..Args::default()
+            }
+        );
+        assert!(Args::parse(["--size".to_string(), "9-1".to_string()]).is_err());
+        assert!(Args::parse(["--sessions".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_percentile() {
+        let latencies = (1..=100).map(Duration::from_millis).collect::<Vec<_>>();
+        assert_eq!(percentile(&latencies, 50), Some(Duration::from_millis(50)));
+        assert_eq!(percentile(&latencies, 99), Some(Duration::from_millis(99)));
+        assert_eq!(percentile(&[], 50), None);
+    }
+}
@@ -0,0 +1,162 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use tokio::io::AsyncWriteExt;
+
+use crate::protocol::Mail;
+use crate::queue::QueueId;
+use crate::schema::Message;
+use crate::sink::{DeliveryResult, MailSink};
+
+/// Maildir the messages are delivered to, for mail clients and IMAP servers
+/// such as mutt or Dovecot to read, or as a local archive
+#[derive(Clone, Debug)]
+pub struct Maildir {
+    dir: PathBuf,
+    /// Host name in the file names, with the characters Maildir reserves escaped
+    host: String,
+}
+
+impl Maildir {
+    /// Opens the Maildir at `dir`, creating it with its `tmp`, `new` and `cur`
+    /// subdirectories if needed. `host` is the host name in the file names.
+    pub async fn open(dir: impl AsRef<Path>, host: &str) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        for subdir in ["tmp", "new", "cur"] {
+            tokio::fs::create_dir_all(dir.join(subdir))
+                .await
+                .with_context(|| format!("cannot create Maildir {}", dir.display()))?;
+        }
+        Ok(Self {
+            dir,
+            host: host.replace('/', "\\057").replace(':', "\\072"),
+        })
+    }
+
+    /// Writes a message to `tmp`, then moves it to `new` once it is on disk,
+    /// returning its path. The Maildir is fsynced too, so the message is still
+    /// there after a crash. Lines end with LF, as mail clients expect of Maildirs,
+    /// and the envelope sender is in `Return-Path`.
+    pub async fn add(&self, mail: &Mail) -> Result<PathBuf> {
+        let name = self.unique_name();
+        let tmp = self.dir.join("tmp").join(&name);
+        let new = self.dir.join("new").join(&name);
+        let mut data = format!("Return-Path: {}\n", mail.from).into_bytes();
+        data.extend(to_lf(&mail.data));
+        let write = async {
+            let mut file = tokio::fs::File::create(&tmp).await?;
+            file.write_all(&data).await?;
+            file.sync_all().await?;
+            tokio::fs::rename(&tmp, &new).await?;
+            tokio::fs::File::open(self.dir.join("new"))
+                .await?
+                .sync_all()
+                .await
+        };
+        if let Err(err) = write.await {
+            tokio::fs::remove_file(&tmp).await.ok();
+            return Err(err).with_context(|| format!("cannot deliver to {}", new.display()));
+        }
+        Ok(new)
+    }
+
+    /// Deletes the messages of `new` and `cur` last written before `before`,
+    /// returning how many
+    pub async fn remove_older(&self, before: DateTime<Utc>) -> Result<usize> {
+        let mut removed = 0;
+        for subdir in ["new", "cur"] {
+            let dir = self.dir.join(subdir);
+            let mut entries = tokio::fs::read_dir(&dir)
+                .await
+                .with_context(|| format!("cannot read {}", dir.display()))?;
+            while let Some(entry) = entries.next_entry().await? {
+                let metadata = entry.metadata().await?;
+                if metadata.is_file() && DateTime::<Utc>::from(metadata.modified()?) < before {
+                    let path = entry.path();
+                    tokio::fs::remove_file(&path)
+                        .await
+                        .with_context(|| format!("cannot remove {}", path.display()))?;
+                    removed += 1;
+                }
+            }
+        }
+        Ok(removed)
+    }
+
+    /// A file name no other delivery uses: the time, the process id and a
+    /// per-process counter, then the host name
+    fn unique_name(&self) -> String {
+        static COUNTER: AtomicU32 = AtomicU32::new(0);
+        let now = Utc::now();
+        format!(
+            "{}.M{}P{}Q{}.{}",
+            now.timestamp(),
+            now.timestamp_subsec_micros(),
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed),
+            self.host
+        )
+    }
+}
+
+impl MailSink for Maildir {
+    fn endpoint(&self) -> String {
+        format!("maildir://{}", self.dir.display())
+    }
+
+    async fn deliver(&self, id: &QueueId, mail: &Mail, _: &Message) -> DeliveryResult {
+        let path = self.add(mail).await?;
+        tracing::debug!("Delivered mail {id} to {}", path.display());
+        Ok(())
+    }
+}
+
+/// The message with its CRLF line endings turned into LF
+pub(crate) fn to_lf(data: &[u8]) -> Vec<u8> {
+    let mut lf = Vec::with_capacity(data.len());
+    for (index, &byte) in data.iter().enumerate() {
+        if byte == b'\r' && data.get(index + 1) == Some(&b'\n') {
+            continue;
+        }
+        lf.push(byte);
+    }
+    lf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_lf() {
+        assert_eq!(to_lf(b"a\r\nb\rc\r\n"), b"a\nb\rc\n");
+    }
+
+    #[tokio::test]
+    async fn test_add() {
+        let dir = std::env::temp_dir().join(format!("maildir-test-{}", QueueId::generate()));
+        let maildir = Maildir::open(&dir, "localhost").await.unwrap();
+        let mail = Mail {
+            from: "<a@example.com>".into(),
+            to: vec!["<b@example.com>".into()],
+            data: b"Subject: hi\r\n\r\nhello\r\n".to_vec(),
+        };
+        let first = maildir.add(&mail).await.unwrap();
+        let second = maildir.add(&mail).await.unwrap();
+        assert_ne!(first, second);
+        assert_eq!(first.parent().unwrap(), dir.join("new"));
+        assert_eq!(
+            std::fs::read(&first).unwrap(),
+            b"Return-Path: <a@example.com>\nSubject: hi\n\nhello\n"
+        );
+        assert_eq!(std::fs::read_dir(dir.join("tmp")).unwrap().count(), 0);
+
+        let hour = chrono::Duration::hours(1);
+        assert_eq!(maildir.remove_older(Utc::now() - hour).await.unwrap(), 0);
+        assert_eq!(maildir.remove_older(Utc::now() + hour).await.unwrap(), 2);
+        assert!(!first.exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,39 +1,514 @@
 use anyhow::{Context, Result};
-use tokio::net::TcpListener;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::os::unix::fs::PermissionsExt;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream, UnixListener};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::Semaphore;
 
-use std::env;
-
-use smtp_forward::smtp;
-
-/// A helper function for cleaning up old mail from the database
+#[cfg(feature = "relay")]
+use smtp_forward::alias::Aliases;
+use smtp_forward::config::{Command, Config};
+use smtp_forward::deadletter::{self, DeadLetters};
+use smtp_forward::domains::Domains;
+use smtp_forward::error::SmtpError;
+use smtp_forward::forward::Forwarder;
+use smtp_forward::helo::HeloPolicy;
+use smtp_forward::ledger::Ledger;
+use smtp_forward::payload;
+use smtp_forward::protocol::{Reply, StateMachine};
+use smtp_forward::ratelimit::{ClientRates, ClientSessions};
+use smtp_forward::received::ReceivedMail;
+use smtp_forward::recipients::Recipients;
+use smtp_forward::senders::Senders;
+use smtp_forward::tarpit::Tarpit;
+use smtp_forward::wal::Wal;
+use smtp_forward::{admin, auth, loadgen, replay, smtp};
 
 #[tokio::main]
 async fn main() -> Result<()> {
     tracing_subscriber::fmt::init();
 
-    let addr = format!(
-        "0.0.0.0:{}",
-        std::env::var("PORT").unwrap_or_else(|_| "25".into())
-    );
+    let (command, config) = Config::load()?;
+    match command {
+        Some(Command::Loadgen { args }) => return loadgen::run(loadgen::Args::parse(args)?).await,
+        Some(Command::Replay { args }) => {
+            let args = replay::Args::parse(args)?;
+            let destination = match &args.destination {
+                Some(url) => config.destination_for(url)?,
+                None => config.destination()?,
+            };
+            return replay::run(args, destination, &config.payload_options()?).await;
+        }
+        Some(Command::DeadLetters { args }) => {
+            let args = deadletter::Args::parse(args)?;
+            // Routed as the server routes mail, to the tenants and every fanout endpoint
+            let destinations = match config.forward_url {
+                Some(_) => [vec![config.destination()?], config.fanout()?].concat(),
+                None => Vec::new(),
+            };
+            let tenants = config.tenants()?;
+            return deadletter::run(args, config.dead_letter_dir(), destinations, &tenants).await;
+        }
+        Some(Command::Cleanup) => {
+            let cleanup = config
+                .cleanup()
+                .await?
+                .context("retention-days (RETENTION_DAYS) is not set")?;
+            let removed = cleanup.run(chrono::Utc::now()).await?;
+            tracing::info!("Removed {removed} messages past their retention");
+            return Ok(());
+        }
+        #[cfg(feature = "sqlite")]
+        Some(Command::Export { args }) => {
+            let database = config.database().await?.context(
+                "database-path (DATABASE_PATH) is not set, there is no stored mail to export",
+            )?;
+            let args = smtp_forward::mbox::Args::parse(args)?;
+            return smtp_forward::mbox::export(args, &database).await;
+        }
+        None => {}
+    }
+    config.validate()?;
+    let domain = config.domain().to_string();
+
+    let wal_path = config.wal_path();
+    // A high-availability pair shares the WAL, and elects which instance delivers
+    let lease = config.lease()?;
+    let (wal, pending) = match lease {
+        Some(_) => (Wal::open_shared(wal_path).await?, Vec::new()),
+        None => Wal::open(wal_path).await?,
+    };
+    // The server records the HELO name of each mail in the `Received` field it adds
+    let tenants = config
+        .tenants()?
+        .into_iter()
+        .map(|mut tenant| {
+            tenant.payload_options.received_by = Some(domain.clone());
+            tenant
+        })
+        .collect::<Vec<_>>();
+    let payload_options = payload::Options {
+        received_by: Some(domain.clone()),
+        ..config.payload_options()?
+    };
+    // Inbound mail is checked as it is received, and the results reported in the payload
+    #[cfg(feature = "dns")]
+    let checks = config.checks()?.map(Arc::new);
+    #[cfg(feature = "dns")]
+    let (tenants, payload_options) = {
+        let authserv_id = checks.as_ref().map(|checks| checks.authserv_id.clone());
+        let tenants = tenants
+            .into_iter()
+            .map(|mut tenant| {
+                tenant.payload_options.authserv_id = authserv_id.clone();
+                tenant
+            })
+            .collect::<Vec<_>>();
+        let payload_options = payload::Options {
+            authserv_id,
+            ..payload_options
+        };
+        (tenants, payload_options)
+    };
+    // Inbound mail is scanned for spam as it is received, and its score reported in the payload
+    #[cfg(feature = "rspamd")]
+    let rspamd = config.rspamd().map(Arc::new);
+    #[cfg(feature = "rspamd")]
+    let (tenants, payload_options) = {
+        let spam_score = rspamd.is_some();
+        let tenants = tenants
+            .into_iter()
+            .map(|mut tenant| {
+                tenant.payload_options.spam_score = spam_score;
+                tenant
+            })
+            .collect::<Vec<_>>();
+        let payload_options = payload::Options {
+            spam_score,
+            ..payload_options
+        };
+        (tenants, payload_options)
+    };
+    let retention = tenants
+        .iter()
+        .filter_map(|tenant| Some((tenant.name.clone(), tenant.retention?)))
+        .collect::<HashMap<_, _>>();
+    let ledger = Ledger::open(config.ledger_path(), |tenant, _| {
+        tenant
+            .and_then(|tenant| retention.get(tenant))
+            .copied()
+            .unwrap_or_else(|| config.ledger_retention())
+    })
+    .await?;
+    // Counted by the SMTP sessions, reported in the admin API
+    let senders = config.senders()?.map(Arc::new);
+    let forwarder = Forwarder::new(
+        config.destination()?,
+        payload_options,
+        config.priority(),
+        config.schedule()?,
+        wal,
+        ledger,
+        config.chaos()?,
+    )?
+    .with_sync_delivery(config.sync_delivery.unwrap_or(false))
+    .with_object_store(config.object_store()?)
+    .with_message_ids(config.message_ids().await?)
+    .with_batching(config.batching())
+    .with_tenants(tenants)?
+    .with_retry(config.retry()?)
+    .with_dead_letters(DeadLetters::open(config.dead_letter_dir()).await?)
+    .with_sinks(config.sinks().await?)
+    .with_senders(senders.clone());
+    #[cfg(feature = "sqlite")]
+    let forwarder = forwarder.with_spool(config.spool().await?);
+    let forwarder = Arc::new(forwarder);
 
-    let domain = &std::env::var("DOMAIN").unwrap_or_else(|_| "smtp.deepwith.in".into());
+    // Retry whatever was accepted but not delivered before the last shutdown
+    for entry in pending {
+        forwarder.requeue(entry);
+    }
+    // and whatever the spool kept that the WAL lost; a pair shares the WAL instead
+    #[cfg(feature = "sqlite")]
+    if lease.is_none() {
+        forwarder.requeue_spooled().await?;
+    }
+    if let Some(lease) = lease {
+        forwarder.set_leader(false);
+        lease.spawn(forwarder.clone());
+    }
+    forwarder.spawn_workers();
+    config.usage()?.spawn(forwarder.usage().clone());
+    if let Some(cleanup) = config.cleanup().await? {
+        cleanup.spawn();
+    }
 
     tracing::info!("edgemail server for {domain} started");
 
-    let listener = TcpListener::bind(&addr).await?;
-    tracing::info!("Listening on: {}", addr);
+    if let Some(addr) = config.admin_addr {
+        let token = config
+            .admin_token
+            .clone()
+            .context("admin-token (ADMIN_TOKEN) is required by admin-addr")?;
+        let forwarder = forwarder.clone();
+        tokio::spawn(async move {
+            if let Err(err) = admin::serve(admin::Config { addr, token }, forwarder).await {
+                tracing::error!("Admin API failed: {err:#}");
+            }
+        });
+    }
+
+    let settings = config.smtp();
+    // The recipient rules are read again on SIGHUP, the sessions picking them up at once
+    let recipients = config.recipients()?;
+    {
+        let recipients = recipients.clone();
+        let mut hangups = signal(SignalKind::hangup())?;
+        tokio::spawn(async move {
+            while hangups.recv().await.is_some() {
+                if let Err(err) = recipients.reload() {
+                    tracing::error!("Cannot reload the recipient rules: {err:#}");
+                }
+            }
+        });
+    }
 
-    // Main loop: accept connections and spawn a task to handle them
-    loop {
-        let (stream, addr) = listener.accept().await?;
-        tracing::info!("Accepted a connection from {}", addr);
+    #[cfg(feature = "relay")]
+    let aliases = config.aliases()?.map(Arc::new);
 
-        tokio::task::LocalSet::new()
-            .run_until(async move {
-                let smtp = smtp::Server::new(domain, stream).await?;
-                smtp.serve().await
-            })
-            .await
-            .ok();
+    // Local MTAs can hand mail off over a Unix socket, speaking SMTP or LMTP
+    if let Some(path) = &config.unix_socket {
+        // A socket left over by a previous run would make bind fail
+        std::fs::remove_file(path).ok();
+        let listener = UnixListener::bind(path)
+            .with_context(|| format!("cannot listen on Unix socket {}", path.display()))?;
+        let mode = config.unix_socket_mode()?;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+        tracing::info!("Listening on: {}", path.display());
+
+        let (settings, forwarder) = (settings.clone(), forwarder.clone());
+        let recipients = recipients.clone();
+        #[cfg(feature = "relay")]
+        let aliases = aliases.clone();
+        #[cfg(feature = "dns")]
+        let authserv_id = checks.as_ref().map(|checks| checks.authserv_id.clone());
+        tokio::spawn(async move {
+            loop {
+                let stream = match listener.accept().await {
+                    Ok((stream, _)) => stream,
+                    Err(err) => {
+                        tracing::error!("Cannot accept on Unix socket: {err}");
+                        continue;
+                    }
+                };
+                tracing::info!("Accepted a local connection");
+                // Each in its own task, so one slow MTA doesn't hold up the others
+                let (settings, forwarder) = (settings.clone(), forwarder.clone());
+                let recipients = recipients.clone();
+                #[cfg(feature = "relay")]
+                let aliases = aliases.clone();
+                #[cfg(feature = "dns")]
+                let authserv_id = authserv_id.clone();
+                tokio::spawn(async move {
+                    let session = async {
+                        let smtp = smtp::Server::new(&settings, stream, forwarder)
+                            .await?
+                            .with_recipients(recipients);
+                        #[cfg(feature = "relay")]
+                        let smtp = match aliases {
+                            Some(aliases) => smtp.with_aliases(aliases),
+                            None => smtp,
+                        };
+                        // Not checked, but still kept from passing results off as ours
+                        #[cfg(feature = "dns")]
+                        let smtp = match authserv_id {
+                            Some(authserv_id) => smtp.with_authserv_id(authserv_id),
+                            None => smtp,
+                        };
+                        smtp.serve().await
+                    };
+                    if let Err(err) = session.await {
+                        tracing::warn!("Local session failed: {err}");
+                    }
+                });
+            }
+        });
+    }
+
+    let sessions = Sessions {
+        connections: Arc::new(Semaphore::new(config.max_connections())),
+        client_sessions: config
+            .max_connections_per_ip
+            .map(|max| Arc::new(ClientSessions::new(max))),
+        settings,
+        forwarder: forwarder.clone(),
+        users: config.users()?.map(Arc::new),
+        tokens: config.tokens()?.map(Arc::new),
+        helo_policy: config.helo_policy(),
+        domains: config.domains(),
+        recipients,
+        #[cfg(feature = "relay")]
+        aliases,
+        senders,
+        tarpit: config.tarpit(),
+        rates: config
+            .client_limits()?
+            .map(|limits| Arc::new(ClientRates::new(limits))),
+        #[cfg(feature = "dns")]
+        checks,
+        #[cfg(feature = "rspamd")]
+        rspamd,
+        #[cfg(any(feature = "rustls", feature = "native-tls"))]
+        tls: config
+            .tls()?
+            .map(|(cert, key)| smtp_forward::tls::acceptor(cert, key))
+            .transpose()?,
+    };
+
+    // Implicit TLS (SMTPS, usually on port 465): the handshake comes before the greeting
+    #[cfg(any(feature = "rustls", feature = "native-tls"))]
+    if let Some(port) = config.smtps_port {
+        anyhow::ensure!(
+            sessions.tls.is_some(),
+            "smtps-port requires tls-cert and tls-key"
+        );
+        sessions.listen(port, Mode::ImplicitTls).await?;
+    }
+    // Submission (usually on port 587): a relay entry point for authenticated clients
+    if let Some(port) = config.submission_port {
+        anyhow::ensure!(
+            sessions.users.is_some() || sessions.tokens.is_some(),
+            "submission-port requires auth-users or XOAUTH2 tokens"
+        );
+        sessions.listen(port, Mode::Submission).await?;
+    }
+
+    // With the port off, only the implicit TLS or submission listeners run
+    if let Some(port) = config.port() {
+        sessions.listen(port, Mode::Plain).await?;
+    }
+    // Serve the listeners until the process is stopped
+    std::future::pending().await
+}
+
+/// How a TCP listener runs its sessions
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Mode {
+    /// Receiving mail as an MX, offering STARTTLS
+    Plain,
+    /// SMTPS, TLS from the first byte
+    #[cfg(any(feature = "rustls", feature = "native-tls"))]
+    ImplicitTls,
+    /// Message submission, taking mail only from authenticated clients
+    Submission,
+}
+
+/// What the TCP listeners share to set up their sessions
+#[derive(Clone)]
+struct Sessions {
+    /// Sessions running on all the listeners, up to `MAX_CONNECTIONS`
+    connections: Arc<Semaphore>,
+    /// Sessions running for each client address, up to `MAX_CONNECTIONS_PER_IP`
+    client_sessions: Option<Arc<ClientSessions>>,
+    settings: smtp::Settings,
+    forwarder: Arc<Forwarder>,
+    users: Option<Arc<auth::Users>>,
+    tokens: Option<Arc<auth::Tokens>>,
+    helo_policy: Option<HeloPolicy>,
+    /// Domains recipients are accepted for, any unless configured
+    domains: Option<Domains>,
+    /// Rules the recipients are accepted by, reloaded on SIGHUP
+    recipients: Arc<Recipients>,
+    /// Addresses recipients are taken for, if configured
+    #[cfg(feature = "relay")]
+    aliases: Option<Arc<Aliases>>,
+    /// Rates of the clients, by address
+    rates: Option<Arc<ClientRates>>,
+    /// Quotas of the sender domains
+    senders: Option<Arc<Senders>>,
+    /// Delays the replies to suspicious clients
+    tarpit: Option<Tarpit>,
+    #[cfg(feature = "dns")]
+    checks: Option<Arc<smtp_forward::checks::Checks>>,
+    #[cfg(feature = "rspamd")]
+    rspamd: Option<Arc<smtp_forward::rspamd::Rspamd>>,
+    #[cfg(any(feature = "rustls", feature = "native-tls"))]
+    tls: Option<smtp_forward::tls::TlsAcceptor>,
+}
+
+impl Sessions {
+    /// Runs a session with a client connected to a listener in `mode`
+    async fn serve(
+        self,
+        stream: TcpStream,
+        addr: SocketAddr,
+        mode: Mode,
+    ) -> Result<Vec<ReceivedMail>, SmtpError> {
+        let smtp = smtp::Server::new(&self.settings, stream, self.forwarder)
+            .await?
+            .with_peer(addr);
+        let smtp = match self.users {
+            Some(users) => smtp.with_users(users),
+            None => smtp,
+        };
+        let smtp = match self.tokens {
+            Some(tokens) => smtp.with_tokens(tokens),
+            None => smtp,
+        };
+        let smtp = match mode {
+            Mode::Submission => smtp.requiring_auth(),
+            _ => smtp,
+        };
+        let smtp = match self.helo_policy {
+            Some(policy) => smtp.with_helo_policy(policy),
+            None => smtp,
+        };
+        let smtp = match self.domains {
+            Some(domains) => smtp.with_domains(domains),
+            None => smtp,
+        };
+        let smtp = smtp.with_recipients(self.recipients);
+        #[cfg(feature = "relay")]
+        let smtp = match self.aliases {
+            Some(aliases) => smtp.with_aliases(aliases),
+            None => smtp,
+        };
+        let smtp = match self.rates {
+            Some(rates) => smtp.with_rates(rates),
+            None => smtp,
+        };
+        let smtp = match self.senders {
+            Some(senders) => smtp.with_senders(senders),
+            None => smtp,
+        };
+        let smtp = match self.tarpit {
+            Some(tarpit) => smtp.with_tarpit(tarpit),
+            None => smtp,
+        };
+        // Submission clients are our users, not other servers
+        #[cfg(feature = "dns")]
+        let smtp = match self.checks {
+            Some(checks) if mode != Mode::Submission => smtp.with_checks(checks),
+            // Still kept from passing results off as ours
+            Some(checks) => smtp.with_authserv_id(&checks.authserv_id),
+            None => smtp,
+        };
+        #[cfg(feature = "rspamd")]
+        let smtp = match self.rspamd {
+            Some(rspamd) => smtp.with_rspamd(rspamd),
+            None => smtp,
+        };
+        #[cfg(any(feature = "rustls", feature = "native-tls"))]
+        let smtp = match (mode, self.tls) {
+            (Mode::ImplicitTls, Some(tls)) => smtp.with_implicit_tls(tls).await?,
+            (_, Some(tls)) => smtp.with_tls(tls),
+            (_, None) => smtp,
+        };
+        smtp.serve().await
     }
+
+    /// Listens on `port` in the background, each session in a task of its own
+    /// so a stalled client or TLS handshake doesn't hold up the others.
+    /// Clients beyond the connection limit are turned away with a 421.
+    async fn listen(&self, port: u16, mode: Mode) -> Result<()> {
+        let addr = format!("0.0.0.0:{port}");
+        let listener = TcpListener::bind(&addr).await?;
+        tracing::info!("Listening for {mode:?} on: {addr}");
+        let sessions = self.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, addr) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(err) => {
+                        tracing::error!("Cannot accept on {mode:?} listener: {err}");
+                        continue;
+                    }
+                };
+                let Ok(permit) = sessions.connections.clone().try_acquire_owned() else {
+                    tracing::warn!("Too many connections, turning away {addr}");
+                    tokio::spawn(turn_away(stream, StateMachine::TOO_BUSY));
+                    continue;
+                };
+                let client_permit = match &sessions.client_sessions {
+                    Some(client_sessions) => match client_sessions.acquire(addr.ip()) {
+                        Some(permit) => Some(permit),
+                        None => {
+                            tracing::warn!(
+                                "Too many connections from {}, turning it away",
+                                addr.ip()
+                            );
+                            tokio::spawn(turn_away(stream, StateMachine::COME_BACK_LATER));
+                            continue;
+                        }
+                    },
+                    None => None,
+                };
+                tracing::info!("Accepted a {mode:?} connection from {}", addr);
+                let sessions = sessions.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = sessions.serve(stream, addr, mode).await {
+                        tracing::warn!("{mode:?} session failed: {err}");
+                    }
+                    drop(permit);
+                    drop(client_permit);
+                });
+            }
+        });
+        Ok(())
+    }
+}
+
+/// Tells a client why it is turned away, e.g. the server being too busy, and hangs up
+async fn turn_away(mut stream: TcpStream, reply: Reply) {
+    // Bounded, so a client not reading can't hold on to the socket
+    let busy = async {
+        stream.write_all(reply.as_bytes()).await?;
+        stream.shutdown().await
+    };
+    tokio::time::timeout(std::time::Duration::from_secs(10), busy)
+        .await
+        .ok();
 }
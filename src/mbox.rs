@@ -0,0 +1,218 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+use crate::maildir::to_lf;
+use crate::protocol::Mail;
+use crate::queue::QueueId;
+use crate::schema::Message;
+use crate::sink::{DeliveryResult, MailSink};
+
+/// mbox file the messages are appended to, in the mboxrd variant:
+/// lines starting with `From `, after any number of `>`, get one more `>`,
+/// so readers can tell them from the separators and undo the quoting
+pub struct Mbox {
+    path: PathBuf,
+    /// Held while appending, so messages don't interleave
+    file: Mutex<tokio::fs::File>,
+}
+
+impl Mbox {
+    /// Opens the mbox at `path` for appending, creating it if needed
+    pub async fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await
+            .with_context(|| format!("cannot open mbox {}", path.display()))?;
+        Ok(Self {
+            path,
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Appends a message received at `at`, waiting for it to reach the disk
+    pub async fn append(&self, mail: &Mail, at: DateTime<Utc>) -> Result<()> {
+        let entry = entry(mail, at);
+        let mut file = self.file.lock().await;
+        file.write_all(&entry)
+            .await
+            .with_context(|| format!("cannot append to {}", self.path.display()))?;
+        file.sync_data().await?;
+        Ok(())
+    }
+}
+
+impl MailSink for Mbox {
+    fn endpoint(&self) -> String {
+        format!("mbox://{}", self.path.display())
+    }
+
+    async fn deliver(&self, _: &QueueId, mail: &Mail, _: &Message) -> DeliveryResult {
+        self.append(mail, Utc::now()).await
+    }
+}
+
+/// A message as an mboxrd entry: the `From ` separator with the envelope sender
+/// and the date, the quoted message with LF line endings, and a blank line
+pub fn entry(mail: &Mail, at: DateTime<Utc>) -> Vec<u8> {
+    let sender = mail.from.trim_matches(|c| c == '<' || c == '>');
+    let sender = match sender {
+        "" => "MAILER-DAEMON",
+        sender => sender,
+    };
+    let mut entry = format!("From {sender} {}\n", at.format("%a %b %e %H:%M:%S %Y")).into_bytes();
+    let data = to_lf(&mail.data);
+    for line in data.split_inclusive(|&byte| byte == b'\n') {
+        let quotes = line.iter().take_while(|&&byte| byte == b'>').count();
+        if line[quotes..].starts_with(b"From ") {
+            entry.push(b'>');
+        }
+        entry.extend_from_slice(line);
+    }
+    if !entry.ends_with(b"\n") {
+        entry.push(b'\n');
+    }
+    entry.push(b'\n');
+    entry
+}
+
+/// Arguments of the `export` subcommand
+#[cfg(feature = "sqlite")]
+#[derive(Debug, Default, PartialEq)]
+pub struct Args {
+    /// Only the mail with this envelope recipient, case-insensitively
+    pub recipient: Option<String>,
+    /// Only the mail stored on this day or later
+    pub since: Option<chrono::NaiveDate>,
+    /// Only the mail stored on this day or earlier
+    pub until: Option<chrono::NaiveDate>,
+    /// mbox file the mail is appended to
+    pub path: PathBuf,
+}
+
+#[cfg(feature = "sqlite")]
+impl Args {
+    /// Parses `export [--to <recipient>] [--since <YYYY-MM-DD>] [--until <YYYY-MM-DD>] <mbox>`,
+    /// `args` starting after the subcommand name
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self> {
+        const USAGE: &str =
+            "usage: export [--to <recipient>] [--since <YYYY-MM-DD>] [--until <YYYY-MM-DD>] <mbox>";
+        let mut parsed = Self::default();
+        let mut path = None;
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--to" => parsed.recipient = Some(args.next().context("--to needs an address")?),
+                "--since" => {
+                    let since = args.next().context("--since needs a date")?;
+                    parsed.since = Some(since.parse().context("invalid --since date")?);
+                }
+                "--until" => {
+                    let until = args.next().context("--until needs a date")?;
+                    parsed.until = Some(until.parse().context("invalid --until date")?);
+                }
+                flag if flag.starts_with("--") => anyhow::bail!("unknown export option {flag}"),
+                _ if path.is_some() => anyhow::bail!(USAGE),
+                mbox => path = Some(PathBuf::from(mbox)),
+            }
+        }
+        parsed.path = path.context(USAGE)?;
+        Ok(parsed)
+    }
+}
+
+/// Appends the mail stored in the database to an mbox file, for importing
+/// into other mail clients. Exported mail keeps the date it was stored at.
+#[cfg(feature = "sqlite")]
+pub async fn export(args: Args, database: &crate::database::Database) -> Result<()> {
+    let day = |date: chrono::NaiveDate| date.and_time(chrono::NaiveTime::MIN).and_utc();
+    let since = args.since.map(day);
+    let until = args.until.and_then(|until| until.succ_opt()).map(day);
+    let recipient = args.recipient.as_deref().map(str::to_lowercase);
+    let mbox = Mbox::open(&args.path).await?;
+    let mut exported = 0;
+    for stored in database.list(since, until).await? {
+        if let Some(recipient) = &recipient {
+            let matches =
+                stored.mail.to.iter().any(|to| {
+                    to.trim_matches(|c| c == '<' || c == '>').to_lowercase() == *recipient
+                });
+            if !matches {
+                continue;
+            }
+        }
+        mbox.append(&stored.mail, stored.stored_at).await?;
+        exported += 1;
+    }
+    tracing::info!("Exported {exported} messages to {}", args.path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    #[test]
+    fn test_entry() {
+        let mail = Mail {
+            from: "<a@example.com>".into(),
+            to: vec!["<b@example.com>".into()],
+            data: b"Subject: hi\r\n\r\nFrom here\r\n>From there\r\nFrom: me".to_vec(),
+        };
+        let at = Utc.with_ymd_and_hms(2024, 1, 2, 3, 4, 5).unwrap();
+        assert_eq!(
+            String::from_utf8(entry(&mail, at)).unwrap(),
+            "From a@example.com Tue Jan  2 03:04:05 2024\n\
+             Subject: hi\n\n>From here\n>>From there\nFrom: me\n\n"
+        );
+        let bounce = Mail {
+            data: b"Subject: bounce\r\n\r\n".to_vec(),
+            ..Mail::default()
+        };
+        assert!(entry(&bounce, at).starts_with(b"From MAILER-DAEMON "));
+    }
+
+    #[tokio::test]
+    async fn test_append() {
+        let path = std::env::temp_dir().join(format!("mbox-test-{}", QueueId::generate()));
+        let mbox = Mbox::open(&path).await.unwrap();
+        let mail = Mail {
+            data: b"Subject: hi\r\n\r\nhello\r\n".to_vec(),
+            ..Mail::default()
+        };
+        mbox.append(&mail, Utc::now()).await.unwrap();
+        mbox.append(&mail, Utc::now()).await.unwrap();
+        let appended = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(appended.matches("\nhello\n\n").count(), 2);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn test_parse_args() {
+        let args = Args::parse(
+            ["--to", "b@example.com", "--since", "2024-01-02", "out.mbox"].map(String::from),
+        )
+        .unwrap();
+        assert_eq!(
+            args,
+            Args {
+                recipient: Some("b@example.com".into()),
+                since: chrono::NaiveDate::from_ymd_opt(2024, 1, 2),
+                until: None,
+                path: "out.mbox".into(),
+            }
+        );
+        assert!(Args::parse(Vec::new()).is_err());
+        assert!(Args::parse(["--since", "yesterday", "out.mbox"].map(String::from)).is_err());
+        assert!(Args::parse(["a.mbox", "b.mbox"].map(String::from)).is_err());
+    }
+}
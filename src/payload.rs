@@ -0,0 +1,339 @@
+use anyhow::{Context, Result};
+use base64::Engine;
+use mail_parser::{Address, ContentType, MessageParser, MessagePart, MimeHeaders};
+
+use crate::authentication;
+use crate::decode;
+use crate::language;
+use crate::protocol::strip_terminator;
+use crate::received;
+use crate::schema::{Attachments, Contact, Content, Message};
+use crate::spam;
+use crate::thumbnail;
+use crate::tracking;
+use crate::urls::{self, Blocklist};
+
+/// Knobs controlling how a received mail is turned into a [`Message`].
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    /// Longest edge, in pixels, of the previews generated for image
+    /// attachments. Thumbnails are skipped when unset.
+    pub thumbnail_size: Option<u32>,
+    /// Domains URLs found in the body are checked against
+    pub url_blocklist: Option<Blocklist>,
+    /// Removes tracking pixels and remote content references from HTML parts
+    pub strip_remote_content: bool,
+    /// Adds the message as received, for consumers needing every byte of it,
+    /// e.g. to check signatures
+    pub include_raw: bool,
+    /// authserv-id of the `Authentication-Results` field the server adds on
+    /// receipt, whose results are reported. Unset, none are, as the sender
+    /// could have forged them.
+    pub authserv_id: Option<String>,
+    /// Reports the spam score of the `X-Spamd-Result` field the server adds on
+    /// receipt. Unset, it isn't, as the sender could have forged it.
+    pub spam_score: bool,
+    /// Domain of the server adding the topmost `Received` field on receipt, whose
+    /// HELO name and early talk are reported. Unset, they aren't, as the sender
+    /// could have forged them.
+    pub received_by: Option<String>,
+}
+
+/// Parses raw message data into the payload forwarded downstream
+pub fn build(raw: &[u8], options: &Options) -> Result<Message> {
+    let data = MessageParser::default()
+        .parse(raw)
+        .context("cannot parse message")?;
+    let from = data
+        .from()
+        .context("message has no From")?
+        .clone()
+        .into_list();
+    anyhow::ensure!(from.len() == 1, "From length not supported");
+    let from = from.first().unwrap();
+    let email = from.address.as_ref().context("From has no address")?;
+    let from = Contact {
+        email: Some(email.to_string()),
+        name: from.name().map(|e| decode::encoded_words(e).into_owned()),
+    };
+    let to = contacts(data.to());
+    let cc = contacts(data.cc());
+    let bcc = contacts(data.bcc());
+    let reply_to = contacts(data.reply_to());
+    // Decode the raw header ourselves when it has encoded-words,
+    // as mail_parser silently truncates words with broken padding.
+    let subject = data
+        .header_raw("Subject")
+        .filter(|raw| raw.contains("=?"))
+        .map(|raw| decode::encoded_words(raw.trim()).into_owned())
+        .or_else(|| data.subject().map(|e| e.to_string()));
+    let attachments = data
+        .attachments()
+        .map(|attachment| {
+            let is_image = attachment
+                .content_type()
+                .is_some_and(|ctype| ctype.c_type.eq_ignore_ascii_case("image"));
+            let thumbnail = options
+                .thumbnail_size
+                .filter(|_| is_image)
+                .and_then(|size| thumbnail::generate(attachment.contents(), size));
+            Attachments {
+                filename: decode::encoded_words(attachment.attachment_name().unwrap_or_default())
+                    .into_owned(),
+                content_type: attachment.content_type().map(mime_type),
+                content_id: attachment
+                    .content_id()
+                    .map(|id| id.trim_matches(|c| c == '<' || c == '>').to_string()),
+                disposition: attachment
+                    .content_disposition()
+                    .map(|disposition| disposition.c_type.to_lowercase()),
+                content: attachment.contents().to_vec(),
+                url: None,
+                size: None,
+                expires_at: None,
+                thumbnail,
+            }
+        })
+        .collect();
+    let mut content = data
+        .parts
+        .iter()
+        .map(|part| Content {
+            value: text_value(part),
+            mime: part.content_type().map(mime_type),
+        })
+        .filter(|f| f.value.is_some())
+        .collect::<Vec<_>>();
+    let language = language::detect(&content);
+    let urls = urls::extract(&content, options.url_blocklist.as_ref());
+    let mut removed_content = Vec::new();
+    if options.strip_remote_content {
+        for part in &mut content {
+            let is_html = part
+                .mime
+                .as_deref()
+                .is_some_and(|mime| mime.eq_ignore_ascii_case("text/html"));
+            if let (true, Some(value)) = (is_html, &mut part.value) {
+                let (html, removed) = tracking::strip(value);
+                *value = html;
+                removed_content.extend(removed);
+            }
+        }
+    }
+
+    let internationalized_addresses = [&from]
+        .into_iter()
+        .chain(&to)
+        .chain(&cc)
+        .chain(&bcc)
+        .chain(&reply_to)
+        .filter_map(|contact| contact.email.as_deref())
+        .any(|email| !email.is_ascii());
+    let stamp = options
+        .received_by
+        .as_deref()
+        .and_then(|by| received::parse(raw, by));
+    Ok(Message {
+        from,
+        to,
+        reply_to,
+        cc,
+        bcc,
+        subject,
+        content,
+        language,
+        urls,
+        removed_content,
+        attachments,
+        raw: options
+            .include_raw
+            .then(|| base64::engine::general_purpose::STANDARD.encode(strip_terminator(raw))),
+        internationalized_addresses,
+        duplicate_of: None,
+        authentication: options
+            .authserv_id
+            .as_deref()
+            .and_then(|authserv_id| authentication::parse(raw, authserv_id)),
+        spam: options.spam_score.then(|| spam::parse(raw)).flatten(),
+        helo: stamp.as_ref().and_then(|stamp| stamp.helo.clone()),
+        early_talker: stamp.is_some_and(|stamp| stamp.early_talker),
+    })
+}
+
+/// Bytes of the decoded attachments of a raw message, as [`build`] would report
+/// them, without building the rest of the payload
+pub fn attachment_bytes(raw: &[u8]) -> usize {
+    MessageParser::default().parse(raw).map_or(0, |data| {
+        data.attachments()
+            .map(|attachment| attachment.contents().len())
+            .sum()
+    })
+}
+
+fn mime_type(ctype: &ContentType) -> String {
+    match &ctype.c_subtype {
+        Some(subtype) => format!("{}/{}", ctype.c_type, subtype),
+        None => ctype.c_type.to_string(),
+    }
+}
+
+fn contacts(address: Option<&Address>) -> Vec<Contact> {
+    address
+        .map(|to| to.clone().into_list())
+        .unwrap_or_default()
+        .into_iter()
+        .map(|address| Contact {
+            email: address.address.map(|e| e.to_string()),
+            name: address.name.map(|e| decode::encoded_words(&e).into_owned()),
+        })
+        .collect()
+}
+
+/// Decoded text of a part. Text parts mail_parser could not map
+/// to a known charset are kept as lossy UTF-8 rather than dropped.
+fn text_value(part: &MessagePart) -> Option<String> {
+    if let Some(text) = part.text_contents() {
+        return Some(text.to_string());
+    }
+    let is_text = part
+        .content_type()
+        .is_some_and(|ctype| ctype.c_type.eq_ignore_ascii_case("text"));
+    (is_text && !part.is_message() && !part.is_multipart())
+        .then(|| String::from_utf8_lossy(part.contents()).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_internationalized_addresses() {
+        let raw = "From: José <josé@exämple.com>\r\n\
+                   To: 用户@例子.广告\r\n\
+                   Subject: hi\r\n\
+                   \r\n\
+                   hi\r\n";
+        let message = build(raw.as_bytes(), &Options::default()).unwrap();
+        assert_eq!(message.from.email.as_deref(), Some("josé@exämple.com"));
+        assert_eq!(message.to[0].email.as_deref(), Some("用户@例子.广告"));
+        assert!(message.internationalized_addresses);
+
+        let raw = b"From: a@example.com\r\nTo: b@example.com\r\n\r\nhi\r\n";
+        let message = build(raw, &Options::default()).unwrap();
+        assert!(!message.internationalized_addresses);
+        let json = serde_json::to_string(&message).unwrap();
+        assert!(!json.contains("internationalizedAddresses"));
+        assert!(!json.contains("raw"));
+    }
+
+    #[test]
+    fn test_attachments() {
+        let raw = "From: a@example.com\r\n\
+                   Content-Type: multipart/related; boundary=b\r\n\
+                   \r\n\
+                   --b\r\n\
+                   Content-Type: text/html\r\n\
+                   \r\n\
+                   <img src=\"cid:logo@example.com\">\r\n\
+                   --b\r\n\
+                   Content-Type: image/png\r\n\
+                   Content-ID: <logo@example.com>\r\n\
+                   Content-Disposition: inline; filename=logo.png\r\n\
+                   Content-Transfer-Encoding: base64\r\n\
+                   \r\n\
+                   iVBORw0K\r\n\
+                   --b--\r\n";
+        let message = build(raw.as_bytes(), &Options::default()).unwrap();
+        let attachment = &message.attachments[0];
+        assert_eq!(attachment.filename, "logo.png");
+        assert_eq!(attachment.content_type.as_deref(), Some("image/png"));
+        assert_eq!(attachment.content_id.as_deref(), Some("logo@example.com"));
+        assert_eq!(attachment.disposition.as_deref(), Some("inline"));
+        assert_eq!(attachment.content, b"\x89PNG\r\n");
+        let json = serde_json::to_value(attachment).unwrap();
+        assert_eq!(json["content"], "iVBORw0K");
+        assert_eq!(json["contentType"], "image/png");
+        assert_eq!(attachment_bytes(raw.as_bytes()), attachment.content.len());
+    }
+
+    #[test]
+    fn test_include_raw() {
+        let raw = b"From: a@example.com\r\nX-Obscure: kept\r\n\r\nhi\r\n";
+        let options = Options {
+            include_raw: true,
+            ..Options::default()
+        };
+        let message = build(&[raw.as_slice(), b".\r\n"].concat(), &options).unwrap();
+        let decoded = base64::engine::general_purpose::STANDARD
+            .decode(message.raw.unwrap())
+            .unwrap();
+        assert_eq!(decoded, raw);
+    }
+
+    #[test]
+    fn test_spam_score() {
+        let raw = b"X-Spamd-Result: default: True [9.00 / 15.00]; BAYES_SPAM(5.10)\r\n\
+                    From: a@example.com\r\n\r\nhi\r\n.\r\n";
+        let message = build(raw, &Options::default()).unwrap();
+        assert_eq!(message.spam, None);
+
+        let options = Options {
+            spam_score: true,
+            ..Options::default()
+        };
+        let spam = build(raw, &options).unwrap().spam.unwrap();
+        assert_eq!((spam.score, spam.tagged), (9.0, true));
+        assert_eq!(spam.symbols["BAYES_SPAM"], 5.1);
+    }
+
+    #[test]
+    fn test_helo() {
+        let raw =
+            b"Received: from mail.example.com ([192.0.2.1]) by mx.example.org with ESMTP;\r\n\
+                    \tMon, 1 Jan 2024 00:00:00 +0000\r\n\
+                    From: a@example.com\r\n\r\nhi\r\n.\r\n";
+        let message = build(raw, &Options::default()).unwrap();
+        assert_eq!(message.helo, None);
+
+        let options = Options {
+            received_by: Some("mx.example.org".into()),
+            ..Options::default()
+        };
+        let message = build(raw, &options).unwrap();
+        assert_eq!(message.helo.as_deref(), Some("mail.example.com"));
+        assert!(!message.early_talker);
+        // Reported without the checks, unlike the HELO name SPF records
+        assert_eq!(message.authentication, None);
+    }
+
+    #[test]
+    fn test_decodes_headers_and_bodies() {
+        let raw = "From: Team=?UTF-8?Q?Caf=C3=A9?= <team@example.com>\r\n\
+                   To: =?ISO-8859-1?Q?Andr=E9?= <andre@example.com>\r\n\
+                   Subject: =?UTF-8?B?SGVsbG8gd29ybGQ?=\r\n\
+                   Content-Type: multipart/alternative; boundary=b\r\n\
+                   \r\n\
+                   --b\r\n\
+                   Content-Type: text/plain; charset=utf-8\r\n\
+                   Content-Transfer-Encoding: quoted-printable\r\n\
+                   \r\n\
+                   Caf=C3=A9 =\r\n\
+                   ok\r\n\
+                   --b\r\n\
+                   Content-Type: text/html; charset=utf-8\r\n\
+                   Content-Transfer-Encoding: base64\r\n\
+                   \r\n\
+                   PGI+aGk8L2I+\r\n\
+                   --b--\r\n";
+        let message = build(raw.as_bytes(), &Options::default()).unwrap();
+        assert_eq!(message.subject.as_deref(), Some("Hello world"));
+        assert_eq!(message.from.name.as_deref(), Some("TeamCafé"));
+        assert_eq!(message.to[0].name.as_deref(), Some("André"));
+        let values = message
+            .content
+            .iter()
+            .map(|content| content.value.as_deref().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(values, ["Café ok", "<b>hi</b>"]);
+    }
+}
@@ -0,0 +1,149 @@
+use mail_parser::{HeaderValue, MessageParser};
+use serde::{Deserialize, Serialize};
+
+use crate::protocol::Mail;
+
+/// Delivery priority classes, from the most to the least urgent
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[cfg_attr(feature = "admin", derive(async_graphql::Enum))]
+#[serde(rename_all = "camelCase")]
+pub enum Priority {
+    High,
+    #[default]
+    Normal,
+    Low,
+}
+
+impl Priority {
+    pub const ALL: [Priority; 3] = [Priority::High, Priority::Normal, Priority::Low];
+
+    pub fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// Rules deciding the priority class of an accepted mail
+#[derive(Clone, Debug)]
+pub struct Rules {
+    /// Recipients whose mail jumps the queue: full addresses,
+    /// or `@domain` to match a whole domain
+    pub high_recipients: Vec<String>,
+    /// Messages larger than this many bytes are delivered last
+    pub bulk_size: usize,
+}
+
+impl Default for Rules {
+    fn default() -> Self {
+        Self {
+            high_recipients: Vec::new(),
+            bulk_size: 1024 * 1024,
+        }
+    }
+}
+
+impl Rules {
+    /// Classifies a mail. Large messages and mailing list traffic are low priority,
+    /// mail for priority recipients or flagged urgent by the sender is high priority.
+    pub fn classify(&self, mail: &Mail) -> Priority {
+        if mail.data.len() > self.bulk_size {
+            return Priority::Low;
+        }
+        let headers = MessageParser::default().parse_headers(mail.data.as_slice());
+        let header = |name: &str| {
+            headers
+                .as_ref()
+                .and_then(|message| match message.header(name) {
+                    Some(HeaderValue::Text(text)) => Some(text.to_lowercase()),
+                    Some(HeaderValue::TextList(list)) => {
+                        list.first().map(|text| text.to_lowercase())
+                    }
+                    Some(HeaderValue::Empty) | None => None,
+                    Some(_) => Some(String::new()),
+                })
+        };
+        let is_bulk = header("Precedence")
+            .is_some_and(|precedence| matches!(precedence.as_str(), "bulk" | "list" | "junk"))
+            || header("List-Id").is_some()
+            || header("List-Unsubscribe").is_some();
+        if is_bulk {
+            return Priority::Low;
+        }
+        let is_urgent = header("X-Priority").is_some_and(|priority| priority.starts_with('1'))
+            || header("Importance").is_some_and(|importance| importance == "high");
+        let for_priority_recipient = mail.to.iter().any(|to| {
+            let to = to.trim_matches(|c| c == '<' || c == '>').to_lowercase();
+            self.high_recipients
+                .iter()
+                .any(|recipient| match recipient.strip_prefix('@') {
+                    Some(domain) => to
+                        .rsplit_once('@')
+                        .is_some_and(|(_, to_domain)| to_domain == domain),
+                    None => &to == recipient,
+                })
+        });
+        if is_urgent || for_priority_recipient {
+            Priority::High
+        } else {
+            Priority::Normal
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mail(to: &str, data: &str) -> Mail {
+        Mail {
+            from: "<a@example.com>".into(),
+            to: vec![to.into()],
+            data: data.into(),
+        }
+    }
+
+    #[test]
+    fn test_classify() {
+        let rules = Rules {
+            high_recipients: vec!["@alerts.example.com".into(), "ceo@example.com".into()],
+            bulk_size: 100,
+        };
+        let plain = "Subject: hi\r\n\r\nhello\r\n.\r\n";
+        assert_eq!(
+            rules.classify(&mail("<bob@example.com>", plain)),
+            Priority::Normal
+        );
+        assert_eq!(
+            rules.classify(&mail("<CEO@example.com>", plain)),
+            Priority::High
+        );
+        assert_eq!(
+            rules.classify(&mail("<pager@alerts.example.com>", plain)),
+            Priority::High
+        );
+        assert_eq!(
+            rules.classify(&mail(
+                "<bob@example.com>",
+                "X-Priority: 1 (Highest)\r\n\r\nhello\r\n.\r\n"
+            )),
+            Priority::High
+        );
+        assert_eq!(
+            rules.classify(&mail(
+                "<ceo@example.com>",
+                "Precedence: bulk\r\n\r\nnews\r\n.\r\n"
+            )),
+            Priority::Low
+        );
+        assert_eq!(
+            rules.classify(&mail(
+                "<bob@example.com>",
+                "List-Id: <news.example.com>\r\n\r\nnews\r\n.\r\n"
+            )),
+            Priority::Low
+        );
+        assert_eq!(
+            rules.classify(&mail("<ceo@example.com>", &"x".repeat(101))),
+            Priority::Low
+        );
+    }
+}
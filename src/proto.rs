@@ -0,0 +1,276 @@
+use std::collections::BTreeMap;
+
+use base64::Engine;
+
+use crate::schema;
+
+// Types of proto/edgemail.proto, as prost-build generates them
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Message {
+    #[prost(message, optional, tag = "1")]
+    pub from: Option<Contact>,
+    #[prost(message, repeated, tag = "2")]
+    pub reply_to: Vec<Contact>,
+    #[prost(message, repeated, tag = "3")]
+    pub to: Vec<Contact>,
+    #[prost(message, repeated, tag = "4")]
+    pub cc: Vec<Contact>,
+    #[prost(message, repeated, tag = "5")]
+    pub bcc: Vec<Contact>,
+    #[prost(string, optional, tag = "6")]
+    pub subject: Option<String>,
+    #[prost(message, repeated, tag = "7")]
+    pub content: Vec<Content>,
+    #[prost(string, optional, tag = "8")]
+    pub language: Option<String>,
+    #[prost(message, repeated, tag = "9")]
+    pub urls: Vec<Link>,
+    #[prost(message, repeated, tag = "10")]
+    pub removed_content: Vec<RemovedContent>,
+    #[prost(message, repeated, tag = "11")]
+    pub attachments: Vec<Attachment>,
+    #[prost(bytes = "vec", optional, tag = "12")]
+    pub raw: Option<Vec<u8>>,
+    #[prost(bool, tag = "13")]
+    pub internationalized_addresses: bool,
+    #[prost(string, optional, tag = "14")]
+    pub duplicate_of: Option<String>,
+    #[prost(message, optional, tag = "15")]
+    pub authentication: Option<Authentication>,
+    #[prost(message, optional, tag = "16")]
+    pub spam: Option<Spam>,
+    #[prost(string, optional, tag = "17")]
+    pub helo: Option<String>,
+    #[prost(bool, tag = "18")]
+    pub early_talker: bool,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Authentication {
+    #[prost(message, optional, tag = "1")]
+    pub spf: Option<AuthResult>,
+    #[prost(message, repeated, tag = "2")]
+    pub dkim: Vec<AuthResult>,
+    #[prost(message, optional, tag = "3")]
+    pub dmarc: Option<AuthResult>,
+    #[prost(bool, tag = "4")]
+    pub quarantine: bool,
+    #[prost(message, optional, tag = "5")]
+    pub dnsbl: Option<AuthResult>,
+    #[prost(message, optional, tag = "6")]
+    pub iprev: Option<AuthResult>,
+    #[prost(string, optional, tag = "7")]
+    pub helo: Option<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Spam {
+    #[prost(double, tag = "1")]
+    pub score: f64,
+    #[prost(double, tag = "2")]
+    pub required_score: f64,
+    #[prost(bool, tag = "3")]
+    pub tagged: bool,
+    #[prost(btree_map = "string, double", tag = "4")]
+    pub symbols: BTreeMap<String, f64>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct AuthResult {
+    #[prost(string, tag = "1")]
+    pub result: String,
+    #[prost(btree_map = "string, string", tag = "2")]
+    pub properties: BTreeMap<String, String>,
+    #[prost(string, optional, tag = "3")]
+    pub reason: Option<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Contact {
+    #[prost(string, optional, tag = "1")]
+    pub email: Option<String>,
+    #[prost(string, optional, tag = "2")]
+    pub name: Option<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Content {
+    #[prost(string, optional, tag = "1")]
+    pub mime: Option<String>,
+    #[prost(string, optional, tag = "2")]
+    pub value: Option<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Link {
+    #[prost(string, tag = "1")]
+    pub url: String,
+    #[prost(string, optional, tag = "2")]
+    pub domain: Option<String>,
+    #[prost(bool, optional, tag = "3")]
+    pub blocklisted: Option<bool>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum RemovedKind {
+    Unspecified = 0,
+    TrackingPixel = 1,
+    RemoteImage = 2,
+    RemoteStylesheet = 3,
+    RemoteStyle = 4,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct RemovedContent {
+    #[prost(enumeration = "RemovedKind", tag = "1")]
+    pub kind: i32,
+    #[prost(string, tag = "2")]
+    pub url: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Attachment {
+    #[prost(string, tag = "1")]
+    pub filename: String,
+    #[prost(string, optional, tag = "2")]
+    pub content_type: Option<String>,
+    #[prost(string, optional, tag = "3")]
+    pub content_id: Option<String>,
+    #[prost(string, optional, tag = "4")]
+    pub disposition: Option<String>,
+    #[prost(bytes = "vec", tag = "5")]
+    pub content: Vec<u8>,
+    #[prost(string, optional, tag = "6")]
+    pub url: Option<String>,
+    #[prost(uint64, optional, tag = "7")]
+    pub size: Option<u64>,
+    #[prost(string, optional, tag = "8")]
+    pub expires_at: Option<String>,
+    #[prost(string, optional, tag = "9")]
+    pub thumbnail: Option<String>,
+}
+
+impl From<&schema::Message> for Message {
+    fn from(message: &schema::Message) -> Self {
+        let contacts = |contacts: &[schema::Contact]| contacts.iter().map(Contact::from).collect();
+        Self {
+            from: Some(Contact::from(&message.from)),
+            reply_to: contacts(&message.reply_to),
+            to: contacts(&message.to),
+            cc: contacts(&message.cc),
+            bcc: contacts(&message.bcc),
+            subject: message.subject.clone(),
+            content: message
+                .content
+                .iter()
+                .map(|content| Content {
+                    mime: content.mime.clone(),
+                    value: content.value.clone(),
+                })
+                .collect(),
+            language: message.language.clone(),
+            urls: message
+                .urls
+                .iter()
+                .map(|link| Link {
+                    url: link.url.clone(),
+                    domain: link.domain.clone(),
+                    blocklisted: link.blocklisted,
+                })
+                .collect(),
+            removed_content: message
+                .removed_content
+                .iter()
+                .map(|removed| RemovedContent {
+                    kind: RemovedKind::from(removed.kind) as i32,
+                    url: removed.url.clone(),
+                })
+                .collect(),
+            attachments: message.attachments.iter().map(Attachment::from).collect(),
+            // Carried as bytes rather than base64, the point of the format being compactness
+            raw: message
+                .raw
+                .as_ref()
+                .and_then(|raw| base64::engine::general_purpose::STANDARD.decode(raw).ok()),
+            internationalized_addresses: message.internationalized_addresses,
+            duplicate_of: message.duplicate_of.clone(),
+            authentication: message.authentication.as_ref().map(Authentication::from),
+            spam: message.spam.as_ref().map(Spam::from),
+            helo: message.helo.clone(),
+            early_talker: message.early_talker,
+        }
+    }
+}
+
+impl From<&schema::Authentication> for Authentication {
+    fn from(authentication: &schema::Authentication) -> Self {
+        Self {
+            spf: authentication.spf.as_ref().map(AuthResult::from),
+            dkim: authentication.dkim.iter().map(AuthResult::from).collect(),
+            dmarc: authentication.dmarc.as_ref().map(AuthResult::from),
+            quarantine: authentication.quarantine,
+            dnsbl: authentication.dnsbl.as_ref().map(AuthResult::from),
+            iprev: authentication.iprev.as_ref().map(AuthResult::from),
+            helo: authentication.helo.clone(),
+        }
+    }
+}
+
+impl From<&schema::Spam> for Spam {
+    fn from(spam: &schema::Spam) -> Self {
+        Self {
+            score: spam.score,
+            required_score: spam.required_score,
+            tagged: spam.tagged,
+            symbols: spam.symbols.clone(),
+        }
+    }
+}
+
+impl From<&schema::AuthResult> for AuthResult {
+    fn from(result: &schema::AuthResult) -> Self {
+        Self {
+            result: result.result.clone(),
+            properties: result.properties.clone(),
+            reason: result.reason.clone(),
+        }
+    }
+}
+
+impl From<&schema::Contact> for Contact {
+    fn from(contact: &schema::Contact) -> Self {
+        Self {
+            email: contact.email.clone(),
+            name: contact.name.clone(),
+        }
+    }
+}
+
+impl From<schema::RemovedKind> for RemovedKind {
+    fn from(kind: schema::RemovedKind) -> Self {
+        match kind {
+            schema::RemovedKind::TrackingPixel => Self::TrackingPixel,
+            schema::RemovedKind::RemoteImage => Self::RemoteImage,
+            schema::RemovedKind::RemoteStylesheet => Self::RemoteStylesheet,
+            schema::RemovedKind::RemoteStyle => Self::RemoteStyle,
+        }
+    }
+}
+
+impl From<&schema::Attachments> for Attachment {
+    fn from(attachment: &schema::Attachments) -> Self {
+        Self {
+            filename: attachment.filename.clone(),
+            content_type: attachment.content_type.clone(),
+            content_id: attachment.content_id.clone(),
+            disposition: attachment.disposition.clone(),
+            content: attachment.content.clone(),
+            url: attachment.url.clone(),
+            size: attachment.size.map(|size| size as u64),
+            expires_at: attachment.expires_at.map(|at| at.to_rfc3339()),
+            thumbnail: attachment.thumbnail.clone(),
+        }
+    }
+}
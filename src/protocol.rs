@@ -0,0 +1,1645 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Instant;
+
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "relay")]
+use crate::alias::Aliases;
+use crate::auth::{Exchange, Step, Users};
+use crate::domains::Domains;
+use crate::error::SmtpError;
+use crate::helo::HeloPolicy;
+use crate::queue::QueueId;
+use crate::recipients::Recipients;
+#[cfg(feature = "relay")]
+use crate::srs;
+
+/// Mail as collected by the state machine and journaled in the WAL.
+/// Library consumers get a [`ReceivedMail`](crate::received::ReceivedMail) instead.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Mail {
+    pub from: String,
+    pub to: Vec<String>,
+    /// The message as received, 8-bit content included
+    #[serde(with = "data")]
+    pub data: Vec<u8>,
+}
+
+impl Mail {
+    /// Whether the envelope has UTF-8 addresses, as allowed with SMTPUTF8
+    pub fn internationalized(&self) -> bool {
+        !self.from.is_ascii() || self.to.iter().any(|to| !to.is_ascii())
+    }
+}
+
+/// Message data without the DATA terminator, as the client meant it
+pub fn strip_terminator(data: &[u8]) -> &[u8] {
+    data.strip_suffix(b"\r\n.\r\n").map_or(
+        data,
+        // Keep the CRLF ending the last line of the message
+        |message| &data[..message.len() + 2],
+    )
+}
+
+/// Message data is journaled as a string when it is UTF-8, as it always
+/// was before 8BITMIME, and as base64 otherwise
+mod data {
+    use base64::Engine;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(untagged)]
+    enum Data {
+        Text(String),
+        Binary { base64: String },
+    }
+
+    pub fn serialize<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        match std::str::from_utf8(data) {
+            Ok(text) => serializer.serialize_str(text),
+            Err(_) => Data::Binary {
+                base64: base64::engine::general_purpose::STANDARD.encode(data),
+            }
+            .serialize(serializer),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        match Data::deserialize(deserializer)? {
+            Data::Text(text) => Ok(text.into_bytes()),
+            Data::Binary { base64 } => base64::engine::general_purpose::STANDARD
+                .decode(base64)
+                .map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+/// A reply to the client, with its enhanced status code (RFC 3463)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Reply {
+    pub code: u16,
+    /// Enhanced status code, such as `2.1.5`, empty for replies that take none:
+    /// the greeting and intermediate replies
+    pub status: &'static str,
+    pub text: &'static str,
+    /// The reply as sent on the wire
+    line: &'static str,
+}
+
+impl Reply {
+    pub fn as_bytes(&self) -> &'static [u8] {
+        self.line.as_bytes()
+    }
+}
+
+impl std::fmt::Display for Reply {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.line.trim_end())
+    }
+}
+
+/// Builds a [`Reply`], rendered at compile time
+macro_rules! reply {
+    ($code:literal, $text:literal) => {
+        Reply {
+            code: $code,
+            status: "",
+            text: $text,
+            line: concat!($code, " ", $text, "\n"),
+        }
+    };
+    ($code:literal, $status:literal, $text:literal) => {
+        Reply {
+            code: $code,
+            status: $status,
+            text: $text,
+            line: concat!($code, " ", $status, " ", $text, "\n"),
+        }
+    };
+}
+
+/// Progress of an SMTP session
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum State {
+    Fresh,
+    Greeted,
+    ReceivingRcpt(Mail),
+    ReceivingData(Mail),
+    /// Between BDAT chunks (RFC 3030)
+    ReceivingChunks(Mail),
+    Received(Mail),
+}
+
+pub struct StateMachine {
+    pub(crate) state: State,
+    domain: String,
+    ehlo_greeting: String,
+    /// Whether STARTTLS is offered to the client
+    starttls: bool,
+    /// Whether the session runs over TLS
+    tls: bool,
+    /// Name the client introduced itself with
+    pub(crate) helo: Option<String>,
+    /// Whether the client speaks LMTP, greeting with LHLO
+    lmtp: bool,
+    /// Replies to the end of data, once per recipient in LMTP
+    data_reply: Vec<u8>,
+    /// Largest message accepted, in bytes
+    max_size: usize,
+    /// Recipients a mail may have, any number unless set
+    max_recipients: Option<usize>,
+    /// Whether the message being received outgrew `max_size`, its data being discarded
+    oversized: bool,
+    /// Whether the message data received so far ends with a CRLF, starting a new line
+    line_start: bool,
+    /// Size of the BDAT chunk the frontend has to read, and whether it is the last
+    chunk: Option<(usize, bool)>,
+    /// Accounts AUTH is checked against, if any
+    users: Option<Arc<Users>>,
+    /// Whether XOAUTH2 is offered, its tokens checked by the frontend
+    xoauth2: bool,
+    /// SASL exchange awaiting the client's response
+    sasl: Option<Exchange>,
+    /// XOAUTH2 user and bearer token awaiting validation
+    token_check: Option<(String, String)>,
+    /// User the client authenticated as
+    authenticated: Option<String>,
+    /// Whether MAIL is refused until the client authenticated, for submission
+    require_auth: bool,
+    /// Whether PLAIN and LOGIN are taken without TLS, the password in the clear
+    allow_insecure_auth: bool,
+    /// Names unauthenticated clients may not greet with, their MAIL being refused
+    helo_policy: Option<HeloPolicy>,
+    /// Domains recipients are accepted for, any unless set
+    domains: Option<Domains>,
+    /// Rules the recipients are accepted by
+    recipients: Arc<Recipients>,
+    /// Addresses the mail of unauthenticated clients is taken for, if set
+    #[cfg(feature = "relay")]
+    aliases: Option<Arc<Aliases>>,
+    /// Last SASL challenge sent
+    challenge: Vec<u8>,
+    /// Errors the client made so far, and how many it may make
+    errors: usize,
+    max_errors: usize,
+    /// Recipients refused so far, by the domains or the recipient rules
+    refused_recipients: usize,
+    /// Whether the frontend checks the sender of each mail
+    check_senders: bool,
+    /// Sender of the mail awaiting its check by the frontend
+    sender_check: Option<String>,
+    /// Reply refusing the recipients of the mail, when its sender failed the checks
+    refused_sender: Option<Reply>,
+    /// Whether RCPT was given for the mail being received, whether or not it was refused
+    rcpt_given: bool,
+    /// Reply refusing the BDAT chunk being read, its bytes discarded, when the
+    /// mail has no recipient
+    refused_chunk: Option<Reply>,
+    /// Reply to the last line handled
+    reply: Vec<u8>,
+}
+
+/// An state machine capable of handling SMTP commands
+/// for receiving mail.
+/// Use handle_smtp() to handle a single command.
+/// The return value from handle_smtp() is the response
+/// that should be sent back to the client.
+/// It does no I/O, so any transport or runtime can drive it:
+/// the tokio [`Server`](crate::smtp::Server) is one such frontend.
+impl StateMachine {
+    pub const OH_HAI: Reply = reply!(220, "edgemail");
+    pub const KK: Reply = reply!(250, "2.0.0", "Ok");
+    pub const KK_SENDER: Reply = reply!(250, "2.1.0", "Ok");
+    pub const KK_RECIPIENT: Reply = reply!(250, "2.1.5", "Ok");
+    pub const KK_QUEUED: Reply = reply!(250, "2.6.0", "Ok");
+    pub const AUTH_OK: Reply = reply!(235, "2.7.0", "Authentication successful");
+    pub const SEND_DATA_PLZ: Reply = reply!(354, "End data with <CR><LF>.<CR><LF>");
+    pub const KTHXBYE: Reply = reply!(221, "2.0.0", "Bye");
+    pub const UH_OH: Reply = reply!(451, "4.3.0", "Try again later");
+    pub const NO_THANKS: Reply = reply!(550, "5.7.1", "Message rejected");
+    pub const TOO_BUSY: Reply = reply!(421, "4.3.2", "edgemail too busy, try again later");
+    pub const TOO_SLOW: Reply = reply!(
+        421,
+        "4.4.2",
+        "edgemail timeout exceeded, closing connection"
+    );
+    pub const GO_AHEAD_TLS: Reply = reply!(220, "2.0.0", "Ready to start TLS");
+    pub const WHO_ARE_YOU: Reply = reply!(535, "5.7.8", "Authentication credentials invalid");
+    pub const NEVER_MIND: Reply = reply!(501, "5.7.0", "Authentication cancelled");
+    pub const SAY_WHAT: Reply = reply!(504, "5.5.4", "Unrecognized authentication type");
+    pub const LOG_IN_FIRST: Reply = reply!(530, "5.7.0", "Authentication required");
+    pub const ENCRYPT_FIRST: Reply = reply!(
+        538,
+        "5.7.11",
+        "Encryption required for requested authentication mechanism"
+    );
+    pub const TOO_BIG: Reply = reply!(
+        552,
+        "5.3.4",
+        "Message size exceeds fixed maximum message size"
+    );
+    pub const WHAT_IS_THAT: Reply = reply!(500, "5.5.1", "Command not recognized");
+    pub const SAY_AGAIN: Reply = reply!(501, "5.5.2", "Syntax error in parameters or arguments");
+    pub const NOT_NOW: Reply = reply!(503, "5.5.1", "Bad sequence of commands");
+    pub const TOO_EAGER: Reply = reply!(554, "5.5.1", "Talking before the greeting");
+    pub const COME_BACK_LATER: Reply =
+        reply!(421, "4.7.0", "Too many connections, try again later");
+    pub const SLOW_DOWN: Reply = reply!(450, "4.7.1", "Too many messages, try again later");
+    pub const TOO_MANY_RECIPIENTS: Reply = reply!(452, "4.5.3", "Too many recipients");
+    pub const GO_AWAY: Reply = reply!(421, "4.7.0", "Too many errors, closing connection");
+    pub const NO_SUCH_USER: Reply = reply!(550, "5.1.1", "Recipient address rejected");
+    pub const NO_VALID_RECIPIENTS: Reply = reply!(554, "5.5.1", "No valid recipients");
+    pub const NOT_OURS: Reply = reply!(550, "5.7.1", "Relay not permitted");
+    pub const BAD_HELO: Reply = reply!(550, "5.7.1", "HELO/EHLO name not accepted");
+    pub const BLOCKED: Reply = reply!(554, "5.7.1", "Client host blocked by a DNS blocklist");
+    pub const NAMELESS: Reply = reply!(554, "5.7.25", "Reverse DNS validation failed");
+    pub const NOT_FROM_THERE: Reply = reply!(550, "5.7.23", "SPF validation failed");
+    pub const NOT_ALIGNED: Reply =
+        reply!(550, "5.7.1", "Rejected per the DMARC policy of the sender");
+    pub const SPAMMY: Reply = reply!(550, "5.7.1", "Message rejected as spam");
+    pub const MAYBE_SPAMMY: Reply = reply!(
+        451,
+        "4.7.1",
+        "Message deferred as likely spam, try again later"
+    );
+    /// No reply, until the frontend did its part or the client sent more
+    pub const HOLD_YOUR_HORSES: &[u8] = &[];
+    /// Commands known to the server, out of order when they aren't handled
+    const COMMANDS: &[&str] = &[
+        "ehlo", "helo", "lhlo", "mail", "rcpt", "data", "bdat", "rset", "noop", "quit", "auth",
+        "starttls", "vrfy", "expn", "help",
+    ];
+    /// Largest message accepted unless configured otherwise: 25 MiB
+    pub const DEFAULT_MAX_SIZE: usize = 25 * 1024 * 1024;
+    /// Errors a client may make before it is disconnected, unless configured otherwise
+    pub const DEFAULT_MAX_ERRORS: usize = 10;
+
+    pub fn new(domain: impl AsRef<str>) -> Self {
+        tracing::trace!("New state machine initialized");
+        let mut sm = Self {
+            state: State::Fresh,
+            domain: domain.as_ref().to_string(),
+            ehlo_greeting: String::new(),
+            starttls: false,
+            tls: false,
+            helo: None,
+            lmtp: false,
+            data_reply: Vec::new(),
+            max_size: StateMachine::DEFAULT_MAX_SIZE,
+            max_recipients: None,
+            oversized: false,
+            line_start: true,
+            chunk: None,
+            users: None,
+            xoauth2: false,
+            sasl: None,
+            token_check: None,
+            authenticated: None,
+            helo_policy: None,
+            domains: None,
+            recipients: Recipients::builtin(),
+            #[cfg(feature = "relay")]
+            aliases: None,
+            require_auth: false,
+            allow_insecure_auth: false,
+            challenge: Vec::new(),
+            errors: 0,
+            max_errors: StateMachine::DEFAULT_MAX_ERRORS,
+            refused_recipients: 0,
+            check_senders: false,
+            sender_check: None,
+            refused_sender: None,
+            rcpt_given: false,
+            refused_chunk: None,
+            reply: Vec::new(),
+        };
+        sm.update_ehlo_greeting();
+        sm
+    }
+
+    /// Offers STARTTLS in the EHLO reply, for servers able to upgrade the connection
+    pub fn offer_starttls(&mut self, offer: bool) {
+        self.starttls = offer;
+        self.update_ehlo_greeting();
+    }
+
+    /// Checks AUTH against the accounts, offering CRAM-MD5 besides PLAIN and LOGIN
+    pub fn check_auth(&mut self, users: Arc<Users>) {
+        self.users = Some(users);
+        self.update_ehlo_greeting();
+    }
+
+    /// Offers XOAUTH2. The frontend validates the bearer tokens:
+    /// after a command, see [`take_token_check`](Self::take_token_check).
+    pub fn offer_xoauth2(&mut self, offer: bool) {
+        self.xoauth2 = offer;
+        self.update_ehlo_greeting();
+    }
+
+    /// XOAUTH2 user and bearer token the client sent, to be validated
+    /// and reported with [`token_verified`](Self::token_verified)
+    pub fn take_token_check(&mut self) -> Option<(String, String)> {
+        self.token_check.take()
+    }
+
+    /// Reports whether the XOAUTH2 token is valid, returning the reply
+    pub fn token_verified(&mut self, user: String, valid: bool) -> &[u8] {
+        self.sasl_step(Exchange::token_verified(user, valid))
+    }
+
+    /// Has the frontend check the sender of each mail, e.g. with SPF:
+    /// after MAIL, see [`take_sender_check`](Self::take_sender_check).
+    pub fn check_senders(&mut self, check: bool) {
+        self.check_senders = check;
+    }
+
+    /// Sender of the mail just started, to be checked and reported
+    /// with [`sender_checked`](Self::sender_checked)
+    pub fn take_sender_check(&mut self) -> Option<String> {
+        self.sender_check.take()
+    }
+
+    /// Reports the check of the sender: when refused, every recipient
+    /// of the mail is refused with the reply
+    pub fn sender_checked(&mut self, refusal: Option<Reply>) {
+        self.refused_sender = refusal;
+    }
+
+    /// Sets the largest message accepted, advertised with SIZE (RFC 1870)
+    pub fn set_max_size(&mut self, max_size: usize) {
+        self.max_size = max_size;
+        self.update_ehlo_greeting();
+    }
+
+    /// Sets how many recipients a mail may have, refusing the ones beyond
+    /// with a 452 so the client sends them in another mail (RFC 5321 section 4.5.3.1.10)
+    pub fn set_max_recipients(&mut self, max_recipients: usize) {
+        self.max_recipients = Some(max_recipients);
+    }
+
+    /// Size of the BDAT chunk following the last command, and whether it is the last.
+    /// The frontend reads that many bytes, hands them to
+    /// [`receive_chunk`](Self::receive_chunk), then calls [`end_chunk`](Self::end_chunk).
+    pub fn take_chunk(&mut self) -> Option<(usize, bool)> {
+        self.chunk.take()
+    }
+
+    /// Takes bytes of the current BDAT chunk, as they are read
+    pub fn receive_chunk(&mut self, bytes: &[u8]) {
+        let State::ReceivingChunks(mail) = &mut self.state else {
+            return;
+        };
+        if self.oversized {
+            return;
+        }
+        if mail.data.len() + bytes.len() > self.max_size {
+            // Keep reading the chunks, without keeping the data
+            self.oversized = true;
+            mail.data = Vec::new();
+        } else {
+            mail.data.extend_from_slice(bytes);
+        }
+    }
+
+    /// Ends the current BDAT chunk, returning its reply
+    pub fn end_chunk(&mut self, last: bool) -> &[u8] {
+        if let Some(refusal) = self.refused_chunk.take() {
+            return refusal.as_bytes();
+        }
+        let State::ReceivingChunks(mail) = &mut self.state else {
+            return StateMachine::KK.as_bytes();
+        };
+        if !last {
+            return StateMachine::KK.as_bytes();
+        }
+        if self.oversized {
+            tracing::warn!("Refused a message larger than {} bytes", self.max_size);
+            self.oversized = false;
+            let replies = if self.lmtp { mail.to.len().max(1) } else { 1 };
+            self.data_reply = StateMachine::TOO_BIG.as_bytes().repeat(replies);
+            self.state = State::Greeted;
+            return &self.data_reply;
+        }
+        // Shaped like mail received with DATA, terminator included
+        if !mail.data.is_empty() && !mail.data.ends_with(b"\r\n") {
+            mail.data.extend_from_slice(b"\r\n");
+        }
+        mail.data.extend_from_slice(b".\r\n");
+        let State::ReceivingChunks(mail) = std::mem::replace(&mut self.state, State::Fresh) else {
+            unreachable!();
+        };
+        self.state = State::Received(mail);
+        self.data_reply = self.transaction_reply(StateMachine::KK_QUEUED);
+        &self.data_reply
+    }
+
+    /// Sets how many unknown, out of order or malformed commands a client may send.
+    /// They are refused with a 5xx reply, and the session goes on until one too many.
+    pub fn set_max_errors(&mut self, max_errors: usize) {
+        self.max_errors = max_errors;
+    }
+
+    /// Refuses MAIL until the client authenticated, as on a submission port (RFC 6409)
+    pub fn require_auth(&mut self, require: bool) {
+        self.require_auth = require;
+    }
+
+    /// Takes AUTH PLAIN and LOGIN without TLS, refused with a 538 otherwise
+    /// as the password would cross the wire in the clear (RFC 4954)
+    pub fn allow_insecure_auth(&mut self, allow: bool) {
+        self.allow_insecure_auth = allow;
+    }
+
+    /// Refuses the mail of unauthenticated clients greeting with a name the policy refuses
+    pub fn set_helo_policy(&mut self, policy: HeloPolicy) {
+        self.helo_policy = Some(policy);
+    }
+
+    /// Refuses the recipients of other domains than these, unless the client authenticated
+    pub fn set_domains(&mut self, domains: Domains) {
+        self.domains = Some(domains);
+    }
+
+    /// Accepts the recipients by these rules, instead of the default ones
+    pub fn set_recipients(&mut self, recipients: Arc<Recipients>) {
+        self.recipients = recipients;
+    }
+
+    /// Refuses the recipients of unauthenticated clients without an alias, as
+    /// the mail would have nowhere to go. Bounces to SRS addresses are taken.
+    #[cfg(feature = "relay")]
+    pub fn set_aliases(&mut self, aliases: Arc<Aliases>) {
+        self.aliases = Some(aliases);
+    }
+
+    /// Whether the mail of a recipient is ours to take, or the client's to relay
+    fn accepts_recipient(&self, to: &str) -> bool {
+        self.authenticated.is_some()
+            || self
+                .domains
+                .as_ref()
+                .is_none_or(|domains| domains.accepts(to))
+    }
+
+    /// Whether the mail of a recipient has somewhere to go: an alias, unless
+    /// the client authenticated, or the sender of the mail it bounces
+    #[cfg(feature = "relay")]
+    fn has_mailbox(&self, to: &str) -> bool {
+        self.authenticated.is_some()
+            || self
+                .aliases
+                .as_ref()
+                .is_none_or(|aliases| aliases.resolve(to).is_some() || srs::is_srs(to))
+    }
+
+    /// Whether the mail of the client is refused for its HELO/EHLO name
+    fn refuses_helo(&self) -> bool {
+        if self.authenticated.is_some() {
+            return false;
+        }
+        let Some(Err(reason)) = self.helo_policy.as_ref().map(|p| p.check(self.helo())) else {
+            return false;
+        };
+        tracing::info!("Refusing MAIL after HELO {:?}: {reason}", self.helo);
+        true
+    }
+
+    /// Unknown, out of order or malformed commands the client sent so far
+    pub fn errors(&self) -> usize {
+        self.errors
+    }
+
+    /// Recipients refused so far for not being ours or denied by the rules,
+    /// many of them telling of a client guessing addresses
+    pub fn refused_recipients(&self) -> usize {
+        self.refused_recipients
+    }
+
+    /// Whether AUTH is checked, rather than acknowledged blindly
+    fn checks_auth(&self) -> bool {
+        self.users.is_some() || self.xoauth2
+    }
+
+    /// User the client authenticated as, when AUTH is checked
+    pub fn authenticated(&self) -> Option<&str> {
+        self.authenticated.as_deref()
+    }
+
+    /// Whether the session runs over TLS
+    pub fn tls(&self) -> bool {
+        self.tls
+    }
+
+    /// Records that the TLS handshake completed after STARTTLS. The client
+    /// starts over with EHLO, forgetting what it said in plain text (RFC 3207).
+    pub fn tls_started(&mut self) {
+        self.tls = true;
+        self.state = State::Fresh;
+        self.helo = None;
+        self.lmtp = false;
+        self.sasl = None;
+        self.token_check = None;
+        self.authenticated = None;
+        self.sender_check = None;
+        self.refused_sender = None;
+        self.update_ehlo_greeting();
+    }
+
+    fn update_ehlo_greeting(&mut self) {
+        let domain = &self.domain;
+        let starttls = if self.starttls && !self.tls {
+            "250-STARTTLS\n"
+        } else {
+            ""
+        };
+        // Only the mechanisms AUTH is checked with, CRAM-MD5 needing the passwords
+        // and sparing them the wire without TLS. Unchecked, AUTH is acknowledged.
+        let mechanisms = match (self.users.is_some(), self.xoauth2) {
+            (true, true) => "PLAIN LOGIN CRAM-MD5 XOAUTH2",
+            (true, false) => "PLAIN LOGIN CRAM-MD5",
+            (false, true) => "XOAUTH2",
+            (false, false) => "PLAIN LOGIN",
+        };
+        let max_size = self.max_size;
+        self.ehlo_greeting = format!(
+            "250-{domain} Hello {domain}\n250-PIPELINING\n250-ENHANCEDSTATUSCODES\n250-SIZE {max_size}\n250-8BITMIME\n250-SMTPUTF8\n250-CHUNKING\n{starttls}250 AUTH {mechanisms}\n"
+        );
+    }
+
+    /// Progress of the session
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
+    /// Name the client introduced itself with in HELO/EHLO/LHLO
+    pub fn helo(&self) -> Option<&str> {
+        self.helo.as_deref()
+    }
+
+    /// Reply to the end of the data of the received mail: once per recipient
+    /// in LMTP, which reports the outcome of each delivery separately
+    pub fn transaction_reply(&self, reply: Reply) -> Vec<u8> {
+        match &self.state {
+            State::Received(mail) if self.lmtp => reply.as_bytes().repeat(mail.to.len().max(1)),
+            _ => reply.as_bytes().to_vec(),
+        }
+    }
+
+    /// Ends the transaction of the received mail, once the frontend accepted
+    /// or rejected it, letting the client start another (RFC 5321 section 3.3)
+    pub fn end_transaction(&mut self) {
+        if matches!(self.state, State::Received(_)) {
+            self.state = State::Greeted;
+        }
+    }
+
+    /// Handles a single SMTP command and returns a proper SMTP response
+    pub fn handle_smtp(&mut self, raw_msg: &str) -> Result<&[u8], SmtpError> {
+        self.handle_line(raw_msg.as_bytes())
+    }
+
+    /// Handles a line as received, which need not be UTF-8 in the message data.
+    /// Commands the client got wrong are refused with a 5xx reply, and only
+    /// fail the session once there were too many of them.
+    pub fn handle_line(&mut self, line: &[u8]) -> Result<&[u8], SmtpError> {
+        self.reply = match self.handle_command(line) {
+            Ok(reply) => reply.to_vec(),
+            Err(
+                err @ (SmtpError::ProtocolViolation(_)
+                | SmtpError::UnknownCommand(_)
+                | SmtpError::Parse(_)),
+            ) => {
+                self.errors += 1;
+                tracing::debug!("Refusing command ({} errors): {err}", self.errors);
+                if self.errors > self.max_errors {
+                    return Err(SmtpError::TooManyErrors(self.errors));
+                }
+                err.reply().as_bytes().to_vec()
+            }
+            Err(err) => return Err(err),
+        };
+        Ok(&self.reply)
+    }
+
+    fn handle_command(&mut self, line: &[u8]) -> Result<&[u8], SmtpError> {
+        // Until the terminator, commands are just more of the message
+        if matches!(self.state, State::ReceivingData(_)) {
+            return Ok(self.receive_data(line));
+        }
+        let raw_msg = std::str::from_utf8(line)?;
+        // Responses to SASL challenges carry credentials, and aren't logged
+        if let Some(exchange) = self.sasl.take() {
+            let users = self.users.clone().unwrap_or_default();
+            let step = exchange.respond(&users, raw_msg.trim_end());
+            return Ok(self.sasl_step(step));
+        }
+        tracing::trace!("Received {raw_msg} in state {:?}", self.state);
+        let mut msg = raw_msg.split_whitespace();
+        let command = match msg.next() {
+            Some(command) => command.to_lowercase(),
+            None => return Err(SmtpError::Parse("received empty command".into())),
+        };
+        let state = self.state.clone();
+        match (command.as_str(), state) {
+            ("ehlo", State::Fresh) | ("lhlo", State::Fresh) => {
+                tracing::trace!("Sending AUTH info");
+                self.lmtp = command == "lhlo";
+                self.helo = msg.next().map(str::to_string);
+                self.state = State::Greeted;
+                Ok(self.ehlo_greeting.as_bytes())
+            }
+            ("helo", State::Fresh) => {
+                self.helo = msg.next().map(str::to_string);
+                self.state = State::Greeted;
+                Ok(StateMachine::KK.as_bytes())
+            }
+            ("noop", _) | ("help", _) | ("info", _) | ("vrfy", _) | ("expn", _) => {
+                tracing::trace!("Got {command}");
+                Ok(StateMachine::KK.as_bytes())
+            }
+            ("rset", state) => {
+                self.oversized = false;
+                // Only the transaction is reset: the client needn't greet again
+                if state != State::Fresh {
+                    self.state = State::Greeted;
+                }
+                Ok(StateMachine::KK.as_bytes())
+            }
+            ("starttls", State::Greeted) if self.starttls && !self.tls => {
+                tracing::trace!("Starting TLS");
+                Ok(StateMachine::GO_AHEAD_TLS.as_bytes())
+            }
+            ("auth", State::Greeted) if self.checks_auth() => {
+                if self.authenticated.is_some() {
+                    return Err(SmtpError::ProtocolViolation("already authenticated".into()));
+                }
+                let mechanism = msg
+                    .next()
+                    .ok_or_else(|| SmtpError::Parse("received empty AUTH".into()))?;
+                let offered = match mechanism.eq_ignore_ascii_case("xoauth2") {
+                    true => self.xoauth2,
+                    false => self.users.is_some(),
+                };
+                if !offered {
+                    return Ok(StateMachine::SAY_WHAT.as_bytes());
+                }
+                let cleartext = ["plain", "login"]
+                    .iter()
+                    .any(|cleartext| mechanism.eq_ignore_ascii_case(cleartext));
+                if cleartext && !self.tls && !self.allow_insecure_auth {
+                    tracing::debug!("Refusing AUTH {mechanism} without TLS");
+                    return Ok(StateMachine::ENCRYPT_FIRST.as_bytes());
+                }
+                let users = self.users.clone().unwrap_or_default();
+                let nonce = QueueId::generate();
+                match Exchange::start(mechanism, msg.next(), &users, nonce.as_str(), &self.domain) {
+                    Some(step) => Ok(self.sasl_step(step)),
+                    None => Ok(StateMachine::SAY_WHAT.as_bytes()),
+                }
+            }
+            ("auth", _) if self.checks_auth() => Err(SmtpError::ProtocolViolation(format!(
+                "AUTH not allowed in state {:?}",
+                self.state
+            ))),
+            ("auth", _) => {
+                tracing::trace!("Acknowledging AUTH");
+                Ok(StateMachine::AUTH_OK.as_bytes())
+            }
+            ("mail", State::Greeted) if self.require_auth && self.authenticated.is_none() => {
+                tracing::debug!("Refusing MAIL before AUTH");
+                Ok(StateMachine::LOG_IN_FIRST.as_bytes())
+            }
+            ("mail", State::Greeted) if self.refuses_helo() => {
+                Ok(StateMachine::BAD_HELO.as_bytes())
+            }
+            ("mail", State::Greeted) => {
+                tracing::trace!("Receiving MAIL");
+                let from = msg
+                    .next()
+                    .ok_or_else(|| SmtpError::Parse("received empty MAIL".into()))?;
+                let from = from
+                    .strip_prefix("FROM:")
+                    .ok_or_else(|| SmtpError::Parse("received incorrect MAIL".into()))?;
+                tracing::debug!("FROM: {from}");
+                for param in msg {
+                    // SMTPUTF8 takes no value: addresses are kept as sent, UTF-8 or not
+                    let Some((key, value)) = param.split_once('=') else {
+                        continue;
+                    };
+                    match key.to_lowercase().as_str() {
+                        "size" => {
+                            let size = value
+                                .parse::<usize>()
+                                .map_err(|_| SmtpError::Parse(format!("invalid SIZE {value}")))?;
+                            // Refused upfront, rather than after the client sent it all
+                            if size > self.max_size {
+                                tracing::info!("Refusing a message of {size} bytes");
+                                return Ok(StateMachine::TOO_BIG.as_bytes());
+                            }
+                        }
+                        // The data is kept as bytes either way (RFC 6152)
+                        "body"
+                            if value.eq_ignore_ascii_case("7bit")
+                                || value.eq_ignore_ascii_case("8bitmime") => {}
+                        "body" => {
+                            return Err(SmtpError::Parse(format!("unsupported BODY {value}")))
+                        }
+                        _ => {}
+                    }
+                }
+                if self.check_senders {
+                    self.sender_check = Some(from.to_string());
+                }
+                self.refused_sender = None;
+                self.rcpt_given = false;
+                self.state = State::ReceivingRcpt(Mail {
+                    from: from.to_string(),
+                    ..Default::default()
+                });
+                Ok(StateMachine::KK_SENDER.as_bytes())
+            }
+            ("rcpt", State::ReceivingRcpt(mut mail)) => {
+                tracing::trace!("Receiving rcpt");
+                let to = msg
+                    .next()
+                    .ok_or_else(|| SmtpError::Parse("received empty RCPT".into()))?;
+                let to = to
+                    .strip_prefix("TO:")
+                    .ok_or_else(|| SmtpError::Parse("received incorrect RCPT".into()))?;
+                tracing::debug!("TO: {to}");
+                self.rcpt_given = true;
+                if let Some(refusal) = self.refused_sender {
+                    tracing::info!("Refusing {to}, as the sender failed its checks");
+                    self.state = State::ReceivingRcpt(mail);
+                    return Ok(refusal.as_bytes());
+                }
+                if !self.accepts_recipient(to) {
+                    tracing::info!("Refusing {to}, of a domain that isn't ours");
+                    self.refused_recipients += 1;
+                    self.state = State::ReceivingRcpt(mail);
+                    return Ok(StateMachine::NOT_OURS.as_bytes());
+                }
+                if self.max_recipients.is_some_and(|max| mail.to.len() >= max) {
+                    tracing::info!("Refusing {to}, past {} recipients", mail.to.len());
+                    self.state = State::ReceivingRcpt(mail);
+                    return Ok(StateMachine::TOO_MANY_RECIPIENTS.as_bytes());
+                }
+                if !self.recipients.accepts(to) {
+                    tracing::info!("Refusing {to}, denied by the recipient rules");
+                    self.refused_recipients += 1;
+                    self.state = State::ReceivingRcpt(mail);
+                    return Ok(StateMachine::NO_SUCH_USER.as_bytes());
+                }
+                #[cfg(feature = "relay")]
+                if !self.has_mailbox(to) {
+                    tracing::info!("Refusing {to}, which has no alias");
+                    self.refused_recipients += 1;
+                    self.state = State::ReceivingRcpt(mail);
+                    return Ok(StateMachine::NO_SUCH_USER.as_bytes());
+                }
+                mail.to.push(to.to_string());
+                self.state = State::ReceivingRcpt(mail);
+                Ok(StateMachine::KK_RECIPIENT.as_bytes())
+            }
+            // No recipient yet, or all of them refused, e.g. for the rate of the client
+            // (RFC 5321 section 3.3)
+            ("data", State::ReceivingRcpt(mail)) if mail.to.is_empty() => {
+                self.state = State::ReceivingRcpt(mail);
+                Ok(self.no_recipients().as_bytes())
+            }
+            ("data", State::ReceivingRcpt(mail)) => {
+                tracing::trace!("Receiving data");
+                self.state = State::ReceivingData(mail);
+                self.line_start = true;
+                Ok(StateMachine::SEND_DATA_PLZ.as_bytes())
+            }
+            ("bdat", State::ReceivingRcpt(mail)) | ("bdat", State::ReceivingChunks(mail)) => {
+                let size = msg
+                    .next()
+                    .and_then(|size| size.parse::<usize>().ok())
+                    .ok_or_else(|| SmtpError::Parse("received incorrect BDAT".into()))?;
+                let last = msg
+                    .next()
+                    .is_some_and(|last| last.eq_ignore_ascii_case("last"));
+                tracing::trace!("Receiving a chunk of {size} bytes");
+                self.chunk = Some((size, last));
+                // Refused as DATA would be, the client sending the chunk all the same
+                if mail.to.is_empty() {
+                    self.refused_chunk = Some(self.no_recipients());
+                    self.state = State::ReceivingRcpt(mail);
+                    return Ok(StateMachine::HOLD_YOUR_HORSES);
+                }
+                self.state = State::ReceivingChunks(mail);
+                Ok(StateMachine::HOLD_YOUR_HORSES)
+            }
+            ("quit", _) => {
+                tracing::trace!("Got quit");
+                Ok(StateMachine::KTHXBYE.as_bytes())
+            }
+            (msg, state) if StateMachine::COMMANDS.contains(&msg) => Err(
+                SmtpError::ProtocolViolation(format!("{msg} not allowed in state {state:?}")),
+            ),
+            (msg, _) => Err(SmtpError::UnknownCommand(msg.to_string())),
+        }
+    }
+
+    /// Reply to DATA or BDAT for a mail without recipients: out of sequence
+    /// without RCPT, or with no valid recipient once they were all refused
+    fn no_recipients(&self) -> Reply {
+        match self.rcpt_given {
+            true => StateMachine::NO_VALID_RECIPIENTS,
+            false => StateMachine::NOT_NOW,
+        }
+    }
+
+    /// Takes lines of message data up to the terminator, undoing dot-stuffing
+    /// (RFC 5321 section 4.5.2). Frontends hand over one line at a time, anything
+    /// following the terminator in the same call is dropped.
+    fn receive_data(&mut self, mut data: &[u8]) -> &[u8] {
+        tracing::trace!("Receiving data");
+        let State::ReceivingData(mail) = &mut self.state else {
+            return StateMachine::HOLD_YOUR_HORSES;
+        };
+        while !data.is_empty() {
+            let end = data
+                .iter()
+                .position(|&byte| byte == b'\n')
+                .map_or(data.len(), |end| end + 1);
+            let (line, rest) = data.split_at(end);
+            data = rest;
+            let line_start = std::mem::replace(&mut self.line_start, line.ends_with(b"\r\n"));
+            if line_start && line == b".\r\n" {
+                if !data.is_empty() {
+                    tracing::warn!("Dropping {} bytes after the terminator", data.len());
+                }
+                return self.end_data();
+            }
+            if self.oversized {
+                continue;
+            }
+            let line = match line.strip_prefix(b".") {
+                Some(unstuffed) if line_start => unstuffed,
+                _ => line,
+            };
+            mail.data.extend_from_slice(line);
+            if mail.data.len() > self.max_size {
+                // Keep reading up to the terminator, without keeping the data
+                self.oversized = true;
+                mail.data = Vec::new();
+            }
+        }
+        StateMachine::HOLD_YOUR_HORSES
+    }
+
+    /// Ends the message data on its terminator, returning the reply
+    fn end_data(&mut self) -> &[u8] {
+        let State::ReceivingData(mut mail) = std::mem::replace(&mut self.state, State::Greeted)
+        else {
+            return StateMachine::HOLD_YOUR_HORSES;
+        };
+        if self.oversized {
+            tracing::warn!("Refused a message larger than {} bytes", self.max_size);
+            self.oversized = false;
+            let replies = if self.lmtp { mail.to.len().max(1) } else { 1 };
+            self.data_reply = StateMachine::TOO_BIG.as_bytes().repeat(replies);
+            return &self.data_reply;
+        }
+        // Kept with the terminator, as it was sent
+        mail.data.extend_from_slice(b".\r\n");
+        tracing::debug!(
+            "Received data: FROM: {} TO: {} ({} bytes)",
+            mail.from,
+            mail.to.join(", "),
+            mail.data.len()
+        );
+        self.state = State::Received(mail);
+        self.data_reply = self.transaction_reply(StateMachine::KK_QUEUED);
+        &self.data_reply
+    }
+
+    fn sasl_step(&mut self, step: Step) -> &[u8] {
+        match step {
+            Step::Continue(exchange, challenge) => {
+                self.sasl = Some(exchange);
+                self.challenge = format!("334 {challenge}\n").into_bytes();
+                &self.challenge
+            }
+            Step::Token { user, token } => {
+                self.token_check = Some((user, token));
+                StateMachine::HOLD_YOUR_HORSES
+            }
+            Step::Success(user) => {
+                tracing::info!("Authenticated as {user}");
+                self.authenticated = Some(user);
+                StateMachine::AUTH_OK.as_bytes()
+            }
+            Step::Failure => {
+                tracing::warn!("Authentication failed");
+                StateMachine::WHO_ARE_YOU.as_bytes()
+            }
+            Step::Cancelled => StateMachine::NEVER_MIND.as_bytes(),
+        }
+    }
+}
+
+/// Takes responsibility for the mail a session receives
+pub trait Acceptor: Send + Sync {
+    /// Persists a received mail, returning its queue ids, more than one when it
+    /// was split, e.g. between tenants. Only once this succeeds is the mail
+    /// acknowledged to the client, which waits for the reply until `deadline`.
+    fn accept(
+        &self,
+        mail: &Mail,
+        deadline: Instant,
+    ) -> impl Future<Output = anyhow::Result<Vec<QueueId>>> + Send;
+
+    /// Whether to drop the client connection now, for fault injection
+    fn disconnect(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::Engine;
+
+    use crate::recipients::parse;
+
+    use super::*;
+
+    #[test]
+    fn test_regular_flow() {
+        let mut sm = StateMachine::new("dummy");
+        assert_eq!(sm.state, State::Fresh);
+        sm.handle_smtp("HELO localhost").unwrap();
+        assert_eq!(sm.state, State::Greeted);
+        assert_eq!(sm.helo.as_deref(), Some("localhost"));
+        sm.handle_smtp("MAIL FROM: <local@example.com>").unwrap();
+        assert!(matches!(sm.state, State::ReceivingRcpt(_)));
+        sm.handle_smtp("RCPT TO: <a@localhost.com>").unwrap();
+        assert!(matches!(sm.state, State::ReceivingRcpt(_)));
+        sm.handle_smtp("RCPT TO: <b@localhost.com>").unwrap();
+        assert!(matches!(sm.state, State::ReceivingRcpt(_)));
+        sm.handle_smtp("DATA hello world\n").unwrap();
+        assert!(matches!(sm.state, State::ReceivingData(_)));
+        sm.handle_smtp("DATA hello world2\n").unwrap();
+        assert!(matches!(sm.state, State::ReceivingData(_)));
+        // Part of the message, not the end of the session
+        sm.handle_smtp("QUIT\r\n").unwrap();
+        assert!(matches!(sm.state, State::ReceivingData(_)));
+        sm.handle_smtp(".\r\n").unwrap();
+        let State::Received(mail) = &sm.state else {
+            panic!("mail not received: {:?}", sm.state);
+        };
+        assert!(mail.data.ends_with(b"QUIT\r\n.\r\n"));
+    }
+
+    #[test]
+    fn test_received_on_terminator() {
+        let mut sm = StateMachine::new("dummy");
+        sm.handle_smtp("HELO localhost").unwrap();
+        sm.handle_smtp("MAIL FROM:<local@example.com>").unwrap();
+        sm.handle_smtp("RCPT TO:<a@localhost.com>").unwrap();
+        sm.handle_smtp("DATA").unwrap();
+        let resp = sm.handle_smtp("Subject: hi\r\n\r\nhello\r\n").unwrap();
+        assert_eq!(resp, StateMachine::HOLD_YOUR_HORSES);
+        assert!(matches!(sm.state, State::ReceivingData(_)));
+        let resp = sm.handle_smtp("bye\r\n.\r\n").unwrap();
+        assert_eq!(resp, StateMachine::KK_QUEUED.as_bytes());
+        let State::Received(mail) = &sm.state else {
+            panic!("mail not received: {:?}", sm.state);
+        };
+        assert_eq!(mail.data, b"Subject: hi\r\n\r\nhello\r\nbye\r\n.\r\n");
+    }
+
+    #[test]
+    fn test_multiple_transactions() {
+        let mut sm = StateMachine::new("dummy");
+        sm.handle_smtp("EHLO localhost").unwrap();
+        for subject in ["first", "second"] {
+            sm.handle_smtp("MAIL FROM:<local@example.com>").unwrap();
+            sm.handle_smtp("RCPT TO:<a@localhost.com>").unwrap();
+            sm.handle_smtp("DATA").unwrap();
+            let resp = sm
+                .handle_smtp(&format!("Subject: {subject}\r\n.\r\n"))
+                .unwrap();
+            assert_eq!(resp, StateMachine::KK_QUEUED.as_bytes());
+            assert!(matches!(sm.state, State::Received(_)));
+            sm.end_transaction();
+            assert_eq!(sm.state, State::Greeted);
+        }
+        assert_eq!(sm.helo(), Some("localhost"));
+    }
+
+    #[test]
+    fn test_dot_stuffing() {
+        let mut sm = StateMachine::new("dummy");
+        sm.handle_smtp("HELO localhost").unwrap();
+        sm.handle_smtp("MAIL FROM:<local@example.com>").unwrap();
+        sm.handle_smtp("RCPT TO:<a@localhost.com>").unwrap();
+        sm.handle_smtp("DATA").unwrap();
+        for line in ["..\r\n", "...hi\r\n", "a.\r\n", "bare\n", ".\r\n"] {
+            let resp = sm.handle_smtp(line).unwrap();
+            assert_eq!(resp, StateMachine::HOLD_YOUR_HORSES);
+        }
+        // Several lines at once, the terminator among them
+        let resp = sm.handle_smtp("..\r\nbye\r\n.\r\n").unwrap();
+        assert_eq!(resp, StateMachine::KK_QUEUED.as_bytes());
+        let State::Received(mail) = &sm.state else {
+            panic!("mail not received: {:?}", sm.state);
+        };
+        // The dot after a bare LF isn't at the start of a line
+        assert_eq!(
+            mail.data,
+            b".\r\n..hi\r\na.\r\nbare\n.\r\n.\r\nbye\r\n.\r\n"
+        );
+    }
+
+    #[test]
+    fn test_rset_keeps_greeting() {
+        let mut sm = StateMachine::new("dummy");
+        sm.handle_smtp("RSET").unwrap();
+        assert_eq!(sm.state, State::Fresh);
+        sm.handle_smtp("EHLO localhost").unwrap();
+        sm.handle_smtp("MAIL FROM:<local@example.com>").unwrap();
+        sm.handle_smtp("RSET").unwrap();
+        assert_eq!(sm.state, State::Greeted);
+        assert_eq!(sm.helo(), Some("localhost"));
+        let resp = sm.handle_smtp("MAIL FROM:<local@example.com>").unwrap();
+        assert_eq!(resp, StateMachine::KK_SENDER.as_bytes());
+    }
+
+    #[test]
+    fn test_lmtp_replies_per_recipient() {
+        let mut sm = StateMachine::new("dummy");
+        sm.handle_smtp("LHLO localhost").unwrap();
+        sm.handle_smtp("MAIL FROM:<local@example.com>").unwrap();
+        sm.handle_smtp("RCPT TO:<a@localhost.com>").unwrap();
+        sm.handle_smtp("RCPT TO:<b@localhost.com>").unwrap();
+        sm.handle_smtp("DATA").unwrap();
+        let resp = sm.handle_smtp("Subject: hi\r\n\r\nhello\r\n.\r\n").unwrap();
+        assert_eq!(resp, b"250 2.6.0 Ok\n250 2.6.0 Ok\n");
+        assert_eq!(
+            sm.transaction_reply(StateMachine::UH_OH),
+            StateMachine::UH_OH.as_bytes().repeat(2)
+        );
+    }
+
+    #[test]
+    fn test_ehlo_advertises_pipelining() {
+        let mut sm = StateMachine::new("dummy");
+        let reply = String::from_utf8(sm.handle_smtp("EHLO localhost").unwrap().to_vec()).unwrap();
+        assert!(reply.lines().any(|line| line == "250-PIPELINING"));
+    }
+
+    #[test]
+    fn test_enhanced_status_codes() {
+        let mut sm = StateMachine::new("dummy");
+        assert_eq!(StateMachine::OH_HAI.as_bytes(), b"220 edgemail\n");
+        let reply = String::from_utf8(sm.handle_smtp("EHLO localhost").unwrap().to_vec()).unwrap();
+        assert!(reply.lines().any(|line| line == "250-ENHANCEDSTATUSCODES"));
+        let reply = sm.handle_smtp("MAIL FROM:<a@example.com>").unwrap();
+        assert_eq!(reply, b"250 2.1.0 Ok\n");
+        let reply = sm.handle_smtp("RCPT TO:<b@example.com>").unwrap();
+        assert_eq!(reply, b"250 2.1.5 Ok\n");
+        assert_eq!(StateMachine::TOO_BIG.code, 552);
+        assert_eq!(StateMachine::TOO_BIG.status, "5.3.4");
+        assert_eq!(
+            StateMachine::TOO_BIG.to_string(),
+            "552 5.3.4 Message size exceeds fixed maximum message size"
+        );
+    }
+
+    #[test]
+    fn test_starttls_starts_over() {
+        let mut sm = StateMachine::new("dummy");
+        let reply = String::from_utf8(sm.handle_smtp("EHLO localhost").unwrap().to_vec()).unwrap();
+        assert!(!reply.contains("STARTTLS"));
+        assert_eq!(
+            sm.handle_smtp("STARTTLS").unwrap(),
+            StateMachine::NOT_NOW.as_bytes()
+        );
+
+        let mut sm = StateMachine::new("dummy");
+        sm.offer_starttls(true);
+        let reply = String::from_utf8(sm.handle_smtp("EHLO localhost").unwrap().to_vec()).unwrap();
+        assert!(reply.lines().any(|line| line == "250-STARTTLS"));
+        assert_eq!(
+            sm.handle_smtp("STARTTLS").unwrap(),
+            StateMachine::GO_AHEAD_TLS.as_bytes()
+        );
+        sm.tls_started();
+        assert_eq!(sm.state, State::Fresh);
+        assert_eq!(sm.helo, None);
+        let reply = String::from_utf8(sm.handle_smtp("EHLO localhost").unwrap().to_vec()).unwrap();
+        assert!(!reply.contains("STARTTLS"));
+        assert_eq!(
+            sm.handle_smtp("STARTTLS").unwrap(),
+            StateMachine::NOT_NOW.as_bytes()
+        );
+    }
+
+    #[test]
+    fn test_auth_xoauth2_only() {
+        let mut sm = StateMachine::new("dummy");
+        sm.offer_xoauth2(true);
+        let reply = String::from_utf8(sm.handle_smtp("EHLO localhost").unwrap().to_vec()).unwrap();
+        assert!(reply.ends_with("250 AUTH XOAUTH2\n"));
+        let plain = base64::engine::general_purpose::STANDARD.encode("\0tim\0secret");
+        assert_eq!(
+            sm.handle_smtp(&format!("AUTH PLAIN {plain}")).unwrap(),
+            StateMachine::SAY_WHAT.as_bytes()
+        );
+        assert_eq!(
+            sm.handle_smtp("AUTH LOGIN").unwrap(),
+            StateMachine::SAY_WHAT.as_bytes()
+        );
+        assert!(sm.authenticated().is_none());
+    }
+
+    #[test]
+    fn test_auth_cram_md5() {
+        let mut sm = StateMachine::new("dummy");
+        let reply = String::from_utf8(sm.handle_smtp("EHLO localhost").unwrap().to_vec()).unwrap();
+        assert!(reply.ends_with("250 AUTH PLAIN LOGIN\n"));
+
+        let mut sm = StateMachine::new("dummy");
+        sm.check_auth(Arc::new(Users::parse(r#"{"tim": "secret"}"#).unwrap()));
+        let reply = String::from_utf8(sm.handle_smtp("EHLO localhost").unwrap().to_vec()).unwrap();
+        assert!(reply.ends_with("250 AUTH PLAIN LOGIN CRAM-MD5\n"));
+        let reply = String::from_utf8(sm.handle_smtp("AUTH CRAM-MD5").unwrap().to_vec()).unwrap();
+        let base64 = base64::engine::general_purpose::STANDARD;
+        let challenge = base64
+            .decode(reply.strip_prefix("334 ").unwrap().trim_end())
+            .unwrap();
+        assert!(challenge.ends_with(b"@dummy>"));
+        // A wrong digest, computed with another password
+        let response = base64.encode("tim 00112233445566778899aabbccddeeff");
+        assert_eq!(
+            sm.handle_smtp(&format!("{response}\r\n")).unwrap(),
+            StateMachine::WHO_ARE_YOU.as_bytes()
+        );
+        assert_eq!(sm.authenticated(), None);
+        assert_eq!(
+            sm.handle_smtp("AUTH KERBEROS_V4").unwrap(),
+            StateMachine::SAY_WHAT.as_bytes()
+        );
+        let plain = base64.encode("\0tim\0secret");
+        assert_eq!(
+            sm.handle_smtp(&format!("AUTH PLAIN {plain}")).unwrap(),
+            StateMachine::ENCRYPT_FIRST.as_bytes()
+        );
+        assert_eq!(
+            sm.handle_smtp("AUTH LOGIN").unwrap(),
+            StateMachine::ENCRYPT_FIRST.as_bytes()
+        );
+        assert_eq!(sm.authenticated(), None);
+        // Over TLS, the client greeting again
+        sm.tls_started();
+        sm.handle_smtp("EHLO localhost").unwrap();
+        assert_eq!(
+            sm.handle_smtp(&format!("AUTH PLAIN {plain}")).unwrap(),
+            StateMachine::AUTH_OK.as_bytes()
+        );
+        assert_eq!(sm.authenticated(), Some("tim"));
+
+        let mut sm = StateMachine::new("dummy");
+        sm.check_auth(Arc::new(Users::parse(r#"{"tim": "secret"}"#).unwrap()));
+        sm.allow_insecure_auth(true);
+        sm.handle_smtp("EHLO localhost").unwrap();
+        assert_eq!(
+            sm.handle_smtp(&format!("AUTH PLAIN {plain}")).unwrap(),
+            StateMachine::AUTH_OK.as_bytes()
+        );
+    }
+
+    #[test]
+    fn test_submission_requires_auth() {
+        let mut sm = StateMachine::new("dummy");
+        sm.check_auth(Arc::new(Users::parse(r#"{"tim": "secret"}"#).unwrap()));
+        sm.allow_insecure_auth(true);
+        sm.require_auth(true);
+        sm.handle_smtp("EHLO localhost").unwrap();
+        assert_eq!(
+            sm.handle_smtp("MAIL FROM:<tim@example.com>").unwrap(),
+            StateMachine::LOG_IN_FIRST.as_bytes()
+        );
+        assert_eq!(sm.state, State::Greeted);
+        let plain = base64::engine::general_purpose::STANDARD.encode("\0tim\0secret");
+        sm.handle_smtp(&format!("AUTH PLAIN {plain}")).unwrap();
+        assert_eq!(
+            sm.handle_smtp("MAIL FROM:<tim@example.com>").unwrap(),
+            StateMachine::KK_SENDER.as_bytes()
+        );
+    }
+
+    #[test]
+    fn test_refused_sender() {
+        let mut sm = StateMachine::new("dummy");
+        sm.check_senders(true);
+        sm.handle_smtp("EHLO localhost").unwrap();
+        sm.handle_smtp("MAIL FROM:<spoofed@example.com>").unwrap();
+        assert_eq!(
+            sm.take_sender_check().as_deref(),
+            Some("<spoofed@example.com>")
+        );
+        assert_eq!(sm.take_sender_check(), None);
+        sm.sender_checked(Some(StateMachine::NOT_FROM_THERE));
+        assert_eq!(
+            sm.handle_smtp("RCPT TO:<a@localhost.com>").unwrap(),
+            StateMachine::NOT_FROM_THERE.as_bytes()
+        );
+        let State::ReceivingRcpt(mail) = &sm.state else {
+            panic!("transaction ended: {:?}", sm.state);
+        };
+        assert!(mail.to.is_empty());
+        assert_eq!(
+            sm.handle_smtp("DATA").unwrap(),
+            StateMachine::NO_VALID_RECIPIENTS.as_bytes()
+        );
+
+        // The next mail is checked on its own
+        sm.handle_smtp("RSET").unwrap();
+        sm.handle_smtp("MAIL FROM:<local@example.com>").unwrap();
+        assert!(sm.take_sender_check().is_some());
+        sm.sender_checked(None);
+        assert_eq!(
+            sm.handle_smtp("RCPT TO:<a@localhost.com>").unwrap(),
+            StateMachine::KK_RECIPIENT.as_bytes()
+        );
+    }
+
+    #[test]
+    fn test_helo_policy() {
+        let mut sm = StateMachine::new("mx.example.org");
+        sm.set_helo_policy(HeloPolicy {
+            reject_ip: true,
+            reject_own: Some("mx.example.org".into()),
+            require_fqdn: true,
+        });
+        sm.handle_smtp("EHLO mx.example.org").unwrap();
+        assert_eq!(sm.helo(), Some("mx.example.org"));
+        assert_eq!(
+            sm.handle_smtp("MAIL FROM:<spoofed@example.org>").unwrap(),
+            StateMachine::BAD_HELO.as_bytes()
+        );
+        assert_eq!(sm.state, State::Greeted);
+
+        let mut sm = StateMachine::new("mx.example.org");
+        sm.set_helo_policy(HeloPolicy {
+            require_fqdn: true,
+            ..Default::default()
+        });
+        sm.handle_smtp("HELO mail.example.com").unwrap();
+        assert_eq!(
+            sm.handle_smtp("MAIL FROM:<a@example.com>").unwrap(),
+            StateMachine::KK_SENDER.as_bytes()
+        );
+
+        // Authenticated clients greet as they like
+        let mut sm = StateMachine::new("mx.example.org");
+        sm.check_auth(Arc::new(Users::parse(r#"{"tim": "secret"}"#).unwrap()));
+        sm.allow_insecure_auth(true);
+        sm.set_helo_policy(HeloPolicy {
+            require_fqdn: true,
+            ..Default::default()
+        });
+        sm.handle_smtp("EHLO laptop").unwrap();
+        assert_eq!(
+            sm.handle_smtp("MAIL FROM:<tim@example.com>").unwrap(),
+            StateMachine::BAD_HELO.as_bytes()
+        );
+        let plain = base64::engine::general_purpose::STANDARD.encode("\0tim\0secret");
+        sm.handle_smtp(&format!("AUTH PLAIN {plain}")).unwrap();
+        assert_eq!(
+            sm.handle_smtp("MAIL FROM:<tim@example.com>").unwrap(),
+            StateMachine::KK_SENDER.as_bytes()
+        );
+    }
+
+    #[test]
+    fn test_max_recipients() {
+        let mut sm = StateMachine::new("mx.example.org");
+        sm.set_max_recipients(2);
+        sm.handle_smtp("EHLO mail.example.com").unwrap();
+        sm.handle_smtp("MAIL FROM:<a@example.com>").unwrap();
+        for (to, reply) in [
+            ("<b@example.org>", StateMachine::KK_RECIPIENT),
+            ("<c@example.org>", StateMachine::KK_RECIPIENT),
+            ("<d@example.org>", StateMachine::TOO_MANY_RECIPIENTS),
+        ] {
+            let resp = sm.handle_smtp(&format!("RCPT TO:{to}")).unwrap();
+            assert_eq!(resp, reply.as_bytes());
+        }
+        sm.handle_smtp("RSET").unwrap();
+        sm.handle_smtp("MAIL FROM:<a@example.com>").unwrap();
+        assert_eq!(
+            sm.handle_smtp("DATA").unwrap(),
+            StateMachine::NOT_NOW.as_bytes()
+        );
+        assert_eq!(
+            sm.handle_smtp("RCPT TO:<d@example.org>").unwrap(),
+            StateMachine::KK_RECIPIENT.as_bytes()
+        );
+    }
+
+    #[test]
+    fn test_recipients() {
+        let mut sm = StateMachine::new("mx.example.org");
+        sm.handle_smtp("EHLO mail.example.com").unwrap();
+        sm.handle_smtp("MAIL FROM:<a@example.com>").unwrap();
+        assert_eq!(
+            sm.handle_smtp("RCPT TO:<hostmaster@example.org>").unwrap(),
+            StateMachine::NO_SUCH_USER.as_bytes()
+        );
+
+        let rules = "allow hostmaster@example.org\ndeny *@example.org";
+        sm.set_recipients(Arc::new(Recipients::new(parse(rules).unwrap())));
+        for (to, reply) in [
+            ("<hostmaster@example.org>", StateMachine::KK_RECIPIENT),
+            ("<b@example.org>", StateMachine::NO_SUCH_USER),
+            ("<c@example.net>", StateMachine::KK_RECIPIENT),
+        ] {
+            let resp = sm.handle_smtp(&format!("RCPT TO:{to}")).unwrap();
+            assert_eq!(resp, reply.as_bytes());
+        }
+        let State::ReceivingRcpt(mail) = &sm.state else {
+            panic!("not receiving recipients: {:?}", sm.state);
+        };
+        assert_eq!(mail.to, ["<hostmaster@example.org>", "<c@example.net>"]);
+        assert_eq!(sm.refused_recipients(), 2);
+    }
+
+    #[cfg(feature = "relay")]
+    #[test]
+    fn test_aliases() {
+        let mut sm = StateMachine::new("fwd.example");
+        sm.set_aliases(Arc::new(
+            Aliases::parse("hello@fwd.example me@gmail.com").unwrap(),
+        ));
+        sm.handle_smtp("EHLO mail.example.com").unwrap();
+        sm.handle_smtp("MAIL FROM:<a@example.com>").unwrap();
+        for (to, reply) in [
+            ("<Hello@fwd.example>", StateMachine::KK_RECIPIENT),
+            ("<nobody@fwd.example>", StateMachine::NO_SUCH_USER),
+            (
+                "<SRS0=hash=tt=example.com=a@fwd.example>",
+                StateMachine::KK_RECIPIENT,
+            ),
+        ] {
+            let resp = sm.handle_smtp(&format!("RCPT TO:{to}")).unwrap();
+            assert_eq!(resp, reply.as_bytes(), "{to}");
+        }
+        assert_eq!(sm.refused_recipients(), 1);
+    }
+
+    #[test]
+    fn test_domains() {
+        let mut sm = StateMachine::new("mx.example.org");
+        sm.check_auth(Arc::new(Users::parse(r#"{"tim": "secret"}"#).unwrap()));
+        sm.allow_insecure_auth(true);
+        sm.set_domains(Domains::new("example.org", "example.net"));
+        sm.handle_smtp("EHLO mail.example.com").unwrap();
+        sm.handle_smtp("MAIL FROM:<a@example.com>").unwrap();
+        for (to, reply) in [
+            ("<b@example.org>", StateMachine::KK_RECIPIENT),
+            ("<c@example.net>", StateMachine::KK_RECIPIENT),
+            ("<d@example.com>", StateMachine::NOT_OURS),
+        ] {
+            let resp = sm.handle_smtp(&format!("RCPT TO:{to}")).unwrap();
+            assert_eq!(resp, reply.as_bytes());
+        }
+        let State::ReceivingRcpt(mail) = &sm.state else {
+            panic!("not receiving recipients: {:?}", sm.state);
+        };
+        assert_eq!(mail.to, ["<b@example.org>", "<c@example.net>"]);
+
+        // Authenticated clients relay anywhere
+        sm.handle_smtp("RSET").unwrap();
+        let plain = base64::engine::general_purpose::STANDARD.encode("\0tim\0secret");
+        sm.handle_smtp(&format!("AUTH PLAIN {plain}")).unwrap();
+        sm.handle_smtp("MAIL FROM:<tim@example.org>").unwrap();
+        assert_eq!(
+            sm.handle_smtp("RCPT TO:<d@example.com>").unwrap(),
+            StateMachine::KK_RECIPIENT.as_bytes()
+        );
+    }
+
+    #[test]
+    fn test_size_limit() {
+        let mut sm = StateMachine::new("dummy");
+        sm.set_max_size(100);
+        let reply = String::from_utf8(sm.handle_smtp("EHLO localhost").unwrap().to_vec()).unwrap();
+        assert!(reply.lines().any(|line| line == "250-SIZE 100"));
+        assert_eq!(
+            sm.handle_smtp("MAIL FROM:<a@example.com> SIZE=101")
+                .unwrap(),
+            StateMachine::TOO_BIG.as_bytes()
+        );
+        assert_eq!(sm.state, State::Greeted);
+        sm.handle_smtp("MAIL FROM:<a@example.com> SIZE=50").unwrap();
+        sm.handle_smtp("RCPT TO:<b@example.com>").unwrap();
+        sm.handle_smtp("DATA").unwrap();
+        // Lies about the size
+        for _ in 0..5 {
+            let resp = sm.handle_smtp(&format!("{}\r\n", "x".repeat(30))).unwrap();
+            assert_eq!(resp, StateMachine::HOLD_YOUR_HORSES);
+        }
+        assert_eq!(
+            sm.handle_smtp(".\r\n").unwrap(),
+            StateMachine::TOO_BIG.as_bytes()
+        );
+        assert_eq!(sm.state, State::Greeted);
+        // The session goes on
+        sm.handle_smtp("MAIL FROM:<a@example.com>").unwrap();
+        sm.handle_smtp("RCPT TO:<b@example.com>").unwrap();
+        sm.handle_smtp("DATA").unwrap();
+        sm.handle_smtp("hi\r\n").unwrap();
+        assert_eq!(
+            sm.handle_smtp(".\r\n").unwrap(),
+            StateMachine::KK_QUEUED.as_bytes()
+        );
+    }
+
+    #[test]
+    fn test_size_limit_boundary() {
+        // The limit is on the message, not counting the terminator or dot-stuffing
+        let received = |lines: &[&str]| {
+            let mut sm = StateMachine::new("dummy");
+            sm.set_max_size(10);
+            sm.handle_smtp("EHLO localhost").unwrap();
+            sm.handle_smtp("MAIL FROM:<a@example.com> SIZE=10").unwrap();
+            sm.handle_smtp("RCPT TO:<b@example.com>").unwrap();
+            sm.handle_smtp("DATA").unwrap();
+            for line in lines {
+                sm.handle_smtp(line).unwrap();
+            }
+            sm.handle_smtp(".\r\n").unwrap().to_vec()
+        };
+        assert_eq!(
+            received(&["12345678\r\n"]),
+            StateMachine::KK_QUEUED.as_bytes()
+        );
+        assert_eq!(
+            received(&["..2345678\r\n"]),
+            StateMachine::KK_QUEUED.as_bytes()
+        );
+        assert_eq!(
+            received(&["1234\r\n", "5678\r\n"]),
+            StateMachine::TOO_BIG.as_bytes()
+        );
+        assert_eq!(
+            received(&["123456789\r\n"]),
+            StateMachine::TOO_BIG.as_bytes()
+        );
+
+        let chunked = |size: usize| {
+            let mut sm = StateMachine::new("dummy");
+            sm.set_max_size(10);
+            sm.handle_smtp("EHLO localhost").unwrap();
+            sm.handle_smtp("MAIL FROM:<a@example.com>").unwrap();
+            sm.handle_smtp("RCPT TO:<b@example.com>").unwrap();
+            sm.handle_smtp(&format!("BDAT {size} LAST")).unwrap();
+            assert_eq!(sm.take_chunk(), Some((size, true)));
+            sm.receive_chunk(&[b'x'; 64][..size - 2]);
+            sm.receive_chunk(b"\r\n");
+            sm.end_chunk(true).to_vec()
+        };
+        assert_eq!(chunked(10), StateMachine::KK_QUEUED.as_bytes());
+        assert_eq!(chunked(11), StateMachine::TOO_BIG.as_bytes());
+    }
+
+    #[test]
+    fn test_8bit_data() {
+        let mut sm = StateMachine::new("dummy");
+        let reply = String::from_utf8(sm.handle_smtp("EHLO localhost").unwrap().to_vec()).unwrap();
+        assert!(reply.lines().any(|line| line == "250-8BITMIME"));
+        sm.handle_smtp("MAIL FROM:<a@example.com> BODY=8BITMIME")
+            .unwrap();
+        sm.handle_smtp("RCPT TO:<b@example.com>").unwrap();
+        sm.handle_smtp("DATA").unwrap();
+        // Latin-1, not UTF-8
+        sm.handle_line(b"caf\xe9\r\n").unwrap();
+        assert_eq!(
+            sm.handle_line(b".\r\n").unwrap(),
+            StateMachine::KK_QUEUED.as_bytes()
+        );
+        let State::Received(mail) = &sm.state else {
+            panic!("mail not received: {:?}", sm.state);
+        };
+        assert_eq!(mail.data, b"caf\xe9\r\n.\r\n");
+        // Journaled as base64, as it isn't text
+        let json = serde_json::to_string(mail).unwrap();
+        assert!(json.contains(r#""data":{"base64":"#), "{json}");
+        assert_eq!(&serde_json::from_str::<Mail>(&json).unwrap(), mail);
+        let text = Mail {
+            data: b"hi".to_vec(),
+            ..Default::default()
+        };
+        let json = serde_json::to_string(&text).unwrap();
+        assert!(json.contains(r#""data":"hi""#), "{json}");
+        assert_eq!(serde_json::from_str::<Mail>(&json).unwrap(), text);
+    }
+
+    #[test]
+    fn test_smtputf8() {
+        let mut sm = StateMachine::new("dummy");
+        let reply = String::from_utf8(sm.handle_smtp("EHLO localhost").unwrap().to_vec()).unwrap();
+        assert!(reply.lines().any(|line| line == "250-SMTPUTF8"));
+        sm.handle_smtp("MAIL FROM:<josé@exämple.com> SMTPUTF8\r\n")
+            .unwrap();
+        sm.handle_smtp("RCPT TO:<用户@例子.广告>\r\n").unwrap();
+        let State::ReceivingRcpt(mail) = &sm.state else {
+            panic!("MAIL refused: {:?}", sm.state);
+        };
+        assert_eq!(mail.from, "<josé@exämple.com>");
+        assert_eq!(mail.to, ["<用户@例子.广告>"]);
+        assert!(mail.internationalized());
+    }
+
+    #[test]
+    fn test_bdat() {
+        let mut sm = StateMachine::new("dummy");
+        let reply = String::from_utf8(sm.handle_smtp("EHLO localhost").unwrap().to_vec()).unwrap();
+        assert!(reply.lines().any(|line| line == "250-CHUNKING"));
+        sm.handle_smtp("MAIL FROM:<a@example.com>").unwrap();
+        sm.handle_smtp("RCPT TO:<b@example.com>").unwrap();
+        for (chunk, last) in [("Subject: hi\r\n", false), ("\r\n.hello", true)] {
+            let command = format!(
+                "BDAT {}{}\r\n",
+                chunk.len(),
+                if last { " LAST" } else { "" }
+            );
+            let resp = sm.handle_smtp(&command).unwrap();
+            assert_eq!(resp, StateMachine::HOLD_YOUR_HORSES);
+            assert_eq!(sm.take_chunk(), Some((chunk.len(), last)));
+            sm.receive_chunk(chunk.as_bytes());
+            let reply = if last {
+                StateMachine::KK_QUEUED
+            } else {
+                StateMachine::KK
+            };
+            assert_eq!(sm.end_chunk(last), reply.as_bytes());
+        }
+        let State::Received(mail) = &sm.state else {
+            panic!("mail not received: {:?}", sm.state);
+        };
+        // Not dot-stuffed
+        assert_eq!(mail.data, b"Subject: hi\r\n\r\n.hello\r\n.\r\n");
+    }
+
+    #[test]
+    fn test_bdat_without_recipients() {
+        let mut sm = StateMachine::new("dummy");
+        sm.set_max_recipients(0);
+        sm.handle_smtp("EHLO localhost").unwrap();
+        sm.handle_smtp("MAIL FROM:<a@example.com>").unwrap();
+        let send = |sm: &mut StateMachine| {
+            let resp = sm.handle_smtp("BDAT 5 LAST").unwrap();
+            assert_eq!(resp, StateMachine::HOLD_YOUR_HORSES);
+            // The chunk is read all the same, and discarded
+            assert_eq!(sm.take_chunk(), Some((5, true)));
+            sm.receive_chunk(b"hello");
+            let reply = sm.end_chunk(true).to_vec();
+            assert!(matches!(&sm.state, State::ReceivingRcpt(mail) if mail.data.is_empty()));
+            reply
+        };
+        assert_eq!(send(&mut sm), StateMachine::NOT_NOW.as_bytes());
+        sm.handle_smtp("RCPT TO:<b@example.com>").unwrap();
+        assert_eq!(send(&mut sm), StateMachine::NO_VALID_RECIPIENTS.as_bytes());
+    }
+
+    #[test]
+    fn test_no_greeting() {
+        let mut sm = StateMachine::new("dummy");
+        assert_eq!(sm.state, State::Fresh);
+        for command in [
+            "MAIL FROM: <local@example.com>",
+            "RCPT TO: <local@example.com>",
+            "DATA hey",
+        ] {
+            let reply = sm.handle_smtp(command).unwrap();
+            assert_eq!(reply, StateMachine::NOT_NOW.as_bytes());
+        }
+        let reply = sm.handle_smtp("GARBAGE").unwrap();
+        assert_eq!(reply, StateMachine::WHAT_IS_THAT.as_bytes());
+        // The session goes on
+        assert_eq!(sm.state, State::Fresh);
+        sm.handle_smtp("HELO localhost").unwrap();
+        assert_eq!(sm.state, State::Greeted);
+    }
+
+    #[test]
+    fn test_too_many_errors() {
+        let mut sm = StateMachine::new("dummy");
+        sm.set_max_errors(2);
+        sm.handle_smtp("HELO localhost").unwrap();
+        let reply = sm.handle_smtp("MAIL TO:<a@example.com>").unwrap();
+        assert_eq!(reply, StateMachine::SAY_AGAIN.as_bytes());
+        sm.handle_smtp("GARBAGE").unwrap();
+        let err = sm.handle_smtp("GARBAGE").unwrap_err();
+        assert!(matches!(err, SmtpError::TooManyErrors(3)));
+        assert_eq!(err.reply_code(), 421);
+    }
+}
@@ -0,0 +1,240 @@
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::format::Format;
+use crate::protocol::Mail;
+use crate::queue::QueueId;
+use crate::schema::Message;
+use crate::sink::{DeliveryResult, MailSink};
+
+const SCOPE: &str = "https://www.googleapis.com/auth/pubsub";
+const JWT_BEARER: &str = "urn:ietf:params:oauth:grant-type:jwt-bearer";
+
+/// Fields of a service account key file used to sign token requests
+#[derive(Deserialize)]
+struct ServiceAccount {
+    client_email: String,
+    private_key: String,
+    token_uri: String,
+    project_id: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Claims<'a> {
+    iss: &'a str,
+    scope: &'a str,
+    aud: &'a str,
+    iat: i64,
+    exp: i64,
+}
+
+#[derive(Deserialize)]
+struct Token {
+    access_token: String,
+    expires_in: u64,
+}
+
+/// Google Cloud Pub/Sub topic the messages are published to, through the REST API
+pub struct PubSubSink {
+    client: reqwest::Client,
+    /// Service account signing for access tokens, none on the emulator
+    account: Option<ServiceAccount>,
+    token: Mutex<Option<(String, Instant)>>,
+    base_url: String,
+    /// Full name of the topic, `projects/{project}/topics/{topic}`
+    pub topic: String,
+    /// How the messages are encoded, `json`, `msgpack` or `protobuf`
+    pub format: Format,
+}
+
+impl PubSubSink {
+    /// Publishes to `topic`, either its full name or its id in the project of the
+    /// service account or `project_id`, authenticating with the service account key
+    /// file at `credentials`. With an `emulator_host` the messages go to the emulator,
+    /// unauthenticated.
+    pub fn new(
+        topic: &str,
+        format: Format,
+        credentials: Option<&Path>,
+        emulator_host: Option<&str>,
+        project_id: Option<&str>,
+    ) -> Result<Self> {
+        anyhow::ensure!(
+            matches!(
+                format,
+                Format::Json | Format::MessagePack | Format::Protobuf
+            ),
+            "pubsub-format must be json, msgpack or protobuf"
+        );
+        let (account, base_url) = match emulator_host {
+            Some(host) => (None, format!("http://{host}")),
+            None => {
+                let path = credentials.context(
+                    "google-application-credentials (GOOGLE_APPLICATION_CREDENTIALS) \
+                     is required by pubsub-topic",
+                )?;
+                let key = std::fs::read_to_string(path).with_context(|| {
+                    format!("cannot read service account key {}", path.display())
+                })?;
+                let account = serde_json::from_str::<ServiceAccount>(&key)
+                    .with_context(|| format!("invalid service account key {}", path.display()))?;
+                (Some(account), "https://pubsub.googleapis.com".into())
+            }
+        };
+        let topic = if topic.starts_with("projects/") {
+            topic.to_string()
+        } else {
+            let project = account
+                .as_ref()
+                .and_then(|account| account.project_id.clone())
+                .or_else(|| project_id.map(str::to_string))
+                .context("pubsub-topic must be projects/{project}/topics/{topic}")?;
+            format!("projects/{project}/topics/{topic}")
+        };
+        Ok(Self {
+            client: reqwest::Client::new(),
+            account,
+            token: Mutex::new(None),
+            base_url,
+            topic,
+            format,
+        })
+    }
+
+    /// Access token of the service account, renewed a minute before it expires
+    async fn token(&self) -> Result<Option<String>> {
+        let Some(account) = &self.account else {
+            return Ok(None);
+        };
+        let mut token = self.token.lock().await;
+        if let Some((token, expires)) = &*token {
+            if Instant::now() < *expires {
+                return Ok(Some(token.clone()));
+            }
+        }
+        let now = chrono::Utc::now().timestamp();
+        let assertion = jsonwebtoken::encode(
+            &jsonwebtoken::Header::new(jsonwebtoken::Algorithm::RS256),
+            &Claims {
+                iss: &account.client_email,
+                scope: SCOPE,
+                aud: &account.token_uri,
+                iat: now,
+                exp: now + 3600,
+            },
+            &jsonwebtoken::EncodingKey::from_rsa_pem(account.private_key.as_bytes())
+                .context("invalid service account private key")?,
+        )?;
+        let granted = self
+            .client
+            .post(&account.token_uri)
+            .form(&[("grant_type", JWT_BEARER), ("assertion", &assertion)])
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .context("cannot get a Pub/Sub access token")?
+            .bytes()
+            .await?;
+        let granted = serde_json::from_slice::<Token>(&granted)?;
+        let expires = Instant::now() + Duration::from_secs(granted.expires_in.saturating_sub(60));
+        *token = Some((granted.access_token.clone(), expires));
+        Ok(Some(granted.access_token))
+    }
+}
+
+impl MailSink for PubSubSink {
+    fn endpoint(&self) -> String {
+        format!("pubsub://{}", self.topic)
+    }
+
+    /// Publishes a message, with the queue id and the content type as attributes
+    async fn deliver(&self, id: &QueueId, mail: &Mail, message: &Message) -> DeliveryResult {
+        let body = self.format.encode(id, mail, message, None)?;
+        let request = serde_json::json!({
+            "messages": [{
+                "data": base64::engine::general_purpose::STANDARD.encode(&body.bytes),
+                "attributes": {
+                    "id": id.as_str(),
+                    "content-type": body.content_type,
+                },
+            }],
+        });
+        let mut request = self
+            .client
+            .post(format!("{}/v1/{}:publish", self.base_url, self.topic))
+            .timeout(Duration::from_secs(30))
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(serde_json::to_vec(&request)?);
+        if let Some(token) = self.token().await? {
+            request = request.bearer_auth(token);
+        }
+        request
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .with_context(|| format!("cannot publish to Pub/Sub topic {}", self.topic))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::payload;
+
+    #[tokio::test]
+    async fn test_publish_to_emulator() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0; 4096];
+            // The body is all in once it ends the JSON object
+            while !request.ends_with(b"}") {
+                let read = stream.read(&mut buffer).await.unwrap();
+                request.extend_from_slice(&buffer[..read]);
+            }
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\n{}")
+                .await
+                .unwrap();
+            String::from_utf8(request).unwrap()
+        });
+
+        let sink = PubSubSink {
+            client: reqwest::Client::new(),
+            account: None,
+            token: Mutex::new(None),
+            base_url: format!("http://127.0.0.1:{port}"),
+            topic: "projects/test/topics/mail".into(),
+            format: Format::Json,
+        };
+        let mail = Mail {
+            data: b"From: a@example.com\r\nSubject: hi\r\n\r\nhello\r\n".to_vec(),
+            ..Mail::default()
+        };
+        let message = payload::build(&mail.data, &payload::Options::default()).unwrap();
+        let id = QueueId::generate();
+        sink.deliver(&id, &mail, &message).await.unwrap();
+
+        let request = server.await.unwrap();
+        assert!(request.starts_with("POST /v1/projects/test/topics/mail:publish "));
+        let (_, body) = request.split_once("\r\n\r\n").unwrap();
+        let body: serde_json::Value = serde_json::from_str(body).unwrap();
+        let published = &body["messages"][0];
+        assert_eq!(published["attributes"]["id"], id.as_str());
+        let data = base64::engine::general_purpose::STANDARD
+            .decode(published["data"].as_str().unwrap())
+            .unwrap();
+        let data: serde_json::Value = serde_json::from_slice(&data).unwrap();
+        assert_eq!(data["subject"], "hi");
+    }
+}
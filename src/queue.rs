@@ -0,0 +1,392 @@
+#[cfg(feature = "forward")]
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::atomic::{AtomicU32, Ordering};
+#[cfg(feature = "forward")]
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "forward")]
+use tokio::sync::Notify;
+
+use crate::priority::Priority;
+use crate::protocol::Mail;
+#[cfg(feature = "forward")]
+use crate::ring::HashRing;
+
+/// Identifier assigned to a message once it is accepted,
+/// stable for the whole life of the message in the server.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct QueueId(String);
+
+impl QueueId {
+    /// Generates a new unique id, made of the acceptance time,
+    /// the process id and a per-process counter.
+    pub fn generate() -> Self {
+        static COUNTER: AtomicU32 = AtomicU32::new(0);
+        let now = chrono::Utc::now();
+        let counter = COUNTER.fetch_add(1, Ordering::Relaxed) & 0xFFFF;
+        Self(format!(
+            "{}{:06}{:X}{counter:04X}",
+            now.format("%Y%m%d%H%M%S"),
+            now.timestamp_subsec_micros(),
+            std::process::id()
+        ))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for QueueId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// An accepted mail waiting for delivery
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueuedMail {
+    pub id: QueueId,
+    pub mail: Mail,
+    pub priority: Priority,
+    /// Delivery is deferred until then by a schedule rule
+    pub not_before: Option<DateTime<Utc>>,
+    /// Tenant the recipients belong to, the default destination when none
+    pub tenant: Option<String>,
+    /// Delivery attempts that failed so far, since the server started
+    pub attempts: u32,
+    /// Mail this one duplicates, when duplicates are flagged
+    pub duplicate_of: Option<QueueId>,
+}
+
+/// A lower priority class is served at the latest after
+/// being passed over this many times, so it never starves.
+#[cfg(feature = "forward")]
+const STARVATION_LIMIT: u32 = 8;
+
+#[cfg(feature = "forward")]
+#[derive(Debug, Default)]
+struct Classes {
+    entries: [VecDeque<QueuedMail>; 3],
+    /// How many times each class was passed over while non-empty
+    skipped: [u32; 3],
+    /// Deferred mail, held back until it is due
+    scheduled: Vec<QueuedMail>,
+}
+
+#[cfg(feature = "forward")]
+impl Classes {
+    fn push(&mut self, entry: QueuedMail) {
+        if entry
+            .not_before
+            .is_some_and(|not_before| not_before > Utc::now())
+        {
+            self.scheduled.push(entry);
+        } else {
+            self.entries[entry.priority.index()].push_back(entry);
+        }
+    }
+
+    /// Moves deferred mail that became due into its priority class
+    fn promote_due(&mut self, now: DateTime<Utc>) {
+        let (due, scheduled) = std::mem::take(&mut self.scheduled)
+            .into_iter()
+            .partition::<Vec<_>, _>(|entry| entry.not_before.is_none_or(|t| t <= now));
+        self.scheduled = scheduled;
+        for entry in due {
+            self.entries[entry.priority.index()].push_back(entry);
+        }
+    }
+
+    fn next_due(&self) -> Option<DateTime<Utc>> {
+        self.scheduled
+            .iter()
+            .filter_map(|entry| entry.not_before)
+            .min()
+    }
+
+    fn pop(&mut self) -> Option<QueuedMail> {
+        let starved = Priority::ALL.into_iter().rev().find(|priority| {
+            self.skipped[priority.index()] >= STARVATION_LIMIT
+                && !self.entries[priority.index()].is_empty()
+        });
+        let priority = starved.or_else(|| {
+            Priority::ALL
+                .into_iter()
+                .find(|priority| !self.entries[priority.index()].is_empty())
+        })?;
+        for other in Priority::ALL {
+            if other == priority {
+                self.skipped[other.index()] = 0;
+            } else if !self.entries[other.index()].is_empty() {
+                self.skipped[other.index()] += 1;
+            }
+        }
+        self.entries[priority.index()].pop_front()
+    }
+}
+
+/// In-memory queue of accepted mail, drained by the delivery workers.
+/// Mail is served by priority class, oldest first within a class.
+/// Durability is the WAL's job: whatever is in here is also journaled.
+#[cfg(feature = "forward")]
+#[derive(Debug, Default)]
+pub struct Queue {
+    classes: Mutex<Classes>,
+    notify: Notify,
+}
+
+#[cfg(feature = "forward")]
+impl Queue {
+    /// Adds a mail at the back of its priority class, or holds it
+    /// until it's due when deferred, waking up a worker
+    pub fn push(&self, entry: QueuedMail) {
+        self.classes.lock().unwrap().push(entry);
+        self.notify.notify_one();
+    }
+
+    /// Takes the next due mail, waiting for one if there is none
+    pub async fn pop(&self) -> QueuedMail {
+        loop {
+            // Register interest before checking, so a push in between isn't missed
+            let notified = self.notify.notified();
+            let next_due = {
+                let mut classes = self.classes.lock().unwrap();
+                classes.promote_due(Utc::now());
+                if let Some(entry) = classes.pop() {
+                    return entry;
+                }
+                classes.next_due()
+            };
+            match next_due {
+                Some(next_due) => {
+                    let wait = (next_due - Utc::now()).to_std().unwrap_or_default();
+                    tokio::select! {
+                        _ = notified => {}
+                        _ = tokio::time::sleep(wait) => {}
+                    }
+                }
+                None => notified.await,
+            }
+        }
+    }
+
+    /// Copy of the mail waiting, in delivery order, deferred mail last
+    pub fn snapshot(&self) -> Vec<QueuedMail> {
+        let classes = self.classes.lock().unwrap();
+        let mut scheduled = classes.scheduled.clone();
+        scheduled.sort_by_key(|entry| entry.not_before);
+        classes
+            .entries
+            .iter()
+            .flatten()
+            .chain(&scheduled)
+            .cloned()
+            .collect()
+    }
+
+    /// Forgets all the mail waiting, returning it
+    pub fn clear(&self) -> Vec<QueuedMail> {
+        let mut classes = self.classes.lock().unwrap();
+        let mut entries = classes
+            .entries
+            .iter_mut()
+            .flat_map(std::mem::take)
+            .collect::<Vec<_>>();
+        entries.append(&mut classes.scheduled);
+        classes.skipped = [0; 3];
+        entries
+    }
+
+    /// Number of mails waiting, deferred ones included
+    pub fn len(&self) -> usize {
+        let classes = self.classes.lock().unwrap();
+        classes.entries.iter().map(VecDeque::len).sum::<usize>() + classes.scheduled.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Delivery queue split into shards by recipient domain, each drained by its own
+/// worker. Mail for a domain always lands in the same shard, so it is delivered
+/// in order, one message at a time, while different domains proceed in parallel.
+#[cfg(feature = "forward")]
+#[derive(Debug)]
+pub struct ShardedQueue {
+    ring: HashRing,
+    shards: Vec<Queue>,
+}
+
+#[cfg(feature = "forward")]
+impl ShardedQueue {
+    pub fn new(shards: usize) -> Self {
+        let shards = shards.max(1);
+        Self {
+            ring: HashRing::new((0..shards).map(|shard| shard.to_string())),
+            shards: (0..shards).map(|_| Queue::default()).collect(),
+        }
+    }
+
+    pub fn shards(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Shard of the mail, picked by the domain of its first recipient
+    pub fn shard(&self, entry: &QueuedMail) -> usize {
+        let domain = entry
+            .mail
+            .to
+            .first()
+            .and_then(|to| to.trim_matches(|c| c == '<' || c == '>').rsplit_once('@'))
+            .map(|(_, domain)| domain.to_lowercase())
+            .unwrap_or_default();
+        self.ring.node(&domain).unwrap_or_default()
+    }
+
+    pub fn push(&self, entry: QueuedMail) {
+        self.shards[self.shard(&entry)].push(entry);
+    }
+
+    /// Takes the next due mail of the shard, waiting for one if there is none
+    pub async fn pop(&self, shard: usize) -> QueuedMail {
+        self.shards[shard].pop().await
+    }
+
+    /// Copy of the mail waiting, shard by shard
+    pub fn snapshot(&self) -> Vec<QueuedMail> {
+        self.shards.iter().flat_map(Queue::snapshot).collect()
+    }
+
+    /// Forgets all the mail waiting, returning it
+    pub fn clear(&self) -> Vec<QueuedMail> {
+        self.shards.iter().flat_map(Queue::clear).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(Queue::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "forward")]
+    fn entry(id: QueueId, priority: Priority) -> QueuedMail {
+        QueuedMail {
+            id,
+            mail: Mail::default(),
+            priority,
+            not_before: None,
+            tenant: None,
+            attempts: 0,
+            duplicate_of: None,
+        }
+    }
+
+    #[cfg(feature = "forward")]
+    #[tokio::test]
+    async fn test_pop_waits_for_push() {
+        let queue = std::sync::Arc::new(Queue::default());
+        let popper = tokio::spawn({
+            let queue = queue.clone();
+            async move { (queue.pop().await.id, queue.pop().await.id) }
+        });
+        let (first, second) = (QueueId::generate(), QueueId::generate());
+        queue.push(entry(first.clone(), Priority::Normal));
+        queue.push(entry(second.clone(), Priority::Normal));
+        assert_eq!(popper.await.unwrap(), (first, second));
+        assert!(queue.is_empty());
+    }
+
+    #[cfg(feature = "forward")]
+    #[tokio::test]
+    async fn test_priority_order_without_starvation() {
+        let queue = Queue::default();
+        let low = QueueId::generate();
+        queue.push(entry(low.clone(), Priority::Low));
+        let high = (0..20).map(|_| QueueId::generate()).collect::<Vec<_>>();
+        for id in &high {
+            queue.push(entry(id.clone(), Priority::High));
+        }
+        let urgent = QueueId::generate();
+        queue.push(entry(urgent.clone(), Priority::High));
+        let mut order = Vec::new();
+        while !queue.is_empty() {
+            order.push(queue.pop().await.id);
+        }
+        assert_eq!(
+            order[..STARVATION_LIMIT as usize],
+            high[..STARVATION_LIMIT as usize]
+        );
+        assert_eq!(order[STARVATION_LIMIT as usize], low);
+        assert_eq!(order.last(), Some(&urgent));
+    }
+
+    #[cfg(feature = "forward")]
+    #[tokio::test]
+    async fn test_deferred_mail_waits() {
+        let queue = Queue::default();
+        let (later, now) = (QueueId::generate(), QueueId::generate());
+        queue.push(QueuedMail {
+            not_before: Some(Utc::now() + chrono::Duration::milliseconds(200)),
+            tenant: None,
+            attempts: 0,
+            ..entry(later.clone(), Priority::High)
+        });
+        queue.push(entry(now.clone(), Priority::Low));
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue.pop().await.id, now);
+        let started = std::time::Instant::now();
+        assert_eq!(queue.pop().await.id, later);
+        assert!(started.elapsed() >= std::time::Duration::from_millis(150));
+    }
+
+    #[cfg(feature = "forward")]
+    #[tokio::test]
+    async fn test_domain_stays_in_its_shard() {
+        let queue = ShardedQueue::new(4);
+        let for_domain = |domain: &str| QueuedMail {
+            mail: Mail {
+                to: vec![format!("<someone@{domain}>")],
+                ..Mail::default()
+            },
+            ..entry(QueueId::generate(), Priority::Normal)
+        };
+        let ids = (0..5)
+            .map(|_| {
+                let entry = for_domain("Example.com");
+                let id = entry.id.clone();
+                queue.push(entry);
+                id
+            })
+            .collect::<Vec<_>>();
+        let shard = queue.shard(&for_domain("example.com"));
+        assert_eq!(queue.len(), 5);
+        let mut order = Vec::new();
+        for _ in 0..5 {
+            order.push(queue.pop(shard).await.id);
+        }
+        assert_eq!(order, ids);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_queue_ids_are_unique() {
+        let ids = (0..1000)
+            .map(|_| QueueId::generate())
+            .collect::<std::collections::HashSet<_>>();
+        assert_eq!(ids.len(), 1000);
+    }
+}
@@ -0,0 +1,269 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+
+/// Buckets kept before the full ones are dropped, so clients seen once don't pile up
+const MAX_BUCKETS: usize = 10_000;
+
+/// Spaces out requests so that no more than a given number
+/// start per second, callers waiting their turn in order.
+#[derive(Debug)]
+pub struct RateLimiter {
+    interval: Duration,
+    next: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    /// Allows `per_second` requests per second, which may be fractional,
+    /// but not so small that the interval between them overflows
+    pub fn new(per_second: f64) -> Result<Self> {
+        anyhow::ensure!(per_second > 0.0, "rate {per_second} is not positive");
+        let interval = Duration::try_from_secs_f64(1.0 / per_second)
+            .with_context(|| format!("rate {per_second} is too small"))?;
+        Ok(Self {
+            interval,
+            next: Mutex::new(Instant::now()),
+        })
+    }
+
+    /// Waits until the caller may send its request
+    pub async fn acquire(&self) {
+        let slot = {
+            let mut next = self.next.lock().await;
+            let slot = (*next).max(Instant::now());
+            *next = slot + self.interval;
+            slot
+        };
+        tokio::time::sleep_until(slot).await;
+    }
+}
+
+/// Token buckets by key, e.g. client address, each allowing `limit` takes
+/// per `period`, in bursts of up to `limit`
+#[derive(Debug)]
+pub struct Buckets<K> {
+    limit: u32,
+    period: Duration,
+    /// Tokens left in each bucket, as of when it was last taken from
+    buckets: std::sync::Mutex<HashMap<K, (f64, Instant)>>,
+}
+
+impl<K: Eq + Hash> Buckets<K> {
+    pub fn new(limit: u32, period: Duration) -> Self {
+        Self {
+            limit,
+            period,
+            buckets: std::sync::Mutex::default(),
+        }
+    }
+
+    /// Takes a token from the bucket of `key`, returning whether there was one
+    pub fn take(&self, key: K, now: Instant) -> bool {
+        self.take_limited(key, self.limit, now)
+    }
+
+    /// Whether the bucket of `key` has a token left, without taking it
+    pub fn has(&self, key: &K, now: Instant) -> bool {
+        self.has_limited(key, self.limit, now)
+    }
+
+    /// Whether the bucket of `key` has a token left, allowing `limit` takes
+    /// per period for that key, without taking it
+    pub fn has_limited(&self, key: &K, limit: u32, now: Instant) -> bool {
+        let limit = f64::from(limit);
+        let per_second = limit / self.period.as_secs_f64();
+        let buckets = self.buckets.lock().unwrap();
+        let tokens = match buckets.get(key) {
+            Some((tokens, taken)) => {
+                tokens + now.saturating_duration_since(*taken).as_secs_f64() * per_second
+            }
+            None => limit,
+        };
+        tokens.min(limit) >= 1.0
+    }
+
+    /// Takes a token from the bucket of `key`, allowing `limit` takes per period
+    /// for that key rather than the one of all buckets
+    pub fn take_limited(&self, key: K, limit: u32, now: Instant) -> bool {
+        let limit = f64::from(limit);
+        let per_second = limit / self.period.as_secs_f64();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_BUCKETS {
+            // Refilled a whole period later
+            buckets.retain(|_, (_, taken)| now.saturating_duration_since(*taken) < self.period);
+        }
+        let (tokens, taken) = buckets.entry(key).or_insert((limit, now));
+        let refill = now.saturating_duration_since(*taken).as_secs_f64() * per_second;
+        *tokens = (*tokens + refill).min(limit);
+        *taken = now;
+        if *tokens < 1.0 {
+            return false;
+        }
+        *tokens -= 1.0;
+        true
+    }
+}
+
+/// What each client address may do, refused beyond
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ClientLimits {
+    pub connections_per_minute: Option<u32>,
+    pub messages_per_hour: Option<u32>,
+    pub recipients_per_message: Option<usize>,
+}
+
+/// Rates of the clients by address, shared by their sessions
+#[derive(Debug)]
+pub struct ClientRates {
+    connections: Option<Buckets<IpAddr>>,
+    messages: Option<Buckets<IpAddr>>,
+    /// Recipients a mail may have, left to each session to enforce
+    pub recipients_per_message: Option<usize>,
+}
+
+impl ClientRates {
+    pub fn new(limits: ClientLimits) -> Self {
+        Self {
+            connections: limits
+                .connections_per_minute
+                .map(|limit| Buckets::new(limit, Duration::from_secs(60))),
+            messages: limits
+                .messages_per_hour
+                .map(|limit| Buckets::new(limit, Duration::from_secs(3600))),
+            recipients_per_message: limits.recipients_per_message,
+        }
+    }
+
+    /// Counts a connection of the client, returning whether it is within its rate
+    pub fn connect(&self, ip: IpAddr) -> bool {
+        self.connections
+            .as_ref()
+            .is_none_or(|connections| connections.take(ip, Instant::now()))
+    }
+
+    /// Whether the client may send another mail, within its rate, without counting it
+    pub fn may_send(&self, ip: IpAddr) -> bool {
+        self.messages
+            .as_ref()
+            .is_none_or(|messages| messages.has(&ip, Instant::now()))
+    }
+
+    /// Counts a mail of the client, once accepted, so mail given up on
+    /// or refused doesn't use its rate up
+    pub fn message(&self, ip: IpAddr) {
+        if let Some(messages) = &self.messages {
+            messages.take(ip, Instant::now());
+        }
+    }
+}
+
+/// Sessions running for each client address, up to a limit, so a single
+/// host can't take all the connections
+#[derive(Debug)]
+pub struct ClientSessions {
+    max: usize,
+    active: std::sync::Mutex<HashMap<IpAddr, usize>>,
+}
+
+impl ClientSessions {
+    pub fn new(max: usize) -> Self {
+        Self {
+            max,
+            active: std::sync::Mutex::default(),
+        }
+    }
+
+    /// Counts a session of the client, none when it has too many already.
+    /// The session is counted until the permit is dropped.
+    pub fn acquire(self: &Arc<Self>, ip: IpAddr) -> Option<SessionPermit> {
+        let mut active = self.active.lock().unwrap();
+        let sessions = active.entry(ip).or_default();
+        if *sessions >= self.max {
+            return None;
+        }
+        *sessions += 1;
+        Some(SessionPermit {
+            sessions: self.clone(),
+            ip,
+        })
+    }
+}
+
+/// A session counted by [`ClientSessions`], until dropped
+#[derive(Debug)]
+pub struct SessionPermit {
+    sessions: Arc<ClientSessions>,
+    ip: IpAddr,
+}
+
+impl Drop for SessionPermit {
+    fn drop(&mut self) {
+        let mut active = self.sessions.active.lock().unwrap();
+        if let Some(sessions) = active.get_mut(&self.ip) {
+            *sessions -= 1;
+            if *sessions == 0 {
+                active.remove(&self.ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_spaces_requests() {
+        let limiter = RateLimiter::new(20.0).unwrap();
+        let started = Instant::now();
+        for _ in 0..5 {
+            limiter.acquire().await;
+        }
+        // The first request goes right away, the others 50ms apart
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(200), "{elapsed:?}");
+        assert!(elapsed < Duration::from_millis(400), "{elapsed:?}");
+
+        for rate in [0.0, -1.0, f64::NAN, 1e-300] {
+            assert!(RateLimiter::new(rate).is_err(), "{rate}");
+        }
+    }
+
+    #[test]
+    fn test_buckets() {
+        let buckets = Buckets::new(3, Duration::from_secs(60));
+        let now = Instant::now();
+        let (a, b): (IpAddr, IpAddr) = ("192.0.2.1".parse().unwrap(), "192.0.2.2".parse().unwrap());
+        assert!((0..3).all(|_| buckets.has(&a, now) && buckets.take(a, now)));
+        assert!(!buckets.has(&a, now));
+        assert!(!buckets.take(a, now));
+        assert!(buckets.take(b, now));
+        // A token every 20 seconds
+        assert!(!buckets.take(a, now + Duration::from_secs(19)));
+        assert!(buckets.take(a, now + Duration::from_secs(21)));
+        assert!(!buckets.take(a, now + Duration::from_secs(22)));
+        // Refilled up to the limit, not beyond
+        let later = now + Duration::from_secs(3600);
+        assert!((0..3).all(|_| buckets.take(a, later)));
+        assert!(!buckets.take(a, later));
+    }
+
+    #[test]
+    fn test_client_sessions() {
+        let sessions = Arc::new(ClientSessions::new(2));
+        let (a, b): (IpAddr, IpAddr) = ("192.0.2.1".parse().unwrap(), "192.0.2.2".parse().unwrap());
+        let first = sessions.acquire(a).unwrap();
+        let _second = sessions.acquire(a).unwrap();
+        assert!(sessions.acquire(a).is_none());
+        assert!(sessions.acquire(b).is_some());
+        drop(first);
+        assert!(sessions.acquire(a).is_some());
+        assert!(sessions.active.lock().unwrap().get(&b).is_none());
+    }
+}
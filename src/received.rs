@@ -0,0 +1,209 @@
+use std::net::SocketAddr;
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::header::{field_name, field_value, split};
+use crate::payload;
+use crate::protocol::{self, Mail};
+use crate::queue::QueueId;
+use crate::schema::Message;
+
+/// What is known about the SMTP session a mail was received in
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Session {
+    /// Address of the connected client
+    pub peer: Option<SocketAddr>,
+    /// Name the client introduced itself with in HELO/EHLO
+    pub helo: Option<String>,
+    /// Domain the server answered as
+    pub server_domain: String,
+    /// Whether the session ran over TLS, with STARTTLS or implicit TLS
+    #[serde(default)]
+    pub tls: bool,
+    /// Whether the client talked before it was greeted, as spam bots do
+    #[serde(default)]
+    pub early_talker: bool,
+}
+
+/// A mail accepted by the server, as handed to library consumers
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReceivedMail {
+    /// Queue ids the mail was accepted under, one for each part it was split into
+    pub ids: Vec<QueueId>,
+    /// Envelope sender, from MAIL FROM
+    pub sender: String,
+    /// Envelope recipients, from RCPT TO
+    pub recipients: Vec<String>,
+    /// The message as sent by the client, without the DATA terminator
+    pub raw: Vec<u8>,
+    pub received_at: DateTime<Utc>,
+    pub session: Session,
+}
+
+impl ReceivedMail {
+    pub fn new(ids: Vec<QueueId>, mail: &Mail, session: Session) -> Self {
+        Self {
+            ids,
+            sender: mail.from.clone(),
+            recipients: mail.to.clone(),
+            raw: protocol::strip_terminator(&mail.data).to_vec(),
+            received_at: Utc::now(),
+            session,
+        }
+    }
+
+    /// Builds the payload forwarded downstream from the raw message
+    pub fn to_message(&self, options: &payload::Options) -> Result<Message> {
+        payload::build(&self.raw, options)
+    }
+}
+
+impl Session {
+    /// The message with the `Received` field recording the session on top (RFC 5321),
+    /// e.g. `Received: from mail.example.com ([192.0.2.1]) by mx.example.org with ESMTPS`,
+    /// followed by `(early talker)` when the client talked before it was greeted
+    pub fn stamp(&self, message: &[u8]) -> Vec<u8> {
+        let mut received = String::from("Received:");
+        if let Some(helo) = &self.helo {
+            received.push_str(&format!(" from {helo}"));
+        }
+        if let Some(peer) = self.peer {
+            received.push_str(&format!(" ([{}])", peer.ip()));
+        }
+        let with = if self.tls { "ESMTPS" } else { "ESMTP" };
+        received.push_str(&format!(" by {} with {with}", self.server_domain));
+        if self.early_talker {
+            received.push_str(" (early talker)");
+        }
+        received.push_str(&format!(";\r\n\t{}\r\n", Utc::now().to_rfc2822()));
+        let mut stamped = received.into_bytes();
+        stamped.extend_from_slice(message);
+        stamped
+    }
+}
+
+/// What the `Received` field the server added tells of the session
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Stamp {
+    pub helo: Option<String>,
+    pub early_talker: bool,
+}
+
+/// What the topmost `Received` field tells of the session, when it was added by `by`,
+/// none when the field isn't ours
+pub(crate) fn parse(message: &[u8], by: &str) -> Option<Stamp> {
+    let (fields, _) = split(message);
+    let field = fields
+        .iter()
+        .find(|field| field_name(field) == "received")?;
+    let value = field_value(field);
+    // The date follows the last semicolon
+    let clauses = value
+        .rsplit_once(';')
+        .map_or(&value[..], |(clauses, _)| clauses);
+    let tokens = clauses.split_whitespace().collect::<Vec<_>>();
+    // Ours is the last `by`, the HELO name before it being the client's to choose
+    let at = tokens
+        .iter()
+        .rposition(|token| token.eq_ignore_ascii_case("by"))?;
+    if !tokens
+        .get(at + 1)
+        .is_some_and(|host| host.eq_ignore_ascii_case(by))
+    {
+        return None;
+    }
+    let helo = match tokens[..at] {
+        [from, helo, ..] if from.eq_ignore_ascii_case("from") => Some(helo.to_string()),
+        _ => None,
+    };
+    Some(Stamp {
+        helo,
+        early_talker: tokens[at..].join(" ").contains("(early talker)"),
+    })
+}
+
+impl TryFrom<&ReceivedMail> for Message {
+    type Error = anyhow::Error;
+
+    fn try_from(mail: &ReceivedMail) -> Result<Self> {
+        mail.to_message(&payload::Options::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_mail() {
+        let mail = Mail {
+            from: "<a@example.com>".into(),
+            to: vec!["<b@example.com>".into()],
+            data: "From: a@example.com\r\nSubject: hi\r\n\r\nhello\r\n.\r\n".into(),
+        };
+        let received = ReceivedMail::new(vec![QueueId::generate()], &mail, Session::default());
+        assert_eq!(
+            received.raw,
+            b"From: a@example.com\r\nSubject: hi\r\n\r\nhello\r\n"
+        );
+        assert_eq!(received.recipients, mail.to);
+        let message = Message::try_from(&received).unwrap();
+        assert_eq!(message.subject.as_deref(), Some("hi"));
+    }
+
+    #[test]
+    fn test_stamp() {
+        let session = Session {
+            peer: Some("192.0.2.1:25000".parse().unwrap()),
+            helo: Some("mail.example.com".into()),
+            server_domain: "mx.example.org".into(),
+            tls: true,
+            early_talker: false,
+        };
+        let stamped = session.stamp(b"Subject: hi\r\n\r\nhello\r\n");
+        let stamped = String::from_utf8(stamped).unwrap();
+        assert!(stamped.starts_with(
+            "Received: from mail.example.com ([192.0.2.1]) by mx.example.org with ESMTPS;\r\n\t"
+        ));
+        assert!(stamped.ends_with("\r\nSubject: hi\r\n\r\nhello\r\n"));
+        assert_eq!(
+            parse(stamped.as_bytes(), "MX.example.org"),
+            Some(Stamp {
+                helo: Some("mail.example.com".into()),
+                early_talker: false,
+            })
+        );
+        // Only the topmost field is ours, the sender's below it may be forged
+        assert_eq!(parse(stamped.as_bytes(), "mx.example.net"), None);
+
+        let early = Session {
+            helo: Some("by".into()),
+            server_domain: "mx.example.org".into(),
+            early_talker: true,
+            ..Default::default()
+        };
+        let stamped = early.stamp(b"Received: from forged.example by mx.example.org\r\n\r\n");
+        assert!(
+            stamped.starts_with(b"Received: from by by mx.example.org with ESMTP (early talker);")
+        );
+        assert_eq!(
+            parse(&stamped, "mx.example.org"),
+            Some(Stamp {
+                helo: Some("by".into()),
+                early_talker: true,
+            })
+        );
+
+        let local = Session {
+            server_domain: "mx.example.org".into(),
+            ..Default::default()
+        };
+        let stamped = local.stamp(b"Received: from forged.example by mx.example.org\r\n\r\n");
+        assert!(stamped.starts_with(b"Received: by mx.example.org with ESMTP;"));
+        assert_eq!(parse(&stamped, "mx.example.org"), Some(Stamp::default()));
+    }
+}
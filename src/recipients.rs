@@ -0,0 +1,202 @@
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, OnceLock, RwLock};
+
+use anyhow::{Context, Result};
+use regex::Regex;
+
+/// Rules used unless configured otherwise, keeping the administrative
+/// addresses out of reach, so no one can register certificates for the domain
+const DEFAULT_RULES: &str = "deny *admin*\ndeny *postmaster*\ndeny *hostmaster*";
+
+/// What becomes of the recipients a rule matches
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    Allow,
+    Deny,
+}
+
+/// Addresses a rule matches, always lowercase and without angle brackets
+#[derive(Clone, Debug)]
+pub enum Pattern {
+    /// An exact address, e.g. `alice@example.com`
+    Address(String),
+    /// A glob matching the address whole, `*` matching any run of characters
+    /// and `?` any one, e.g. `*admin*@example.com`
+    Glob(Regex),
+    /// A regular expression between slashes, e.g. `/^(host|post)master@/`
+    Regex(Regex),
+}
+
+impl Pattern {
+    pub fn matches(&self, address: &str) -> bool {
+        match self {
+            Self::Address(pattern) => pattern == address,
+            Self::Glob(regex) | Self::Regex(regex) => regex.is_match(address),
+        }
+    }
+}
+
+impl FromStr for Pattern {
+    type Err = anyhow::Error;
+
+    fn from_str(pattern: &str) -> Result<Self> {
+        if let Some(regex) = pattern
+            .strip_prefix('/')
+            .and_then(|pattern| pattern.strip_suffix('/'))
+        {
+            return Ok(Self::Regex(
+                Regex::new(regex).with_context(|| format!("invalid regex {regex}"))?,
+            ));
+        }
+        if !pattern.contains(['*', '?']) {
+            return Ok(Self::Address(pattern.to_lowercase()));
+        }
+        let glob = pattern
+            .to_lowercase()
+            .chars()
+            .map(|c| match c {
+                '*' => ".*".to_string(),
+                '?' => ".".to_string(),
+                c => regex::escape(&c.to_string()),
+            })
+            .collect::<String>();
+        Ok(Self::Glob(Regex::new(&format!("^{glob}$"))?))
+    }
+}
+
+/// A line of the rules: `allow` or `deny`, then the pattern
+#[derive(Clone, Debug)]
+pub struct Rule {
+    pub action: Action,
+    pub pattern: Pattern,
+}
+
+/// Parses rules, one per line. Empty lines and lines starting with `#` are ignored.
+pub fn parse(rules: &str) -> Result<Vec<Rule>> {
+    let mut parsed = Vec::new();
+    for (number, line) in rules.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let rule = line
+            .split_once(char::is_whitespace)
+            .context("expected an action and a pattern")
+            .and_then(|(action, pattern)| {
+                let action = match action {
+                    "allow" => Action::Allow,
+                    "deny" => Action::Deny,
+                    _ => anyhow::bail!("unknown action {action}, expected allow or deny"),
+                };
+                Ok(Rule {
+                    action,
+                    pattern: pattern.trim().parse()?,
+                })
+            })
+            .with_context(|| format!("invalid recipient rule on line {}", number + 1))?;
+        parsed.push(rule);
+    }
+    Ok(parsed)
+}
+
+/// Which recipients are accepted. The first rule matching a recipient decides,
+/// and recipients no rule matches are accepted.
+/// Rules read from a file can be reloaded while sessions use them.
+#[derive(Debug)]
+pub struct Recipients {
+    /// File the rules were read from, reread by [`reload`](Self::reload)
+    path: Option<PathBuf>,
+    rules: RwLock<Vec<Rule>>,
+}
+
+impl Recipients {
+    pub fn new(rules: Vec<Rule>) -> Self {
+        Self {
+            path: None,
+            rules: RwLock::new(rules),
+        }
+    }
+
+    /// The default rules, denying the administrative addresses
+    pub fn builtin() -> Arc<Self> {
+        static BUILTIN: OnceLock<Arc<Recipients>> = OnceLock::new();
+        BUILTIN
+            .get_or_init(|| Arc::new(Self::new(parse(DEFAULT_RULES).unwrap())))
+            .clone()
+    }
+
+    /// Reads the rules from a file, reloaded from it on [`Recipients::reload`]
+    pub fn load(path: impl Into<PathBuf>) -> Result<Arc<Self>> {
+        let recipients = Self {
+            path: Some(path.into()),
+            rules: RwLock::default(),
+        };
+        recipients.reload()?;
+        Ok(Arc::new(recipients))
+    }
+
+    /// Reads the rules from their file again, keeping the current ones when it is invalid
+    pub fn reload(&self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let rules = std::fs::read_to_string(path)
+            .with_context(|| format!("cannot read recipient rules {}", path.display()))?;
+        let rules =
+            parse(&rules).with_context(|| format!("invalid recipient rules {}", path.display()))?;
+        tracing::info!("Loaded {} recipient rules", rules.len());
+        *self.rules.write().unwrap() = rules;
+        Ok(())
+    }
+
+    /// Whether a recipient, as in RCPT, is accepted
+    pub fn accepts(&self, recipient: &str) -> bool {
+        let address = recipient
+            .trim_start_matches('<')
+            .trim_end_matches('>')
+            .to_lowercase();
+        self.rules
+            .read()
+            .unwrap()
+            .iter()
+            .find(|rule| rule.pattern.matches(&address))
+            .is_none_or(|rule| rule.action == Action::Allow)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_accepts() {
+        let recipients = Recipients::new(
+            parse(
+                "# The postmaster of the main domain is read\n\
+                 allow postmaster@example.com\n\
+                 deny *master@*\n\
+                 deny /^(admin|root)\\b/\n\
+                 \n\
+                 deny ??@example.org",
+            )
+            .unwrap(),
+        );
+        assert!(recipients.accepts("<Postmaster@Example.com>"));
+        assert!(!recipients.accepts("<postmaster@example.org>"));
+        assert!(!recipients.accepts("<hostmaster@example.com>"));
+        assert!(!recipients.accepts("<admin.team@example.com>"));
+        assert!(recipients.accepts("<administration@example.com>"));
+        assert!(!recipients.accepts("<ab@example.org>"));
+        assert!(recipients.accepts("<abc@example.org>"));
+        assert!(recipients.accepts("<alice@example.com>"));
+
+        let builtin = Recipients::builtin();
+        assert!(!builtin.accepts("<webadmin@example.com>"));
+        assert!(builtin.accepts("<alice@example.com>"));
+
+        assert!(parse("reject *@example.com").is_err());
+        assert!(parse("deny").is_err());
+        assert!(parse("deny /(/").is_err());
+    }
+}
@@ -0,0 +1,139 @@
+use anyhow::{Context, Result};
+use redis::aio::ConnectionManager;
+
+use crate::format::Format;
+use crate::protocol::Mail;
+use crate::queue::QueueId;
+use crate::schema::Message;
+use crate::sink::{DeliveryResult, MailSink};
+
+/// Redis stream the messages are added to, each entry holding the queue id,
+/// the content type and the encoded message
+pub struct RedisSink {
+    connection: ConnectionManager,
+    pub stream: String,
+    /// Entries the stream is trimmed to, approximately
+    pub max_len: Option<usize>,
+    /// Channel notified of each entry added, if any
+    pub channel: Option<String>,
+    /// How the messages are encoded, `json`, `msgpack` or `protobuf`
+    pub format: Format,
+}
+
+impl RedisSink {
+    /// Connects to the server at `url`, e.g. `redis://localhost:6379/0`, adding
+    /// the messages to `stream`, trimmed to `max_len` entries when given, and
+    /// announcing each entry with PUBLISH on `channel`, if any
+    pub async fn connect(
+        url: &str,
+        stream: impl Into<String>,
+        max_len: Option<usize>,
+        channel: Option<String>,
+        format: Format,
+    ) -> Result<Self> {
+        anyhow::ensure!(
+            matches!(
+                format,
+                Format::Json | Format::MessagePack | Format::Protobuf
+            ),
+            "redis-format must be json, msgpack or protobuf"
+        );
+        let client = redis::Client::open(url).context("invalid redis-url")?;
+        let connection = ConnectionManager::new(client)
+            .await
+            .context("cannot connect to Redis")?;
+        Ok(Self {
+            connection,
+            stream: stream.into(),
+            max_len,
+            channel,
+            format,
+        })
+    }
+}
+
+impl MailSink for RedisSink {
+    fn endpoint(&self) -> String {
+        format!("redis://{}", self.stream)
+    }
+
+    /// Adds a message to the stream, then publishes its queue id and entry id
+    /// as JSON on the channel, if any
+    async fn deliver(&self, id: &QueueId, mail: &Mail, message: &Message) -> DeliveryResult {
+        let body = self.format.encode(id, mail, message, None)?;
+        let mut connection = self.connection.clone();
+        let entry: String = xadd(
+            &self.stream,
+            self.max_len,
+            id,
+            &body.content_type,
+            &body.bytes,
+        )
+        .query_async(&mut connection)
+        .await
+        .with_context(|| format!("cannot add to Redis stream {}", self.stream))?;
+        if let Some(channel) = &self.channel {
+            let notification = serde_json::json!({
+                "id": id,
+                "stream": self.stream,
+                "entry": entry,
+            });
+            // The entry is in the stream, so a lost notification is not retried
+            if let Err(err) = redis::cmd("PUBLISH")
+                .arg(channel)
+                .arg(notification.to_string())
+                .query_async::<_, i64>(&mut connection)
+                .await
+            {
+                tracing::warn!("Cannot notify Redis channel {channel} of mail {id}: {err}");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The XADD of an entry, trimming the stream to about `max_len` entries
+fn xadd(
+    stream: &str,
+    max_len: Option<usize>,
+    id: &QueueId,
+    content_type: &str,
+    bytes: &[u8],
+) -> redis::Cmd {
+    let mut xadd = redis::cmd("XADD");
+    xadd.arg(stream);
+    if let Some(max_len) = max_len {
+        xadd.arg("MAXLEN").arg("~").arg(max_len);
+    }
+    xadd.arg("*")
+        .arg("id")
+        .arg(id.as_str())
+        .arg("content-type")
+        .arg(content_type)
+        .arg("message")
+        .arg(bytes);
+    xadd
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_xadd() {
+        let id = QueueId::generate();
+        let packed = |max_len| {
+            let command = xadd("mail", max_len, &id, "application/json", b"{}");
+            String::from_utf8(command.get_packed_command()).unwrap()
+        };
+        let expected = format!(
+            "*9\r\n$4\r\nXADD\r\n$4\r\nmail\r\n$1\r\n*\r\n$2\r\nid\r\n${}\r\n{id}\r\n",
+            id.as_str().len()
+        );
+        assert!(packed(None).starts_with(&expected));
+        assert!(packed(None).ends_with("$7\r\nmessage\r\n$2\r\n{}\r\n"));
+        assert!(packed(Some(1000)).starts_with(
+            "*12\r\n$4\r\nXADD\r\n$4\r\nmail\r\n$6\r\nMAXLEN\r\n$1\r\n~\r\n$4\r\n1000\r\n"
+        ));
+    }
+}
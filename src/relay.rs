@@ -0,0 +1,457 @@
+use anyhow::{Context, Result};
+use chrono::Utc;
+use hickory_resolver::error::ResolveErrorKind;
+use hickory_resolver::proto::op::ResponseCode;
+use hickory_resolver::TokioAsyncResolver;
+
+use crate::alias::Aliases;
+use crate::arc::Sealer;
+use crate::dkim::Signer;
+use crate::format;
+use crate::protocol::Mail;
+use crate::queue::QueueId;
+use crate::schema::Message;
+use crate::sink::{DeliveryResult, MailSink};
+use crate::smtp_client::{ClientError, Config, Envelope, Pool, Reply};
+use crate::srs::Srs;
+
+/// `Received` fields a message may have before it is taken for a loop and
+/// refused, as RFC 5321 section 6.3 asks of relays
+const MAX_HOPS: usize = 50;
+
+/// Where relayed mail is sent
+pub enum Route {
+    /// To the mail exchangers of each recipient domain, as a forwarding MTA does
+    Mx {
+        resolver: Box<TokioAsyncResolver>,
+        port: u16,
+    },
+    /// To a single server relaying it further, e.g. the one of an email provider
+    Smarthost { host: String, port: u16 },
+}
+
+/// Delivers the messages over outbound SMTP, as received, to their envelope recipients,
+/// or to the addresses they are aliases of
+pub struct Relay {
+    route: Route,
+    /// Name of this host in the `Received` fields
+    host: String,
+    pool: Pool,
+    aliases: Option<Aliases>,
+    srs: Option<Srs>,
+    dkim: Option<Signer>,
+    arc: Option<Sealer>,
+}
+
+impl Relay {
+    pub fn new(route: Route, config: Config) -> Self {
+        Self {
+            route,
+            host: config.helo_name.clone(),
+            pool: Pool::new(config),
+            aliases: None,
+            srs: None,
+            dkim: None,
+            arc: None,
+        }
+    }
+
+    /// Forwards the mail of the aliases to their destinations, rather than
+    /// relaying it to the recipients. Recipients without an alias are skipped,
+    /// the server refusing them at RCPT unless the client authenticated.
+    pub fn with_aliases(mut self, aliases: Aliases) -> Self {
+        self.aliases = Some(aliases);
+        self
+    }
+
+    /// Rewrites the envelope sender with SRS, so relayed mail passes the SPF
+    /// checks of its destination, and sends the bounces coming back on
+    pub fn with_srs(mut self, srs: Srs) -> Self {
+        self.srs = Some(srs);
+        self
+    }
+
+    /// Signs the relayed messages with DKIM
+    pub fn with_dkim(mut self, signer: Signer) -> Self {
+        self.dkim = Some(signer);
+        self
+    }
+
+    /// Adds an ARC set to the relayed messages, on top of their DKIM signature
+    pub fn with_arc(mut self, sealer: Sealer) -> Self {
+        self.arc = Some(sealer);
+        self
+    }
+
+    /// Where a message is relayed to: the original senders of the bounces to our
+    /// SRS addresses, and the recipients or the destinations of their aliases.
+    /// Bounces to forged or expired SRS addresses are dropped.
+    fn recipients(&self, id: &QueueId, mail: &Mail) -> Vec<String> {
+        let mut to = Vec::new();
+        let mut others = Vec::new();
+        for recipient in &mail.to {
+            let reversed = self
+                .srs
+                .as_ref()
+                .map(|srs| srs.reverse(address(recipient), Utc::now()));
+            match reversed {
+                Some(Ok(Some(original))) => to.push(original),
+                Some(Err(err)) => tracing::warn!("Dropping mail {id} to {recipient}: {err:#}"),
+                Some(Ok(None)) | None => others.push(recipient.clone()),
+            }
+        }
+        match &self.aliases {
+            Some(aliases) => to.extend(aliases.expand(&others)),
+            None => to.extend(others.iter().map(|to| address(to).to_string())),
+        }
+        to
+    }
+
+    /// Sends the message to its recipients, or to those of a single domain, with
+    /// a `Received` field on top. Mail with [`MAX_HOPS`] of them already is refused,
+    /// as it loops.
+    async fn relay(&self, id: &QueueId, mail: &Mail, domain: Option<&str>) -> DeliveryResult {
+        let to = self.recipients(id, mail);
+        if to.is_empty() {
+            tracing::debug!("Mail {id} has no recipient to relay to");
+            return Ok(());
+        }
+        let hops = format::header_fields(format::headers(mail))
+            .iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case("Received"))
+            .count();
+        if hops >= MAX_HOPS {
+            return Err(rejected(
+                554,
+                format!("5.4.6 mail {id} went through {hops} hops, it loops"),
+            ));
+        }
+        let from = match &self.srs {
+            Some(srs) => srs.forward(address(&mail.from), Utc::now()),
+            None => address(&mail.from).to_string(),
+        };
+        let mut data = format!(
+            "Received: by {} with ESMTP id {id};\r\n\t{}\r\n",
+            self.host,
+            Utc::now().to_rfc2822()
+        )
+        .into_bytes();
+        data.extend_from_slice(&mail.data);
+        if let Some(signer) = &self.dkim {
+            data = signer.sign(&data, Utc::now());
+        }
+        if let Some(sealer) = &self.arc {
+            data = sealer.seal(&data, Utc::now());
+        }
+        let groups = match &self.route {
+            Route::Smarthost { .. } => vec![(String::new(), to)],
+            Route::Mx { .. } => by_domain(&to),
+        };
+        for (group, to) in groups {
+            if domain.is_some_and(|domain| domain != group) {
+                continue;
+            }
+            let hosts = self.hosts(&group).await?;
+            let envelope = Envelope {
+                from: from.clone(),
+                to,
+            };
+            self.send(&hosts, &envelope, &data).await?;
+            tracing::debug!("Relayed mail {id} to {}", envelope.to.join(", "));
+        }
+        Ok(())
+    }
+
+    /// Hosts to try for a recipient domain, in order
+    async fn hosts(&self, domain: &str) -> Result<Vec<(String, u16)>> {
+        match &self.route {
+            Route::Smarthost { host, port } => Ok(vec![(host.clone(), *port)]),
+            Route::Mx { resolver, port } => {
+                let records = match resolver.mx_lookup(format!("{domain}.")).await {
+                    Ok(lookup) => lookup
+                        .iter()
+                        .map(|mx| (mx.preference(), mx.exchange().to_ascii()))
+                        .collect(),
+                    Err(err) => match err.kind() {
+                        ResolveErrorKind::NoRecordsFound {
+                            response_code: ResponseCode::NXDomain,
+                            ..
+                        } => return Err(rejected(550, format!("domain {domain} does not exist"))),
+                        ResolveErrorKind::NoRecordsFound { .. } => Vec::new(),
+                        _ => {
+                            return Err(err)
+                                .with_context(|| format!("cannot look up the MX of {domain}"))
+                        }
+                    },
+                };
+                Ok(exchangers(domain, records)?
+                    .into_iter()
+                    .map(|host| (host, *port))
+                    .collect())
+            }
+        }
+    }
+
+    /// Sends a message to the first of the hosts taking it. A host refusing it
+    /// for good is not worth going past, as the others serve the same domain.
+    async fn send(&self, hosts: &[(String, u16)], envelope: &Envelope, data: &[u8]) -> Result<()> {
+        let mut failure = None;
+        for (host, port) in hosts {
+            match self.pool.send(host, *port, envelope, data).await {
+                Ok(sent) => {
+                    for (to, reply) in sent.rejected {
+                        tracing::warn!("{host} refused recipient {to}: {reply}");
+                    }
+                    return Ok(());
+                }
+                Err(err) if err.is_permanent() => {
+                    return Err(err).with_context(|| format!("{host} refused the message"));
+                }
+                Err(err) => {
+                    tracing::debug!("Cannot relay to {host}: {err}");
+                    failure =
+                        Some(anyhow::Error::from(err).context(format!("cannot relay to {host}")));
+                }
+            }
+        }
+        Err(failure.unwrap_or_else(|| anyhow::anyhow!("no host to relay to")))
+    }
+}
+
+impl MailSink for Relay {
+    fn endpoint(&self) -> String {
+        match &self.route {
+            Route::Mx { port, .. } => format!("smtp://mx:{port}"),
+            Route::Smarthost { host, port } => format!("smtp://{host}:{port}"),
+        }
+    }
+
+    /// Sends the message once per recipient domain, or once to the smarthost.
+    /// Recipients refused one by one are logged rather than retried, so the others
+    /// don't get the message twice.
+    async fn deliver(&self, id: &QueueId, mail: &Mail, _: &Message) -> DeliveryResult {
+        self.relay(id, mail, None).await
+    }
+
+    /// The recipient domains, when sending to their MX, so a domain failing
+    /// for now doesn't have the others get the message again
+    fn parts(&self, id: &QueueId, mail: &Mail) -> Vec<String> {
+        match &self.route {
+            Route::Smarthost { .. } => Vec::new(),
+            Route::Mx { .. } => by_domain(&self.recipients(id, mail))
+                .into_iter()
+                .map(|(domain, _)| domain)
+                .collect(),
+        }
+    }
+
+    async fn deliver_part(
+        &self,
+        id: &QueueId,
+        mail: &Mail,
+        _: &Message,
+        domain: &str,
+    ) -> DeliveryResult {
+        self.relay(id, mail, Some(domain)).await
+    }
+}
+
+fn rejected(code: u16, text: String) -> anyhow::Error {
+    ClientError::Rejected(Reply {
+        code,
+        lines: vec![text],
+    })
+    .into()
+}
+
+/// An address without its angle brackets
+fn address(path: &str) -> &str {
+    path.trim().trim_matches(|c| c == '<' || c == '>')
+}
+
+/// The recipients grouped by lowercase domain, in the order they came
+fn by_domain(to: &[String]) -> Vec<(String, Vec<String>)> {
+    let mut groups: Vec<(String, Vec<String>)> = Vec::new();
+    for to in to {
+        let to = address(to);
+        let domain = to
+            .rsplit_once('@')
+            .map_or("", |(_, domain)| domain)
+            .to_lowercase();
+        match groups.iter_mut().find(|(other, _)| *other == domain) {
+            Some((_, group)) => group.push(to.to_string()),
+            None => groups.push((domain, vec![to.to_string()])),
+        }
+    }
+    groups
+}
+
+/// Mail exchangers of a domain from its MX records, most preferred first.
+/// A domain without any is its own exchanger, and one with a null MX
+/// (RFC 7505) takes no mail.
+fn exchangers(domain: &str, mut records: Vec<(u16, String)>) -> Result<Vec<String>> {
+    if records.is_empty() {
+        return Ok(vec![domain.to_string()]);
+    }
+    records.sort_by_key(|(preference, _)| *preference);
+    let hosts = records
+        .into_iter()
+        .map(|(_, exchange)| exchange.trim_end_matches('.').to_string())
+        .filter(|exchange| !exchange.is_empty())
+        .collect::<Vec<_>>();
+    if hosts.is_empty() {
+        return Err(rejected(
+            556,
+            format!("domain {domain} does not accept mail"),
+        ));
+    }
+    Ok(hosts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_by_domain() {
+        let to = ["<a@Example.com>", "<b@other.org>", "<c@example.com>"].map(String::from);
+        assert_eq!(
+            by_domain(&to),
+            [
+                (
+                    "example.com".to_string(),
+                    vec!["a@Example.com".to_string(), "c@example.com".to_string()]
+                ),
+                ("other.org".to_string(), vec!["b@other.org".to_string()]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_recipients() {
+        let srs = Srs::new("secret", "fwd.example", 21);
+        let bounce = srs.forward("alice@example.com", Utc::now());
+        let relay = Relay::new(
+            Route::Smarthost {
+                host: "smtp.example.com".into(),
+                port: 587,
+            },
+            Config::default(),
+        )
+        .with_aliases(Aliases::parse("hello@fwd.example me@gmail.com").unwrap())
+        .with_srs(srs);
+        let mail = Mail {
+            to: vec![
+                "<hello@fwd.example>".into(),
+                format!("<{bounce}>"),
+                format!("<{}>", bounce.replace("alice", "mallory")),
+                "<nobody@fwd.example>".into(),
+            ],
+            ..Mail::default()
+        };
+        assert_eq!(
+            relay.recipients(&QueueId::generate(), &mail),
+            ["alice@example.com", "me@gmail.com"]
+        );
+    }
+
+    #[test]
+    fn test_exchangers() {
+        let records = vec![
+            (20, "mx2.example.com.".to_string()),
+            (10, "mx1.example.com.".to_string()),
+        ];
+        assert_eq!(
+            exchangers("example.com", records).unwrap(),
+            ["mx1.example.com", "mx2.example.com"]
+        );
+        assert_eq!(
+            exchangers("example.com", Vec::new()).unwrap(),
+            ["example.com"]
+        );
+        let null = exchangers("example.com", vec![(0, ".".to_string())]).unwrap_err();
+        assert!(!crate::retry::is_transient(&null));
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    async fn test_relay_received_and_hops() {
+        use std::sync::{Arc, Mutex};
+        use std::time::Instant;
+
+        use crate::protocol::Acceptor;
+
+        #[derive(Default)]
+        struct Collect(Mutex<Vec<Mail>>);
+
+        impl Acceptor for Collect {
+            async fn accept(&self, mail: &Mail, _: Instant) -> anyhow::Result<Vec<QueueId>> {
+                self.0.lock().unwrap().push(mail.clone());
+                Ok(vec![QueueId::generate()])
+            }
+        }
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let collect = Arc::new(Collect::default());
+        let server = tokio::spawn({
+            let collect = collect.clone();
+            async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                let server =
+                    crate::smtp::Server::new(&crate::smtp::Settings::new("test"), stream, collect)
+                        .await
+                        .unwrap();
+                server.serve().await.unwrap()
+            }
+        });
+        let relay = Relay::new(
+            Route::Smarthost {
+                host: "127.0.0.1".into(),
+                port,
+            },
+            Config {
+                helo_name: "fwd.example".into(),
+                ..Config::default()
+            },
+        );
+        let id = QueueId::generate();
+        let mail = Mail {
+            from: "<a@example.com>".into(),
+            to: vec!["<b@example.org>".into()],
+            data: b"Received: by mx.example.com\r\nFrom: a@example.com\r\n\r\nhello\r\n".to_vec(),
+        };
+        let message = crate::payload::build(&mail.data, &Default::default()).unwrap();
+        relay.deliver(&id, &mail, &message).await.unwrap();
+        drop(relay);
+        server.await.unwrap();
+        let relayed = collect.0.lock().unwrap().remove(0);
+        let relayed = String::from_utf8(relayed.data).unwrap();
+        // Under the field of the server relayed to
+        assert!(
+            relayed.contains(&format!(
+                "\r\nReceived: by fwd.example with ESMTP id {id};\r\n\t"
+            )),
+            "{relayed}"
+        );
+        assert!(relayed
+            .ends_with("Received: by mx.example.com\r\nFrom: a@example.com\r\n\r\nhello\r\n.\r\n"));
+
+        let looping = Mail {
+            data: "Received: by mx.example.com\r\n"
+                .repeat(MAX_HOPS)
+                .into_bytes(),
+            ..mail
+        };
+        // Refused before connecting to the smarthost, there being none there
+        let nowhere = Relay::new(
+            Route::Smarthost {
+                host: "127.0.0.1".into(),
+                port: 1,
+            },
+            Config::default(),
+        );
+        let err = nowhere.deliver(&id, &looping, &message).await.unwrap_err();
+        assert!(!crate::retry::is_transient(&err), "{err:#}");
+    }
+}
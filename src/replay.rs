@@ -0,0 +1,136 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+
+use crate::forward::Destination;
+use crate::payload;
+use crate::protocol::Mail;
+use crate::queue::QueueId;
+
+/// Arguments of the `replay` subcommand
+#[derive(Debug, Default, PartialEq)]
+pub struct Args {
+    /// Raw message files, or directories of them
+    pub paths: Vec<PathBuf>,
+    /// Destination URL replacing the configured one
+    pub destination: Option<String>,
+    /// Prints the payloads instead of delivering them
+    pub dry_run: bool,
+}
+
+impl Args {
+    /// Parses `replay [--destination <url>] [--dry-run] <path>...`,
+    /// `args` starting after the subcommand name
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self> {
+        let mut parsed = Self::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--destination" => {
+                    parsed.destination = Some(args.next().context("--destination needs a URL")?)
+                }
+                "--dry-run" => parsed.dry_run = true,
+                flag if flag.starts_with("--") => bail!("unknown replay option {flag}"),
+                path => parsed.paths.push(path.into()),
+            }
+        }
+        if parsed.paths.is_empty() {
+            bail!("usage: replay [--destination <url>] [--dry-run] <message or directory>...");
+        }
+        Ok(parsed)
+    }
+}
+
+/// Lists the message files to replay, directories expanded in name order
+fn messages(paths: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let mut messages = Vec::new();
+    for path in paths {
+        if path.is_dir() {
+            let mut entries = std::fs::read_dir(path)
+                .with_context(|| format!("cannot read {}", path.display()))?
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<std::io::Result<Vec<_>>>()?;
+            entries.retain(|entry| entry.is_file());
+            entries.sort();
+            messages.extend(entries);
+        } else {
+            messages.push(path.clone());
+        }
+    }
+    Ok(messages)
+}
+
+/// Runs stored raw messages through the current parsing and delivery pipeline,
+/// e.g. after fixing a parser bug. Each message is replayed with a fresh queue id,
+/// so the destination doesn't drop it as a duplicate of the first delivery.
+/// `destination` is the configured one, or the one given in the arguments.
+pub async fn run(args: Args, destination: Destination, options: &payload::Options) -> Result<()> {
+    let client = reqwest::Client::new();
+    let (mut replayed, mut failed) = (0, 0);
+    for path in messages(&args.paths)? {
+        match replay(&path, &destination, options, &client, args.dry_run).await {
+            Ok(()) => replayed += 1,
+            Err(err) => {
+                tracing::warn!("Cannot replay {}: {err:#}", path.display());
+                failed += 1;
+            }
+        }
+    }
+    tracing::info!("Replayed {replayed} messages, {failed} failed");
+    if failed > 0 {
+        bail!("{failed} messages could not be replayed");
+    }
+    Ok(())
+}
+
+async fn replay(
+    path: &Path,
+    destination: &Destination,
+    options: &payload::Options,
+    client: &reqwest::Client,
+    dry_run: bool,
+) -> Result<()> {
+    let raw = std::fs::read(path)?;
+    let message = payload::build(&raw, options)?;
+    if dry_run {
+        println!("{}", serde_json::to_string_pretty(&message)?);
+        return Ok(());
+    }
+    let id = QueueId::generate();
+    let mail = Mail {
+        data: raw,
+        ..Default::default()
+    };
+    destination.forward(client, &id, &mail, &message).await?;
+    tracing::info!("Replayed {} as {id}", path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_args() {
+        let args = Args::parse(
+            [
+                "--dry-run",
+                "spool",
+                "--destination",
+                "https://example.com/hook",
+            ]
+            .map(String::from),
+        )
+        .unwrap();
+        assert_eq!(
+            args,
+            Args {
+                paths: vec!["spool".into()],
+                destination: Some("https://example.com/hook".into()),
+                dry_run: true,
+            }
+        );
+        assert!(Args::parse(Vec::new()).is_err());
+        assert!(Args::parse(["--force".to_string()]).is_err());
+    }
+}
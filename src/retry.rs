@@ -0,0 +1,171 @@
+use std::time::Duration;
+
+use anyhow::Result;
+
+/// How failed deliveries are retried: after delays doubling from `base_delay`
+/// up to `max_delay`, until `max_attempts` attempts failed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RetryPolicy {
+    /// Attempts before giving up on a message, the first one included
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    /// Fraction of each delay that is randomized, between 0 and 1,
+    /// so mail that failed together isn't retried all at once
+    pub jitter: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 10,
+            base_delay: Duration::from_secs(30),
+            max_delay: Duration::from_secs(60 * 60),
+            jitter: 0.2,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Checks the policy makes sense, the jitter being a fraction of the delays
+    pub fn validate(&self) -> Result<()> {
+        anyhow::ensure!(
+            (0.0..=1.0).contains(&self.jitter),
+            "retry-jitter must be between 0 and 1"
+        );
+        Ok(())
+    }
+
+    /// Delay before the next attempt, after `attempts` attempts failed,
+    /// or `None` when it's time to give up
+    pub fn delay(&self, attempts: u32) -> Option<Duration> {
+        if attempts >= self.max_attempts {
+            return None;
+        }
+        let exponent = attempts.saturating_sub(1).min(31);
+        let delay = self
+            .base_delay
+            .saturating_mul(1 << exponent)
+            .min(self.max_delay);
+        // Shortened rather than lengthened, so max_delay holds
+        Some(delay.mul_f64(1.0 - self.jitter * rand::random::<f64>()))
+    }
+}
+
+/// Whether a failed delivery may succeed when tried again: network errors,
+/// timeouts, 5xx, 408 and 429 replies. Other 4xx replies mean the endpoint
+/// refuses the message, and retrying won't change its mind, as do 5xx SMTP replies.
+pub fn is_transient(err: &anyhow::Error) -> bool {
+    #[cfg(feature = "client")]
+    if let Some(err) = err.downcast_ref::<crate::smtp_client::ClientError>() {
+        return !err.is_permanent();
+    }
+    let Some(status) = err
+        .downcast_ref::<reqwest::Error>()
+        .and_then(reqwest::Error::status)
+    else {
+        return true;
+    };
+    status.is_server_error()
+        || status == reqwest::StatusCode::REQUEST_TIMEOUT
+        || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delays_double_up_to_max() {
+        let policy = RetryPolicy {
+            max_attempts: 5,
+            base_delay: Duration::from_secs(10),
+            max_delay: Duration::from_secs(30),
+            jitter: 0.0,
+        };
+        let delays = (1..=5)
+            .map(|attempts| policy.delay(attempts))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            delays,
+            [
+                Some(Duration::from_secs(10)),
+                Some(Duration::from_secs(20)),
+                Some(Duration::from_secs(30)),
+                Some(Duration::from_secs(30)),
+                None
+            ]
+        );
+
+        let jittery = RetryPolicy {
+            jitter: 0.5,
+            ..policy
+        };
+        for _ in 0..20 {
+            let delay = jittery.delay(1).unwrap();
+            assert!((Duration::from_secs(5)..=Duration::from_secs(10)).contains(&delay));
+        }
+    }
+
+    #[test]
+    fn test_transient_errors() {
+        assert!(is_transient(&anyhow::anyhow!("connection reset")));
+        #[cfg(feature = "client")]
+        {
+            use crate::smtp_client::{ClientError, Reply};
+            let reply = |code| {
+                anyhow::Error::from(ClientError::Rejected(Reply {
+                    code,
+                    lines: Vec::new(),
+                }))
+                .context("mx.example.com refused the message")
+            };
+            assert!(is_transient(&reply(451)));
+            assert!(!is_transient(&reply(550)));
+        }
+    }
+
+    /// The error of a request to a server answering `status`
+    async fn status_error(status: u16) -> anyhow::Error {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0; 4096];
+            while !request.ends_with(b"\r\n\r\n") {
+                let read = stream.read(&mut buffer).await.unwrap();
+                request.extend_from_slice(&buffer[..read]);
+            }
+            let reply = format!("HTTP/1.1 {status} Status\r\ncontent-length: 0\r\n\r\n");
+            stream.write_all(reply.as_bytes()).await.unwrap();
+        });
+        reqwest::get(format!("http://127.0.0.1:{port}"))
+            .await
+            .unwrap()
+            .error_for_status()
+            .unwrap_err()
+            .into()
+    }
+
+    #[tokio::test]
+    async fn test_transient_statuses() {
+        for status in [408, 429, 500, 502, 503] {
+            assert!(is_transient(&status_error(status).await), "{status}");
+        }
+        for status in [400, 401, 403, 404, 410, 413, 422] {
+            assert!(!is_transient(&status_error(status).await), "{status}");
+        }
+        // Nothing listening, the connection is refused
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+        let err = reqwest::get(format!("http://127.0.0.1:{port}"))
+            .await
+            .unwrap_err();
+        assert!(err.is_connect());
+        assert!(is_transient(&err.into()));
+    }
+}
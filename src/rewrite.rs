@@ -0,0 +1,202 @@
+use std::str::FromStr;
+
+use anyhow::{bail, Result};
+use linkify::{LinkFinder, LinkKind};
+use url::form_urlencoded;
+
+use crate::schema::Message;
+
+/// How URLs in forwarded bodies are rewritten for a destination
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum UrlRewrite {
+    /// Makes URLs non-clickable: `https://evil.com` becomes `hxxps://evil[.]com`
+    Defang,
+    /// Sends clicks through a redirector: the percent-encoded URL
+    /// is appended to the given prefix, e.g. `https://r.example.com/?u=`
+    Redirect(String),
+}
+
+impl FromStr for UrlRewrite {
+    type Err = anyhow::Error;
+
+    /// Parses `defang` or `redirect:<prefix>`
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once(':') {
+            _ if s.eq_ignore_ascii_case("defang") => Ok(Self::Defang),
+            Some((mode, prefix)) if mode.eq_ignore_ascii_case("redirect") && !prefix.is_empty() => {
+                Ok(Self::Redirect(prefix.to_string()))
+            }
+            _ => bail!("unknown URL rewrite {s:?}, expected `defang` or `redirect:<prefix>`"),
+        }
+    }
+}
+
+impl UrlRewrite {
+    /// Rewrites the URLs in text and HTML parts and in the extracted URL list
+    pub fn apply(&self, message: &mut Message) {
+        for content in &mut message.content {
+            let Some(mime) = content.mime.as_deref() else {
+                continue;
+            };
+            let Some(value) = &mut content.value else {
+                continue;
+            };
+            if mime.eq_ignore_ascii_case("text/html") {
+                *value = self.rewrite_html(value);
+            } else if mime.eq_ignore_ascii_case("text/plain") {
+                *value = self.rewrite_text(value);
+            }
+        }
+        for link in &mut message.urls {
+            link.url = self.rewrite_url(&link.url);
+        }
+    }
+
+    /// Rewrites every URL found in the text
+    pub fn rewrite_text(&self, text: &str) -> String {
+        let mut finder = LinkFinder::new();
+        finder.kinds(&[LinkKind::Url]);
+        let mut rewritten = String::with_capacity(text.len());
+        let mut last = 0;
+        for link in finder.links(text) {
+            rewritten.push_str(&text[last..link.start()]);
+            rewritten.push_str(&self.rewrite_url(link.as_str()));
+            last = link.end();
+        }
+        rewritten.push_str(&text[last..]);
+        rewritten
+    }
+
+    /// Rewrites every URL found in HTML, whose character references are decoded
+    /// first, so that `?a=1&amp;b=2` is redirected to `?a=1&b=2` as a browser
+    /// would follow it, and the rewritten URL escaped again
+    pub fn rewrite_html(&self, html: &str) -> String {
+        let mut finder = LinkFinder::new();
+        finder.kinds(&[LinkKind::Url]);
+        let mut rewritten = String::with_capacity(html.len());
+        let mut last = 0;
+        for link in finder.links(html) {
+            rewritten.push_str(&html[last..link.start()]);
+            let url = self.rewrite_url(&unescape(link.as_str()));
+            rewritten.push_str(&url.replace('&', "&amp;"));
+            last = link.end();
+        }
+        rewritten.push_str(&html[last..]);
+        rewritten
+    }
+
+    /// Rewrites a single URL
+    pub fn rewrite_url(&self, url: &str) -> String {
+        match self {
+            Self::Defang => defang(url),
+            Self::Redirect(prefix) => {
+                let encoded = form_urlencoded::byte_serialize(url.as_bytes()).collect::<String>();
+                format!("{prefix}{encoded}")
+            }
+        }
+    }
+}
+
+/// Replaces the scheme's `t`s with `x`s and brackets the dots of the host
+fn defang(url: &str) -> String {
+    let Some((scheme, rest)) = url.split_once("://") else {
+        return url.replace('.', "[.]");
+    };
+    let scheme = scheme.replacen("tt", "xx", 1);
+    let host_end = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+    let (host, path) = rest.split_at(host_end);
+    format!("{scheme}://{}{path}", host.replace('.', "[.]"))
+}
+
+/// Decodes the character references a URL may hold in HTML: `&amp;`, `&quot;`,
+/// `&lt;`, `&gt;`, `&apos;` and numeric ones. Others are kept as they are.
+fn unescape(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let reference = rest[1..]
+            .find(';')
+            .map(|end| &rest[1..end + 1])
+            .filter(|reference| reference.len() <= 8);
+        let character = reference.and_then(|reference| match reference {
+            "amp" => Some('&'),
+            "quot" => Some('"'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "apos" => Some('\''),
+            _ => {
+                let number = reference.strip_prefix('#')?;
+                let code = match number.strip_prefix(['x', 'X']) {
+                    Some(hex) => u32::from_str_radix(hex, 16),
+                    None => number.parse(),
+                };
+                char::from_u32(code.ok()?)
+            }
+        });
+        match (reference, character) {
+            (Some(reference), Some(character)) => {
+                decoded.push(character);
+                rest = &rest[reference.len() + 2..];
+            }
+            _ => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("defang".parse::<UrlRewrite>().unwrap(), UrlRewrite::Defang);
+        assert_eq!(
+            "redirect:https://r.example.com/?u="
+                .parse::<UrlRewrite>()
+                .unwrap(),
+            UrlRewrite::Redirect("https://r.example.com/?u=".into())
+        );
+        assert!("redirect:".parse::<UrlRewrite>().is_err());
+        assert!("shorten".parse::<UrlRewrite>().is_err());
+    }
+
+    #[test]
+    fn test_defang() {
+        assert_eq!(
+            UrlRewrite::Defang
+                .rewrite_text(r#"<a href="https://evil.example.com/a.php?x=1">go</a>"#),
+            r#"<a href="hxxps://evil[.]example[.]com/a.php?x=1">go</a>"#
+        );
+        assert_eq!(
+            UrlRewrite::Defang.rewrite_text("see http://a.example.org and more"),
+            "see hxxp://a[.]example[.]org and more"
+        );
+    }
+
+    #[test]
+    fn test_redirect() {
+        let rewrite = UrlRewrite::Redirect("https://r.example.com/?u=".into());
+        assert_eq!(
+            rewrite.rewrite_text("go to https://a.com/x?y=1 now"),
+            "go to https://r.example.com/?u=https%3A%2F%2Fa.com%2Fx%3Fy%3D1 now"
+        );
+        // The href is the URL with `&` escaped, which the redirector must not see
+        assert_eq!(
+            rewrite.rewrite_html(r#"<a href="https://a.com/?x=1&amp;y=2&#38;z=3">go</a>"#),
+            r#"<a href="https://r.example.com/?u=https%3A%2F%2Fa.com%2F%3Fx%3D1%26y%3D2%26z%3D3">go</a>"#
+        );
+        let rewrite = UrlRewrite::Redirect("https://r.example.com/?s=1&u=".into());
+        assert_eq!(
+            rewrite.rewrite_html(r#"<a href="https://a.com/">go</a>"#),
+            r#"<a href="https://r.example.com/?s=1&amp;u=https%3A%2F%2Fa.com%2F">go</a>"#
+        );
+        assert_eq!(unescape("a&amp;b&unknown;c&d&#x3c;"), "a&b&unknown;c&d<");
+    }
+}
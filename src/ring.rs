@@ -0,0 +1,80 @@
+/// Points each node gets on the ring, evening out the share of keys per node
+const VIRTUAL_NODES: usize = 64;
+
+/// Consistent hash ring mapping keys to nodes, e.g. recipient domains
+/// to delivery workers or to the instances of a cluster. Adding or removing
+/// a node only moves the keys of that node. The hash is stable across builds
+/// and platforms, so instances sharing the node names agree on the mapping.
+#[derive(Clone, Debug)]
+pub struct HashRing {
+    /// Points on the ring and the index of the node owning them, sorted
+    points: Vec<(u64, usize)>,
+}
+
+impl HashRing {
+    pub fn new(nodes: impl IntoIterator<Item = impl AsRef<str>>) -> Self {
+        let mut points = nodes
+            .into_iter()
+            .enumerate()
+            .flat_map(|(index, node)| {
+                let node = node.as_ref().to_string();
+                (0..VIRTUAL_NODES).map(move |point| (hash(&format!("{node}#{point}")), index))
+            })
+            .collect::<Vec<_>>();
+        points.sort_unstable();
+        Self { points }
+    }
+
+    /// Index of the node owning the key, the first node clockwise from its hash.
+    /// None when the ring has no nodes.
+    pub fn node(&self, key: &str) -> Option<usize> {
+        let hash = hash(key);
+        let point = self.points.partition_point(|(point, _)| *point < hash);
+        self.points
+            .get(point)
+            .or_else(|| self.points.first())
+            .map(|(_, node)| *node)
+    }
+}
+
+/// 64-bit FNV-1a, with the MurmurHash3 finalizer spreading similar keys apart
+fn hash(key: &str) -> u64 {
+    let mut hash = key.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    });
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^ (hash >> 33)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keys_move_only_with_their_node() {
+        let three = HashRing::new(["a", "b", "c"]);
+        let four = HashRing::new(["a", "b", "c", "d"]);
+        let keys = (0..1000)
+            .map(|i| format!("domain{i}.example"))
+            .collect::<Vec<_>>();
+        let mut per_node = [0; 3];
+        for key in &keys {
+            let node = three.node(key).unwrap();
+            per_node[node] += 1;
+            let moved = four.node(key).unwrap();
+            assert!(
+                moved == node || moved == 3,
+                "{key} moved from {node} to {moved}"
+            );
+        }
+        // Roughly even, a third each
+        assert!(
+            per_node.iter().all(|count| (250..420).contains(count)),
+            "{per_node:?}"
+        );
+        assert_eq!(HashRing::new(Vec::<String>::new()).node("a"), None);
+    }
+}
@@ -0,0 +1,188 @@
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use crate::protocol::{strip_terminator, Mail, Reply, StateMachine};
+use crate::schema::Spam;
+
+/// Reply of rspamd to a scan, as far as it matters here
+#[derive(Debug, Deserialize)]
+struct Scanned {
+    score: f64,
+    required_score: f64,
+    /// What rspamd would do with the message, e.g. `no action`, `add header` or `reject`
+    action: String,
+    #[serde(default)]
+    symbols: BTreeMap<String, Symbol>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Symbol {
+    #[serde(default)]
+    score: f64,
+}
+
+/// A local rspamd, scoring the mail received for spam over its HTTP protocol.
+/// Mail over the reject score is refused, mail rspamd soft rejects or greylists
+/// deferred, and mail over the tag score tagged as spam, the scores recorded
+/// on top of the message either way.
+#[derive(Clone, Debug)]
+pub struct Rspamd {
+    client: reqwest::Client,
+    /// Base URL of the normal worker, e.g. `http://localhost:11333`
+    url: String,
+    /// Sent to rspamd, when its controller requires one
+    pub password: Option<String>,
+    /// Score mail is refused from, rspamd's `reject` action deciding without it
+    pub reject_score: Option<f64>,
+    /// Score mail is tagged as spam from, rspamd's `add header` and
+    /// `rewrite subject` actions deciding without it
+    pub tag_score: Option<f64>,
+    /// How long a scan may take
+    pub timeout: Duration,
+}
+
+impl Rspamd {
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            url: url.into(),
+            password: None,
+            reject_score: None,
+            tag_score: None,
+            timeout: Duration::from_secs(10),
+        }
+    }
+
+    /// Scans a mail, from the client at `ip` introduced as `helo` and authenticated
+    /// as `user`, if at all. Returns its spam score, and the refusal it calls for.
+    pub async fn scan(
+        &self,
+        mail: &Mail,
+        ip: Option<IpAddr>,
+        helo: Option<&str>,
+        user: Option<&str>,
+    ) -> Result<(Spam, Option<Reply>)> {
+        let address = |path: &str| {
+            path.trim_start_matches('<')
+                .trim_end_matches('>')
+                .to_string()
+        };
+        let mut request = self
+            .client
+            .post(format!("{}/checkv2", self.url.trim_end_matches('/')))
+            .timeout(self.timeout)
+            .header("From", address(&mail.from))
+            .body(strip_terminator(&mail.data).to_vec());
+        for recipient in &mail.to {
+            request = request.header("Rcpt", address(recipient));
+        }
+        let headers = [
+            ("IP", ip.map(|ip| ip.to_string())),
+            ("Helo", helo.map(str::to_string)),
+            ("User", user.map(str::to_string)),
+            ("Password", self.password.clone()),
+        ];
+        for (name, value) in headers
+            .into_iter()
+            .filter_map(|(name, value)| Some((name, value?)))
+        {
+            request = request.header(name, value);
+        }
+        let response = request
+            .send()
+            .await?
+            .error_for_status()
+            .context("rspamd failed")?
+            .text()
+            .await?;
+        let scanned = serde_json::from_str(&response).context("invalid rspamd reply")?;
+        Ok(self.judge(scanned))
+    }
+
+    /// The spam score of a scanned mail, and the refusal it calls for
+    fn judge(&self, scanned: Scanned) -> (Spam, Option<Reply>) {
+        let rejected = match self.reject_score {
+            Some(reject_score) => scanned.score >= reject_score,
+            None => scanned.action == "reject",
+        };
+        let tagged = match self.tag_score {
+            Some(tag_score) => scanned.score >= tag_score,
+            None => matches!(scanned.action.as_str(), "add header" | "rewrite subject"),
+        };
+        let spam = Spam {
+            score: scanned.score,
+            required_score: scanned.required_score,
+            tagged: tagged || rejected,
+            symbols: scanned
+                .symbols
+                .into_iter()
+                .map(|(name, symbol)| (name, symbol.score))
+                .collect(),
+        };
+        let refusal = match scanned.action.as_str() {
+            _ if rejected => Some(StateMachine::SPAMMY),
+            // Taken once it comes back, e.g. past the greylisting delay
+            "soft reject" | "greylist" => Some(StateMachine::MAYBE_SPAMMY),
+            _ => None,
+        };
+        (spam, refusal)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scanned(score: f64, action: &str) -> Scanned {
+        serde_json::from_value(serde_json::json!({
+            "is_skipped": false,
+            "score": score,
+            "required_score": 15.0,
+            "action": action,
+            "symbols": {
+                "BAYES_SPAM": {"name": "BAYES_SPAM", "score": 5.1, "options": ["99.99%"]},
+                "MIME_GOOD": {"name": "MIME_GOOD", "score": -0.1}
+            },
+            "message-id": "1@example.com"
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_judge() {
+        let rspamd = Rspamd::new("http://localhost:11333");
+        let (spam, refusal) = rspamd.judge(scanned(5.0, "no action"));
+        assert_eq!((spam.score, spam.tagged, refusal), (5.0, false, None));
+        assert_eq!(spam.symbols["BAYES_SPAM"], 5.1);
+        assert_eq!(spam.symbols["MIME_GOOD"], -0.1);
+        let (spam, refusal) = rspamd.judge(scanned(7.0, "add header"));
+        assert_eq!((spam.tagged, refusal), (true, None));
+        let (_, refusal) = rspamd.judge(scanned(16.0, "reject"));
+        assert_eq!(refusal, Some(StateMachine::SPAMMY));
+        for action in ["soft reject", "greylist"] {
+            let (spam, refusal) = rspamd.judge(scanned(6.0, action));
+            assert_eq!(
+                (spam.tagged, refusal),
+                (false, Some(StateMachine::MAYBE_SPAMMY))
+            );
+        }
+
+        let rspamd = Rspamd {
+            reject_score: Some(10.0),
+            tag_score: Some(4.0),
+            ..rspamd
+        };
+        let (spam, refusal) = rspamd.judge(scanned(5.0, "no action"));
+        assert_eq!((spam.tagged, refusal), (true, None));
+        let (_, refusal) = rspamd.judge(scanned(12.0, "add header"));
+        assert_eq!(refusal, Some(StateMachine::SPAMMY));
+        let (spam, refusal) = rspamd.judge(scanned(3.0, "reject"));
+        assert_eq!((spam.tagged, refusal), (false, None));
+        let (_, refusal) = rspamd.judge(scanned(12.0, "greylist"));
+        assert_eq!(refusal, Some(StateMachine::SPAMMY));
+    }
+}
@@ -0,0 +1,203 @@
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Duration, NaiveTime, Utc};
+
+use crate::priority::Priority;
+use crate::protocol::Mail;
+
+/// Which messages a schedule rule applies to
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Matcher {
+    /// Low priority mail: mailing lists, newsletters and large messages
+    Bulk,
+    /// Mail for a recipient address, or a whole `@domain`
+    To(String),
+    /// Mail from a sender address, or a whole `@domain`
+    From(String),
+}
+
+/// When matching messages get delivered
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Defer {
+    /// A fixed delay after acceptance
+    Delay(Duration),
+    /// Only within a daily UTC time window, which may span midnight
+    Window(NaiveTime, NaiveTime),
+}
+
+/// Defers delivery of the messages it matches
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rule {
+    pub matcher: Matcher,
+    pub defer: Defer,
+}
+
+impl FromStr for Rule {
+    type Err = anyhow::Error;
+
+    /// Parses `<matcher> <defer>`, where the matcher is one of `bulk`,
+    /// `to:<address|@domain>` or `from:<address|@domain>`, and the deferral
+    /// is `delay:<minutes>` or `window:<HH:MM>-<HH:MM>`.
+    fn from_str(s: &str) -> Result<Self> {
+        let (matcher, defer) = s
+            .trim()
+            .split_once(char::is_whitespace)
+            .with_context(|| format!("invalid schedule rule {s:?}"))?;
+        let matcher = match matcher.split_once(':') {
+            None if matcher.eq_ignore_ascii_case("bulk") => Matcher::Bulk,
+            Some(("to", address)) => Matcher::To(address.to_lowercase()),
+            Some(("from", address)) => Matcher::From(address.to_lowercase()),
+            _ => bail!("invalid schedule matcher {matcher:?}"),
+        };
+        let defer = match defer.trim().split_once(':') {
+            Some(("delay", minutes)) => Defer::Delay(Duration::minutes(
+                minutes.parse().context("invalid schedule delay")?,
+            )),
+            Some(("window", window)) => {
+                let (start, end) = window
+                    .split_once('-')
+                    .with_context(|| format!("invalid schedule window {window:?}"))?;
+                Defer::Window(
+                    NaiveTime::parse_from_str(start, "%H:%M")?,
+                    NaiveTime::parse_from_str(end, "%H:%M")?,
+                )
+            }
+            _ => bail!("invalid schedule deferral {defer:?}"),
+        };
+        Ok(Self { matcher, defer })
+    }
+}
+
+impl Rule {
+    fn matches(&self, mail: &Mail, priority: Priority) -> bool {
+        let address_matches = |address: &str, pattern: &str| {
+            let address = address
+                .trim_matches(|c| c == '<' || c == '>')
+                .to_lowercase();
+            match pattern.strip_prefix('@') {
+                Some(domain) => address
+                    .rsplit_once('@')
+                    .is_some_and(|(_, address_domain)| address_domain == domain),
+                None => address == pattern,
+            }
+        };
+        match &self.matcher {
+            Matcher::Bulk => priority == Priority::Low,
+            Matcher::To(pattern) => mail.to.iter().any(|to| address_matches(to, pattern)),
+            Matcher::From(pattern) => address_matches(&mail.from, pattern),
+        }
+    }
+
+    /// Earliest time the rule allows delivery, None meaning right away
+    fn not_before(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match self.defer {
+            Defer::Delay(delay) => Some(now + delay),
+            Defer::Window(start, end) => {
+                let time = now.time();
+                let inside = if start <= end {
+                    start <= time && time < end
+                } else {
+                    time >= start || time < end
+                };
+                if inside {
+                    return None;
+                }
+                let today = now.date_naive().and_time(start).and_utc();
+                Some(if today > now {
+                    today
+                } else {
+                    today + Duration::days(1)
+                })
+            }
+        }
+    }
+}
+
+/// Parses `;` separated rules
+pub fn parse_rules(rules: &str) -> Result<Vec<Rule>> {
+    rules
+        .split(';')
+        .filter(|rule| !rule.trim().is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+/// Computes when a mail may be delivered according to the first matching rule
+pub fn not_before(
+    rules: &[Rule],
+    mail: &Mail,
+    priority: Priority,
+    now: DateTime<Utc>,
+) -> Option<DateTime<Utc>> {
+    rules
+        .iter()
+        .find(|rule| rule.matches(mail, priority))
+        .and_then(|rule| rule.not_before(now))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(time: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(time).unwrap().into()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            "bulk window:01:00-05:00".parse::<Rule>().unwrap(),
+            Rule {
+                matcher: Matcher::Bulk,
+                defer: Defer::Window(
+                    NaiveTime::from_hms_opt(1, 0, 0).unwrap(),
+                    NaiveTime::from_hms_opt(5, 0, 0).unwrap()
+                ),
+            }
+        );
+        assert_eq!(
+            "to:@News.example.com delay:30".parse::<Rule>().unwrap(),
+            Rule {
+                matcher: Matcher::To("@news.example.com".into()),
+                defer: Defer::Delay(Duration::minutes(30)),
+            }
+        );
+        assert!("everything delay:30".parse::<Rule>().is_err());
+        assert!("bulk window:1-5".parse::<Rule>().is_err());
+    }
+
+    #[test]
+    fn test_not_before() {
+        let rules = [
+            "to:@news.example.com delay:30".parse().unwrap(),
+            "bulk window:22:00-04:00".parse().unwrap(),
+        ];
+        let mail = Mail {
+            from: "<a@example.com>".into(),
+            to: vec!["<digest@news.example.com>".into()],
+            data: Vec::new(),
+        };
+        let now = at("2024-01-01T12:00:00Z");
+        assert_eq!(
+            not_before(&rules, &mail, Priority::Low, now),
+            Some(at("2024-01-01T12:30:00Z"))
+        );
+        let mail = Mail {
+            to: vec!["<bob@example.com>".into()],
+            ..mail
+        };
+        assert_eq!(not_before(&rules, &mail, Priority::Normal, now), None);
+        assert_eq!(
+            not_before(&rules, &mail, Priority::Low, now),
+            Some(at("2024-01-01T22:00:00Z"))
+        );
+        let night = at("2024-01-01T23:00:00Z");
+        assert_eq!(not_before(&rules, &mail, Priority::Low, night), None);
+        let morning = at("2024-01-02T05:00:00Z");
+        assert_eq!(
+            not_before(&rules, &mail, Priority::Low, morning),
+            Some(at("2024-01-02T22:00:00Z"))
+        );
+    }
+}
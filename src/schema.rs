@@ -1,6 +1,9 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Message {
     pub from: Contact,
@@ -10,10 +13,94 @@ pub struct Message {
     pub bcc: Vec<Contact>,
     pub subject: Option<String>,
     pub content: Vec<Content>,
+    /// ISO 639-3 code of the body language, when it could be detected
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+    pub urls: Vec<Link>,
+    /// Remote content stripped from HTML parts, for privacy-focused deployments
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub removed_content: Vec<RemovedContent>,
     pub attachments: Vec<Attachments>,
+    /// The message as received, base64-encoded, when asked for
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub raw: Option<String>,
+    /// Whether the envelope or the headers have UTF-8 addresses (SMTPUTF8, RFC 6531)
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub internationalized_addresses: bool,
+    /// Queue id of the mail this one duplicates, with the same Message-ID and recipients,
+    /// when duplicates are flagged
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duplicate_of: Option<String>,
+    /// Results of the authentication checks run on receipt, when the server checks
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub authentication: Option<Authentication>,
+    /// Spam score rspamd gave the message on receipt, when the server scans
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub spam: Option<Spam>,
+    /// Name the client introduced itself with in HELO/EHLO, from the `Received`
+    /// field the server adds on receipt
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub helo: Option<String>,
+    /// Whether the client talked before it was greeted, as spam bots do
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub early_talker: bool,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Authentication {
+    /// SPF check of the envelope sender's domain, or of the HELO name for bounces
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub spf: Option<AuthResult>,
+    /// Verification of each DKIM signature of the message, in the order of their fields
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub dkim: Vec<AuthResult>,
+    /// DMARC evaluation of the From domain, with the SPF and DKIM results
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dmarc: Option<AuthResult>,
+    /// Whether the mail failed DMARC and its domain asks for it to be quarantined
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub quarantine: bool,
+    /// Listing of the client in the DNS blocklists, `listed` from the score threshold
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dnsbl: Option<AuthResult>,
+    /// Reverse DNS check of the client, passing when its name points back to it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iprev: Option<AuthResult>,
+    /// Name the client introduced itself with in HELO/EHLO, as recorded with SPF
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub helo: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Spam {
+    /// Score of the message, the higher the spammier
+    pub score: f64,
+    /// Score rspamd considers the message spam from
+    pub required_score: f64,
+    /// Whether the message was tagged as spam, for its score
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub tagged: bool,
+    /// Rules the message matched, e.g. `BAYES_SPAM`, with their scores
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub symbols: BTreeMap<String, f64>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthResult {
+    /// Result of the check, e.g. `pass`, `fail` or `temperror`
+    pub result: String,
+    /// Why the check didn't pass, e.g. `body hash mismatch`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    /// What was checked, e.g. `smtp.mailfrom` with the sender's domain
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub properties: BTreeMap<String, String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Contact {
     pub email: Option<String>,
@@ -21,15 +108,114 @@ pub struct Contact {
     pub name: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Content {
     pub mime: Option<String>,
     pub value: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Link {
+    pub url: String,
+    /// Registrable domain of the URL host, e.g. `example.co.uk`
+    pub domain: Option<String>,
+    /// Whether the host is on a blocklist, when blocklists are configured
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub blocklisted: Option<bool>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RemovedContent {
+    pub kind: RemovedKind,
+    pub url: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum RemovedKind {
+    /// 1x1 image used to track when the message is opened
+    TrackingPixel,
+    /// Image or background loaded from a remote server
+    RemoteImage,
+    /// `<link>`ed style sheet
+    RemoteStylesheet,
+    /// `url(...)` reference in CSS
+    RemoteStyle,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct Attachments {
     pub filename: String,
+    /// MIME type, e.g. `application/pdf`
+    pub content_type: Option<String>,
+    /// `Content-ID` the HTML parts refer to inline attachments by, without the brackets
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_id: Option<String>,
+    /// `inline` or `attachment`, from `Content-Disposition`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub disposition: Option<String>,
+    /// Decoded content, base64-encoded in the payload,
+    /// empty when the attachment is too large and uploaded instead
+    #[serde(with = "base64_content")]
     pub content: Vec<u8>,
+    /// Presigned link to the uploaded content
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// Size in bytes of the uploaded content
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<usize>,
+    /// When the link stops working
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<DateTime<Utc>>,
+    /// PNG preview of image attachments, as a `data:` URI
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thumbnail: Option<String>,
+}
+
+/// Base64 in JSON, bytes in binary formats like MessagePack
+mod base64_content {
+    use base64::Engine;
+    use serde::de::Visitor;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(content: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        match serializer.is_human_readable() {
+            true => {
+                serializer.serialize_str(&base64::engine::general_purpose::STANDARD.encode(content))
+            }
+            false => serializer.serialize_bytes(content),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        if !deserializer.is_human_readable() {
+            return deserializer.deserialize_bytes(Bytes);
+        }
+        let content = String::deserialize(deserializer)?;
+        base64::engine::general_purpose::STANDARD
+            .decode(content)
+            .map_err(serde::de::Error::custom)
+    }
+
+    struct Bytes;
+
+    impl<'de> Visitor<'de> for Bytes {
+        type Value = Vec<u8>;
+
+        fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+            formatter.write_str("bytes")
+        }
+
+        fn visit_bytes<E: serde::de::Error>(self, bytes: &[u8]) -> Result<Vec<u8>, E> {
+            Ok(bytes.to_vec())
+        }
+
+        fn visit_byte_buf<E: serde::de::Error>(self, bytes: Vec<u8>) -> Result<Vec<u8>, E> {
+            Ok(bytes)
+        }
+    }
 }
@@ -0,0 +1,177 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use tokio::time::Instant;
+
+use crate::ratelimit::Buckets;
+
+/// Sender domains counted before the ones idle for a day are forgotten
+const MAX_SENDERS: usize = 10_000;
+
+/// What the mail of a sender domain amounted to since the server started
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SenderStats {
+    pub domain: String,
+    /// Mails accepted, counted against the quota of the domain
+    pub messages: u64,
+    /// Mails deferred for being over the quota of the domain
+    pub deferred: u64,
+    pub last_seen: DateTime<Utc>,
+}
+
+/// Hourly quotas of the sender domains, the MAIL FROM ones, and what their mail
+/// amounted to. A domain over its quota has its mail deferred until it is back under.
+#[derive(Debug)]
+pub struct Senders {
+    /// Quota of the domains without one of their own, none for no limit
+    default: Option<u32>,
+    /// Quotas of their own, by lowercase domain
+    quotas: HashMap<String, u32>,
+    /// Each bucket limited by the quota of its domain
+    buckets: Buckets<String>,
+    stats: Mutex<HashMap<String, SenderStats>>,
+}
+
+impl Senders {
+    /// Parses `domain=messages` quotas, comma separated, overriding the default
+    pub fn new(default: Option<u32>, quotas: &str) -> Result<Self> {
+        let mut senders = Self {
+            default,
+            quotas: HashMap::new(),
+            buckets: Buckets::new(0, Duration::from_secs(3600)),
+            stats: Mutex::default(),
+        };
+        for entry in quotas
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+        {
+            let (domain, messages) = entry
+                .split_once('=')
+                .with_context(|| format!("invalid quota {entry}, expected domain=messages"))?;
+            let messages = messages
+                .trim()
+                .parse()
+                .with_context(|| format!("invalid quota of {domain}"))?;
+            senders
+                .quotas
+                .insert(domain.trim().to_lowercase(), messages);
+        }
+        Ok(senders)
+    }
+
+    /// Whether `sender`, as in MAIL, may send another mail within the quota of its
+    /// domain, without counting it. Mail over the quota is recorded as deferred.
+    pub fn may_send(&self, sender: &str) -> bool {
+        let Some(domain) = domain(sender) else {
+            return true;
+        };
+        let allowed = match self.quota(&domain) {
+            Some(quota) => self.buckets.has_limited(&domain, quota, Instant::now()),
+            None => true,
+        };
+        if !allowed {
+            self.record(domain, |stats| stats.deferred += 1);
+        }
+        allowed
+    }
+
+    /// Counts a mail from `sender` against the quota of its domain, once accepted,
+    /// so mail refused or merely claiming the domain doesn't use it up.
+    /// Bounces, from the null sender, are not counted.
+    pub fn message(&self, sender: &str) {
+        let Some(domain) = domain(sender) else {
+            return;
+        };
+        if let Some(quota) = self.quota(&domain) {
+            self.buckets
+                .take_limited(domain.clone(), quota, Instant::now());
+        }
+        self.record(domain, |stats| stats.messages += 1);
+    }
+
+    fn quota(&self, domain: &str) -> Option<u32> {
+        self.quotas.get(domain).copied().or(self.default)
+    }
+
+    fn record(&self, domain: String, update: impl FnOnce(&mut SenderStats)) {
+        let now = Utc::now();
+        let mut stats = self.stats.lock().unwrap();
+        if stats.len() >= MAX_SENDERS {
+            stats.retain(|_, stats| now - stats.last_seen < chrono::Duration::days(1));
+        }
+        let stats = stats.entry(domain.clone()).or_insert_with(|| SenderStats {
+            domain,
+            messages: 0,
+            deferred: 0,
+            last_seen: now,
+        });
+        update(stats);
+        stats.last_seen = now;
+    }
+
+    /// What the mail of the sender domains amounted to, busiest first
+    pub fn stats(&self) -> Vec<SenderStats> {
+        let mut stats = self
+            .stats
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        stats.sort_by(|a, b| b.messages.cmp(&a.messages).then(a.domain.cmp(&b.domain)));
+        stats
+    }
+}
+
+/// The lowercase domain of a sender, none for the null sender
+fn domain(sender: &str) -> Option<String> {
+    let address = sender.trim_start_matches('<').trim_end_matches('>');
+    let (_, domain) = address.rsplit_once('@')?;
+    Some(domain.trim_end_matches('.').to_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quotas() {
+        let senders = Senders::new(Some(2), "Example.org=1, example.net = 0").unwrap();
+        // Only the mail accepted counts
+        assert!((0..3).all(|_| senders.may_send("<a@example.com>")));
+        senders.message("<a@example.com>");
+        assert!(senders.may_send("<b@EXAMPLE.com>"));
+        senders.message("<b@EXAMPLE.com>");
+        assert!(!senders.may_send("<c@example.com>"));
+        assert!(senders.may_send("<a@example.org>"));
+        senders.message("<a@example.org>");
+        assert!(!senders.may_send("<a@example.org>"));
+        assert!(!senders.may_send("<a@example.net>"));
+        assert!(senders.may_send("<>"));
+        senders.message("<>");
+
+        let stats = senders.stats();
+        let counts = stats
+            .iter()
+            .map(|stats| (stats.domain.as_str(), stats.messages, stats.deferred))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            counts,
+            [
+                ("example.com", 2, 1),
+                ("example.org", 1, 1),
+                ("example.net", 0, 1)
+            ]
+        );
+
+        assert!(Senders::new(None, "example.com").is_err());
+        assert!(Senders::new(None, "example.com=many").is_err());
+        assert!(Senders::new(None, "example.com=5")
+            .unwrap()
+            .may_send("<a@example.com>"));
+    }
+}
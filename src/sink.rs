@@ -0,0 +1,218 @@
+use std::future::Future;
+use std::pin::Pin;
+
+use anyhow::Result;
+
+use crate::protocol::Mail;
+use crate::queue::QueueId;
+use crate::schema::Message;
+
+/// Outcome of handing a message to a sink. Failures are retried
+/// when [`retry::is_transient`](crate::retry::is_transient) says they may pass.
+pub type DeliveryResult = Result<()>;
+
+/// Where the mail of no tenant is handed over, besides its destinations:
+/// a broker, a queue, a store, or whatever an embedding application registers.
+pub trait MailSink: Send + Sync {
+    /// Name of the sink in the journal and the ledger, e.g. `kafka://mail`
+    fn endpoint(&self) -> String;
+
+    /// Hands a message over, `mail` being what it was built from, its raw data
+    /// included. The queue id is the same across retries of the message.
+    fn deliver(
+        &self,
+        id: &QueueId,
+        mail: &Mail,
+        message: &Message,
+    ) -> impl Future<Output = DeliveryResult> + Send;
+
+    /// Whether the sink needs the content of the attachments, having nowhere to
+    /// put the link of one uploaded to the object store
+    fn needs_attachment_content(&self) -> bool {
+        false
+    }
+
+    /// Parts a message is handed over in, apart from each other, e.g. one per
+    /// recipient domain. The ledger records each part that took the message, so
+    /// its retries go to the others only. None for a sink taking it at once.
+    fn parts(&self, _id: &QueueId, _mail: &Mail) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// Hands a message over to one of its [`parts`](Self::parts)
+    fn deliver_part(
+        &self,
+        id: &QueueId,
+        mail: &Mail,
+        message: &Message,
+        _part: &str,
+    ) -> impl Future<Output = DeliveryResult> + Send {
+        self.deliver(id, mail, message)
+    }
+}
+
+/// [`MailSink`] with a boxed future, so sinks of different types share a registry
+pub(crate) trait DynSink: Send + Sync {
+    fn endpoint(&self) -> String;
+
+    fn deliver<'a>(
+        &'a self,
+        id: &'a QueueId,
+        mail: &'a Mail,
+        message: &'a Message,
+    ) -> Pin<Box<dyn Future<Output = DeliveryResult> + Send + 'a>>;
+
+    fn needs_attachment_content(&self) -> bool;
+
+    fn parts(&self, id: &QueueId, mail: &Mail) -> Vec<String>;
+
+    fn deliver_part<'a>(
+        &'a self,
+        id: &'a QueueId,
+        mail: &'a Mail,
+        message: &'a Message,
+        part: &'a str,
+    ) -> Pin<Box<dyn Future<Output = DeliveryResult> + Send + 'a>>;
+}
+
+impl<S: MailSink> DynSink for S {
+    fn endpoint(&self) -> String {
+        MailSink::endpoint(self)
+    }
+
+    fn deliver<'a>(
+        &'a self,
+        id: &'a QueueId,
+        mail: &'a Mail,
+        message: &'a Message,
+    ) -> Pin<Box<dyn Future<Output = DeliveryResult> + Send + 'a>> {
+        Box::pin(MailSink::deliver(self, id, mail, message))
+    }
+
+    fn needs_attachment_content(&self) -> bool {
+        MailSink::needs_attachment_content(self)
+    }
+
+    fn parts(&self, id: &QueueId, mail: &Mail) -> Vec<String> {
+        MailSink::parts(self, id, mail)
+    }
+
+    fn deliver_part<'a>(
+        &'a self,
+        id: &'a QueueId,
+        mail: &'a Mail,
+        message: &'a Message,
+        part: &'a str,
+    ) -> Pin<Box<dyn Future<Output = DeliveryResult> + Send + 'a>> {
+        Box::pin(MailSink::deliver_part(self, id, mail, message, part))
+    }
+}
+
+/// Sinks the mail of no tenant is delivered to, in the order they were registered
+#[derive(Default)]
+pub struct SinkRegistry {
+    sinks: Vec<Box<dyn DynSink>>,
+}
+
+impl SinkRegistry {
+    /// Adds a sink, delivered to after the ones registered before it.
+    /// Its endpoint must be unique, as the ledger records deliveries by endpoint.
+    pub fn register(&mut self, sink: impl MailSink + 'static) -> Result<&mut Self> {
+        let endpoint = MailSink::endpoint(&sink);
+        anyhow::ensure!(
+            self.sinks.iter().all(|other| other.endpoint() != endpoint),
+            "sink {endpoint} is registered twice"
+        );
+        self.sinks.push(Box::new(sink));
+        Ok(self)
+    }
+
+    /// Endpoints of the registered sinks
+    pub fn endpoints(&self) -> impl Iterator<Item = String> + '_ {
+        self.sinks.iter().map(|sink| sink.endpoint())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sinks.is_empty()
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &dyn DynSink> {
+        self.sinks.iter().map(Box::as_ref)
+    }
+}
+
+/// Domain of the first recipient, lowercase, which keys or groups the mail on
+/// the brokers that keep order per key
+pub fn recipient_domain(mail: &Mail) -> String {
+    mail.to
+        .first()
+        .map(|to| to.trim_matches(|c| c == '<' || c == '>'))
+        .and_then(|to| to.rsplit_once('@'))
+        .map(|(_, domain)| domain.to_lowercase())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::payload;
+
+    /// Sink keeping the raw data of what it is given
+    #[derive(Default)]
+    struct Collect(Arc<Mutex<Vec<Vec<u8>>>>);
+
+    impl MailSink for Collect {
+        fn endpoint(&self) -> String {
+            "collect://".into()
+        }
+
+        async fn deliver(&self, _: &QueueId, mail: &Mail, _: &Message) -> DeliveryResult {
+            self.0.lock().unwrap().push(mail.data.clone());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_registry() {
+        let mut registry = SinkRegistry::default();
+        assert!(registry.is_empty());
+        let collect = Collect::default();
+        let received = collect.0.clone();
+        registry.register(collect).unwrap();
+        assert_eq!(registry.endpoints().collect::<Vec<_>>(), ["collect://"]);
+
+        let mail = Mail {
+            data: b"From: a@example.com\r\nSubject: hi\r\n\r\nhello\r\n".to_vec(),
+            ..Mail::default()
+        };
+        let message = payload::build(&mail.data, &payload::Options::default()).unwrap();
+        for sink in registry.iter() {
+            sink.deliver(&QueueId::generate(), &mail, &message)
+                .await
+                .unwrap();
+        }
+        assert_eq!(*received.lock().unwrap(), [mail.data]);
+    }
+
+    #[test]
+    fn test_register_twice() {
+        let mut registry = SinkRegistry::default();
+        registry.register(Collect::default()).unwrap();
+        let Err(err) = registry.register(Collect::default()) else {
+            panic!("registered twice");
+        };
+        assert_eq!(err.to_string(), "sink collect:// is registered twice");
+    }
+
+    #[test]
+    fn test_recipient_domain() {
+        let mail = Mail {
+            to: vec!["<Ann@Example.COM>".into(), "<b@example.org>".into()],
+            ..Mail::default()
+        };
+        assert_eq!(recipient_domain(&mail), "example.com");
+        assert_eq!(recipient_domain(&Mail::default()), "");
+    }
+}
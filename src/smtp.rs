@@ -1,383 +1,1590 @@
-use anyhow::{Context, Result};
-use mail_parser::{MessageParser, MimeHeaders};
+use std::borrow::Cow;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
 use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{
+    AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, ReadBuf,
+};
 
-use crate::schema::{Attachments, Contact, Content, Message};
+#[cfg(feature = "relay")]
+use crate::alias::Aliases;
+use crate::auth::Tokens;
+#[cfg(feature = "dns")]
+use crate::authentication;
+#[cfg(feature = "dns")]
+use crate::checks::{Checks, Verdict};
+use crate::domains::Domains;
+use crate::error::SmtpError;
+use crate::helo::HeloPolicy;
+#[cfg(any(feature = "dns", feature = "rspamd"))]
+use crate::protocol::Reply;
+use crate::protocol::{Acceptor, Mail, State, StateMachine};
+use crate::ratelimit::ClientRates;
+use crate::received::{ReceivedMail, Session};
+use crate::recipients::Recipients;
+#[cfg(feature = "rspamd")]
+use crate::rspamd::Rspamd;
+#[cfg(feature = "rspamd")]
+use crate::schema::Spam;
+use crate::senders::Senders;
+#[cfg(feature = "rspamd")]
+use crate::spam;
+#[cfg(feature = "dns")]
+use crate::spf::SpfResult;
+use crate::tarpit::Tarpit;
 
-#[derive(Clone, Debug, Default, PartialEq, Eq)]
-pub struct Mail {
-    pub from: String,
-    pub to: Vec<String>,
-    pub data: String,
+/// Longest line accepted from a client, commands and message lines alike
+const MAX_LINE_LENGTH: u64 = 65536;
+
+/// How long clients may take, so stalled or malicious ones don't hold
+/// their connection forever. A timed out client is told so with a 421.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Timeouts {
+    /// For each command or line of data, and each reply sent (RFC 5321 section 4.5.3.2)
+    pub command: Duration,
+    /// For the whole session
+    pub session: Option<Duration>,
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
-enum State {
-    Fresh,
-    Greeted,
-    ReceivingRcpt(Mail),
-    ReceivingData(Mail),
-    Received(Mail),
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            command: Duration::from_secs(300),
+            session: None,
+        }
+    }
 }
 
-struct StateMachine {
-    state: State,
-    ehlo_greeting: String,
+/// What a session needs to know besides its stream, handed to [`Server::new`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Settings {
+    /// Domain the server answers as
+    pub domain: String,
+    pub timeouts: Timeouts,
+    /// Largest message accepted, in bytes
+    pub max_size: usize,
+    /// Bad commands a client may send before it is disconnected
+    pub max_errors: usize,
+    /// How long the greeting is held back, catching the clients that
+    /// don't wait for it as they should (RFC 5321 section 3.1)
+    pub greeting_delay: Option<Duration>,
+    /// Whether the clients talking before the greeting are turned away,
+    /// rather than flagged in their sessions and tarpitted
+    pub reject_early_talkers: bool,
+    /// Whether AUTH PLAIN and LOGIN are taken without TLS, the password in the clear
+    pub allow_insecure_auth: bool,
 }
 
-/// An state machine capable of handling SMTP commands
-/// for receiving mail.
-/// Use handle_smtp() to handle a single command.
-/// The return value from handle_smtp() is the response
-/// that should be sent back to the client.
-impl StateMachine {
-    const OH_HAI: &[u8] = b"220 edgemail\n";
-    const KK: &[u8] = b"250 Ok\n";
-    const AUTH_OK: &[u8] = b"235 Ok\n";
-    const SEND_DATA_PLZ: &[u8] = b"354 End data with <CR><LF>.<CR><LF>\n";
-    const KTHXBYE: &[u8] = b"221 Bye\n";
-    const HOLD_YOUR_HORSES: &[u8] = &[];
-
-    pub fn new(domain: impl AsRef<str>) -> Self {
-        tracing::trace!("New state machine initialized");
-        let domain = domain.as_ref();
-        let ehlo_greeting = format!("250-{domain} Hello {domain}\n250 AUTH PLAIN LOGIN\n");
+impl Settings {
+    /// Settings for a server answering as `domain`, with the default limits
+    pub fn new(domain: impl Into<String>) -> Self {
         Self {
-            state: State::Fresh,
-            ehlo_greeting,
-        }
-    }
-
-    /// Handles a single SMTP command and returns a proper SMTP response
-    pub fn handle_smtp(&mut self, raw_msg: &str) -> Result<&[u8]> {
-        tracing::trace!("Received {raw_msg} in state {:?}", self.state);
-        let mut msg = raw_msg.split_whitespace();
-        let command = msg.next().context("received empty command")?.to_lowercase();
-        let state = self.state.clone();
-        match (command.as_str(), state) {
-            ("ehlo", State::Fresh) => {
-                tracing::trace!("Sending AUTH info");
-                self.state = State::Greeted;
-                Ok(self.ehlo_greeting.as_bytes())
-            }
-            ("helo", State::Fresh) => {
-                self.state = State::Greeted;
-                Ok(StateMachine::KK)
-            }
-            ("noop", _) | ("help", _) | ("info", _) | ("vrfy", _) | ("expn", _) => {
-                tracing::trace!("Got {command}");
-                Ok(StateMachine::KK)
-            }
-            ("rset", _) => {
-                self.state = State::Fresh;
-                Ok(StateMachine::KK)
-            }
-            ("auth", _) => {
-                tracing::trace!("Acknowledging AUTH");
-                Ok(StateMachine::AUTH_OK)
-            }
-            ("mail", State::Greeted) => {
-                tracing::trace!("Receiving MAIL");
-                let from = msg.next().context("received empty MAIL")?;
-                let from = from
-                    .strip_prefix("FROM:")
-                    .context("received incorrect MAIL")?;
-                tracing::debug!("FROM: {from}");
-                self.state = State::ReceivingRcpt(Mail {
-                    from: from.to_string(),
-                    ..Default::default()
-                });
-                Ok(StateMachine::KK)
-            }
-            ("rcpt", State::ReceivingRcpt(mut mail)) => {
-                tracing::trace!("Receiving rcpt");
-                let to = msg.next().context("received empty RCPT")?;
-                let to = to.strip_prefix("TO:").context("received incorrect RCPT")?;
-                tracing::debug!("TO: {to}");
-                if Self::legal_recipient(to) {
-                    mail.to.push(to.to_string());
-                } else {
-                    tracing::warn!("Illegal recipient: {to}")
-                }
-                self.state = State::ReceivingRcpt(mail);
-                Ok(StateMachine::KK)
-            }
-            ("data", State::ReceivingRcpt(mail)) => {
-                tracing::trace!("Receiving data");
-                self.state = State::ReceivingData(mail);
-                Ok(StateMachine::SEND_DATA_PLZ)
-            }
-            ("quit", State::ReceivingData(mail)) => {
-                tracing::trace!(
-                    "Received data: FROM: {} TO:{} DATA:{}",
-                    mail.from,
-                    mail.to.join(", "),
-                    mail.data
-                );
-                self.state = State::Received(mail);
-                Ok(StateMachine::KTHXBYE)
-            }
-            ("quit", _) => {
-                tracing::warn!("Received quit before getting any data");
-                Ok(StateMachine::KTHXBYE)
-            }
-            (_, State::ReceivingData(mut mail)) => {
-                tracing::trace!("Receiving data");
-                let resp = if raw_msg.ends_with("\r\n.\r\n") {
-                    StateMachine::KK
-                } else {
-                    StateMachine::HOLD_YOUR_HORSES
-                };
-                mail.data += raw_msg;
-                self.state = State::ReceivingData(mail);
-                Ok(resp)
-            }
-            (msg, state) => {
-                tracing::trace!(
-                    "Bailing out: Unexpected message received in state {state:?}: {msg}"
-                );
-                anyhow::bail!(
-                    "Unexpected message received in state {:?}: {raw_msg}",
-                    self.state
-                )
-            }
+            domain: domain.into(),
+            timeouts: Timeouts::default(),
+            max_size: StateMachine::DEFAULT_MAX_SIZE,
+            max_errors: StateMachine::DEFAULT_MAX_ERRORS,
+            greeting_delay: None,
+            reject_early_talkers: false,
+            allow_insecure_auth: false,
+        }
+    }
+}
+
+/// Connection to the client, upgraded in place by STARTTLS
+enum Stream<S> {
+    Plain(S),
+    #[cfg(any(feature = "rustls", feature = "native-tls"))]
+    Tls(Box<crate::tls::ServerStream<S>>),
+    /// Only while the handshake is in progress
+    #[cfg(any(feature = "rustls", feature = "native-tls"))]
+    Upgrading,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for Stream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(any(feature = "rustls", feature = "native-tls"))]
+            Stream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(any(feature = "rustls", feature = "native-tls"))]
+            Stream::Upgrading => Poll::Ready(Err(io::ErrorKind::NotConnected.into())),
         }
     }
+}
 
-    /// Filter out admin, administrator, postmaster and hostmaster
-    /// to prevent being able to register certificates for the domain.
-    /// The check is over-eager, but it also makes it simpler.
-    fn legal_recipient(to: &str) -> bool {
-        let to = to.to_lowercase();
-        !to.contains("admin") && !to.contains("postmaster") && !to.contains("hostmaster")
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for Stream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(any(feature = "rustls", feature = "native-tls"))]
+            Stream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(any(feature = "rustls", feature = "native-tls"))]
+            Stream::Upgrading => Poll::Ready(Err(io::ErrorKind::NotConnected.into())),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(any(feature = "rustls", feature = "native-tls"))]
+            Stream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(any(feature = "rustls", feature = "native-tls"))]
+            Stream::Upgrading => Poll::Ready(Err(io::ErrorKind::NotConnected.into())),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(any(feature = "rustls", feature = "native-tls"))]
+            Stream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(any(feature = "rustls", feature = "native-tls"))]
+            Stream::Upgrading => Poll::Ready(Err(io::ErrorKind::NotConnected.into())),
+        }
     }
 }
 
 /// SMTP server, which handles user connections
 /// and replicates received messages to the database.
-pub struct Server {
-    stream: tokio::net::TcpStream,
+/// Serves SMTP or LMTP over any stream, e.g. TCP or a Unix socket.
+pub struct Server<S, A> {
+    /// Buffered, so commands are read one CRLF-terminated line at a time
+    stream: BufReader<Stream<S>>,
     state_machine: StateMachine,
+    acceptor: Arc<A>,
+    domain: String,
+    peer: Option<SocketAddr>,
+    /// Validates XOAUTH2 bearer tokens, when offered
+    tokens: Option<Arc<Tokens>>,
+    /// Rates of the clients, shared with the other sessions
+    rates: Option<Arc<ClientRates>>,
+    /// Quotas of the sender domains, shared with the other sessions
+    senders: Option<Arc<Senders>>,
+    /// Delays the replies to the client once it is suspicious
+    tarpit: Option<Tarpit>,
+    /// Replies delayed so far
+    tarpitted: u32,
+    timeouts: Timeouts,
+    greeting_delay: Option<Duration>,
+    reject_early_talkers: bool,
+    /// Whether the client talked before it was greeted
+    early_talker: bool,
+    /// When the session must be over, per the session timeout
+    deadline: Option<tokio::time::Instant>,
+    #[cfg(any(feature = "rustls", feature = "native-tls"))]
+    tls: Option<crate::tls::TlsAcceptor>,
+    /// Checks the senders, recording the results on top of their mail
+    #[cfg(feature = "dns")]
+    checks: Option<Arc<Checks>>,
+    /// authserv-id of the `Authentication-Results` fields stripped from the mail
+    /// when it isn't checked, as they are forged
+    #[cfg(feature = "dns")]
+    authserv_id: Option<String>,
+    /// What the checks of the client concluded, on connection
+    #[cfg(feature = "dns")]
+    client: Verdict,
+    /// What the checks of the current mail concluded
+    #[cfg(feature = "dns")]
+    verdict: Verdict,
+    /// Scores the mail for spam, recording the score on top of it
+    #[cfg(feature = "rspamd")]
+    rspamd: Option<Arc<Rspamd>>,
+    /// Spam score of the current mail, unless it couldn't be scanned
+    #[cfg(feature = "rspamd")]
+    spam: Option<Spam>,
 }
 
-impl Server {
+impl<S: AsyncRead + AsyncWrite + Unpin, A: Acceptor> Server<S, A> {
     /// Creates a new server from a connected stream
-    pub async fn new(domain: impl AsRef<str>, stream: tokio::net::TcpStream) -> Result<Self> {
+    pub async fn new(settings: &Settings, stream: S, acceptor: Arc<A>) -> Result<Self, SmtpError> {
+        let mut state_machine = StateMachine::new(&settings.domain);
+        state_machine.set_max_size(settings.max_size);
+        state_machine.set_max_errors(settings.max_errors);
+        state_machine.allow_insecure_auth(settings.allow_insecure_auth);
         Ok(Self {
-            stream,
-            state_machine: StateMachine::new(domain),
+            stream: BufReader::new(Stream::Plain(stream)),
+            state_machine,
+            acceptor,
+            domain: settings.domain.clone(),
+            peer: None,
+            tokens: None,
+            rates: None,
+            senders: None,
+            tarpit: None,
+            tarpitted: 0,
+            timeouts: settings.timeouts,
+            greeting_delay: settings.greeting_delay,
+            reject_early_talkers: settings.reject_early_talkers,
+            early_talker: false,
+            deadline: None,
+            #[cfg(any(feature = "rustls", feature = "native-tls"))]
+            tls: None,
+            #[cfg(feature = "dns")]
+            checks: None,
+            #[cfg(feature = "dns")]
+            authserv_id: None,
+            #[cfg(feature = "dns")]
+            client: Verdict::default(),
+            #[cfg(feature = "dns")]
+            verdict: Verdict::default(),
+            #[cfg(feature = "rspamd")]
+            rspamd: None,
+            #[cfg(feature = "rspamd")]
+            spam: None,
         })
     }
 
-    /// Runs the server loop, accepting and handling SMTP commands
-    pub async fn serve(mut self) -> Result<()> {
+    /// Checks AUTH against the accounts, instead of acknowledging any
+    pub fn with_users(mut self, users: Arc<crate::auth::Users>) -> Self {
+        self.state_machine.check_auth(users);
+        self
+    }
+
+    /// Offers XOAUTH2, validating the bearer tokens with `tokens`
+    pub fn with_tokens(mut self, tokens: Arc<Tokens>) -> Self {
+        self.state_machine.offer_xoauth2(true);
+        self.tokens = Some(tokens);
+        self
+    }
+
+    /// Refuses the mail of unauthenticated clients greeting with a name the policy refuses
+    pub fn with_helo_policy(mut self, policy: HeloPolicy) -> Self {
+        self.state_machine.set_helo_policy(policy);
+        self
+    }
+
+    /// Refuses the recipients of other domains than these, so unauthenticated
+    /// clients can't relay through the server
+    pub fn with_domains(mut self, domains: Domains) -> Self {
+        self.state_machine.set_domains(domains);
+        self
+    }
+
+    /// Accepts the recipients by these rules, instead of the default ones
+    pub fn with_recipients(mut self, recipients: Arc<Recipients>) -> Self {
+        self.state_machine.set_recipients(recipients);
+        self
+    }
+
+    /// Refuses the recipients without an alias, unless the client authenticated
+    #[cfg(feature = "relay")]
+    pub fn with_aliases(mut self, aliases: Arc<Aliases>) -> Self {
+        self.state_machine.set_aliases(aliases);
+        self
+    }
+
+    /// Limits the rates of the client, turning it away with a 421 when it connects
+    /// too often and deferring its mail with a 450 when it sends too much
+    pub fn with_rates(mut self, rates: Arc<ClientRates>) -> Self {
+        if let Some(max) = rates.recipients_per_message {
+            self.state_machine.set_max_recipients(max);
+        }
+        self.state_machine.check_senders(true);
+        self.rates = Some(rates);
+        self
+    }
+
+    /// Defers the mail of sender domains over their quota with a 450
+    pub fn with_senders(mut self, senders: Arc<Senders>) -> Self {
+        self.state_machine.check_senders(true);
+        self.senders = Some(senders);
+        self
+    }
+
+    /// Delays the replies to the client once it is suspicious, e.g. for its protocol errors
+    pub fn with_tarpit(mut self, tarpit: Tarpit) -> Self {
+        self.tarpit = Some(tarpit);
+        self
+    }
+
+    /// Takes mail only from authenticated clients, as a submission server
+    pub fn requiring_auth(mut self) -> Self {
+        self.state_machine.require_auth(true);
+        self
+    }
+
+    /// Offers STARTTLS, upgrading the connection with the acceptor's certificate
+    #[cfg(any(feature = "rustls", feature = "native-tls"))]
+    pub fn with_tls(mut self, acceptor: crate::tls::TlsAcceptor) -> Self {
+        self.state_machine.offer_starttls(true);
+        self.tls = Some(acceptor);
+        self
+    }
+
+    /// Checks the client on connection, e.g. with DNS blocklists, and the sender
+    /// and message of each mail, e.g. with SPF, refusing them when configured to
+    /// and recording the results on top of the message
+    #[cfg(feature = "dns")]
+    pub fn with_checks(mut self, checks: Arc<Checks>) -> Self {
+        self.state_machine.check_senders(true);
+        self.checks = Some(checks);
+        self
+    }
+
+    /// Strips the `Authentication-Results` fields claiming this authserv-id
+    /// from the mail, for clients whose mail isn't checked, e.g. submission ones,
+    /// so they can't pass results of their own off as ours
+    #[cfg(feature = "dns")]
+    pub fn with_authserv_id(mut self, authserv_id: impl Into<String>) -> Self {
+        self.authserv_id = Some(authserv_id.into());
+        self
+    }
+
+    /// Scans the mail received with rspamd, refusing or tagging it by its
+    /// spam score and recording the score on top of the message
+    #[cfg(feature = "rspamd")]
+    pub fn with_rspamd(mut self, rspamd: Arc<Rspamd>) -> Self {
+        self.rspamd = Some(rspamd);
+        self
+    }
+
+    /// Sets the address of the connected client, for network streams
+    pub fn with_peer(mut self, peer: SocketAddr) -> Self {
+        self.peer = Some(peer);
+        self
+    }
+
+    /// Runs the server loop, accepting and handling SMTP commands.
+    /// Returns the mail accepted during the session.
+    pub async fn serve(mut self) -> Result<Vec<ReceivedMail>, SmtpError> {
+        self.deadline = self
+            .timeouts
+            .session
+            .map(|session| tokio::time::Instant::now() + session);
+        if let Some((rates, peer)) = self.rates.as_ref().zip(self.peer) {
+            if !rates.connect(peer.ip()) {
+                tracing::info!("Turning away {peer}, connecting too often");
+                self.send(StateMachine::COME_BACK_LATER.as_bytes()).await?;
+                return Ok(Vec::new());
+            }
+        }
+        #[cfg(feature = "dns")]
+        if let Some(refusal) = self.check_client().await {
+            self.send(refusal.as_bytes()).await?;
+            return Ok(Vec::new());
+        }
+        self.early_talker = self.wait_to_greet().await?;
+        if self.early_talker && self.reject_early_talkers {
+            tracing::info!("Turning away {:?}, talking before the greeting", self.peer);
+            self.send(StateMachine::TOO_EAGER.as_bytes()).await?;
+            return Ok(Vec::new());
+        }
+        self.tarpit().await;
         self.greet().await?;
 
-        let mut buf = vec![0; 65536];
+        let mut line = Vec::new();
+        let mut received = Vec::new();
+        // Replies to pipelined commands, sent together once the batch is handled
+        let mut replies = Vec::new();
         loop {
-            let n = self.stream.read(&mut buf).await?;
+            line.clear();
+            let n = match tokio::time::timeout(self.time_left(), self.read_line(&mut line)).await {
+                Ok(n) => n?,
+                Err(_) => return Err(self.time_out().await),
+            };
+            if self.acceptor.disconnect() {
+                return Ok(received);
+            }
 
             if n == 0 {
                 tracing::info!("Received EOF");
-                self.state_machine.handle_smtp("quit").ok();
                 break;
             }
-            let msg = std::str::from_utf8(&buf[0..n])?;
-            let response = self.state_machine.handle_smtp(msg)?;
+            let mut response = match self.state_machine.handle_line(&line) {
+                Ok(response) => response.to_vec(),
+                Err(err) => {
+                    // Best effort, the session is over either way
+                    replies.extend_from_slice(err.reply().as_bytes());
+                    self.send(&replies).await.ok();
+                    return Err(err);
+                }
+            };
+            if let Some((user, token)) = self.state_machine.take_token_check() {
+                response = self.verify_token(user, token).await;
+            }
+            if let Some(sender) = self.state_machine.take_sender_check() {
+                self.check_sender(&sender).await;
+            }
+            if let Some((size, last)) = self.state_machine.take_chunk() {
+                match tokio::time::timeout(self.time_left(), self.read_chunk(size)).await {
+                    Ok(read) => read?,
+                    Err(_) => return Err(self.time_out().await),
+                }
+                response = self.state_machine.end_chunk(last).to_vec();
+            }
+            if let State::Received(mail) = &self.state_machine.state {
+                #[cfg(feature = "dns")]
+                let refusal = {
+                    let verdict = self.check_message(mail).await;
+                    self.verdict.results.extend(verdict.results);
+                    verdict.refusal
+                };
+                #[cfg(not(feature = "dns"))]
+                let refusal = None;
+                #[cfg(feature = "rspamd")]
+                let refusal = match refusal {
+                    None => {
+                        let (spam, refusal) = self.scan(mail).await;
+                        self.spam = spam;
+                        refusal
+                    }
+                    refusal => refusal,
+                };
+                // The mail must hit the journal before the client is told it's ours
+                let accepted = match refusal {
+                    Some(refusal) => {
+                        tracing::info!("Refusing mail from {}: {refusal}", mail.from);
+                        Err(refusal)
+                    }
+                    None => {
+                        let mail = self.authenticated(mail);
+                        let deadline = std::time::Instant::now() + self.time_left();
+                        let accepted = self.acceptor.accept(&mail, deadline).await;
+                        accepted
+                            .map(|ids| ReceivedMail::new(ids, &mail, self.session()))
+                            .map_err(|err| match err.downcast_ref::<SmtpError>() {
+                                Some(rejected @ SmtpError::Rejected(_)) => {
+                                    tracing::info!("Mail {rejected}");
+                                    rejected.reply()
+                                }
+                                _ => {
+                                    tracing::error!("Cannot accept mail: {err:#}");
+                                    StateMachine::UH_OH
+                                }
+                            })
+                    }
+                };
+                match accepted {
+                    Ok(mail) => {
+                        self.count(&mail);
+                        received.push(mail)
+                    }
+                    Err(reply) => response = self.state_machine.transaction_reply(reply),
+                }
+                // Either way, the client may go on with another mail
+                self.state_machine.end_transaction();
+            }
             if response != StateMachine::HOLD_YOUR_HORSES {
-                self.stream.write_all(response).await?;
+                replies.extend_from_slice(&response);
             } else {
                 tracing::debug!("Not responding, awaiting more data");
             }
-            if response == StateMachine::KTHXBYE {
+            // Flush once every command received so far got its reply (RFC 2920)
+            if !replies.is_empty()
+                && (self.stream.buffer().is_empty() || response == StateMachine::KTHXBYE.as_bytes())
+            {
+                self.tarpit().await;
+                self.send(&replies).await?;
+                replies.clear();
+            }
+            if response == StateMachine::KTHXBYE.as_bytes() {
                 break;
             }
+            if response == StateMachine::GO_AHEAD_TLS.as_bytes() {
+                self.send(&replies).await?;
+                replies.clear();
+                self.start_tls().await?;
+            }
         }
         tracing::trace!("State machine exited {:?}", self.state_machine.state);
-        match self.state_machine.state {
-            State::Received(mail) => 'rec: {
-                tracing::info!("Sending mail");
-                tracing::info!("{mail:?}");
-                let data = MessageParser::default().parse(&mail.data);
-                if let Some(data) = data {
-                    let Some(from) = data.from() else {
-                        break 'rec;
-                    };
-                    let from = from.clone().into_list();
-                    if from.len() != 1 {
-                        tracing::warn!("From length not supported");
-                        break 'rec;
-                    }
-                    let from = from.first().unwrap();
-                    let Some(email) = &from.address else {
-                        tracing::warn!("From ??");
-                        break 'rec;
-                    };
-                    let from = Contact {
-                        email: Some(email.to_string()),
-                        name: from.name().map(|e| e.to_string()),
-                    };
-                    let to = data
-                        .to()
-                        .map(|to| to.clone().into_list())
-                        .unwrap_or_default()
-                        .into_iter()
-                        .map(|address| Contact {
-                            email: address.address.map(|e| e.to_string()),
-                            name: address.name.map(|e| e.to_string()),
-                        })
-                        .collect::<Vec<_>>();
-                    let cc = data
-                        .cc()
-                        .map(|to| to.clone().into_list())
-                        .unwrap_or_default()
-                        .into_iter()
-                        .map(|address| Contact {
-                            email: address.address.map(|e| e.to_string()),
-                            name: address.name.map(|e| e.to_string()),
-                        })
-                        .collect::<Vec<_>>();
-                    let bcc = data
-                        .bcc()
-                        .map(|to| to.clone().into_list())
-                        .unwrap_or_default()
-                        .into_iter()
-                        .map(|address| Contact {
-                            email: address.address.map(|e| e.to_string()),
-                            name: address.name.map(|e| e.to_string()),
-                        })
-                        .collect::<Vec<_>>();
-                    let reply_to = data
-                        .reply_to()
-                        .map(|to| to.clone().into_list())
-                        .unwrap_or_default()
-                        .into_iter()
-                        .map(|address| Contact {
-                            email: address.address.map(|e| e.to_string()),
-                            name: address.name.map(|e| e.to_string()),
-                        })
-                        .collect::<Vec<_>>();
-                    let subject = data.subject().map(|e| e.to_string());
-                    let attachments = data
-                        .attachments()
-                        .into_iter()
-                        .map(|attachment| Attachments {
-                            filename: attachment.attachment_name().unwrap_or_default().to_string(),
-                            content: attachment.contents().to_vec(),
-                        })
-                        .collect();
-                    let content = data
-                        .parts
-                        .into_iter()
-                        .map(|part| Content {
-                            value: part.text_contents().map(|e| e.to_string()),
-                            mime: part.content_type().map(|e| {
-                                if let Some(subtyp) = &e.c_subtype {
-                                    format!("{}/{}", e.c_type, subtyp)
-                                } else {
-                                    format!("{}", e.c_type)
-                                }
-                            }),
-                        })
-                        .filter(|f| f.value.is_some())
-                        .collect::<Vec<_>>();
-
-                    let message = Message {
-                        from,
-                        to,
-                        reply_to,
-                        cc,
-                        bcc,
-                        subject,
-                        content,
-                        attachments,
-                    };
-                    tracing::trace!("Sending {message:?}");
-                    let json = serde_json::to_string(&message);
-                    let Ok(json) = json else {
-                        break 'rec;
-                    };
-                    let client = reqwest::Client::new();
-                    tracing::trace!("Sending json {json:?}");
-                    let resp = client
-                        .post("https://worker-email-production.deepgauravraj.workers.dev/api/email")
-                        .header("Content-Type", "application/json")
-                        .header(
-                            "Authorization",
-                            std::env::var("EMAIL_TOKEN").unwrap_or_default(),
-                        )
-                        .body(json)
-                        .send()
-                        .await;
-                    match resp {
-                        Ok(resp) => {
-                            let resp = resp.text().await.unwrap_or_default();
-                            tracing::debug!("RECEIVED SEND Response {resp}")
-                        }
-                        Err(err) => tracing::warn!("SEND ERROR {err:?}"),
-                    }
-                } else {
-                    tracing::warn!("Cant parse message, discarding")
-                }
-                // self.db.lock().await.replicate(mail).await?;
+        // Mail is accepted on its terminator: anything left is incomplete
+        if let State::ReceivingData(mail) | State::ReceivingChunks(mail) = &self.state_machine.state
+        {
+            tracing::info!(
+                "Discarding mail from {} cut short by EOF, after {} bytes",
+                mail.from,
+                mail.data.len()
+            );
+        }
+        Ok(received)
+    }
+
+    /// Reads the next line into `line`, terminator included, however the bytes
+    /// arrive: a line split across reads is put back together, and lines
+    /// arriving together are returned one at a time. Returns 0 at EOF.
+    async fn read_line(&mut self, line: &mut Vec<u8>) -> Result<usize, SmtpError> {
+        let n = (&mut self.stream)
+            .take(MAX_LINE_LENGTH)
+            .read_until(b'\n', line)
+            .await?;
+        if n as u64 == MAX_LINE_LENGTH && !line.ends_with(b"\n") {
+            return Err(SmtpError::ProtocolViolation(format!(
+                "line longer than {MAX_LINE_LENGTH} bytes"
+            )));
+        }
+        Ok(n)
+    }
+
+    /// Validates an XOAUTH2 token, returning the reply. Failing to validate
+    /// it, e.g. with the introspection endpoint down, counts as invalid.
+    async fn verify_token(&mut self, user: String, token: String) -> Vec<u8> {
+        let valid = match &self.tokens {
+            Some(tokens) => tokens.validate(&user, &token).await.unwrap_or_else(|err| {
+                tracing::error!("Cannot validate XOAUTH2 token: {err:#}");
+                false
+            }),
+            None => false,
+        };
+        self.state_machine.token_verified(user, valid).to_vec()
+    }
+
+    /// Counts the mail accepted against the rate of the client and the quota of
+    /// its sender's domain. When SPF is checked, only the mail the domain
+    /// authorized counts for it, as anyone can claim to send from it.
+    fn count(&self, mail: &ReceivedMail) {
+        let Some(peer) = self.peer else {
+            return;
+        };
+        if let Some(rates) = &self.rates {
+            rates.message(peer.ip());
+        }
+        #[cfg(feature = "dns")]
+        let authorized = self
+            .verdict
+            .spf
+            .as_ref()
+            .is_none_or(|(result, _)| *result == SpfResult::Pass);
+        #[cfg(not(feature = "dns"))]
+        let authorized = true;
+        if let (Some(senders), true) = (&self.senders, authorized) {
+            senders.message(&mail.sender);
+        }
+    }
+
+    /// Checks the client on connection, returning the reply refusing it if it fails.
+    /// Clients on a Unix socket are local, and not checked.
+    #[cfg(feature = "dns")]
+    async fn check_client(&mut self) -> Option<Reply> {
+        let (Some(checks), Some(peer)) = (&self.checks, self.peer) else {
+            return None;
+        };
+        self.client = checks.client(peer.ip()).await;
+        if let Some(refusal) = self.client.refusal {
+            tracing::info!("Refusing client {peer}: {refusal}");
+        }
+        self.client.refusal
+    }
+
+    /// Checks the sender of the mail just started, whose recipients are refused
+    /// if it fails: the rate of the client's mail and the quota of the sender's
+    /// domain, then the DNS checks. Clients on a Unix socket are local, and not checked.
+    async fn check_sender(&mut self, sender: &str) {
+        if let Some((rates, peer)) = self.rates.as_ref().zip(self.peer) {
+            if !rates.may_send(peer.ip()) {
+                tracing::info!("Deferring mail from {peer}, sending too much");
+                self.state_machine
+                    .sender_checked(Some(StateMachine::SLOW_DOWN));
+                return;
+            }
+        }
+        if let (Some(senders), Some(_)) = (&self.senders, self.peer) {
+            if !senders.may_send(sender) {
+                tracing::info!("Deferring mail from {sender}, its domain over its quota");
+                self.state_machine
+                    .sender_checked(Some(StateMachine::SLOW_DOWN));
+                return;
+            }
+        }
+        #[cfg(feature = "dns")]
+        self.check_sender_dns(sender).await;
+        #[cfg(not(feature = "dns"))]
+        let _ = sender;
+    }
+
+    /// Checks the sender of the mail just started with the DNS, e.g. with SPF
+    #[cfg(feature = "dns")]
+    async fn check_sender_dns(&mut self, sender: &str) {
+        let (Some(checks), Some(peer)) = (&self.checks, self.peer) else {
+            self.verdict = Verdict::default();
+            return;
+        };
+        let verdict = checks
+            .sender(peer.ip(), sender, self.state_machine.helo())
+            .await;
+        self.state_machine.sender_checked(verdict.refusal);
+        self.verdict = verdict;
+    }
+
+    /// Checks the mail received, from a client on a Unix socket not checked either
+    #[cfg(feature = "dns")]
+    async fn check_message(&self, mail: &Mail) -> Verdict {
+        match (&self.checks, self.peer) {
+            (Some(checks), Some(_)) => checks.message(&mail.data, &self.verdict).await,
+            _ => Verdict::default(),
+        }
+    }
+
+    /// Scans the mail received with rspamd, returning its spam score and the refusal
+    /// it calls for. Mail rspamd fails to scan is taken unscanned, rather than deferred.
+    #[cfg(feature = "rspamd")]
+    async fn scan(&self, mail: &Mail) -> (Option<Spam>, Option<Reply>) {
+        let Some(rspamd) = &self.rspamd else {
+            return (None, None);
+        };
+        let scanned = rspamd
+            .scan(
+                mail,
+                self.peer.map(|peer| peer.ip()),
+                self.state_machine.helo.as_deref(),
+                self.state_machine.authenticated(),
+            )
+            .await;
+        match scanned {
+            Ok((spam, refusal)) => (Some(spam), refusal),
+            Err(err) => {
+                tracing::warn!("Cannot scan mail from {}: {err:#}", mail.from);
+                (None, None)
             }
-            State::ReceivingData(mail) => {
-                tracing::info!("Received EOF before receiving QUIT");
-                tracing::info!("Discarding mail EOF");
-                tracing::info!("{mail:?}");
-                // self.db.lock().await.replicate(mail).await?;
+        }
+    }
+
+    /// The mail with the results of the checks and its spam score on top, when checked,
+    /// and without the `Authentication-Results` fields forged as ours either way,
+    /// under the `Received` field recording the session
+    fn authenticated(&self, mail: &Mail) -> Mail {
+        let data = Cow::Borrowed(&mail.data[..]);
+        #[cfg(feature = "dns")]
+        let data = match (&self.checks, &self.authserv_id) {
+            (Some(checks), _) => {
+                let results = [&self.client.results[..], &self.verdict.results].concat();
+                Cow::Owned(authentication::stamp(&data, &checks.authserv_id, &results))
             }
-            _ => {}
+            (None, Some(authserv_id)) => Cow::Owned(authentication::strip(&data, authserv_id)),
+            (None, None) => data,
+        };
+        #[cfg(feature = "rspamd")]
+        let data = match &self.rspamd {
+            Some(_) => Cow::Owned(spam::stamp(&data, self.spam.as_ref())),
+            None => data,
+        };
+        Mail {
+            from: mail.from.clone(),
+            to: mail.to.clone(),
+            data: self.session().stamp(&data),
+        }
+    }
+
+    /// Runs the TLS handshake right away, for implicit TLS (SMTPS) listeners
+    /// where the client speaks TLS from the first byte
+    #[cfg(any(feature = "rustls", feature = "native-tls"))]
+    pub async fn with_implicit_tls(
+        mut self,
+        acceptor: crate::tls::TlsAcceptor,
+    ) -> Result<Self, SmtpError> {
+        self.tls = Some(acceptor);
+        self.start_tls().await?;
+        Ok(self)
+    }
+
+    /// Runs the TLS handshake after STARTTLS was accepted. Commands the client
+    /// pipelined after STARTTLS are dropped, as they were sent in plain text.
+    #[cfg(any(feature = "rustls", feature = "native-tls"))]
+    async fn start_tls(&mut self) -> Result<(), SmtpError> {
+        let Some(acceptor) = &self.tls else {
+            return Err(SmtpError::ProtocolViolation("TLS is not available".into()));
+        };
+        let stream = std::mem::replace(&mut self.stream, BufReader::new(Stream::Upgrading));
+        let Stream::Plain(stream) = stream.into_inner() else {
+            return Err(SmtpError::ProtocolViolation("TLS already started".into()));
+        };
+        let stream = tokio::time::timeout(self.time_left(), crate::tls::accept(acceptor, stream))
+            .await
+            .map_err(|_| SmtpError::Timeout)??;
+        self.stream = BufReader::new(Stream::Tls(Box::new(stream)));
+        self.state_machine.tls_started();
+        tracing::debug!("TLS session started");
+        Ok(())
+    }
+
+    #[cfg(not(any(feature = "rustls", feature = "native-tls")))]
+    async fn start_tls(&mut self) -> Result<(), SmtpError> {
+        Err(SmtpError::ProtocolViolation("TLS is not available".into()))
+    }
+
+    /// Reads a BDAT chunk of `size` bytes into the state machine,
+    /// a bounded piece at a time however large the chunk claims to be
+    async fn read_chunk(&mut self, size: usize) -> Result<(), SmtpError> {
+        let mut left = size as u64;
+        while left > 0 {
+            let piece = self.stream.fill_buf().await?;
+            if piece.is_empty() {
+                return Err(SmtpError::ProtocolViolation(format!(
+                    "connection closed {left} bytes before the end of a chunk"
+                )));
+            }
+            let n = piece.len().min(left as usize);
+            self.state_machine.receive_chunk(&piece[..n]);
+            self.stream.consume(n);
+            left -= n as u64;
         }
         Ok(())
     }
 
+    /// Metadata of the session, for the mail received in it
+    fn session(&self) -> Session {
+        Session {
+            peer: self.peer,
+            helo: self.state_machine.helo.clone(),
+            server_domain: self.domain.clone(),
+            tls: self.state_machine.tls(),
+            early_talker: self.early_talker,
+        }
+    }
+
+    /// Holds the next replies back when the client is suspicious, longer each time.
+    /// Clients on a Unix socket are local, and not tarpitted.
+    async fn tarpit(&mut self) {
+        let (Some(tarpit), Some(peer)) = (self.tarpit, self.peer) else {
+            return;
+        };
+        let sm = &self.state_machine;
+        let mut suspicious = tarpit.suspicious(sm.errors(), sm.refused_recipients());
+        suspicious |= self.early_talker;
+        #[cfg(feature = "dns")]
+        {
+            suspicious |= self.client.listed;
+        }
+        if !suspicious {
+            return;
+        }
+        if self.tarpitted == 0 {
+            tracing::info!("Tarpitting {peer}");
+        }
+        // No longer than the client has left, so a tarpitted session still ends
+        // by its deadline rather than holding its connection slot past it
+        let delay = tarpit.delay(self.tarpitted).min(self.time_left());
+        self.tarpitted = self.tarpitted.saturating_add(1);
+        tokio::time::sleep(delay).await;
+    }
+
+    /// Holds the greeting back for the greeting delay, if any, watching the client.
+    /// Returns whether it talked in the meantime, what it sent kept to be read after.
+    async fn wait_to_greet(&mut self) -> Result<bool, SmtpError> {
+        let Some(delay) = self.greeting_delay else {
+            return Ok(false);
+        };
+        let greeting = tokio::time::Instant::now() + delay;
+        let early = match tokio::time::timeout_at(greeting, self.stream.fill_buf()).await {
+            Ok(read) => !read?.is_empty(),
+            Err(_) => false,
+        };
+        // Talking early doesn't get the greeting any sooner
+        tokio::time::sleep_until(greeting).await;
+        Ok(early)
+    }
+
     /// Sends the initial SMTP greeting
-    async fn greet(&mut self) -> Result<()> {
-        self.stream
-            .write_all(StateMachine::OH_HAI)
+    async fn greet(&mut self) -> Result<(), SmtpError> {
+        self.send(StateMachine::OH_HAI.as_bytes()).await
+    }
+
+    /// Sends replies, within the time left for the client to read them
+    async fn send(&mut self, replies: &[u8]) -> Result<(), SmtpError> {
+        tokio::time::timeout(self.time_left(), self.stream.write_all(replies))
             .await
-            .map_err(|e| e.into())
+            .map_err(|_| SmtpError::Timeout)?
+            .map_err(SmtpError::from)
+    }
+
+    /// Time the client has for its next command: the command timeout,
+    /// unless the session deadline comes first
+    fn time_left(&self) -> Duration {
+        let left = self
+            .deadline
+            .map(|deadline| deadline.saturating_duration_since(tokio::time::Instant::now()));
+        left.map_or(self.timeouts.command, |left| {
+            left.min(self.timeouts.command)
+        })
+    }
+
+    /// Tells the client it took too long, before the connection is closed
+    async fn time_out(&mut self) -> SmtpError {
+        tracing::info!("Client timed out");
+        // Best effort, the client may well not be reading either
+        tokio::time::timeout(
+            Duration::from_secs(1),
+            self.stream.write_all(StateMachine::TOO_SLOW.as_bytes()),
+        )
+        .await
+        .ok();
+        SmtpError::Timeout
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::sync::Mutex;
+    use std::time::Instant;
+
+    use tokio::io::{duplex, AsyncBufReadExt};
+
     use super::*;
+    use crate::protocol::Mail;
+    use crate::queue::QueueId;
+
+    #[derive(Default)]
+    struct Collect(Mutex<Vec<Mail>>);
+
+    impl Acceptor for Collect {
+        async fn accept(&self, mail: &Mail, _: Instant) -> anyhow::Result<Vec<QueueId>> {
+            self.0.lock().unwrap().push(mail.clone());
+            Ok(vec![QueueId::generate()])
+        }
+    }
+
+    /// The mail as the client sent it, without the `Received` field the server adds
+    fn unstamped(data: &[u8]) -> &[u8] {
+        let (fields, _) = crate::header::split(data);
+        assert!(fields[0].starts_with(b"Received: "));
+        &data[fields[0].len() + 2..]
+    }
+
+    /// Fails to take the first mail, as a full disk or unreachable spool would
+    #[derive(Default)]
+    struct Flaky(std::sync::atomic::AtomicBool);
+
+    impl Acceptor for Flaky {
+        async fn accept(&self, _: &Mail, _: Instant) -> anyhow::Result<Vec<QueueId>> {
+            if !self.0.swap(true, std::sync::atomic::Ordering::SeqCst) {
+                anyhow::bail!("spool unavailable");
+            }
+            Ok(vec![QueueId::generate()])
+        }
+    }
+
+    #[tokio::test]
+    async fn test_temporary_failure_when_not_accepted() {
+        let (client, server) = duplex(1024);
+        let server = tokio::spawn(async move {
+            Server::new(&Settings::new("test"), server, Arc::new(Flaky::default()))
+                .await?
+                .serve()
+                .await
+        });
+        let (read, mut write) = tokio::io::split(client);
+        let mut replies = BufReader::new(read).lines();
+        write.write_all(b"HELO client\r\n").await.unwrap();
+        for _ in 0..2 {
+            replies.next_line().await.unwrap().unwrap();
+        }
+        // The client retries, as it was told to
+        for expected in ["451 4.3.0 Try again later", "250 2.6.0 Ok"] {
+            write
+                .write_all(
+                    b"MAIL FROM:<a@example.com>\r\nRCPT TO:<b@example.com>\r\nDATA\r\nhi\r\n.\r\n",
+                )
+                .await
+                .unwrap();
+            for _ in 0..3 {
+                replies.next_line().await.unwrap().unwrap();
+            }
+            assert_eq!(replies.next_line().await.unwrap().unwrap(), expected);
+        }
+        write.write_all(b"QUIT\r\n").await.unwrap();
+        assert_eq!(replies.next_line().await.unwrap().unwrap(), "221 2.0.0 Bye");
+        assert_eq!(server.await.unwrap().unwrap().len(), 1);
+    }
+
+    /// Refuses mail for spam@, as an endpoint judging the mail would
+    struct Picky;
+
+    impl Acceptor for Picky {
+        async fn accept(&self, mail: &Mail, _: Instant) -> anyhow::Result<Vec<QueueId>> {
+            if mail.to.iter().any(|to| to.contains("spam@")) {
+                return Err(SmtpError::Rejected("403 Forbidden".into()).into());
+            }
+            Ok(vec![QueueId::generate()])
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rejected_mail_gets_550() {
+        let (client, server) = duplex(1024);
+        let server = tokio::spawn(async move {
+            Server::new(&Settings::new("test"), server, Arc::new(Picky))
+                .await?
+                .serve()
+                .await
+        });
+        let (read, mut write) = tokio::io::split(client);
+        let mut replies = BufReader::new(read).lines();
+        write.write_all(b"HELO client\r\n").await.unwrap();
+        for _ in 0..2 {
+            replies.next_line().await.unwrap().unwrap();
+        }
+        for (to, expected) in [
+            ("spam", "550 5.7.1 Message rejected"),
+            ("ham", "250 2.6.0 Ok"),
+        ] {
+            let mail = format!(
+                "MAIL FROM:<a@example.com>\r\nRCPT TO:<{to}@example.com>\r\nDATA\r\nhi\r\n.\r\n"
+            );
+            write.write_all(mail.as_bytes()).await.unwrap();
+            for _ in 0..3 {
+                replies.next_line().await.unwrap().unwrap();
+            }
+            assert_eq!(replies.next_line().await.unwrap().unwrap(), expected);
+        }
+        write.write_all(b"QUIT\r\n").await.unwrap();
+        assert_eq!(replies.next_line().await.unwrap().unwrap(), "221 2.0.0 Bye");
+        assert_eq!(server.await.unwrap().unwrap().len(), 1);
+    }
+
+    /// Keeps the deadline of the last mail, as an acceptor delivering it right away would
+    #[derive(Default)]
+    struct Deadline(Mutex<Option<Instant>>);
+
+    impl Acceptor for Deadline {
+        async fn accept(&self, _: &Mail, deadline: Instant) -> anyhow::Result<Vec<QueueId>> {
+            *self.0.lock().unwrap() = Some(deadline);
+            Ok(vec![QueueId::generate()])
+        }
+    }
+
+    #[tokio::test]
+    async fn test_accept_within_session_deadline() {
+        let (client, server) = duplex(1024);
+        let acceptor = Arc::new(Deadline::default());
+        let started = Instant::now();
+        let server = tokio::spawn({
+            let acceptor = acceptor.clone();
+            async move {
+                let settings = Settings {
+                    timeouts: Timeouts {
+                        command: Duration::from_secs(300),
+                        session: Some(Duration::from_secs(2)),
+                    },
+                    ..Settings::new("test")
+                };
+                Server::new(&settings, server, acceptor)
+                    .await?
+                    .serve()
+                    .await
+            }
+        });
+        let (read, mut write) = tokio::io::split(client);
+        let mut replies = BufReader::new(read).lines();
+        write
+            .write_all(b"HELO client\r\nMAIL FROM:<a@example.com>\r\nRCPT TO:<b@example.com>\r\nDATA\r\nhi\r\n.\r\nQUIT\r\n")
+            .await
+            .unwrap();
+        while replies.next_line().await.unwrap().is_some() {}
+        assert_eq!(server.await.unwrap().unwrap().len(), 1);
+        // The session's, not the 5 minutes of a command
+        let deadline = acceptor.0.lock().unwrap().unwrap();
+        assert!(deadline < started + Duration::from_secs(3));
+        assert!(deadline > Instant::now());
+    }
+
+    #[tokio::test]
+    async fn test_tarpit_within_session_deadline() {
+        let (client, server) = duplex(1024);
+        let started = Instant::now();
+        let server = tokio::spawn(async move {
+            let settings = Settings {
+                timeouts: Timeouts {
+                    command: Duration::from_secs(300),
+                    session: Some(Duration::from_secs(1)),
+                },
+                ..Settings::new("test")
+            };
+            Server::new(&settings, server, Arc::new(Collect::default()))
+                .await?
+                .with_peer("192.0.2.1:2525".parse().unwrap())
+                .with_tarpit(Tarpit {
+                    errors: 1,
+                    ..Tarpit::new(Duration::from_secs(60))
+                })
+                .serve()
+                .await
+        });
+        let (read, mut write) = tokio::io::split(client);
+        let mut replies = BufReader::new(read).lines();
+        write
+            .write_all(b"HELO client\r\nFOO\r\nNOOP\r\n")
+            .await
+            .unwrap();
+        while let Ok(Some(_)) = replies.next_line().await {}
+        assert!(matches!(server.await.unwrap(), Err(SmtpError::Timeout)));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn test_commands_split_and_coalesced() {
+        let (client, server) = duplex(1024);
+        let collect = Arc::new(Collect::default());
+        let server = tokio::spawn({
+            let collect = collect.clone();
+            async move {
+                Server::new(&Settings::new("test"), server, collect)
+                    .await?
+                    .serve()
+                    .await
+            }
+        });
+        let (read, mut write) = tokio::io::split(client);
+        let mut replies = BufReader::new(read).lines();
+        assert_eq!(replies.next_line().await.unwrap().unwrap(), "220 edgemail");
+        // A command split across writes
+        for chunk in ["HE", "LO client", "\r\n"] {
+            write.write_all(chunk.as_bytes()).await.unwrap();
+            write.flush().await.unwrap();
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(replies.next_line().await.unwrap().unwrap(), "250 2.0.0 Ok");
+        // Several commands in one write
+        write
+            .write_all(b"MAIL FROM:<a@example.com>\r\nRCPT TO:<b@example.com>\r\nDATA\r\n")
+            .await
+            .unwrap();
+        for reply in [
+            "250 2.1.0 Ok",
+            "250 2.1.5 Ok",
+            "354 End data with <CR><LF>.<CR><LF>",
+        ] {
+            assert_eq!(replies.next_line().await.unwrap().unwrap(), reply);
+        }
+        write
+            .write_all(b"Subject: hi\r\n\r\nhello\r\n.\r\nQUIT\r\n")
+            .await
+            .unwrap();
+        assert_eq!(replies.next_line().await.unwrap().unwrap(), "250 2.6.0 Ok");
+        assert_eq!(replies.next_line().await.unwrap().unwrap(), "221 2.0.0 Bye");
+
+        let received = server.await.unwrap().unwrap();
+        assert_eq!(received.len(), 1);
+        assert!(received[0]
+            .raw
+            .starts_with(b"Received: from client by test with ESMTP;\r\n\t"));
+        assert_eq!(unstamped(&received[0].raw), b"Subject: hi\r\n\r\nhello\r\n");
+        assert_eq!(collect.0.lock().unwrap()[0].to, ["<b@example.com>"]);
+    }
+
+    #[tokio::test]
+    async fn test_several_mails_per_session() {
+        let (client, server) = duplex(1024);
+        let collect = Arc::new(Collect::default());
+        let server = tokio::spawn({
+            let collect = collect.clone();
+            async move {
+                Server::new(&Settings::new("test"), server, collect)
+                    .await?
+                    .serve()
+                    .await
+            }
+        });
+        let (read, mut write) = tokio::io::split(client);
+        let mut replies = BufReader::new(read).lines();
+        assert_eq!(replies.next_line().await.unwrap().unwrap(), "220 edgemail");
+        write.write_all(b"EHLO client\r\n").await.unwrap();
+        while replies
+            .next_line()
+            .await
+            .unwrap()
+            .unwrap()
+            .starts_with("250-")
+        {}
+        for to in ["<b@example.com>", "<c@example.com>"] {
+            write
+                .write_all(
+                    format!("MAIL FROM:<a@example.com>\r\nRCPT TO:{to}\r\nDATA\r\n").as_bytes(),
+                )
+                .await
+                .unwrap();
+            for _ in 0..3 {
+                replies.next_line().await.unwrap().unwrap();
+            }
+            write.write_all(b"hi\r\n.\r\n").await.unwrap();
+            assert_eq!(replies.next_line().await.unwrap().unwrap(), "250 2.6.0 Ok");
+        }
+        write.write_all(b"QUIT\r\n").await.unwrap();
+        assert_eq!(replies.next_line().await.unwrap().unwrap(), "221 2.0.0 Bye");
+
+        let received = server.await.unwrap().unwrap();
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[1].recipients, ["<c@example.com>"]);
+        assert_eq!(collect.0.lock().unwrap().len(), 2);
+    }
+
+    #[cfg(feature = "dns")]
+    #[tokio::test]
+    async fn test_forged_results_stripped_unchecked() {
+        let (client, server) = duplex(1024);
+        let collect = Arc::new(Collect::default());
+        let server = tokio::spawn({
+            let collect = collect.clone();
+            async move {
+                Server::new(&Settings::new("test"), server, collect)
+                    .await?
+                    .with_authserv_id("mx.example.org")
+                    .serve()
+                    .await
+            }
+        });
+        let (read, mut write) = tokio::io::split(client);
+        let mut replies = BufReader::new(read).lines();
+        replies.next_line().await.unwrap().unwrap();
+        write
+            .write_all(
+                b"HELO client\r\nMAIL FROM:<a@example.com>\r\nRCPT TO:<b@example.com>\r\nDATA\r\n",
+            )
+            .await
+            .unwrap();
+        for _ in 0..4 {
+            replies.next_line().await.unwrap().unwrap();
+        }
+        write
+            .write_all(
+                b"Authentication-Results: MX.example.org; dmarc=pass\r\n\
+                  Authentication-Results: other.example; spf=fail\r\n\
+                  From: a@example.com\r\n\r\nhi\r\n.\r\nQUIT\r\n",
+            )
+            .await
+            .unwrap();
+        server.await.unwrap().unwrap();
+        assert_eq!(
+            unstamped(&collect.0.lock().unwrap()[0].data),
+            b"Authentication-Results: other.example; spf=fail\r\n\
+              From: a@example.com\r\n\r\nhi\r\n.\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_mail_delivered_on_terminator() {
+        let (client, server) = duplex(1024);
+        let collect = Arc::new(Collect::default());
+        let server = tokio::spawn({
+            let collect = collect.clone();
+            async move {
+                Server::new(&Settings::new("test"), server, collect)
+                    .await?
+                    .serve()
+                    .await
+            }
+        });
+        let (read, mut write) = tokio::io::split(client);
+        let mut replies = BufReader::new(read).lines();
+        write
+            .write_all(b"HELO client\r\nMAIL FROM:<a@example.com>\r\nRCPT TO:<b@example.com>\r\nDATA\r\nQUIT\r\n.\r\n")
+            .await
+            .unwrap();
+        for _ in 0..5 {
+            replies.next_line().await.unwrap().unwrap();
+        }
+        assert_eq!(replies.next_line().await.unwrap().unwrap(), "250 2.6.0 Ok");
+        // Accepted before the session is over
+        assert_eq!(collect.0.lock().unwrap().len(), 1);
+        // A second mail cut short, then the client hangs up without QUIT
+        write
+            .write_all(
+                b"MAIL FROM:<a@example.com>\r\nRCPT TO:<b@example.com>\r\nDATA\r\nhalf a\r\n",
+            )
+            .await
+            .unwrap();
+        for _ in 0..3 {
+            replies.next_line().await.unwrap().unwrap();
+        }
+        drop(write);
+        drop(replies);
+
+        let received = server.await.unwrap().unwrap();
+        assert_eq!(received.len(), 1);
+        assert_eq!(unstamped(&received[0].raw), b"QUIT\r\n");
+        assert_eq!(collect.0.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_bdat_chunks() {
+        let (client, server) = duplex(1024);
+        let server = tokio::spawn(async move {
+            let collect = Arc::new(Collect::default());
+            Server::new(&Settings::new("test"), server, collect)
+                .await?
+                .serve()
+                .await
+        });
+        let (read, mut write) = tokio::io::split(client);
+        let mut replies = BufReader::new(read).lines();
+        assert_eq!(replies.next_line().await.unwrap().unwrap(), "220 edgemail");
+        // Chunks aren't lines: the second one has a bare LF and no line ending
+        write
+            .write_all(b"HELO client\r\nMAIL FROM:<a@example.com>\r\nRCPT TO:<b@example.com>\r\nBDAT 13\r\nSubject: hi\r\nBDAT 10 LAST\r\n\r\nhi\nthereQUIT\r\n")
+            .await
+            .unwrap();
+        for reply in [
+            "250 2.0.0 Ok",
+            "250 2.1.0 Ok",
+            "250 2.1.5 Ok",
+            "250 2.0.0 Ok",
+            "250 2.6.0 Ok",
+            "221 2.0.0 Bye",
+        ] {
+            assert_eq!(replies.next_line().await.unwrap().unwrap(), reply);
+        }
+        let received = server.await.unwrap().unwrap();
+        assert_eq!(
+            unstamped(&received[0].raw),
+            b"Subject: hi\r\n\r\nhi\nthere\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bad_commands_get_replies() {
+        let (client, server) = duplex(1024);
+        let server = tokio::spawn(async move {
+            let settings = Settings {
+                max_errors: 2,
+                ..Settings::new("test")
+            };
+            Server::new(&settings, server, Arc::new(Collect::default()))
+                .await?
+                .serve()
+                .await
+        });
+        let (read, mut write) = tokio::io::split(client);
+        let mut replies = BufReader::new(read).lines();
+        assert_eq!(replies.next_line().await.unwrap().unwrap(), "220 edgemail");
+        write
+            .write_all(b"DATA\r\nHELO client\r\nFOO\r\nBAR\r\n")
+            .await
+            .unwrap();
+        for reply in [
+            "503 5.5.1 Bad sequence of commands",
+            "250 2.0.0 Ok",
+            "500 5.5.1 Command not recognized",
+            "421 4.7.0 Too many errors, closing connection",
+        ] {
+            assert_eq!(replies.next_line().await.unwrap().unwrap(), reply);
+        }
+        assert!(matches!(
+            server.await.unwrap(),
+            Err(SmtpError::TooManyErrors(3))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_early_talkers() {
+        for reject in [false, true] {
+            let (mut client, server) = duplex(1024);
+            let server = tokio::spawn(async move {
+                let settings = Settings {
+                    greeting_delay: Some(Duration::from_millis(50)),
+                    reject_early_talkers: reject,
+                    ..Settings::new("test")
+                };
+                Server::new(&settings, server, Arc::new(Collect::default()))
+                    .await?
+                    .serve()
+                    .await
+            });
+            client
+                .write_all(b"HELO client\r\nMAIL FROM:<a@example.com>\r\nRCPT TO:<b@example.com>\r\nDATA\r\nFrom: a@example.com\r\n\r\nhi\r\n.\r\nQUIT\r\n")
+                .await
+                .unwrap();
+            let mut replies = BufReader::new(client).lines();
+            let greeting = replies.next_line().await.unwrap().unwrap();
+            if reject {
+                assert_eq!(greeting, "554 5.5.1 Talking before the greeting");
+                assert!(server.await.unwrap().unwrap().is_empty());
+                continue;
+            }
+            // Served all the same, once greeted
+            assert_eq!(greeting, "220 edgemail");
+            while replies.next_line().await.unwrap().is_some() {}
+            let received = server.await.unwrap().unwrap();
+            assert!(received[0].session.early_talker);
+            // And reported in the payload
+            let options = crate::payload::Options {
+                received_by: Some("test".into()),
+                ..Default::default()
+            };
+            let message = received[0].to_message(&options).unwrap();
+            assert!(message.early_talker);
+            assert_eq!(message.helo.as_deref(), Some("client"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_idle_client_times_out() {
+        let (client, server) = duplex(1024);
+        let server = tokio::spawn(async move {
+            let settings = Settings {
+                timeouts: Timeouts {
+                    command: Duration::from_millis(50),
+                    session: None,
+                },
+                ..Settings::new("test")
+            };
+            Server::new(&settings, server, Arc::new(Collect::default()))
+                .await?
+                .serve()
+                .await
+        });
+        let mut replies = BufReader::new(client).lines();
+        assert_eq!(replies.next_line().await.unwrap().unwrap(), "220 edgemail");
+        let reply = replies.next_line().await.unwrap().unwrap();
+        assert!(reply.starts_with("421 "), "{reply}");
+        assert!(matches!(server.await.unwrap(), Err(SmtpError::Timeout)));
+    }
+
+    #[tokio::test]
+    async fn test_client_rates() {
+        let rates = Arc::new(ClientRates::new(crate::ratelimit::ClientLimits {
+            connections_per_minute: Some(1),
+            messages_per_hour: Some(1),
+            recipients_per_message: None,
+        }));
+        let peer: SocketAddr = "192.0.2.1:2525".parse().unwrap();
+        let session = |stream| {
+            let rates = rates.clone();
+            tokio::spawn(async move {
+                Server::new(&Settings::new("test"), stream, Arc::new(Collect::default()))
+                    .await?
+                    .with_peer(peer)
+                    .with_rates(rates)
+                    .serve()
+                    .await
+            })
+        };
 
-    #[test]
-    fn test_regular_flow() {
-        let mut sm = StateMachine::new("dummy");
-        assert_eq!(sm.state, State::Fresh);
-        sm.handle_smtp("HELO localhost").unwrap();
-        assert_eq!(sm.state, State::Greeted);
-        sm.handle_smtp("MAIL FROM: <local@example.com>").unwrap();
-        assert!(matches!(sm.state, State::ReceivingRcpt(_)));
-        sm.handle_smtp("RCPT TO: <a@localhost.com>").unwrap();
-        assert!(matches!(sm.state, State::ReceivingRcpt(_)));
-        sm.handle_smtp("RCPT TO: <b@localhost.com>").unwrap();
-        assert!(matches!(sm.state, State::ReceivingRcpt(_)));
-        sm.handle_smtp("DATA hello world\n").unwrap();
-        assert!(matches!(sm.state, State::ReceivingData(_)));
-        sm.handle_smtp("DATA hello world2\n").unwrap();
-        assert!(matches!(sm.state, State::ReceivingData(_)));
-        sm.handle_smtp("QUIT").unwrap();
-        assert!(matches!(sm.state, State::Received(_)));
-    }
-
-    #[test]
-    fn test_no_greeting() {
-        let mut sm = StateMachine::new("dummy");
-        assert_eq!(sm.state, State::Fresh);
-        for command in [
-            "MAIL FROM: <local@example.com>",
-            "RCPT TO: <local@example.com>",
-            "DATA hey",
-            "GARBAGE",
+        let (client, server) = duplex(1024);
+        let server = session(server);
+        let (read, mut write) = tokio::io::split(client);
+        let mut replies = BufReader::new(read).lines();
+        write.write_all(b"HELO client\r\n").await.unwrap();
+        for _ in 0..2 {
+            replies.next_line().await.unwrap().unwrap();
+        }
+        // Mail given up on doesn't count
+        for _ in 0..2 {
+            write
+                .write_all(b"MAIL FROM:<a@example.com>\r\nRCPT TO:<b@example.com>\r\nRSET\r\n")
+                .await
+                .unwrap();
+            for reply in ["250 2.1.0 Ok", "250 2.1.5 Ok", "250 2.0.0 Ok"] {
+                assert_eq!(replies.next_line().await.unwrap().unwrap(), reply);
+            }
+        }
+        write
+            .write_all(
+                b"MAIL FROM:<a@example.com>\r\nRCPT TO:<b@example.com>\r\nDATA\r\n\
+                  Subject: hi\r\n\r\nhello\r\n.\r\n",
+            )
+            .await
+            .unwrap();
+        for _ in 0..3 {
+            replies.next_line().await.unwrap().unwrap();
+        }
+        assert_eq!(replies.next_line().await.unwrap().unwrap(), "250 2.6.0 Ok");
+        write
+            .write_all(b"MAIL FROM:<a@example.com>\r\nRCPT TO:<b@example.com>\r\nDATA\r\n")
+            .await
+            .unwrap();
+        for reply in [
+            "250 2.1.0 Ok",
+            "450 4.7.1 Too many messages, try again later",
+            "554 5.5.1 No valid recipients",
         ] {
-            assert!(sm.handle_smtp(command).is_err());
+            assert_eq!(replies.next_line().await.unwrap().unwrap(), reply);
         }
+        write.write_all(b"QUIT\r\n").await.unwrap();
+        assert_eq!(replies.next_line().await.unwrap().unwrap(), "221 2.0.0 Bye");
+        server.await.unwrap().unwrap();
+
+        // Connecting again within the minute
+        let (client, server) = duplex(1024);
+        let server = session(server);
+        let mut replies = BufReader::new(client).lines();
+        assert_eq!(
+            replies.next_line().await.unwrap().unwrap(),
+            "421 4.7.0 Too many connections, try again later"
+        );
+        assert!(server.await.unwrap().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_sender_quotas() {
+        let senders = Arc::new(Senders::new(Some(1), "").unwrap());
+        let (client, server) = duplex(1024);
+        let server = tokio::spawn({
+            let senders = senders.clone();
+            async move {
+                Server::new(&Settings::new("test"), server, Arc::new(Collect::default()))
+                    .await?
+                    .with_peer("192.0.2.1:2525".parse().unwrap())
+                    .with_senders(senders)
+                    .serve()
+                    .await
+            }
+        });
+        let (read, mut write) = tokio::io::split(client);
+        let mut replies = BufReader::new(read).lines();
+        // Mail given up on doesn't use the quota of the domain up
+        write
+            .write_all(
+                b"HELO client\r\nMAIL FROM:<a@example.com>\r\nRCPT TO:<b@example.com>\r\nRSET\r\n\
+                  MAIL FROM:<a@example.com>\r\nRCPT TO:<b@example.com>\r\nDATA\r\n\
+                  Subject: hi\r\n\r\nhello\r\n.\r\n",
+            )
+            .await
+            .unwrap();
+        for _ in 0..8 {
+            replies.next_line().await.unwrap().unwrap();
+        }
+        assert_eq!(replies.next_line().await.unwrap().unwrap(), "250 2.6.0 Ok");
+        write
+            .write_all(b"MAIL FROM:<c@EXAMPLE.com>\r\nRCPT TO:<b@example.com>\r\nQUIT\r\n")
+            .await
+            .unwrap();
+        for reply in [
+            "250 2.1.0 Ok",
+            "450 4.7.1 Too many messages, try again later",
+            "221 2.0.0 Bye",
+        ] {
+            assert_eq!(replies.next_line().await.unwrap().unwrap(), reply);
+        }
+        assert_eq!(server.await.unwrap().unwrap().len(), 1);
+        assert_eq!(senders.stats()[0].messages, 1);
+    }
+
+    /// Next reply line, without its terminator
+    #[cfg(feature = "rustls")]
+    async fn reply(client: &mut (impl AsyncBufReadExt + Unpin)) -> String {
+        let mut line = String::new();
+        client.read_line(&mut line).await.unwrap();
+        line.trim_end().to_string()
+    }
+
+    /// Sends EHLO, returning the lines of the reply
+    #[cfg(feature = "rustls")]
+    async fn ehlo(client: &mut (impl AsyncBufReadExt + AsyncWrite + Unpin)) -> Vec<String> {
+        client.write_all(b"EHLO client\r\n").await.unwrap();
+        client.flush().await.unwrap();
+        let mut lines = Vec::new();
+        while lines
+            .last()
+            .is_none_or(|line: &String| line.starts_with("250-"))
+        {
+            lines.push(reply(client).await);
+        }
+        lines
+    }
+
+    #[cfg(feature = "rustls")]
+    const TEST_CERTS: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/tls");
+
+    #[cfg(feature = "rustls")]
+    fn test_acceptor() -> crate::tls::TlsAcceptor {
+        crate::tls::acceptor(
+            format!("{TEST_CERTS}/cert.pem").as_ref(),
+            format!("{TEST_CERTS}/key.pem").as_ref(),
+        )
+        .unwrap()
+    }
+
+    /// Connects to the test server as localhost, trusting its self-signed certificate
+    #[cfg(feature = "rustls")]
+    async fn connect_tls<S: AsyncRead + AsyncWrite + Unpin>(
+        stream: S,
+    ) -> tokio_rustls::client::TlsStream<S> {
+        use tokio_rustls::rustls::{self, Certificate, RootCertStore, ServerName};
+
+        let mut roots = RootCertStore::empty();
+        let cert = std::fs::read(format!("{TEST_CERTS}/cert.pem")).unwrap();
+        for cert in rustls_pemfile::certs(&mut cert.as_slice()).unwrap() {
+            roots.add(&Certificate(cert)).unwrap();
+        }
+        let config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        tokio_rustls::TlsConnector::from(Arc::new(config))
+            .connect(ServerName::try_from("localhost").unwrap(), stream)
+            .await
+            .unwrap()
+    }
+
+    /// Sends a mail with a single recipient and quits
+    #[cfg(feature = "rustls")]
+    async fn send_mail(client: &mut (impl AsyncBufReadExt + AsyncWrite + Unpin)) {
+        for (command, expected) in [
+            ("MAIL FROM:<a@example.com>", "250 2.1.0 Ok"),
+            ("RCPT TO:<b@example.com>", "250 2.1.5 Ok"),
+            ("DATA", "354 End data with <CR><LF>.<CR><LF>"),
+            ("hello\r\n.", "250 2.6.0 Ok"),
+            ("QUIT", "221 2.0.0 Bye"),
+        ] {
+            client
+                .write_all(format!("{command}\r\n").as_bytes())
+                .await
+                .unwrap();
+            client.flush().await.unwrap();
+            assert_eq!(reply(client).await, expected);
+        }
+    }
+
+    #[cfg(feature = "rustls")]
+    #[tokio::test]
+    async fn test_starttls() {
+        let (client, server) = duplex(16384);
+        let server = tokio::spawn(async move {
+            let server =
+                Server::new(&Settings::new("test"), server, Arc::new(Collect::default())).await?;
+            server.with_tls(test_acceptor()).serve().await
+        });
+
+        let mut client = BufReader::new(client);
+        assert_eq!(reply(&mut client).await, "220 edgemail");
+        assert!(ehlo(&mut client)
+            .await
+            .iter()
+            .any(|line| line == "250-STARTTLS"));
+        client.write_all(b"STARTTLS\r\n").await.unwrap();
+        assert_eq!(reply(&mut client).await, "220 2.0.0 Ready to start TLS");
+
+        let mut client = BufReader::new(connect_tls(client.into_inner()).await);
+        // The session starts over, without STARTTLS on offer
+        let lines = ehlo(&mut client).await;
+        assert!(!lines.iter().any(|line| line.contains("STARTTLS")));
+        send_mail(&mut client).await;
+
+        let received = server.await.unwrap().unwrap();
+        assert_eq!(received.len(), 1);
+        assert!(received[0].session.tls);
+        assert_eq!(unstamped(&received[0].raw), b"hello\r\n");
+    }
+
+    #[cfg(feature = "rustls")]
+    #[tokio::test]
+    async fn test_implicit_tls() {
+        let (client, server) = duplex(16384);
+        let server = tokio::spawn(async move {
+            Server::new(&Settings::new("test"), server, Arc::new(Collect::default()))
+                .await?
+                .with_implicit_tls(test_acceptor())
+                .await?
+                .serve()
+                .await
+        });
+
+        let mut client = BufReader::new(connect_tls(client).await);
+        assert_eq!(reply(&mut client).await, "220 edgemail");
+        let lines = ehlo(&mut client).await;
+        assert!(!lines.iter().any(|line| line.contains("STARTTLS")));
+        send_mail(&mut client).await;
+
+        let received = server.await.unwrap().unwrap();
+        assert!(received[0].session.tls);
     }
 }
@@ -0,0 +1,622 @@
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use base64::Engine;
+use thiserror::Error;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, ReadBuf};
+use tokio::net::TcpStream;
+
+/// A reply from the server: its code and the text of each line
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Reply {
+    pub code: u16,
+    pub lines: Vec<String>,
+}
+
+impl Reply {
+    pub fn is_positive(&self) -> bool {
+        (200..400).contains(&self.code)
+    }
+}
+
+impl std::fmt::Display for Reply {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.code, self.lines.join(" "))
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ClientError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("timed out talking to the server")]
+    Timeout,
+    /// The server refused a command
+    #[error("server replied {0}")]
+    Rejected(Reply),
+    /// The server doesn't offer something the configuration requires
+    #[error("{0}")]
+    Unsupported(String),
+    /// The server sent something that isn't SMTP
+    #[error("protocol error: {0}")]
+    Protocol(String),
+}
+
+impl ClientError {
+    /// Whether retrying later can't help, i.e. the server replied 5xx
+    pub fn is_permanent(&self) -> bool {
+        matches!(self, Self::Rejected(reply) if reply.code >= 500)
+    }
+}
+
+/// When to encrypt the connection with STARTTLS
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Tls {
+    Never,
+    /// Whenever the server offers it
+    #[default]
+    Opportunistic,
+    /// Fail rather than sending in clear text
+    Required,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+/// How the client connects and introduces itself
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Name sent in EHLO
+    pub helo_name: String,
+    pub tls: Tls,
+    pub credentials: Option<Credentials>,
+    /// Limit for connecting and for each reply
+    pub timeout: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            helo_name: "localhost".into(),
+            tls: Tls::default(),
+            credentials: None,
+            timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Extensions the server advertised in its EHLO reply
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Extensions {
+    pub starttls: bool,
+    pub pipelining: bool,
+    pub eight_bit_mime: bool,
+    pub smtputf8: bool,
+    /// Largest message the server accepts, if it said so
+    pub size: Option<usize>,
+    /// SASL mechanisms, uppercase
+    pub auth: Vec<String>,
+}
+
+impl Extensions {
+    fn parse(reply: &Reply) -> Self {
+        let mut extensions = Self::default();
+        // The first line is the greeting, the others one extension each
+        for line in reply.lines.iter().skip(1) {
+            let mut words = line.split_whitespace();
+            let Some(keyword) = words.next() else {
+                continue;
+            };
+            match keyword.to_uppercase().as_str() {
+                "STARTTLS" => extensions.starttls = true,
+                "PIPELINING" => extensions.pipelining = true,
+                "8BITMIME" => extensions.eight_bit_mime = true,
+                "SMTPUTF8" => extensions.smtputf8 = true,
+                "SIZE" => extensions.size = words.next().and_then(|size| size.parse().ok()),
+                "AUTH" => extensions.auth = words.map(str::to_uppercase).collect(),
+                _ => {}
+            }
+        }
+        extensions
+    }
+}
+
+/// Sender and recipients of a message
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Envelope {
+    pub from: String,
+    pub to: Vec<String>,
+}
+
+/// Outcome of sending a message that at least one recipient accepted
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Sent {
+    pub accepted: Vec<String>,
+    /// Recipients the server refused, with its reply
+    pub rejected: Vec<(String, Reply)>,
+    /// Reply to the end of the data
+    pub reply: Reply,
+}
+
+enum Stream {
+    Plain(TcpStream),
+    #[cfg(any(feature = "rustls", feature = "native-tls"))]
+    Tls(Box<crate::tls::ClientStream<TcpStream>>),
+    /// Left behind while upgrading to TLS
+    #[cfg_attr(not(any(feature = "rustls", feature = "native-tls")), allow(dead_code))]
+    Closed,
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(any(feature = "rustls", feature = "native-tls"))]
+            Stream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+            Stream::Closed => Poll::Ready(Err(std::io::ErrorKind::NotConnected.into())),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(any(feature = "rustls", feature = "native-tls"))]
+            Stream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+            Stream::Closed => Poll::Ready(Err(std::io::ErrorKind::NotConnected.into())),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(any(feature = "rustls", feature = "native-tls"))]
+            Stream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+            Stream::Closed => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(any(feature = "rustls", feature = "native-tls"))]
+            Stream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+            Stream::Closed => Poll::Ready(Ok(())),
+        }
+    }
+}
+
+/// An SMTP session with a server, ready to send messages
+pub struct Connection {
+    stream: BufReader<Stream>,
+    extensions: Extensions,
+    timeout: Duration,
+    encrypted: bool,
+}
+
+impl Connection {
+    /// Connects to `host`, greets it and, as configured, starts TLS and authenticates
+    pub async fn connect(host: &str, port: u16, config: &Config) -> Result<Self, ClientError> {
+        let tcp = tokio::time::timeout(config.timeout, TcpStream::connect((host, port)))
+            .await
+            .map_err(|_| ClientError::Timeout)??;
+        let mut connection = Self {
+            stream: BufReader::new(Stream::Plain(tcp)),
+            extensions: Extensions::default(),
+            timeout: config.timeout,
+            encrypted: false,
+        };
+        connection.expect(220).await?;
+        connection.ehlo(&config.helo_name).await?;
+
+        if config.tls != Tls::Never && connection.extensions.starttls {
+            connection.starttls(host, &config.helo_name).await?;
+        }
+        if config.tls == Tls::Required && !connection.encrypted {
+            return Err(ClientError::Unsupported(format!(
+                "{host} does not support STARTTLS"
+            )));
+        }
+        if let Some(credentials) = &config.credentials {
+            connection.authenticate(credentials).await?;
+        }
+        Ok(connection)
+    }
+
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.encrypted
+    }
+
+    async fn ehlo(&mut self, helo_name: &str) -> Result<(), ClientError> {
+        let reply = self.command(&format!("EHLO {helo_name}")).await?;
+        self.extensions = Extensions::parse(&reply);
+        Ok(())
+    }
+
+    #[cfg(any(feature = "rustls", feature = "native-tls"))]
+    async fn starttls(&mut self, host: &str, helo_name: &str) -> Result<(), ClientError> {
+        self.command("STARTTLS").await?;
+        let stream = std::mem::replace(&mut self.stream, BufReader::new(Stream::Closed));
+        let Stream::Plain(tcp) = stream.into_inner() else {
+            return Err(ClientError::Protocol(
+                "connection is already encrypted".into(),
+            ));
+        };
+        let tls = crate::tls::connect(host, tcp).await?;
+        self.stream = BufReader::new(Stream::Tls(Box::new(tls)));
+        self.encrypted = true;
+        // Anything learnt before TLS must be forgotten, and asked again
+        self.ehlo(helo_name).await
+    }
+
+    #[cfg(not(any(feature = "rustls", feature = "native-tls")))]
+    async fn starttls(&mut self, host: &str, _helo_name: &str) -> Result<(), ClientError> {
+        tracing::debug!("{host} offers STARTTLS, but no TLS backend is enabled");
+        Ok(())
+    }
+
+    async fn authenticate(&mut self, credentials: &Credentials) -> Result<(), ClientError> {
+        let base64 = base64::engine::general_purpose::STANDARD;
+        let mechanisms = &self.extensions.auth;
+        if mechanisms.iter().any(|mechanism| mechanism == "PLAIN") {
+            let token = format!("\0{}\0{}", credentials.username, credentials.password);
+            self.command(&format!("AUTH PLAIN {}", base64.encode(token)))
+                .await?;
+        } else if mechanisms.iter().any(|mechanism| mechanism == "LOGIN") {
+            self.command("AUTH LOGIN").await?;
+            self.command(&base64.encode(&credentials.username)).await?;
+            self.command(&base64.encode(&credentials.password)).await?;
+        } else {
+            return Err(ClientError::Unsupported(
+                "server offers no supported AUTH mechanism".into(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Sends a message, `data` being the raw message without the DATA terminator.
+    /// Recipients may be refused one by one, but at least one must accept the message.
+    /// With PIPELINING, the envelope goes out in a single round trip.
+    pub async fn send(&mut self, envelope: &Envelope, data: &[u8]) -> Result<Sent, ClientError> {
+        let mut commands = vec![format!("MAIL FROM:<{}>", envelope.from)];
+        commands.extend(envelope.to.iter().map(|to| format!("RCPT TO:<{to}>")));
+        commands.push("DATA".into());
+
+        let mut replies = Vec::with_capacity(commands.len());
+        if self.extensions.pipelining {
+            let batch = commands
+                .iter()
+                .map(|command| format!("{command}\r\n"))
+                .collect::<String>();
+            self.stream.get_mut().write_all(batch.as_bytes()).await?;
+            for _ in &commands {
+                replies.push(self.reply().await?);
+            }
+        } else {
+            for command in &commands {
+                self.write_line(command).await?;
+                let reply = self.reply().await?;
+                let positive = reply.is_positive();
+                replies.push(reply);
+                // Without pipelining, there is no point going on after MAIL fails
+                if !positive && replies.len() == 1 {
+                    break;
+                }
+            }
+        }
+
+        let mail = replies.remove(0);
+        let data_reply = replies.pop();
+        let mut sent = Sent {
+            accepted: Vec::new(),
+            rejected: Vec::new(),
+            reply: mail.clone(),
+        };
+        for (to, reply) in envelope.to.iter().zip(replies) {
+            if reply.is_positive() {
+                sent.accepted.push(to.clone());
+            } else {
+                sent.rejected.push((to.clone(), reply));
+            }
+        }
+        let data_reply = match data_reply {
+            Some(reply) if mail.is_positive() && reply.code == 354 => reply,
+            reply => {
+                if reply.as_ref().is_some_and(|reply| reply.code == 354) {
+                    // Nobody to deliver to: send nothing, which the server then discards
+                    self.write_line(".").await?;
+                    self.reply().await?;
+                }
+                self.command("RSET").await.ok();
+                let refusal = [Some(mail), reply]
+                    .into_iter()
+                    .flatten()
+                    .chain(sent.rejected.into_iter().map(|(_, reply)| reply))
+                    .find(|reply| !reply.is_positive());
+                return Err(ClientError::Rejected(refusal.unwrap_or(Reply {
+                    code: 554,
+                    lines: vec!["no valid recipients".into()],
+                })));
+            }
+        };
+        tracing::trace!("Server ready for data: {data_reply}");
+
+        self.stream.get_mut().write_all(&dot_stuff(data)).await?;
+        sent.reply = self.expect(250).await?;
+        Ok(sent)
+    }
+
+    /// Ends the session politely
+    pub async fn quit(mut self) -> Result<(), ClientError> {
+        self.command("QUIT").await?;
+        Ok(())
+    }
+
+    async fn write_line(&mut self, line: &str) -> Result<(), ClientError> {
+        let line = format!("{line}\r\n");
+        self.stream.get_mut().write_all(line.as_bytes()).await?;
+        Ok(())
+    }
+
+    /// Sends a command, failing unless the reply is positive
+    async fn command(&mut self, command: &str) -> Result<Reply, ClientError> {
+        self.write_line(command).await?;
+        let reply = self.reply().await?;
+        if reply.is_positive() {
+            Ok(reply)
+        } else {
+            Err(ClientError::Rejected(reply))
+        }
+    }
+
+    async fn expect(&mut self, code: u16) -> Result<Reply, ClientError> {
+        let reply = self.reply().await?;
+        if reply.code == code {
+            Ok(reply)
+        } else {
+            Err(ClientError::Rejected(reply))
+        }
+    }
+
+    /// Reads a reply, which spans several lines when they read `<code>-`
+    async fn reply(&mut self) -> Result<Reply, ClientError> {
+        let mut reply = Reply {
+            code: 0,
+            lines: Vec::new(),
+        };
+        loop {
+            let mut line = String::new();
+            let read = tokio::time::timeout(self.timeout, self.stream.read_line(&mut line))
+                .await
+                .map_err(|_| ClientError::Timeout)??;
+            if read == 0 {
+                return Err(ClientError::Io(std::io::ErrorKind::UnexpectedEof.into()));
+            }
+            let line = line.trim_end();
+            let code = line
+                .get(..3)
+                .and_then(|code| code.parse().ok())
+                .ok_or_else(|| ClientError::Protocol(format!("invalid reply {line:?}")))?;
+            reply.code = code;
+            reply
+                .lines
+                .push(line.get(4..).unwrap_or_default().to_string());
+            if line.as_bytes().get(3) != Some(&b'-') {
+                return Ok(reply);
+            }
+        }
+    }
+}
+
+/// Escapes lines starting with a dot and appends the DATA terminator
+fn dot_stuff(data: &[u8]) -> Vec<u8> {
+    let mut stuffed = Vec::with_capacity(data.len() + 5);
+    let mut line_start = true;
+    for &byte in data {
+        if line_start && byte == b'.' {
+            stuffed.push(b'.');
+        }
+        stuffed.push(byte);
+        line_start = byte == b'\n';
+    }
+    if !line_start {
+        stuffed.extend_from_slice(b"\r\n");
+    }
+    stuffed.extend_from_slice(b".\r\n");
+    stuffed
+}
+
+type Idle = HashMap<(String, u16), Vec<(Connection, Instant)>>;
+
+/// Connections kept open per destination host, so that consecutive messages
+/// to the same server don't each pay for a new handshake
+pub struct Pool {
+    config: Config,
+    /// Most connections kept idle per host
+    max_idle: usize,
+    /// Idle connections older than this are closed rather than reused
+    idle_timeout: Duration,
+    idle: Mutex<Idle>,
+}
+
+impl Pool {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            max_idle: 4,
+            idle_timeout: Duration::from_secs(60),
+            idle: Mutex::default(),
+        }
+    }
+
+    /// Sends a message to `host`, over an idle connection when there is one
+    pub async fn send(
+        &self,
+        host: &str,
+        port: u16,
+        envelope: &Envelope,
+        data: &[u8],
+    ) -> Result<Sent, ClientError> {
+        let key = (host.to_string(), port);
+        let idle = self.take_idle(&key);
+        let (connection, result) = match idle {
+            Some(mut connection) => match connection.send(envelope, data).await {
+                // The server may have dropped the idle connection, try once more afresh
+                Err(ClientError::Io(err)) => {
+                    tracing::debug!("Pooled connection to {host} failed: {err}");
+                    let mut connection = Connection::connect(host, port, &self.config).await?;
+                    let result = connection.send(envelope, data).await;
+                    (connection, result)
+                }
+                result => (connection, result),
+            },
+            None => {
+                let mut connection = Connection::connect(host, port, &self.config).await?;
+                let result = connection.send(envelope, data).await;
+                (connection, result)
+            }
+        };
+        match &result {
+            Ok(_) | Err(ClientError::Rejected(_)) => {
+                let mut idle = self.idle.lock().unwrap();
+                let connections = idle.entry(key).or_default();
+                if connections.len() < self.max_idle {
+                    connections.push((connection, Instant::now()));
+                    return result;
+                }
+            }
+            _ => {}
+        }
+        connection.quit().await.ok();
+        result
+    }
+
+    fn take_idle(&self, key: &(String, u16)) -> Option<Connection> {
+        let mut idle = self.idle.lock().unwrap();
+        let connections = idle.get_mut(key)?;
+        while let Some((connection, since)) = connections.pop() {
+            if since.elapsed() < self.idle_timeout {
+                return Some(connection);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dot_stuff() {
+        assert_eq!(
+            dot_stuff(b"Subject: hi\r\n\r\n.hidden\r\nend"),
+            b"Subject: hi\r\n\r\n..hidden\r\nend\r\n.\r\n"
+        );
+    }
+
+    #[test]
+    fn test_extensions() {
+        let reply = Reply {
+            code: 250,
+            lines: [
+                "mx.example.com",
+                "PIPELINING",
+                "SIZE 1000",
+                "AUTH plain LOGIN",
+            ]
+            .map(String::from)
+            .to_vec(),
+        };
+        assert_eq!(
+            Extensions::parse(&reply),
+            Extensions {
+                pipelining: true,
+                size: Some(1000),
+                auth: vec!["PLAIN".into(), "LOGIN".into()],
+                ..Extensions::default()
+            }
+        );
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    async fn test_send_to_server() {
+        use std::sync::Arc;
+
+        use crate::protocol::{Acceptor, Mail};
+        use crate::queue::QueueId;
+
+        #[derive(Default)]
+        struct Collect(Mutex<Vec<Mail>>);
+
+        impl Acceptor for Collect {
+            async fn accept(&self, mail: &Mail, _: Instant) -> anyhow::Result<Vec<QueueId>> {
+                self.0.lock().unwrap().push(mail.clone());
+                Ok(vec![QueueId::generate()])
+            }
+        }
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let collect = Arc::new(Collect::default());
+        let server = tokio::spawn({
+            let collect = collect.clone();
+            async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                let server =
+                    crate::smtp::Server::new(&crate::smtp::Settings::new("test"), stream, collect)
+                        .await
+                        .unwrap();
+                server.serve().await.unwrap()
+            }
+        });
+
+        let config = Config {
+            credentials: Some(Credentials {
+                username: "user".into(),
+                password: "secret".into(),
+            }),
+            ..Config::default()
+        };
+        let mut connection = Connection::connect("127.0.0.1", port, &config)
+            .await
+            .unwrap();
+        let envelope = Envelope {
+            from: "a@example.com".into(),
+            to: vec!["b@example.com".into()],
+        };
+        let sent = connection
+            .send(&envelope, b"Subject: hi\r\n\r\nhello\r\n")
+            .await
+            .unwrap();
+        assert_eq!(sent.accepted, envelope.to);
+        connection.quit().await.unwrap();
+
+        assert_eq!(server.await.unwrap().len(), 1);
+        let mail = collect.0.lock().unwrap().remove(0);
+        assert_eq!(mail.from, "<a@example.com>");
+        assert!(mail
+            .data
+            .ends_with(b"\r\nSubject: hi\r\n\r\nhello\r\n.\r\n"));
+    }
+}
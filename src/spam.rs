@@ -0,0 +1,121 @@
+use crate::header::{field_name, field_value, split};
+use crate::schema::Spam;
+
+/// Fields recording the spam score, only ever added by the server
+const FIELDS: [&str; 2] = ["x-spamd-result", "x-spam"];
+
+/// The message with the spam score on top, in an `X-Spamd-Result` field as rspamd
+/// adds it, e.g. `default: True [7.20 / 15.00]; BAYES_SPAM(5.10); MISSING_DATE(1.00)`,
+/// and `X-Spam: Yes` when tagged. Fields of the sender's claiming a score are forged,
+/// and removed, whether or not the message was scanned.
+pub fn stamp(message: &[u8], spam: Option<&Spam>) -> Vec<u8> {
+    let mut stamped = Vec::new();
+    if let Some(spam) = spam {
+        let tagged = if spam.tagged { "True" } else { "False" };
+        stamped.extend_from_slice(
+            format!(
+                "X-Spamd-Result: default: {tagged} [{:.2} / {:.2}]",
+                spam.score, spam.required_score
+            )
+            .as_bytes(),
+        );
+        for (symbol, score) in &spam.symbols {
+            stamped.extend_from_slice(format!(";\r\n\t{symbol}({score:.2})").as_bytes());
+        }
+        stamped.extend_from_slice(b"\r\n");
+        if spam.tagged {
+            stamped.extend_from_slice(b"X-Spam: Yes\r\n");
+        }
+    }
+
+    let (fields, _) = split(message);
+    let mut copied = 0;
+    for field in fields {
+        if !FIELDS.contains(&field_name(field).as_str()) {
+            continue;
+        }
+        let start = field.as_ptr() as usize - message.as_ptr() as usize;
+        tracing::info!("Removing a forged {}", String::from_utf8_lossy(field));
+        stamped.extend_from_slice(&message[copied..start]);
+        copied = (start + field.len() + 2).min(message.len());
+    }
+    stamped.extend_from_slice(&message[copied..]);
+    stamped
+}
+
+/// The spam score of the message, for the payload, none when it has no
+/// `X-Spamd-Result` field or an invalid one
+pub fn parse(message: &[u8]) -> Option<Spam> {
+    let (fields, _) = split(message);
+    let field = fields
+        .iter()
+        .find(|field| field_name(field) == "x-spamd-result")?;
+    let value = field_value(field);
+    let mut parts = value.split(';').map(str::trim);
+    // `default: True [7.20 / 15.00]`, the metric, whether it's spam and the scores
+    let (_, result) = parts.next()?.split_once(':')?;
+    let (tagged, scores) = result.trim().split_once('[')?;
+    let (score, required_score) = scores.trim_end_matches(']').split_once('/')?;
+    let mut spam = Spam {
+        score: score.trim().parse().ok()?,
+        required_score: required_score.trim().parse().ok()?,
+        tagged: tagged.trim().eq_ignore_ascii_case("true"),
+        ..Default::default()
+    };
+    // `BAYES_SPAM(5.10)`, maybe followed by the options of the rule in brackets
+    for part in parts {
+        let Some((symbol, rest)) = part.split_once('(') else {
+            continue;
+        };
+        let Some(score) = rest
+            .split_once(')')
+            .and_then(|(score, _)| score.parse().ok())
+        else {
+            continue;
+        };
+        spam.symbols.insert(symbol.trim().to_string(), score);
+    }
+    Some(spam)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stamp_and_parse() {
+        let message = b"X-Spam: Yes\r\nSubject: hi\r\nX-Spamd-Result: default: False [0.00 / 15.00]\r\n\r\nhello\r\n";
+        let spam = Spam {
+            score: 7.2,
+            required_score: 15.0,
+            tagged: true,
+            symbols: [("BAYES_SPAM".into(), 5.1), ("MISSING_DATE".into(), 1.0)].into(),
+        };
+        let stamped = stamp(message, Some(&spam));
+        assert_eq!(
+            String::from_utf8(stamped.clone()).unwrap(),
+            "X-Spamd-Result: default: True [7.20 / 15.00];\r\n\
+             \tBAYES_SPAM(5.10);\r\n\
+             \tMISSING_DATE(1.00)\r\n\
+             X-Spam: Yes\r\n\
+             Subject: hi\r\n\
+             \r\n\
+             hello\r\n"
+        );
+        assert_eq!(parse(&stamped), Some(spam));
+
+        // Unscanned, the forged fields are removed all the same
+        let unscanned = stamp(message, None);
+        assert_eq!(unscanned, b"Subject: hi\r\n\r\nhello\r\n");
+        assert_eq!(parse(&unscanned), None);
+
+        let rspamd = b"X-Spamd-Result: default: False [1.50 / 15.00];\r\n\
+                       \tR_SPF_ALLOW(-0.20)[+ip4:192.0.2.0/24];\r\n\
+                       \tMIME_GOOD(-0.10)[text/plain]\r\n\r\n";
+        let spam = parse(rspamd).unwrap();
+        assert_eq!((spam.score, spam.tagged), (1.5, false));
+        assert_eq!(spam.symbols["R_SPF_ALLOW"], -0.2);
+        assert_eq!(spam.symbols.len(), 2);
+        assert_eq!(parse(b"X-Spamd-Result: garbage\r\n\r\n"), None);
+    }
+}
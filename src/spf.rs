@@ -0,0 +1,664 @@
+use std::fmt;
+use std::future::Future;
+use std::net::IpAddr;
+use std::pin::Pin;
+
+use crate::dns::{LookupError, Resolver};
+
+/// Lookups a check may cause, those of includes and redirects included (RFC 7208 §4.6.4)
+const MAX_LOOKUPS: usize = 10;
+
+/// Lookups finding nothing a check may cause, before the record is taken as broken
+const MAX_VOID_LOOKUPS: usize = 2;
+
+/// Names followed by a single mx or ptr mechanism
+const MAX_NAMES: usize = 10;
+
+/// Outcome of an SPF check (RFC 7208 §2.6)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SpfResult {
+    /// The domain has no SPF record
+    None,
+    /// The domain says nothing about the client
+    Neutral,
+    Pass,
+    /// The domain says the client may not send its mail
+    Fail,
+    /// The domain says the client probably may not send its mail
+    SoftFail,
+    /// A lookup failed, the check may succeed later
+    TempError,
+    /// The record is broken
+    PermError,
+}
+
+impl SpfResult {
+    /// Name of the result, as in `Authentication-Results`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::Neutral => "neutral",
+            Self::Pass => "pass",
+            Self::Fail => "fail",
+            Self::SoftFail => "softfail",
+            Self::TempError => "temperror",
+            Self::PermError => "permerror",
+        }
+    }
+}
+
+impl fmt::Display for SpfResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Checks whether the client at `ip` may send mail from `domain`, as said by its
+/// SPF record. `sender` is the MAIL FROM address, `postmaster@<helo>` for bounces,
+/// and `helo` the name the client introduced itself with.
+pub async fn check(
+    resolver: &impl Resolver,
+    ip: IpAddr,
+    domain: &str,
+    sender: &str,
+    helo: &str,
+) -> SpfResult {
+    let domain = domain.trim_end_matches('.');
+    if !domain.contains('.') || domain.split('.').any(|label| label.is_empty()) {
+        return SpfResult::None;
+    }
+    let mut check = Check {
+        resolver,
+        ip: ip.to_canonical(),
+        sender,
+        helo,
+        lookups: 0,
+        void_lookups: 0,
+    };
+    match check.evaluate(domain.to_string()).await {
+        Ok(result) | Err(result) => result,
+    }
+}
+
+/// A check in progress, counting its lookups
+struct Check<'a, R> {
+    resolver: &'a R,
+    ip: IpAddr,
+    sender: &'a str,
+    helo: &'a str,
+    lookups: usize,
+    void_lookups: usize,
+}
+
+/// Result of a record, or the error ending the whole check
+type Evaluation<'b> = Pin<Box<dyn Future<Output = Result<SpfResult, SpfResult>> + Send + 'b>>;
+
+impl<'a, R: Resolver> Check<'a, R> {
+    /// Evaluates the record of `domain`, boxed as includes and redirects recurse
+    fn evaluate(&mut self, domain: String) -> Evaluation<'_> {
+        Box::pin(async move {
+            let Some(record) = self.record(&domain).await? else {
+                return Ok(SpfResult::None);
+            };
+            let terms = parse(&record).ok_or(SpfResult::PermError)?;
+            let mut redirect = None;
+            let mut all = false;
+            for term in &terms {
+                match term {
+                    Term::Directive(qualifier, mechanism) => {
+                        all |= matches!(mechanism, Mechanism::All);
+                        if self.matches(mechanism, &domain).await? {
+                            return Ok(*qualifier);
+                        }
+                    }
+                    Term::Redirect(_) if redirect.is_some() => return Err(SpfResult::PermError),
+                    Term::Redirect(target) => redirect = Some(*target),
+                    Term::Modifier => {}
+                }
+            }
+            // A redirect only applies when nothing matched and there is no `all`
+            match redirect.filter(|_| !all) {
+                Some(target) => {
+                    self.count_lookup()?;
+                    let target = self.expand(target, &domain)?;
+                    match self.evaluate(target).await? {
+                        SpfResult::None => Err(SpfResult::PermError),
+                        result => Ok(result),
+                    }
+                }
+                None => Ok(SpfResult::Neutral),
+            }
+        })
+    }
+
+    /// The SPF record of a domain, none when it has none
+    async fn record(&mut self, domain: &str) -> Result<Option<String>, SpfResult> {
+        let records = match self.resolver.txt(domain).await {
+            Ok(records) => records,
+            Err(LookupError::NotFound) => return Ok(None),
+            Err(LookupError::Failed(err)) => {
+                tracing::debug!("Cannot look up the SPF record of {domain}: {err}");
+                return Err(SpfResult::TempError);
+            }
+        };
+        let mut spf = records.into_iter().filter(|record| {
+            let version = record.get(..6).unwrap_or_default();
+            version.eq_ignore_ascii_case("v=spf1")
+                && matches!(record.as_bytes().get(6), None | Some(b' '))
+        });
+        match (spf.next(), spf.next()) {
+            (Some(record), None) => Ok(Some(record)),
+            (None, _) => Ok(None),
+            _ => Err(SpfResult::PermError),
+        }
+    }
+
+    /// Whether a mechanism of the record of `domain` matches the client
+    async fn matches(
+        &mut self,
+        mechanism: &Mechanism<'_>,
+        domain: &str,
+    ) -> Result<bool, SpfResult> {
+        let resolver = self.resolver;
+        match *mechanism {
+            Mechanism::All => Ok(true),
+            Mechanism::Ip(network, length) => Ok(in_network(self.ip, network, length)),
+            Mechanism::Include(target) => {
+                self.count_lookup()?;
+                let target = self.expand(target, domain)?;
+                match self.evaluate(target).await? {
+                    SpfResult::Pass => Ok(true),
+                    SpfResult::None => Err(SpfResult::PermError),
+                    _ => Ok(false),
+                }
+            }
+            Mechanism::A(target, cidr) => {
+                self.count_lookup()?;
+                let target = self.target(target, domain)?;
+                let ips = self.void_checked(resolver.ips(&target).await)?;
+                Ok(ips.into_iter().any(|ip| cidr.contains(ip, self.ip)))
+            }
+            Mechanism::Mx(target, cidr) => {
+                self.count_lookup()?;
+                let target = self.target(target, domain)?;
+                let hosts = self.void_checked(resolver.mx(&target).await)?;
+                if hosts.len() > MAX_NAMES {
+                    return Err(SpfResult::PermError);
+                }
+                for host in hosts {
+                    let ips = self.void_checked(resolver.ips(&host).await)?;
+                    if ips.into_iter().any(|ip| cidr.contains(ip, self.ip)) {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }
+            Mechanism::Ptr(target) => {
+                self.count_lookup()?;
+                let target = self.target(target, domain)?.to_lowercase();
+                // Failed lookups are no match here (RFC 7208 §5.5)
+                let names = resolver.ptr(self.ip).await.unwrap_or_default();
+                for name in names.into_iter().take(MAX_NAMES) {
+                    let name = name.to_lowercase();
+                    if name != target && !name.ends_with(&format!(".{target}")) {
+                        continue;
+                    }
+                    // Only names pointing back at the client count
+                    let ips = resolver.ips(&name).await.unwrap_or_default();
+                    if ips.contains(&self.ip) {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }
+            Mechanism::Exists(target) => {
+                self.count_lookup()?;
+                let target = self.expand(target, domain)?;
+                Ok(!self.void_checked(resolver.ips(&target).await)?.is_empty())
+            }
+        }
+    }
+
+    fn count_lookup(&mut self) -> Result<(), SpfResult> {
+        self.lookups += 1;
+        match self.lookups > MAX_LOOKUPS {
+            true => Err(SpfResult::PermError),
+            false => Ok(()),
+        }
+    }
+
+    /// Records of a lookup, none for a void lookup, of which only a few are allowed
+    fn void_checked<T>(
+        &mut self,
+        lookup: Result<Vec<T>, LookupError>,
+    ) -> Result<Vec<T>, SpfResult> {
+        match lookup {
+            Ok(records) => Ok(records),
+            Err(LookupError::NotFound) => {
+                self.void_lookups += 1;
+                match self.void_lookups > MAX_VOID_LOOKUPS {
+                    true => Err(SpfResult::PermError),
+                    false => Ok(Vec::new()),
+                }
+            }
+            Err(LookupError::Failed(err)) => {
+                tracing::debug!("SPF lookup failed: {err}");
+                Err(SpfResult::TempError)
+            }
+        }
+    }
+
+    /// The domain a mechanism looks up, the one of the record by default
+    fn target(&self, target: Option<&str>, domain: &str) -> Result<String, SpfResult> {
+        match target {
+            Some(target) => self.expand(target, domain),
+            None => Ok(domain.to_string()),
+        }
+    }
+
+    /// Expands the macros of a domain-spec (RFC 7208 §7), `domain` being
+    /// the domain whose record is evaluated
+    fn expand(&self, spec: &str, domain: &str) -> Result<String, SpfResult> {
+        let mut expanded = String::with_capacity(spec.len());
+        let mut rest = spec;
+        while let Some(percent) = rest.find('%') {
+            expanded.push_str(&rest[..percent]);
+            let after = &rest[percent + 1..];
+            let (expansion, length) = match after.chars().next() {
+                Some('%') => ("%".to_string(), 1),
+                Some('_') => (" ".to_string(), 1),
+                Some('-') => ("%20".to_string(), 1),
+                Some('{') => {
+                    let end = after.find('}').ok_or(SpfResult::PermError)?;
+                    (self.macro_value(&after[1..end], domain)?, end + 1)
+                }
+                _ => return Err(SpfResult::PermError),
+            };
+            expanded.push_str(&expansion);
+            rest = &after[length..];
+        }
+        expanded.push_str(rest);
+        // Names too long lose their leftmost labels
+        while expanded.len() > 253 {
+            let Some((_, shorter)) = expanded.split_once('.') else {
+                return Err(SpfResult::PermError);
+            };
+            expanded = shorter.to_string();
+        }
+        Ok(expanded)
+    }
+
+    /// Value of a macro, e.g. `d` or `ir` between the braces
+    fn macro_value(&self, body: &str, domain: &str) -> Result<String, SpfResult> {
+        let mut chars = body.chars();
+        let letter = chars.next().ok_or(SpfResult::PermError)?;
+        let (local, sender_domain) = self
+            .sender
+            .rsplit_once('@')
+            .unwrap_or(("postmaster", self.sender));
+        let value = match letter.to_ascii_lowercase() {
+            's' => self.sender.to_string(),
+            'l' => local.to_string(),
+            'o' => sender_domain.to_string(),
+            'd' => domain.to_string(),
+            'i' => match self.ip {
+                IpAddr::V4(ip) => ip.to_string(),
+                IpAddr::V6(ip) => ip
+                    .octets()
+                    .iter()
+                    .flat_map(|byte| [byte >> 4, byte & 0xf])
+                    .map(|nibble| format!("{nibble:x}"))
+                    .collect::<Vec<_>>()
+                    .join("."),
+            },
+            'p' => "unknown".to_string(),
+            'v' => match self.ip {
+                IpAddr::V4(_) => "in-addr".to_string(),
+                IpAddr::V6(_) => "ip6".to_string(),
+            },
+            'h' => self.helo.to_string(),
+            _ => return Err(SpfResult::PermError),
+        };
+
+        let transformers = chars.as_str();
+        let digits = transformers
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(transformers.len());
+        let keep = match &transformers[..digits] {
+            "" => None,
+            keep => Some(
+                keep.parse::<usize>()
+                    .ok()
+                    .filter(|&keep| keep > 0)
+                    .ok_or(SpfResult::PermError)?,
+            ),
+        };
+        let rest = &transformers[digits..];
+        let (reverse, delimiters) = match rest.strip_prefix(['r', 'R']) {
+            Some(delimiters) => (true, delimiters),
+            None => (false, rest),
+        };
+        if !delimiters.chars().all(|c| ".-+,/_=".contains(c)) {
+            return Err(SpfResult::PermError);
+        }
+        let delimiters = match delimiters {
+            "" => ".",
+            delimiters => delimiters,
+        };
+        let mut parts = value.split(|c| delimiters.contains(c)).collect::<Vec<_>>();
+        if reverse {
+            parts.reverse();
+        }
+        if let Some(keep) = keep {
+            parts.drain(..parts.len().saturating_sub(keep));
+        }
+        let value = parts.join(".");
+        // Uppercase macros are URL-escaped
+        Ok(match letter.is_ascii_uppercase() {
+            true => value
+                .bytes()
+                .map(|byte| match byte {
+                    b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                        (byte as char).to_string()
+                    }
+                    byte => format!("%{byte:02X}"),
+                })
+                .collect(),
+            false => value,
+        })
+    }
+}
+
+enum Term<'r> {
+    Directive(SpfResult, Mechanism<'r>),
+    Redirect(&'r str),
+    /// `exp` or an unknown modifier, ignored
+    Modifier,
+}
+
+enum Mechanism<'r> {
+    All,
+    Include(&'r str),
+    A(Option<&'r str>, Cidr),
+    Mx(Option<&'r str>, Cidr),
+    Ptr(Option<&'r str>),
+    /// `ip4` or `ip6`, with the prefix length
+    Ip(IpAddr, u8),
+    Exists(&'r str),
+}
+
+/// Prefix lengths of the addresses an `a` or `mx` mechanism matches
+#[derive(Clone, Copy)]
+struct Cidr {
+    v4: u8,
+    v6: u8,
+}
+
+impl Cidr {
+    fn contains(&self, network: IpAddr, ip: IpAddr) -> bool {
+        let length = match network {
+            IpAddr::V4(_) => self.v4,
+            IpAddr::V6(_) => self.v6,
+        };
+        in_network(ip, network, length)
+    }
+}
+
+/// The terms of a record, none when it is malformed
+fn parse(record: &str) -> Option<Vec<Term<'_>>> {
+    record.split_whitespace().skip(1).map(term).collect()
+}
+
+fn term(term: &str) -> Option<Term<'_>> {
+    if let Some((name, value)) = term.split_once('=') {
+        let modifier = name.starts_with(|c: char| c.is_ascii_alphabetic())
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c));
+        if modifier {
+            return Some(match name.eq_ignore_ascii_case("redirect") {
+                true if !value.is_empty() => Term::Redirect(value),
+                true => return None,
+                false => Term::Modifier,
+            });
+        }
+    }
+    let (qualifier, rest) = match term.as_bytes().first()? {
+        b'+' => (SpfResult::Pass, &term[1..]),
+        b'-' => (SpfResult::Fail, &term[1..]),
+        b'~' => (SpfResult::SoftFail, &term[1..]),
+        b'?' => (SpfResult::Neutral, &term[1..]),
+        _ => (SpfResult::Pass, term),
+    };
+    let (name, argument) = rest.split_at(rest.find([':', '/']).unwrap_or(rest.len()));
+    let domain = || {
+        argument
+            .strip_prefix(':')
+            .filter(|domain| !domain.is_empty())
+    };
+    let mechanism = match name.to_ascii_lowercase().as_str() {
+        "all" if argument.is_empty() => Mechanism::All,
+        "include" => Mechanism::Include(domain()?),
+        "exists" => Mechanism::Exists(domain()?),
+        "a" => {
+            let (target, cidr) = domain_cidr(argument)?;
+            Mechanism::A(target, cidr)
+        }
+        "mx" => {
+            let (target, cidr) = domain_cidr(argument)?;
+            Mechanism::Mx(target, cidr)
+        }
+        "ptr" if argument.is_empty() => Mechanism::Ptr(None),
+        "ptr" => Mechanism::Ptr(Some(domain()?)),
+        "ip4" | "ip6" => {
+            let network = domain()?;
+            let (ip, length) = network.split_once('/').unwrap_or((network, ""));
+            let ip = match name.eq_ignore_ascii_case("ip4") {
+                true => IpAddr::V4(ip.parse().ok()?),
+                false => IpAddr::V6(ip.parse().ok()?),
+            };
+            let max = if ip.is_ipv4() { 32 } else { 128 };
+            let length = match length {
+                "" => max,
+                length => length.parse().ok().filter(|&length| length <= max)?,
+            };
+            Mechanism::Ip(ip, length)
+        }
+        _ => return None,
+    };
+    Some(Term::Directive(qualifier, mechanism))
+}
+
+/// The domain and prefix lengths of an `a` or `mx` mechanism,
+/// e.g. `:example.com/24//64`
+fn domain_cidr(argument: &str) -> Option<(Option<&str>, Cidr)> {
+    let (domain, cidr) = argument.split_at(argument.find('/').unwrap_or(argument.len()));
+    let domain = match domain {
+        "" => None,
+        domain => Some(
+            domain
+                .strip_prefix(':')
+                .filter(|domain| !domain.is_empty())?,
+        ),
+    };
+    let (v4, v6) = cidr.split_once("//").unwrap_or((cidr, ""));
+    let v4 = match v4 {
+        "" => 32,
+        v4 => v4
+            .strip_prefix('/')?
+            .parse()
+            .ok()
+            .filter(|&length| length <= 32)?,
+    };
+    let v6 = match v6 {
+        "" => 128,
+        v6 => v6.parse().ok().filter(|&length| length <= 128)?,
+    };
+    Some((domain, Cidr { v4, v6 }))
+}
+
+/// Whether an address is in a network of the same family
+fn in_network(ip: IpAddr, network: IpAddr, length: u8) -> bool {
+    match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(network)) => {
+            length == 0 || (u32::from(ip) ^ u32::from(network)) >> (32 - length as u32) == 0
+        }
+        (IpAddr::V6(ip), IpAddr::V6(network)) => {
+            length == 0 || (u128::from(ip) ^ u128::from(network)) >> (128 - length as u32) == 0
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dns::Zone;
+
+    use super::*;
+
+    async fn spf(zone: &Zone, ip: &str, domain: &str) -> SpfResult {
+        let sender = format!("alice@{domain}");
+        check(
+            zone,
+            ip.parse().unwrap(),
+            domain,
+            &sender,
+            "mail.example.org",
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_check() {
+        let zone = Zone::default()
+            .with(
+                "txt",
+                "example.com",
+                "v=spf1 ip4:192.0.2.0/24 a:mail.example.com mx include:_spf.example.net -all",
+            )
+            .with("txt", "example.com", "google-site-verification=abc")
+            .with("ip", "mail.example.com", "198.51.100.7")
+            .with("ip", "mail.example.com", "2001:db8::7")
+            .with("mx", "example.com", "mx.example.com")
+            .with("ip", "mx.example.com", "203.0.113.25")
+            .with("txt", "_spf.example.net", "v=spf1 ip6:2001:db8:1::/48 ~all")
+            .with("txt", "soft.example", "v=spf1 ~all")
+            .with("txt", "redirected.example", "v=spf1 redirect=example.com")
+            .with("txt", "broken.example", "v=spf1 ip4:300.0.0.1 -all")
+            .with("txt", "twice.example", "v=spf1 -all")
+            .with("txt", "twice.example", "v=spf1 +all")
+            .with(
+                "txt",
+                "macro.example",
+                "v=spf1 exists:%{ir}.%{l1r+-}._spf.%{d} -all",
+            )
+            .with("ip", "1.2.0.192.alice._spf.macro.example", "127.0.0.2")
+            .failing("down.example");
+
+        assert_eq!(
+            spf(&zone, "192.0.2.10", "example.com").await,
+            SpfResult::Pass
+        );
+        assert_eq!(
+            spf(&zone, "::ffff:192.0.2.10", "example.com").await,
+            SpfResult::Pass
+        );
+        assert_eq!(
+            spf(&zone, "198.51.100.7", "example.com").await,
+            SpfResult::Pass
+        );
+        assert_eq!(
+            spf(&zone, "2001:db8::7", "example.com").await,
+            SpfResult::Pass
+        );
+        assert_eq!(
+            spf(&zone, "203.0.113.25", "example.com").await,
+            SpfResult::Pass
+        );
+        // Matched by the include, whose soft fail doesn't match
+        assert_eq!(
+            spf(&zone, "2001:db8:1::1", "example.com").await,
+            SpfResult::Pass
+        );
+        assert_eq!(
+            spf(&zone, "203.0.113.1", "example.com").await,
+            SpfResult::Fail
+        );
+        assert_eq!(
+            spf(&zone, "203.0.113.1", "soft.example").await,
+            SpfResult::SoftFail
+        );
+        assert_eq!(
+            spf(&zone, "192.0.2.10", "redirected.example").await,
+            SpfResult::Pass
+        );
+        assert_eq!(
+            spf(&zone, "203.0.113.1", "redirected.example").await,
+            SpfResult::Fail
+        );
+        assert_eq!(
+            spf(&zone, "192.0.2.1", "macro.example").await,
+            SpfResult::Pass
+        );
+        assert_eq!(
+            spf(&zone, "192.0.2.2", "macro.example").await,
+            SpfResult::Fail
+        );
+        assert_eq!(
+            spf(&zone, "192.0.2.1", "nospf.example").await,
+            SpfResult::None
+        );
+        assert_eq!(spf(&zone, "192.0.2.1", "localhost").await, SpfResult::None);
+        assert_eq!(
+            spf(&zone, "192.0.2.1", "broken.example").await,
+            SpfResult::PermError
+        );
+        assert_eq!(
+            spf(&zone, "192.0.2.1", "twice.example").await,
+            SpfResult::PermError
+        );
+        assert_eq!(
+            spf(&zone, "192.0.2.1", "down.example").await,
+            SpfResult::TempError
+        );
+    }
+
+    #[tokio::test]
+    async fn test_limits() {
+        // Each include is a lookup, the eleventh is one too many
+        let mut zone =
+            Zone::default().with("txt", "deep.example", "v=spf1 include:d1.example -all");
+        for depth in 1..=11 {
+            zone = zone.with(
+                "txt",
+                &format!("d{depth}.example"),
+                &format!("v=spf1 include:d{}.example", depth + 1),
+            );
+        }
+        assert_eq!(
+            spf(&zone, "192.0.2.1", "deep.example").await,
+            SpfResult::PermError
+        );
+
+        // Names without addresses are void lookups, only two are allowed
+        let zone = Zone::default()
+            .with(
+                "txt",
+                "void.example",
+                "v=spf1 a:n1.example a:n2.example a:n3.example -all",
+            )
+            .with(
+                "txt",
+                "two.example",
+                "v=spf1 a:n1.example a:n2.example -all",
+            );
+        assert_eq!(
+            spf(&zone, "192.0.2.1", "void.example").await,
+            SpfResult::PermError
+        );
+        assert_eq!(
+            spf(&zone, "192.0.2.1", "two.example").await,
+            SpfResult::Fail
+        );
+    }
+}
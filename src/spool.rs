@@ -0,0 +1,175 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection};
+
+use crate::queue::{QueueId, QueuedMail};
+
+const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS spool (
+        id TEXT PRIMARY KEY,
+        accepted_at TEXT NOT NULL,
+        entry TEXT NOT NULL
+    )";
+
+/// SQLite store the accepted mail is kept in until it is delivered or given up on.
+/// Mail is added before the client is acknowledged, and whatever is still in it
+/// on startup is queued again, so no accepted mail is lost in a crash. A mail
+/// delivered just before a crash may be posted twice, the ledger skipping the
+/// destinations that took it already.
+#[derive(Clone)]
+pub struct Spool {
+    path: PathBuf,
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl Spool {
+    /// Opens the spool at `path`, creating it if needed
+    pub async fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let open = path.clone();
+        let connection = tokio::task::spawn_blocking(move || {
+            let connection = Connection::open(&open)?;
+            connection.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))?;
+            // Each write reaches the disk before the client is acknowledged
+            connection.pragma_update(None, "synchronous", "FULL")?;
+            connection.execute_batch(SCHEMA)?;
+            anyhow::Ok(connection)
+        })
+        .await?
+        .with_context(|| format!("cannot open spool {}", path.display()))?;
+        Ok(Self {
+            path,
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    /// Keeps an accepted mail, or updates it when it is kept already
+    pub async fn add(&self, entry: &QueuedMail, accepted_at: DateTime<Utc>) -> Result<()> {
+        let id = entry.id.clone();
+        let json = serde_json::to_string(entry)?;
+        self.run(move |connection| {
+            connection.execute(
+                "INSERT INTO spool (id, accepted_at, entry) VALUES (?1, ?2, ?3)
+                ON CONFLICT (id) DO UPDATE SET entry = excluded.entry",
+                params![id.as_str(), accepted_at, json],
+            )?;
+            Ok(())
+        })
+        .await
+        .with_context(|| format!("cannot spool mail in {}", self.path.display()))
+    }
+
+    /// Records a failed delivery, the mail being retried once `until` is over
+    pub async fn defer(&self, id: &QueueId, attempts: u32, until: DateTime<Utc>) -> Result<()> {
+        let Some(mut entry) = self.get(id).await? else {
+            return Ok(());
+        };
+        entry.attempts = attempts;
+        entry.not_before = Some(until);
+        let json = serde_json::to_string(&entry)?;
+        let id = id.clone();
+        self.run(move |connection| {
+            connection.execute(
+                "UPDATE spool SET entry = ?2 WHERE id = ?1",
+                params![id.as_str(), json],
+            )?;
+            Ok(())
+        })
+        .await
+        .with_context(|| format!("cannot update spool {}", self.path.display()))
+    }
+
+    /// Forgets a mail once it is delivered or given up on
+    pub async fn remove(&self, id: &QueueId) -> Result<()> {
+        let id = id.clone();
+        self.run(move |connection| {
+            connection.execute("DELETE FROM spool WHERE id = ?1", [id.as_str()])?;
+            Ok(())
+        })
+        .await
+        .with_context(|| format!("cannot remove mail from spool {}", self.path.display()))
+    }
+
+    async fn get(&self, id: &QueueId) -> Result<Option<QueuedMail>> {
+        let id = id.clone();
+        self.run(move |connection| {
+            let mut statement = connection.prepare("SELECT entry FROM spool WHERE id = ?1")?;
+            let mut rows = statement.query([id.as_str()])?;
+            match rows.next()? {
+                Some(row) => Ok(Some(serde_json::from_str(&row.get::<_, String>(0)?)?)),
+                None => Ok(None),
+            }
+        })
+        .await
+    }
+
+    /// The mail kept, oldest first
+    pub async fn pending(&self) -> Result<Vec<QueuedMail>> {
+        self.run(move |connection| {
+            let mut statement =
+                connection.prepare("SELECT entry FROM spool ORDER BY accepted_at")?;
+            let rows = statement.query_map([], |row| row.get::<_, String>(0))?;
+            rows.map(|json| Ok(serde_json::from_str(&json?)?)).collect()
+        })
+        .await
+        .with_context(|| format!("cannot read spool {}", self.path.display()))
+    }
+
+    /// Runs queries on a blocking thread, SQLite calls blocking the caller
+    async fn run<T: Send + 'static>(
+        &self,
+        query: impl FnOnce(&mut Connection) -> Result<T> + Send + 'static,
+    ) -> Result<T> {
+        let connection = self.connection.clone();
+        tokio::task::spawn_blocking(move || query(&mut connection.lock().unwrap())).await?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::priority::Priority;
+    use crate::protocol::Mail;
+
+    #[tokio::test]
+    async fn test_spool() {
+        let path = std::env::temp_dir().join(format!("spool-test-{}", QueueId::generate()));
+        let entry = |id: QueueId| QueuedMail {
+            id,
+            mail: Mail {
+                from: "<a@example.com>".into(),
+                to: vec!["<b@example.com>".into()],
+                data: b"Subject: hi\r\n\r\nhello\r\n".to_vec(),
+            },
+            priority: Priority::Normal,
+            not_before: None,
+            tenant: None,
+            attempts: 0,
+            duplicate_of: None,
+        };
+        let (first, second) = (entry(QueueId::generate()), entry(QueueId::generate()));
+        let now = Utc::now();
+        {
+            let spool = Spool::open(&path).await.unwrap();
+            spool.add(&first, now).await.unwrap();
+            spool
+                .add(&second, now + chrono::Duration::seconds(1))
+                .await
+                .unwrap();
+            let until = now + chrono::Duration::minutes(5);
+            spool.defer(&first.id, 2, until).await.unwrap();
+            spool.remove(&second.id).await.unwrap();
+        }
+        // What is left survives a restart, with its retries
+        let spool = Spool::open(&path).await.unwrap();
+        let pending = spool.pending().await.unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!((&pending[0].id, pending[0].attempts), (&first.id, 2));
+        assert!(pending[0].not_before.is_some());
+        assert_eq!(pending[0].mail, first.mail);
+        drop(spool);
+        std::fs::remove_file(&path).ok();
+    }
+}
@@ -0,0 +1,273 @@
+use anyhow::{Context, Result};
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// Alphabet of the SRS timestamps, base32 as in RFC 4648
+const BASE32: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// Characters of the hash kept in rewritten addresses
+const HASH_LENGTH: usize = 4;
+
+/// Sender Rewriting Scheme: the envelope sender of relayed mail is rewritten
+/// into an address of the forwarder, so SPF checks it against the forwarder's
+/// servers, and bounces coming back to that address are sent on to the original
+/// sender. The address carries a hash and a timestamp, so the forwarder is not
+/// an open relay for forged bounces.
+#[derive(Clone, Debug)]
+pub struct Srs {
+    secret: String,
+    /// Domain of the rewritten addresses, one the forwarder receives mail for
+    pub domain: String,
+    /// Days a bounce may come back after the mail was relayed
+    pub max_age: u16,
+}
+
+impl Srs {
+    pub fn new(secret: impl Into<String>, domain: impl Into<String>, max_age: u16) -> Self {
+        Self {
+            secret: secret.into(),
+            domain: domain.into(),
+            max_age,
+        }
+    }
+
+    /// Rewrites the sender of mail relayed at `now`: `local@domain` becomes
+    /// `SRS0=hash=tt=domain=local@<our domain>`, and an address another forwarder
+    /// rewrote becomes an `SRS1` one, pointing at that forwarder. The null sender
+    /// of bounces is left as it is.
+    pub fn forward(&self, sender: &str, now: DateTime<Utc>) -> String {
+        let Some((local, domain)) = sender.rsplit_once('@') else {
+            return sender.to_string();
+        };
+        if domain.eq_ignore_ascii_case(&self.domain) && self.parse(local).is_some() {
+            // Already ours, e.g. a bounce being sent on
+            return sender.to_string();
+        }
+        if let Some(rest) = strip_tag(local, "SRS0") {
+            let hash = self.hash(&[domain, rest]);
+            return format!("SRS1={hash}={domain}={rest}@{}", self.domain);
+        }
+        // Keeps pointing at the first forwarder rather than at the last one
+        if let Some(Parsed::Srs1 { domain, rest, .. }) = self.parse(local) {
+            let hash = self.hash(&[domain, rest]);
+            return format!("SRS1={hash}={domain}={rest}@{}", self.domain);
+        }
+        let timestamp = timestamp(now);
+        let hash = self.hash(&[&timestamp, domain, local]);
+        format!("SRS0={hash}={timestamp}={domain}={local}@{}", self.domain)
+    }
+
+    /// The address a bounce to one of our rewritten addresses is sent on to,
+    /// none when the recipient is not one. Addresses with a wrong hash, or
+    /// older than `max_age`, are refused.
+    pub fn reverse(&self, recipient: &str, now: DateTime<Utc>) -> Result<Option<String>> {
+        let Some((local, domain)) = recipient.rsplit_once('@') else {
+            return Ok(None);
+        };
+        if !domain.eq_ignore_ascii_case(&self.domain) {
+            return Ok(None);
+        }
+        let Some(parsed) = self.parse(local) else {
+            return Ok(None);
+        };
+        match parsed {
+            Parsed::Srs0 {
+                hash,
+                timestamp,
+                domain,
+                local,
+            } => {
+                self.verify(hash, &[timestamp, domain, local])?;
+                let age = age(timestamp, now).context("invalid SRS timestamp")?;
+                anyhow::ensure!(age <= self.max_age, "SRS address expired {age} days ago");
+                Ok(Some(format!("{local}@{domain}")))
+            }
+            Parsed::Srs1 { hash, domain, rest } => {
+                self.verify(hash, &[domain, rest])?;
+                Ok(Some(format!("SRS0{rest}@{domain}")))
+            }
+        }
+    }
+
+    /// Splits the local part of an SRS address, none when it isn't one
+    fn parse<'a>(&self, local: &'a str) -> Option<Parsed<'a>> {
+        if let Some(rest) = strip_tag(local, "SRS0") {
+            let mut fields = rest[1..].splitn(4, '=');
+            return Some(Parsed::Srs0 {
+                hash: fields.next()?,
+                timestamp: fields.next()?,
+                domain: fields.next()?,
+                local: fields.next()?,
+            });
+        }
+        let rest = strip_tag(local, "SRS1")?;
+        let (hash, rest) = rest[1..].split_once('=')?;
+        let (domain, rest) = rest.split_once('=')?;
+        Some(Parsed::Srs1 { hash, domain, rest })
+    }
+
+    /// MAC of the fields, each preceded by its length, so that moving characters
+    /// from one field to the next changes it
+    fn hash(&self, fields: &[&str]) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.secret.as_bytes())
+            .expect("HMAC takes keys of any size");
+        for field in fields {
+            let field = field.to_lowercase();
+            mac.update(&(field.len() as u64).to_be_bytes());
+            mac.update(field.as_bytes());
+        }
+        let mut hash =
+            base64::engine::general_purpose::STANDARD.encode(mac.finalize().into_bytes());
+        hash.truncate(HASH_LENGTH);
+        hash
+    }
+
+    /// Checks a hash, case-insensitively as some servers lowercase addresses
+    fn verify(&self, hash: &str, fields: &[&str]) -> Result<()> {
+        anyhow::ensure!(
+            hash.eq_ignore_ascii_case(&self.hash(fields)),
+            "invalid SRS hash"
+        );
+        Ok(())
+    }
+}
+
+enum Parsed<'a> {
+    Srs0 {
+        hash: &'a str,
+        timestamp: &'a str,
+        domain: &'a str,
+        local: &'a str,
+    },
+    /// `rest` is what followed `SRS0` in the first forwarder's address,
+    /// its separator included
+    Srs1 {
+        hash: &'a str,
+        domain: &'a str,
+        rest: &'a str,
+    },
+}
+
+/// Whether an address, angle brackets or not, is an SRS one, of a bounce to
+/// mail some forwarder relayed
+pub fn is_srs(address: &str) -> bool {
+    let address = address.trim_matches(|c| c == '<' || c == '>');
+    let local = address.rsplit_once('@').map_or(address, |(local, _)| local);
+    strip_tag(local, "SRS0").is_some() || strip_tag(local, "SRS1").is_some()
+}
+
+/// What follows an `SRS0` or `SRS1` tag, matched case-insensitively,
+/// starting with its `=`, `+` or `-` separator
+fn strip_tag<'a>(local: &'a str, tag: &str) -> Option<&'a str> {
+    let head = local.get(..tag.len())?;
+    let rest = &local[tag.len()..];
+    let separated = rest.starts_with(['=', '+', '-']);
+    (head.eq_ignore_ascii_case(tag) && separated).then_some(rest)
+}
+
+/// The day of `now`, modulo 1024, as two base32 characters
+fn timestamp(now: DateTime<Utc>) -> String {
+    let day = now.timestamp().div_euclid(86400) as usize % 1024;
+    [BASE32[day >> 5], BASE32[day & 31]]
+        .iter()
+        .map(|&c| c as char)
+        .collect()
+}
+
+/// Days between a timestamp and `now`, none when it isn't one
+fn age(timestamp: &str, now: DateTime<Utc>) -> Option<u16> {
+    if timestamp.len() != 2 {
+        return None;
+    }
+    let mut day = 0;
+    for c in timestamp.bytes() {
+        let value = BASE32.iter().position(|&b| b == c.to_ascii_uppercase())?;
+        day = day * 32 + value;
+    }
+    let today = now.timestamp().div_euclid(86400) as usize % 1024;
+    Some(((today + 1024 - day) % 1024) as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    #[test]
+    fn test_forward_and_reverse() {
+        let srs = Srs::new("secret", "fwd.example", 21);
+        let now = Utc.with_ymd_and_hms(2024, 1, 2, 3, 4, 5).unwrap();
+        let rewritten = srs.forward("alice@example.com", now);
+        let (local, domain) = rewritten.rsplit_once('@').unwrap();
+        assert_eq!(domain, "fwd.example");
+        assert!(local.starts_with("SRS0="));
+        assert!(local.ends_with("=example.com=alice"));
+        assert_eq!(srs.forward(&rewritten, now), rewritten);
+        assert_eq!(srs.forward("", now), "");
+
+        assert_eq!(
+            srs.reverse(&rewritten, now + chrono::Duration::days(3))
+                .unwrap()
+                .as_deref(),
+            Some("alice@example.com")
+        );
+        // Some servers lowercase the addresses
+        assert!(srs
+            .reverse(&rewritten.to_lowercase(), now)
+            .unwrap()
+            .is_some());
+        assert!(srs
+            .reverse(&rewritten, now + chrono::Duration::days(30))
+            .is_err());
+        let forged = rewritten.replace("alice", "mallory");
+        assert!(srs.reverse(&forged, now).is_err());
+        assert_eq!(srs.reverse("bob@fwd.example", now).unwrap(), None);
+        assert_eq!(
+            srs.reverse(&rewritten.replace("fwd", "other"), now)
+                .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_srs1() {
+        let first = Srs::new("first", "first.example", 21);
+        let second = Srs::new("second", "second.example", 21);
+        let now = Utc::now();
+        let srs0 = first.forward("alice@example.com", now);
+        let srs1 = second.forward(&srs0, now);
+        assert!(srs1.starts_with("SRS1="));
+        assert!(srs1.contains("=first.example==") && srs1.ends_with("@second.example"));
+        // A third forwarder still points at the first one
+        let third = Srs::new("third", "third.example", 21).forward(&srs1, now);
+        assert!(third.contains("=first.example==") && third.ends_with("@third.example"));
+
+        let bounce = second.reverse(&srs1, now).unwrap().unwrap();
+        assert_eq!(bounce, srs0);
+        assert_eq!(
+            first.reverse(&bounce, now).unwrap().as_deref(),
+            Some("alice@example.com")
+        );
+    }
+
+    #[test]
+    fn test_fields_kept_apart() {
+        let srs = Srs::new("secret", "fwd.example", 21);
+        assert_ne!(srs.hash(&["ab", "c"]), srs.hash(&["a", "bc"]));
+        assert_ne!(
+            srs.hash(&["tt", "example.com", "a"]),
+            srs.hash(&["tt", "example.coma", ""])
+        );
+
+        // The hash of one address doesn't vouch for another splitting the same characters apart
+        let bounce = srs.forward("user@example.com", Utc::now());
+        let (local, _) = bounce.rsplit_once('@').unwrap();
+        let forged = local.replace("=example.com=user", "=example.comu=ser");
+        assert!(srs
+            .reverse(&format!("{forged}@fwd.example"), Utc::now())
+            .is_err());
+    }
+}
@@ -0,0 +1,63 @@
+use std::time::Duration;
+
+/// Delays the replies to suspicious clients, longer with each reply, slowing
+/// spam bots down without turning them away. A client is suspicious once it made
+/// too many protocol errors or had too many recipients refused, as when guessing
+/// addresses, or from the start when it is on the DNS blocklists or talked
+/// before it was greeted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Tarpit {
+    /// Delay of the first reply delayed, doubling with each one after
+    pub delay: Duration,
+    pub max_delay: Duration,
+    /// Protocol errors making a client suspicious
+    pub errors: usize,
+    /// Refused recipients making a client suspicious
+    pub refused_recipients: usize,
+}
+
+impl Tarpit {
+    /// Delays the replies to suspicious clients by `delay` first, up to 30 seconds,
+    /// once they made 3 protocol errors or had 3 recipients refused
+    pub fn new(delay: Duration) -> Self {
+        Self {
+            delay,
+            max_delay: Duration::from_secs(30),
+            errors: 3,
+            refused_recipients: 3,
+        }
+    }
+
+    /// Whether a client is suspicious for what it did so far
+    pub fn suspicious(&self, errors: usize, refused_recipients: usize) -> bool {
+        errors >= self.errors || refused_recipients >= self.refused_recipients
+    }
+
+    /// Delay of a reply, after `delayed` replies to the client were delayed
+    pub fn delay(&self, delayed: u32) -> Duration {
+        self.delay
+            .saturating_mul(2u32.saturating_pow(delayed))
+            .min(self.max_delay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tarpit() {
+        let tarpit = Tarpit {
+            delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(3),
+            errors: 3,
+            refused_recipients: 2,
+        };
+        assert!(!tarpit.suspicious(2, 1));
+        assert!(tarpit.suspicious(3, 0));
+        assert!(tarpit.suspicious(0, 2));
+        let delays = (0..5).map(|delayed| tarpit.delay(delayed).as_millis());
+        assert_eq!(delays.collect::<Vec<_>>(), [500, 1000, 2000, 3000, 3000]);
+        assert_eq!(tarpit.delay(u32::MAX), tarpit.max_delay);
+    }
+}
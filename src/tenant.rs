@@ -0,0 +1,280 @@
+use std::path::Path;
+use std::sync::Mutex;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use serde::Deserialize;
+
+use crate::format::{Compression, Format};
+use crate::forward::{self, Destination};
+use crate::payload;
+use crate::urls::Blocklist;
+
+/// A team or customer served by the deployment. Mail for its recipients
+/// goes to its own destination, with its own payload options and limits.
+#[derive(Debug)]
+pub struct Tenant {
+    pub name: String,
+    /// Recipients routed to the tenant: `@domain` for a whole domain,
+    /// patterns with `*` wildcards for the addresses they match whole,
+    /// e.g. `@*.acme.com` or `alerts-*@acme.com`,
+    /// anything else is an address prefix, e.g. `support+`
+    pub recipients: Vec<String>,
+    pub destination: Destination,
+    pub payload_options: payload::Options,
+    /// Maximum number of mails accepted per day (UTC), counted per recipient group
+    pub daily_quota: Option<u64>,
+    /// How long deliveries to the tenant are remembered in the ledger
+    pub retention: Option<Duration>,
+    /// Mails accepted on the given day
+    accepted: Mutex<(NaiveDate, u64)>,
+}
+
+/// A tenant as written in the tenants file
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct Config {
+    name: String,
+    recipients: Vec<String>,
+    url: String,
+    #[serde(default)]
+    fallback_urls: Vec<String>,
+    #[serde(default)]
+    token: String,
+    signing_secret: Option<String>,
+    #[serde(default)]
+    format: Format,
+    compression: Option<Compression>,
+    compression_threshold: Option<usize>,
+    url_rewrite: Option<String>,
+    rate_limit: Option<f64>,
+    thumbnail_size: Option<u32>,
+    #[serde(default)]
+    url_blocklists: Vec<String>,
+    #[serde(default)]
+    strip_remote_content: bool,
+    #[serde(default)]
+    include_raw: bool,
+    daily_quota: Option<u64>,
+    retention_days: Option<i64>,
+}
+
+impl Tenant {
+    pub fn new(name: impl Into<String>, recipients: Vec<String>, destination: Destination) -> Self {
+        Self {
+            name: name.into(),
+            recipients: recipients
+                .into_iter()
+                .map(|recipient| recipient.trim().to_lowercase())
+                .collect(),
+            destination,
+            payload_options: payload::Options::default(),
+            daily_quota: None,
+            retention: None,
+            accepted: Mutex::new((NaiveDate::MIN, 0)),
+        }
+    }
+
+    fn from_config(config: Config) -> Result<Self> {
+        let name = config.name;
+        let url_rewrite = config
+            .url_rewrite
+            .map(|rewrite| rewrite.parse())
+            .transpose()
+            .with_context(|| format!("invalid urlRewrite for tenant {name}"))?;
+        let url_blocklist = (!config.url_blocklists.is_empty())
+            .then(|| Blocklist::load(config.url_blocklists.iter().map(String::as_str)))
+            .transpose()?;
+        for url in std::iter::once(&config.url).chain(&config.fallback_urls) {
+            forward::check_url(url).with_context(|| format!("invalid url for tenant {name}"))?;
+        }
+        let destination = Destination {
+            url: config.url,
+            fallback_urls: config.fallback_urls,
+            token: config.token,
+            url_rewrite,
+            // The delivery workers are shared by all tenants
+            concurrency: 1,
+            rate_limit: config.rate_limit.filter(|rate| *rate > 0.0),
+            signing_secret: config.signing_secret,
+            format: config.format,
+            compression: config.compression,
+            compression_threshold: config
+                .compression_threshold
+                .unwrap_or(forward::DEFAULT_COMPRESSION_THRESHOLD),
+        };
+        let mut tenant = Self::new(name, config.recipients, destination);
+        tenant.payload_options = payload::Options {
+            thumbnail_size: config.thumbnail_size,
+            url_blocklist,
+            strip_remote_content: config.strip_remote_content,
+            include_raw: config.include_raw,
+            // Set by the server, as it is the one checking
+            authserv_id: None,
+            spam_score: false,
+            received_by: None,
+        };
+        tenant.daily_quota = config.daily_quota;
+        tenant.retention = config.retention_days.map(Duration::days);
+        Ok(tenant)
+    }
+
+    /// Whether mail for the recipient belongs to the tenant
+    pub fn serves(&self, recipient: &str) -> bool {
+        let recipient = recipient
+            .trim_matches(|c| c == '<' || c == '>')
+            .to_lowercase();
+        self.recipients.iter().any(|pattern| {
+            if pattern.contains('*') {
+                let pattern = match pattern.starts_with('@') {
+                    true => format!("*{pattern}"),
+                    false => pattern.clone(),
+                };
+                return wildcard_match(&pattern, &recipient);
+            }
+            match pattern.strip_prefix('@') {
+                Some(domain) => recipient
+                    .rsplit_once('@')
+                    .is_some_and(|(_, recipient_domain)| recipient_domain == domain),
+                None => recipient.starts_with(pattern.as_str()),
+            }
+        })
+    }
+
+    /// Counts a mail against the daily quota, unless the tenant is over it.
+    /// Checked and counted at once, so concurrent sessions can't both take
+    /// the last mail of the quota.
+    pub fn take_quota(&self, now: DateTime<Utc>) -> bool {
+        let mut accepted = self.accepted.lock().unwrap();
+        if accepted.0 != now.date_naive() {
+            *accepted = (now.date_naive(), 0);
+        }
+        if self.daily_quota.is_some_and(|quota| accepted.1 >= quota) {
+            return false;
+        }
+        accepted.1 += 1;
+        true
+    }
+
+    /// Gives back a mail counted by [`Tenant::take_quota`], as it wasn't accepted after all
+    pub fn return_quota(&self, now: DateTime<Utc>) {
+        let mut accepted = self.accepted.lock().unwrap();
+        if accepted.0 == now.date_naive() {
+            accepted.1 = accepted.1.saturating_sub(1);
+        }
+    }
+}
+
+/// Reads the tenants from a JSON file, holding a list of tenants like
+/// `{"name": "acme", "recipients": ["@acme.com"], "url": "https://…", "token": "…"}`,
+/// optionally with `fallbackUrls`, `signingSecret`, `format`, `compression`,
+/// `compressionThreshold`, `urlRewrite`, `rateLimit`,
+/// `thumbnailSize`, `urlBlocklists`, `stripRemoteContent`, `includeRaw`,
+/// `dailyQuota` and `retentionDays`.
+pub fn load(path: &Path) -> Result<Vec<Tenant>> {
+    let file = std::fs::read_to_string(path)
+        .with_context(|| format!("cannot read tenants {}", path.display()))?;
+    parse(&file).with_context(|| format!("invalid tenants {}", path.display()))
+}
+
+fn parse(file: &str) -> Result<Vec<Tenant>> {
+    let configs: Vec<Config> = serde_json::from_str(file)?;
+    let tenants = configs
+        .into_iter()
+        .map(Tenant::from_config)
+        .collect::<Result<Vec<_>>>()?;
+    for (i, tenant) in tenants.iter().enumerate() {
+        anyhow::ensure!(
+            tenants[..i].iter().all(|other| other.name != tenant.name),
+            "duplicate tenant {}",
+            tenant.name
+        );
+    }
+    Ok(tenants)
+}
+
+/// Whether `text` matches `pattern` whole, `*` standing for any run of characters
+pub(crate) fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let Some((first, rest)) = pattern.split_once('*') else {
+        return pattern == text;
+    };
+    let Some(mut text) = text.strip_prefix(first) else {
+        return false;
+    };
+    let mut parts = rest.split('*').peekable();
+    while let Some(part) = parts.next() {
+        if parts.peek().is_none() {
+            return text.len() >= part.len() && text.ends_with(part);
+        }
+        match text.find(part) {
+            Some(at) => text = &text[at + part.len()..],
+            None => return false,
+        }
+    }
+    true
+}
+
+/// The tenant serving the recipient, the first one listed when several do
+pub fn find<'a>(tenants: &'a [Tenant], recipient: &str) -> Option<&'a Tenant> {
+    tenants.iter().find(|tenant| tenant.serves(recipient))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_route() {
+        let tenants = parse(
+            r#"[
+                {"name": "acme", "recipients": ["@Acme.com"], "url": "https://acme.example",
+                 "fallbackUrls": ["https://backup.acme.example"]},
+                {"name": "support", "recipients": ["support+"], "url": "https://support.example",
+                 "token": "secret", "urlRewrite": "defang", "dailyQuota": 1, "retentionDays": 30},
+                {"name": "alerts", "recipients": ["@*.monitor.example", "alerts-*@example.org"],
+                 "url": "https://alerts.example"}
+            ]"#,
+        )
+        .unwrap();
+        assert_eq!(
+            find(&tenants, "<bob@acme.com>").map(|tenant| tenant.name.as_str()),
+            Some("acme")
+        );
+        assert_eq!(
+            find(&tenants, "<Support+billing@example.com>").map(|tenant| tenant.name.as_str()),
+            Some("support")
+        );
+        assert!(find(&tenants, "<bob@sub.acme.com>").is_none());
+        assert!(find(&tenants, "<bob@example.com>").is_none());
+        assert_eq!(
+            find(&tenants, "<ops@eu.Monitor.example>").map(|tenant| tenant.name.as_str()),
+            Some("alerts")
+        );
+        assert_eq!(
+            find(&tenants, "<alerts-disk@example.org>").map(|tenant| tenant.name.as_str()),
+            Some("alerts")
+        );
+        assert!(find(&tenants, "<ops@monitor.example>").is_none());
+        assert!(find(&tenants, "<alerts@example.org>").is_none());
+        assert_eq!(
+            tenants[0].destination.endpoints().collect::<Vec<_>>(),
+            ["https://acme.example", "https://backup.acme.example"]
+        );
+
+        let support = &tenants[1];
+        assert_eq!(support.retention, Some(Duration::days(30)));
+        let now = Utc::now();
+        assert!(support.take_quota(now));
+        assert!(!support.take_quota(now));
+        support.return_quota(now);
+        assert!(support.take_quota(now));
+        assert!(support.take_quota(now + Duration::days(1)));
+
+        assert!(parse(
+            r#"[{"name": "a", "recipients": [], "url": "https://x.example"},
+                         {"name": "a", "recipients": [], "url": "https://y.example"}]"#
+        )
+        .is_err());
+        assert!(parse(r#"[{"name": "a", "recipients": [], "url": "ftp://x.example"}]"#).is_err());
+    }
+}
@@ -0,0 +1,75 @@
+use std::io::Cursor;
+
+use base64::Engine;
+use image::io::{Limits, Reader};
+use image::{DynamicImage, ImageOutputFormat};
+
+/// Longest edge, in pixels, of the images previewed
+const MAX_DIMENSION: u32 = 4096;
+/// Memory the decoding of an image may take, 4096 by 4096 pixels with an alpha channel
+const MAX_ALLOC: u64 = 64 * 1024 * 1024;
+
+/// Renders a PNG preview of an image attachment, no larger than `size`
+/// pixels on its longest edge, as a `data:` URI.
+/// Returns None when the content cannot be decoded as an image, or claims
+/// to be too large to decode, as decompression bombs do.
+pub fn generate(content: &[u8], size: u32) -> Option<String> {
+    let image = match decode(content) {
+        Ok(image) => image,
+        Err(err) => {
+            tracing::debug!("Cannot decode image attachment: {err}");
+            return None;
+        }
+    };
+    let mut png = Cursor::new(Vec::new());
+    image
+        .thumbnail(size, size)
+        .write_to(&mut png, ImageOutputFormat::Png)
+        .ok()?;
+    let encoded = base64::engine::general_purpose::STANDARD.encode(png.into_inner());
+    Some(format!("data:image/png;base64,{encoded}"))
+}
+
+fn decode(content: &[u8]) -> image::ImageResult<DynamicImage> {
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_DIMENSION);
+    limits.max_image_height = Some(MAX_DIMENSION);
+    limits.max_alloc = Some(MAX_ALLOC);
+    let mut reader = Reader::new(Cursor::new(content)).with_guessed_format()?;
+    reader.limits(limits);
+    reader.decode()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_thumbnail_fits_size() {
+        let mut png = Cursor::new(Vec::new());
+        image::DynamicImage::new_rgb8(400, 200)
+            .write_to(&mut png, ImageOutputFormat::Png)
+            .unwrap();
+        let thumb = generate(png.get_ref(), 64).unwrap();
+        let encoded = thumb.strip_prefix("data:image/png;base64,").unwrap();
+        let decoded = base64::engine::general_purpose::STANDARD
+            .decode(encoded)
+            .unwrap();
+        let thumb = image::load_from_memory(&decoded).unwrap();
+        assert_eq!((thumb.width(), thumb.height()), (64, 32));
+    }
+
+    #[test]
+    fn test_too_large() {
+        let mut png = Cursor::new(Vec::new());
+        image::DynamicImage::new_luma8(MAX_DIMENSION + 1, 1)
+            .write_to(&mut png, ImageOutputFormat::Png)
+            .unwrap();
+        assert!(generate(png.get_ref(), 64).is_none());
+    }
+
+    #[test]
+    fn test_not_an_image() {
+        assert!(generate(b"definitely not a picture", 64).is_none());
+    }
+}
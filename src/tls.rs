@@ -0,0 +1,141 @@
+use std::io;
+use std::path::Path;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// A TLS client connection over `S`, with whichever backend the features selected
+#[cfg(feature = "rustls")]
+pub type ClientStream<S> = tokio_rustls::client::TlsStream<S>;
+#[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+pub type ClientStream<S> = tokio_native_tls::TlsStream<S>;
+
+/// Starts a TLS session over `stream`, verifying the certificate of `domain`
+#[cfg(feature = "rustls")]
+pub async fn connect<S>(domain: &str, stream: S) -> io::Result<ClientStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    use std::sync::{Arc, OnceLock};
+    use tokio_rustls::rustls::{self, OwnedTrustAnchor, RootCertStore, ServerName};
+
+    static CONFIG: OnceLock<Arc<rustls::ClientConfig>> = OnceLock::new();
+    let config = CONFIG.get_or_init(|| {
+        let mut roots = RootCertStore::empty();
+        roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| {
+            OwnedTrustAnchor::from_subject_spki_name_constraints(
+                anchor.subject,
+                anchor.spki,
+                anchor.name_constraints,
+            )
+        }));
+        Arc::new(
+            rustls::ClientConfig::builder()
+                .with_safe_defaults()
+                .with_root_certificates(roots)
+                .with_no_client_auth(),
+        )
+    });
+    let name = ServerName::try_from(domain)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    tokio_rustls::TlsConnector::from(config.clone())
+        .connect(name, stream)
+        .await
+}
+
+/// Starts a TLS session over `stream`, verifying the certificate of `domain`
+#[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+pub async fn connect<S>(domain: &str, stream: S) -> io::Result<ClientStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let connector = tokio_native_tls::native_tls::TlsConnector::new().map_err(io::Error::other)?;
+    tokio_native_tls::TlsConnector::from(connector)
+        .connect(domain, stream)
+        .await
+        .map_err(io::Error::other)
+}
+
+#[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+pub use tokio_native_tls::TlsAcceptor;
+/// Accepts TLS connections for STARTTLS, with the server's certificate
+#[cfg(feature = "rustls")]
+pub use tokio_rustls::TlsAcceptor;
+
+/// A TLS connection accepted from a client over `S`
+#[cfg(feature = "rustls")]
+pub type ServerStream<S> = tokio_rustls::server::TlsStream<S>;
+#[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+pub type ServerStream<S> = tokio_native_tls::TlsStream<S>;
+
+/// Runs the server side of the handshake over `stream`
+#[cfg(feature = "rustls")]
+pub async fn accept<S>(acceptor: &TlsAcceptor, stream: S) -> io::Result<ServerStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    acceptor.accept(stream).await
+}
+
+/// Runs the server side of the handshake over `stream`
+#[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+pub async fn accept<S>(acceptor: &TlsAcceptor, stream: S) -> io::Result<ServerStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    acceptor.accept(stream).await.map_err(io::Error::other)
+}
+
+/// Loads a PEM certificate chain and its PEM private key (PKCS#8, RSA or EC)
+/// into an acceptor for client connections
+#[cfg(feature = "rustls")]
+pub fn acceptor(cert: &Path, key: &Path) -> anyhow::Result<TlsAcceptor> {
+    use anyhow::Context;
+    use std::sync::Arc;
+    use tokio_rustls::rustls::{self, Certificate, PrivateKey};
+
+    let read = |path: &Path| {
+        std::fs::read(path).with_context(|| format!("cannot read {}", path.display()))
+    };
+    let certs = rustls_pemfile::certs(&mut read(cert)?.as_slice())
+        .with_context(|| format!("invalid certificate {}", cert.display()))?
+        .into_iter()
+        .map(Certificate)
+        .collect::<Vec<_>>();
+    anyhow::ensure!(!certs.is_empty(), "no certificate in {}", cert.display());
+    let key = rustls_pemfile::read_all(&mut read(key)?.as_slice())
+        .with_context(|| format!("invalid private key {}", key.display()))?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .with_context(|| format!("no private key in {}", key.display()))?;
+    let config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Loads a PEM certificate chain and its PEM private key, which the platform
+/// libraries only take as PKCS#8, into an acceptor for client connections
+#[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+pub fn acceptor(cert: &Path, key: &Path) -> anyhow::Result<TlsAcceptor> {
+    use anyhow::Context;
+    use tokio_native_tls::native_tls::{self, Identity};
+
+    let read = |path: &Path| {
+        std::fs::read(path).with_context(|| format!("cannot read {}", path.display()))
+    };
+    let identity = Identity::from_pkcs8(&read(cert)?, &read(key)?).with_context(|| {
+        format!(
+            "invalid certificate {} or PKCS#8 private key {}",
+            cert.display(),
+            key.display()
+        )
+    })?;
+    let acceptor = native_tls::TlsAcceptor::new(identity)?;
+    Ok(TlsAcceptor::from(acceptor))
+}
@@ -0,0 +1,207 @@
+use std::sync::OnceLock;
+
+use regex::{Captures, Regex};
+
+use crate::schema::{RemovedContent, RemovedKind};
+
+fn img_tag() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| Regex::new(r"(?is)<img\b[^>]*>").unwrap())
+}
+
+fn link_tag() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| Regex::new(r"(?is)<link\b[^>]*>").unwrap())
+}
+
+/// Attributes that make the client fetch a resource: `src`, `background`, `href`...
+fn remote_attribute() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| {
+        Regex::new(r#"(?is)\s(src|srcset|background|href)\s*=\s*(?:"([^"]*)"|'([^']*)'|([^\s>]+))"#)
+            .unwrap()
+    })
+}
+
+/// `url(...)` references in inline styles and style sheets
+fn css_url() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| Regex::new(r#"(?i)url\(\s*["']?([^"')]*)["']?\s*\)"#).unwrap())
+}
+
+/// Tags whose attributes may load remote resources
+fn resource_tag() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| {
+        Regex::new(r"(?is)<(img|body|table|td|th|tr|div|input|video|audio|source)\b[^>]*>").unwrap()
+    })
+}
+
+/// `width`/`height` given either as attributes or inline style
+fn dimension_attribute() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| {
+        Regex::new(r#"(?i)(?:\s(width|height)\s*=\s*["']?|[\s;"'](width|height)\s*:\s*)(\d+)"#)
+            .unwrap()
+    })
+}
+
+fn dimension(tag: &str, name: &str) -> Option<u32> {
+    dimension_attribute()
+        .captures_iter(tag)
+        .find(|captures| {
+            captures
+                .get(1)
+                .or_else(|| captures.get(2))
+                .is_some_and(|attribute| attribute.as_str().eq_ignore_ascii_case(name))
+        })?
+        .get(3)?
+        .as_str()
+        .parse()
+        .ok()
+}
+
+fn is_remote(url: &str) -> bool {
+    let url = url.trim().to_ascii_lowercase();
+    url.starts_with("http://") || url.starts_with("https://") || url.starts_with("//")
+}
+
+fn attribute_value<'a>(captures: &'a Captures) -> &'a str {
+    captures
+        .get(2)
+        .or_else(|| captures.get(3))
+        .or_else(|| captures.get(4))
+        .map_or("", |value| value.as_str())
+}
+
+/// Removes tracking pixels from HTML and neutralizes any other reference
+/// to remote content, so that displaying the message does not phone home.
+/// Returns the cleaned HTML and what was removed.
+pub fn strip(html: &str) -> (String, Vec<RemovedContent>) {
+    let mut removed = Vec::new();
+
+    let html = img_tag().replace_all(html, |tag: &Captures| {
+        let tag = &tag[0];
+        let src = remote_attribute()
+            .captures_iter(tag)
+            .find(|attribute| attribute[1].eq_ignore_ascii_case("src"))
+            .map(|attribute| attribute_value(&attribute).to_string());
+        let tiny = |name| dimension(tag, name).is_some_and(|size| size <= 1);
+        match src {
+            Some(src) if is_remote(&src) && tiny("width") && tiny("height") => {
+                removed.push(RemovedContent {
+                    kind: RemovedKind::TrackingPixel,
+                    url: src,
+                });
+                String::new()
+            }
+            _ => tag.to_string(),
+        }
+    });
+
+    let html = link_tag().replace_all(&html, |tag: &Captures| {
+        let tag = &tag[0];
+        let href = remote_attribute()
+            .captures_iter(tag)
+            .find(|attribute| attribute[1].eq_ignore_ascii_case("href"))
+            .map(|attribute| attribute_value(&attribute).to_string());
+        match href {
+            Some(href) if is_remote(&href) => {
+                removed.push(RemovedContent {
+                    kind: RemovedKind::RemoteStylesheet,
+                    url: href,
+                });
+                String::new()
+            }
+            _ => tag.to_string(),
+        }
+    });
+
+    // Anchors keep their href: following a link is the reader's choice
+    let html = resource_tag()
+        .replace_all(&html, |tag: &Captures| {
+            remote_attribute()
+                .replace_all(&tag[0], |attribute: &Captures| {
+                    let value = attribute_value(attribute);
+                    if attribute[1].eq_ignore_ascii_case("href") || !is_remote(value) {
+                        return attribute[0].to_string();
+                    }
+                    removed.push(RemovedContent {
+                        kind: RemovedKind::RemoteImage,
+                        url: value.to_string(),
+                    });
+                    let name = attribute[1].to_lowercase();
+                    format!(r#" data-blocked-{name}="{}""#, value.replace('"', "&quot;"))
+                })
+                .into_owned()
+        })
+        .into_owned();
+
+    let html = css_url()
+        .replace_all(&html, |url: &Captures| {
+            if !is_remote(&url[1]) {
+                return url[0].to_string();
+            }
+            removed.push(RemovedContent {
+                kind: RemovedKind::RemoteStyle,
+                url: url[1].to_string(),
+            });
+            "url()".to_string()
+        })
+        .into_owned();
+
+    (html, removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tracking_pixel_removed() {
+        let (html, removed) = strip(
+            r#"<p>Hi</p><img src="https://t.example.com/open.gif?id=42" width="1" height="1" alt="">"#,
+        );
+        assert_eq!(html, "<p>Hi</p>");
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].kind, RemovedKind::TrackingPixel);
+        assert_eq!(removed[0].url, "https://t.example.com/open.gif?id=42");
+
+        let (html, removed) =
+            strip(r#"<img style="width:1px;height:1px" src='http://t.example.com/p'>"#);
+        assert_eq!(html, "");
+        assert_eq!(removed[0].kind, RemovedKind::TrackingPixel);
+    }
+
+    #[test]
+    fn test_remote_content_neutralized() {
+        let (html, removed) = strip(concat!(
+            r#"<link rel="stylesheet" href="https://cdn.example.com/a.css">"#,
+            r#"<body background="https://cdn.example.com/bg.png">"#,
+            r#"<img src="https://cdn.example.com/logo.png" width="200">"#,
+            r#"<img src="cid:logo@local">"#,
+            r#"<div style="background: url('https://cdn.example.com/x.png')">"#,
+            r#"<a href="https://example.com/">link</a>"#,
+        ));
+        assert_eq!(
+            html,
+            concat!(
+                r#"<body data-blocked-background="https://cdn.example.com/bg.png">"#,
+                r#"<img data-blocked-src="https://cdn.example.com/logo.png" width="200">"#,
+                r#"<img src="cid:logo@local">"#,
+                r#"<div style="background: url()">"#,
+                r#"<a href="https://example.com/">link</a>"#,
+            )
+        );
+        let kinds = removed.iter().map(|r| r.kind).collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            [
+                RemovedKind::RemoteStylesheet,
+                RemovedKind::RemoteImage,
+                RemovedKind::RemoteImage,
+                RemovedKind::RemoteStyle
+            ]
+        );
+    }
+}
@@ -0,0 +1,148 @@
+use std::collections::HashSet;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use linkify::{LinkFinder, LinkKind};
+use url::Url;
+
+use crate::schema::{Content, Link};
+
+/// Set of domains considered malicious.
+/// A URL is listed when its host or any of its parent domains is in the set.
+#[derive(Clone, Debug, Default)]
+pub struct Blocklist {
+    domains: HashSet<String>,
+}
+
+impl Blocklist {
+    /// Loads blocklist files with one domain per line.
+    /// Empty lines and lines starting with `#` are ignored.
+    pub fn load(paths: impl IntoIterator<Item = impl AsRef<Path>>) -> Result<Self> {
+        let mut blocklist = Self::default();
+        for path in paths {
+            let path = path.as_ref();
+            let list = std::fs::read_to_string(path)
+                .with_context(|| format!("cannot read blocklist {}", path.display()))?;
+            blocklist.extend(list.lines());
+        }
+        tracing::debug!("Loaded {} blocklisted domains", blocklist.domains.len());
+        Ok(blocklist)
+    }
+
+    /// Adds domains to the blocklist
+    pub fn extend<'a>(&mut self, domains: impl IntoIterator<Item = &'a str>) {
+        self.domains.extend(
+            domains
+                .into_iter()
+                .map(str::trim)
+                .filter(|domain| !domain.is_empty() && !domain.starts_with('#'))
+                .map(|domain| domain.trim_end_matches('.').to_lowercase()),
+        );
+    }
+
+    /// Checks whether the host or one of its parent domains is listed
+    pub fn contains(&self, host: &str) -> bool {
+        let host = host.trim_end_matches('.').to_lowercase();
+        let mut domain = host.as_str();
+        loop {
+            if self.domains.contains(domain) {
+                return true;
+            }
+            match domain.split_once('.') {
+                Some((_, parent)) => domain = parent,
+                None => return false,
+            }
+        }
+    }
+}
+
+/// Extracts the distinct URLs found in text and HTML parts,
+/// flagging them against the blocklist when one is configured.
+pub fn extract(content: &[Content], blocklist: Option<&Blocklist>) -> Vec<Link> {
+    let mut finder = LinkFinder::new();
+    finder.kinds(&[LinkKind::Url]);
+    let mut seen = HashSet::new();
+    content
+        .iter()
+        .filter_map(|part| {
+            let mime = part.mime.as_deref()?;
+            let is_html = mime.eq_ignore_ascii_case("text/html");
+            (is_html || mime.eq_ignore_ascii_case("text/plain"))
+                .then_some((part.value.as_deref()?, is_html))
+        })
+        .flat_map(|(text, is_html)| {
+            finder.links(text).map(move |link| {
+                if is_html {
+                    link.as_str().replace("&amp;", "&")
+                } else {
+                    link.as_str().to_string()
+                }
+            })
+        })
+        .filter(|url| seen.insert(url.clone()))
+        .map(|url| {
+            let host = Url::parse(&url)
+                .ok()
+                .and_then(|parsed| parsed.host_str().map(str::to_string));
+            let domain = host
+                .as_deref()
+                .and_then(psl::domain_str)
+                .map(str::to_lowercase);
+            let blocklisted = blocklist
+                .map(|blocklist| host.as_deref().is_some_and(|host| blocklist.contains(host)));
+            Link {
+                url,
+                domain,
+                blocklisted,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn content(mime: &str, value: &str) -> Content {
+        Content {
+            mime: Some(mime.into()),
+            value: Some(value.into()),
+        }
+    }
+
+    #[test]
+    fn test_extract_text_and_html() {
+        let parts = [
+            content("text/plain", "See https://www.example.co.uk/a?b=1 now"),
+            content(
+                "text/html",
+                r#"<a href="https://www.example.co.uk/a?b=1">x</a>
+                   <a href="http://login.evil.example.com/?u=1&amp;p=2">y</a>"#,
+            ),
+        ];
+        let links = extract(&parts, None);
+        assert_eq!(links.len(), 2);
+        assert_eq!(links[0].url, "https://www.example.co.uk/a?b=1");
+        assert_eq!(links[0].domain.as_deref(), Some("example.co.uk"));
+        assert_eq!(links[1].url, "http://login.evil.example.com/?u=1&p=2");
+        assert_eq!(links[1].domain.as_deref(), Some("example.com"));
+        assert_eq!(links[1].blocklisted, None);
+    }
+
+    #[test]
+    fn test_blocklist() {
+        let mut blocklist = Blocklist::default();
+        blocklist.extend(["# phishing", "", "Evil.Example.com"]);
+        assert!(blocklist.contains("login.evil.example.com"));
+        assert!(blocklist.contains("evil.example.com."));
+        assert!(!blocklist.contains("example.com"));
+
+        let parts = [content(
+            "text/plain",
+            "http://login.evil.example.com/ https://example.com/",
+        )];
+        let links = extract(&parts, Some(&blocklist));
+        assert_eq!(links[0].blocklisted, Some(true));
+        assert_eq!(links[1].blocklisted, Some(false));
+    }
+}
@@ -0,0 +1,187 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+
+/// Usage of a tenant over a reporting period
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Usage {
+    /// Accepted mails, counting a mail split between tenants once for each
+    pub messages: u64,
+    /// Size of the accepted mails, as received
+    pub bytes: u64,
+    /// Decoded size of their attachments
+    pub attachment_bytes: u64,
+}
+
+/// A usage record, as appended to the usage log and posted to the billing webhook
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Record {
+    /// None for the mail of the default destination
+    pub tenant: Option<String>,
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    #[serde(flatten)]
+    pub usage: Usage,
+}
+
+#[derive(Debug)]
+struct Period {
+    from: DateTime<Utc>,
+    usage: HashMap<Option<String>, Usage>,
+}
+
+/// Counts the usage of each tenant since the last report
+#[derive(Debug)]
+pub struct Meter {
+    current: Mutex<Period>,
+}
+
+impl Default for Meter {
+    fn default() -> Self {
+        Self {
+            current: Mutex::new(Period {
+                from: Utc::now(),
+                usage: HashMap::new(),
+            }),
+        }
+    }
+}
+
+impl Meter {
+    /// Counts an accepted mail against the tenant
+    pub fn record(&self, tenant: Option<&str>, bytes: usize, attachment_bytes: usize) {
+        let mut current = self.current.lock().unwrap();
+        let usage = current.usage.entry(tenant.map(str::to_string)).or_default();
+        usage.messages += 1;
+        usage.bytes += bytes as u64;
+        usage.attachment_bytes += attachment_bytes as u64;
+    }
+
+    /// Ends the current period, returning the usage of the tenants active in it
+    pub fn take(&self, now: DateTime<Utc>) -> Vec<Record> {
+        let mut current = self.current.lock().unwrap();
+        let Period { from, usage } = std::mem::replace(
+            &mut *current,
+            Period {
+                from: now,
+                usage: HashMap::new(),
+            },
+        );
+        let mut records = usage
+            .into_iter()
+            .map(|(tenant, usage)| Record {
+                tenant,
+                from,
+                to: now,
+                usage,
+            })
+            .collect::<Vec<_>>();
+        records.sort_by(|a, b| a.tenant.cmp(&b.tenant));
+        records
+    }
+}
+
+/// Periodically writes the metered usage to the usage log,
+/// and posts it to the billing webhook when there is one.
+/// Usage of the period in progress is lost if the server stops.
+#[derive(Clone, Debug)]
+pub struct Reporter {
+    pub path: PathBuf,
+    pub interval: Duration,
+    /// Billing endpoint receiving each period's records as a JSON array
+    pub webhook: Option<String>,
+    /// Sent as the `Authorization` header to the webhook
+    pub webhook_token: String,
+}
+
+impl Reporter {
+    /// Reports the usage of the period that just ended, if there was any
+    pub async fn report(&self, meter: &Meter, client: &reqwest::Client) -> Result<()> {
+        let records = meter.take(Utc::now());
+        if records.is_empty() {
+            return Ok(());
+        }
+        let mut lines = String::new();
+        for record in &records {
+            lines += &serde_json::to_string(record)?;
+            lines.push('\n');
+        }
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await
+            .with_context(|| format!("cannot open usage log {}", self.path.display()))?;
+        file.write_all(lines.as_bytes()).await?;
+        file.sync_data().await?;
+
+        if let Some(webhook) = &self.webhook {
+            client
+                .post(webhook)
+                .header("Content-Type", "application/json")
+                .header("Authorization", &self.webhook_token)
+                .body(serde_json::to_string(&records)?)
+                .send()
+                .await?
+                .error_for_status()
+                .context("billing webhook rejected the usage")?;
+        }
+        Ok(())
+    }
+
+    /// Reports the usage every interval, for as long as the server runs
+    pub fn spawn(self, meter: Arc<Meter>) {
+        tokio::spawn(async move {
+            let client = reqwest::Client::new();
+            let mut interval = tokio::time::interval(self.interval);
+            // The first tick completes immediately
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                if let Err(err) = self.report(&meter, &client).await {
+                    tracing::error!("Cannot report usage: {err:#}");
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_take_resets_period() {
+        let meter = Meter::default();
+        meter.record(Some("acme"), 100, 40);
+        meter.record(None, 10, 0);
+        meter.record(Some("acme"), 50, 0);
+        let now = Utc::now();
+        let records = meter.take(now);
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].tenant, None);
+        assert_eq!(records[1].tenant.as_deref(), Some("acme"));
+        assert_eq!(
+            records[1].usage,
+            Usage {
+                messages: 2,
+                bytes: 150,
+                attachment_bytes: 40,
+            }
+        );
+        assert_eq!(records[1].to, now);
+
+        let later = now + chrono::Duration::hours(1);
+        assert!(meter.take(later).is_empty());
+        meter.record(None, 1, 0);
+        assert_eq!(meter.take(Utc::now())[0].from, later);
+    }
+}
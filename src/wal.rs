@@ -0,0 +1,709 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::Mutex;
+
+use crate::priority::Priority;
+use crate::protocol::Mail;
+use crate::queue::{QueueId, QueuedMail};
+
+/// Size a shared log is compacted from, provided it doubled since last compacted
+const COMPACT_SIZE: u64 = 1024 * 1024;
+
+/// A single journal entry
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "camelCase")]
+pub enum Record {
+    /// The message was accepted, and is about to be acknowledged to the client
+    Accepted {
+        id: QueueId,
+        at: DateTime<Utc>,
+        mail: Mail,
+        #[serde(default)]
+        priority: Priority,
+        /// Delivery is deferred until then by a schedule rule
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        not_before: Option<DateTime<Utc>>,
+        /// Tenant the recipients belong to, the default destination when none
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        tenant: Option<String>,
+        /// Mail this one duplicates, when duplicates are flagged
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        duplicate_of: Option<QueueId>,
+    },
+    /// A delivery attempt started
+    Attempt {
+        id: QueueId,
+        at: DateTime<Utc>,
+        /// URL of the destination posted to
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        endpoint: Option<String>,
+    },
+    /// A delivery attempt failed, the message is still pending
+    Failed {
+        id: QueueId,
+        at: DateTime<Utc>,
+        error: String,
+        /// URL of the destination that failed, none when none was tried
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        endpoint: Option<String>,
+    },
+    /// The message is retried once `until` is over, after `attempts` failed ones,
+    /// whichever instance delivers it by then
+    Deferred {
+        id: QueueId,
+        at: DateTime<Utc>,
+        attempts: u32,
+        until: DateTime<Utc>,
+    },
+    /// An endpoint took the message, which is delivered once all its destinations have it
+    Forwarded {
+        id: QueueId,
+        at: DateTime<Utc>,
+        endpoint: String,
+    },
+    /// The message was delivered, nothing left to do
+    Delivered { id: QueueId, at: DateTime<Utc> },
+    /// The message can never be delivered and was given up on
+    Dropped {
+        id: QueueId,
+        at: DateTime<Utc>,
+        reason: String,
+    },
+}
+
+impl Record {
+    /// The message the record is about
+    pub fn id(&self) -> &QueueId {
+        match self {
+            Record::Accepted { id, .. }
+            | Record::Attempt { id, .. }
+            | Record::Failed { id, .. }
+            | Record::Deferred { id, .. }
+            | Record::Forwarded { id, .. }
+            | Record::Delivered { id, .. }
+            | Record::Dropped { id, .. } => id,
+        }
+    }
+}
+
+/// Write-ahead log of accepted messages and their delivery state.
+/// Every record is flushed to disk before returning, so that a message
+/// acknowledged to the client survives a crash until it is delivered.
+pub struct Wal {
+    path: PathBuf,
+    file: Mutex<tokio::fs::File>,
+    /// Set when the log is shared with another instance
+    shared: Option<Shared>,
+}
+
+/// A log shared with another instance. Both take an advisory lock on a lock
+/// file next to it, which the log itself can't carry as compaction replaces it,
+/// and which holds its generation, bumped whenever it is replaced.
+struct Shared {
+    lock: std::fs::File,
+    /// Generation of the log the file appended to was opened at
+    opened: AtomicU64,
+    /// Size of the log when this instance last compacted it
+    compacted_size: AtomicU64,
+    tail: Mutex<Tail>,
+}
+
+/// How far a shared log was read, only the records appended since being
+/// read for the next [`Wal::pending`]
+#[derive(Default)]
+struct Tail {
+    generation: u64,
+    /// Offset of the first line not read yet
+    offset: u64,
+    replay: Replay,
+}
+
+impl Wal {
+    /// Opens the log at `path`, returning it together with the messages
+    /// that were accepted but never delivered, in acceptance order.
+    /// Finished messages are compacted away from the log, their records
+    /// moved to the history next to it.
+    pub async fn open(path: impl AsRef<Path>) -> Result<(Self, Vec<QueuedMail>)> {
+        let path = path.as_ref().to_path_buf();
+        let pending = compact(&path).await?.queued();
+        let file = tokio::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .await?;
+        tracing::info!(
+            "Opened WAL {} with {} pending messages",
+            path.display(),
+            pending.len()
+        );
+        Ok((
+            Self {
+                path,
+                file: Mutex::new(file),
+                shared: None,
+            },
+            pending,
+        ))
+    }
+
+    /// Opens a log shared with another instance, as in a high-availability pair.
+    /// Appends hold an advisory lock so records of the two instances don't
+    /// interleave, and the leader compacts it with [`Wal::compact`] as it grows.
+    /// Pending messages are left for the leader to pick up with [`Wal::pending`].
+    pub async fn open_shared(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await
+            .with_context(|| format!("cannot open WAL {}", path.display()))?;
+        let lock_path = path.with_extension("lock");
+        let lock = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(&lock_path)
+            .with_context(|| format!("cannot open WAL lock {}", lock_path.display()))?;
+        // The file was opened under the lock, so it is the current generation
+        let (lock, generation) = lock_peer(&lock).await?;
+        lock.unlock()?;
+        tracing::info!("Opened shared WAL {}", path.display());
+        Ok(Self {
+            path,
+            file: Mutex::new(file),
+            shared: Some(Shared {
+                lock,
+                opened: AtomicU64::new(generation),
+                compacted_size: AtomicU64::new(0),
+                tail: Mutex::default(),
+            }),
+        })
+    }
+
+    /// Messages accepted but not delivered yet, by this instance or its peer.
+    /// Those of a shared log keep the retry schedule of the instance that last
+    /// tried them. Only the records appended since the last call are read,
+    /// unless the log was compacted in between.
+    pub async fn pending(&self) -> Result<Vec<QueuedMail>> {
+        let _file = self.file.lock().await;
+        let Some(shared) = &self.shared else {
+            let log = tokio::fs::read_to_string(&self.path)
+                .await
+                .with_context(|| format!("cannot read WAL {}", self.path.display()))?;
+            let mut replay = Replay::default();
+            replay.apply(&log);
+            return Ok(replay.queued());
+        };
+        let mut tail = shared.tail.lock().await;
+        let (peer_lock, generation) = lock_peer(&shared.lock).await?;
+        let read = self.read_tail(&mut tail, generation).await;
+        peer_lock.unlock()?;
+        read?;
+        Ok(tail.replay.queued())
+    }
+
+    /// Applies the complete lines appended to the shared log since it was last
+    /// read, starting over when it was compacted in between
+    async fn read_tail(&self, tail: &mut Tail, generation: u64) -> Result<()> {
+        if tail.generation != generation {
+            *tail = Tail {
+                generation,
+                ..Default::default()
+            };
+        }
+        let mut file = tokio::fs::File::open(&self.path)
+            .await
+            .with_context(|| format!("cannot read WAL {}", self.path.display()))?;
+        file.seek(SeekFrom::Start(tail.offset)).await?;
+        let mut appended = Vec::new();
+        file.read_to_end(&mut appended).await?;
+        // A line without its newline is still being written, or torn by a crash
+        let complete = appended
+            .iter()
+            .rposition(|&byte| byte == b'\n')
+            .map_or(0, |end| end + 1);
+        tail.replay
+            .apply(&String::from_utf8_lossy(&appended[..complete]));
+        tail.offset += complete as u64;
+        Ok(())
+    }
+
+    /// Compacts a shared log as [`Wal::open`] does an unshared one, once it is
+    /// over a megabyte and doubled in size since this instance last compacted it.
+    /// Meant for the leader, the peer noticing from the generation of the log
+    /// and reopening it.
+    pub async fn compact(&self) -> Result<()> {
+        self.compact_from(COMPACT_SIZE).await
+    }
+
+    async fn compact_from(&self, min_size: u64) -> Result<()> {
+        let Some(shared) = &self.shared else {
+            return Ok(());
+        };
+        let mut file = self.file.lock().await;
+        let mut tail = shared.tail.lock().await;
+        let size = tokio::fs::metadata(&self.path).await?.len();
+        if size < min_size.max(2 * shared.compacted_size.load(Ordering::Relaxed)) {
+            return Ok(());
+        }
+        let (peer_lock, generation) = lock_peer(&shared.lock).await?;
+        let compacted = async {
+            compact(&self.path).await?;
+            set_generation(&peer_lock, generation + 1)?;
+            *file = tokio::fs::OpenOptions::new()
+                .append(true)
+                .open(&self.path)
+                .await?;
+            shared.opened.store(generation + 1, Ordering::Relaxed);
+            let size = tokio::fs::metadata(&self.path).await?.len();
+            shared.compacted_size.store(size, Ordering::Relaxed);
+            tracing::info!(
+                "Compacted shared WAL {} to {size} bytes",
+                self.path.display()
+            );
+            anyhow::Ok(())
+        }
+        .await;
+        peer_lock.unlock()?;
+        // Read again from the start next time, whether or not it was compacted
+        *tail = Tail::default();
+        compacted
+    }
+
+    /// Reads back every record, those of the messages finished before the log
+    /// was last compacted from the history, then those of the log
+    pub async fn records(&self) -> Result<Vec<Record>> {
+        // Holding the locks keeps appends from leaving a half-written line to read,
+        // and compaction, ours or the peer's, from moving records between the two files
+        let _file = self.file.lock().await;
+        let peer_lock = match &self.shared {
+            Some(shared) => Some(lock_peer(&shared.lock).await?.0),
+            None => None,
+        };
+        let read = async {
+            let history = match tokio::fs::read_to_string(history_path(&self.path)).await {
+                Ok(history) => history,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+                Err(err) => return Err(err).context("cannot read WAL history"),
+            };
+            let log = tokio::fs::read_to_string(&self.path)
+                .await
+                .with_context(|| format!("cannot read WAL {}", self.path.display()))?;
+            Ok(history
+                .lines()
+                .chain(log.lines())
+                .filter_map(|line| serde_json::from_str(line).ok())
+                .collect())
+        }
+        .await;
+        if let Some(peer_lock) = peer_lock {
+            peer_lock.unlock()?;
+        }
+        read
+    }
+
+    /// Appends a record and waits for it to reach the disk
+    pub async fn append(&self, record: &Record) -> Result<()> {
+        let mut line = serde_json::to_string(record)?;
+        line.push('\n');
+        let mut file = self.file.lock().await;
+        let peer_lock = match &self.shared {
+            Some(shared) => {
+                let (peer_lock, generation) = lock_peer(&shared.lock).await?;
+                if shared.opened.load(Ordering::Relaxed) != generation {
+                    // The peer compacted the log, replacing the file appended to
+                    let reopened = tokio::fs::OpenOptions::new()
+                        .append(true)
+                        .open(&self.path)
+                        .await;
+                    match reopened {
+                        Ok(reopened) => *file = reopened,
+                        Err(err) => {
+                            peer_lock.unlock()?;
+                            return Err(err).with_context(|| {
+                                format!("cannot reopen WAL {}", self.path.display())
+                            });
+                        }
+                    }
+                    shared.opened.store(generation, Ordering::Relaxed);
+                }
+                Some(peer_lock)
+            }
+            None => None,
+        };
+        let written = async {
+            file.write_all(line.as_bytes())
+                .await
+                .with_context(|| format!("cannot write WAL {}", self.path.display()))?;
+            file.sync_data().await?;
+            anyhow::Ok(())
+        }
+        .await;
+        if let Some(peer_lock) = peer_lock {
+            peer_lock.unlock()?;
+        }
+        written
+    }
+}
+
+/// Delivery state of the messages of a log, built up as its records are read
+#[derive(Default)]
+struct Replay {
+    /// Messages in acceptance order, finished ones pruned from time to time
+    order: Vec<QueueId>,
+    unfinished: HashMap<QueueId, QueuedMail>,
+}
+
+impl Replay {
+    /// Applies the records of the lines. A corrupt one, as the torn last line
+    /// left by a crash mid-write, is skipped.
+    fn apply(&mut self, lines: &str) {
+        for line in lines.lines().filter(|line| !line.trim().is_empty()) {
+            let record = match serde_json::from_str::<Record>(line) {
+                Ok(record) => record,
+                Err(err) => {
+                    tracing::warn!("Skipping corrupt WAL record: {err}");
+                    continue;
+                }
+            };
+            match record {
+                Record::Accepted {
+                    id,
+                    mail,
+                    priority,
+                    not_before,
+                    tenant,
+                    duplicate_of,
+                    ..
+                } => {
+                    self.order.push(id.clone());
+                    self.unfinished.insert(
+                        id.clone(),
+                        QueuedMail {
+                            id,
+                            mail,
+                            priority,
+                            not_before,
+                            tenant,
+                            duplicate_of,
+                            attempts: 0,
+                        },
+                    );
+                }
+                Record::Deferred {
+                    id,
+                    attempts,
+                    until,
+                    ..
+                } => {
+                    if let Some(entry) = self.unfinished.get_mut(&id) {
+                        entry.attempts = attempts;
+                        entry.not_before = Some(until);
+                    }
+                }
+                Record::Delivered { id, .. } | Record::Dropped { id, .. } => {
+                    self.unfinished.remove(&id);
+                }
+                Record::Attempt { .. } | Record::Failed { .. } | Record::Forwarded { .. } => {}
+            }
+        }
+        if self.order.len() > 2 * self.unfinished.len() + 1024 {
+            let unfinished = &self.unfinished;
+            self.order.retain(|id| unfinished.contains_key(id));
+        }
+    }
+
+    /// The unfinished messages, in acceptance order
+    fn queued(&self) -> Vec<QueuedMail> {
+        let mut seen = HashSet::new();
+        self.order
+            .iter()
+            .filter(|id| seen.insert(*id))
+            .filter_map(|id| self.unfinished.get(id).cloned())
+            .collect()
+    }
+}
+
+/// Rewrites the log at `path` with only the records of the unfinished messages,
+/// moving the others to the history, and returns the state of the messages
+async fn compact(path: &Path) -> Result<Replay> {
+    let log = match tokio::fs::read_to_string(path).await {
+        Ok(log) => log,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(err) => return Err(err).with_context(|| format!("cannot read WAL {}", path.display())),
+    };
+    let mut replay = Replay::default();
+    replay.apply(&log);
+    let (kept, finished): (Vec<_>, Vec<_>) = log
+        .lines()
+        .filter_map(|line| serde_json::from_str::<Record>(line).ok())
+        .partition(|record| replay.unfinished.contains_key(record.id()));
+    append_history(&history_path(path), &finished).await?;
+
+    let compacted = path.with_extension("compact");
+    let mut log = String::new();
+    for record in &kept {
+        log += &serde_json::to_string(record)?;
+        log.push('\n');
+    }
+    let mut file = tokio::fs::File::create(&compacted).await?;
+    file.write_all(log.as_bytes()).await?;
+    file.sync_all().await?;
+    tokio::fs::rename(&compacted, path)
+        .await
+        .with_context(|| format!("cannot compact WAL {}", path.display()))?;
+    Ok(replay)
+}
+
+/// Takes the advisory lock shared with the peer, returning the handle to
+/// release it with and the generation of the log
+async fn lock_peer(lock: &std::fs::File) -> Result<(std::fs::File, u64)> {
+    let mut lock = lock.try_clone()?;
+    tokio::task::spawn_blocking(move || {
+        lock.lock()?;
+        let mut generation = String::new();
+        let read = lock
+            .seek(SeekFrom::Start(0))
+            .and_then(|_| lock.read_to_string(&mut generation));
+        if let Err(err) = read {
+            lock.unlock()?;
+            return Err(err.into());
+        }
+        Ok((lock, generation.trim().parse().unwrap_or(0)))
+    })
+    .await?
+}
+
+/// Records a new generation of the log in the lock file, held by the caller
+fn set_generation(mut lock: &std::fs::File, generation: u64) -> Result<()> {
+    lock.set_len(0)?;
+    lock.seek(SeekFrom::Start(0))?;
+    lock.write_all(generation.to_string().as_bytes())?;
+    lock.sync_data()?;
+    Ok(())
+}
+
+/// Where the records of finished messages are kept once compacted away from
+/// the log at `path`, for the admin API to still tell about them
+fn history_path(path: &Path) -> PathBuf {
+    path.with_extension("history")
+}
+
+/// Appends records to the history, waiting for them to reach the disk
+async fn append_history(path: &Path, records: &[Record]) -> Result<()> {
+    if records.is_empty() {
+        return Ok(());
+    }
+    let mut lines = String::new();
+    for record in records {
+        lines += &serde_json::to_string(record)?;
+        lines.push('\n');
+    }
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+        .with_context(|| format!("cannot open WAL history {}", path.display()))?;
+    file.write_all(lines.as_bytes()).await?;
+    file.sync_data().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mail(data: &str) -> Mail {
+        Mail {
+            from: "a@example.com".into(),
+            to: vec!["b@example.com".into()],
+            data: data.into(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replay_unfinished() {
+        let path = std::env::temp_dir().join(format!("wal-test-{}", QueueId::generate()));
+        let (first, second, third) = (
+            QueueId::generate(),
+            QueueId::generate(),
+            QueueId::generate(),
+        );
+        {
+            let (wal, pending) = Wal::open(&path).await.unwrap();
+            assert!(pending.is_empty());
+            for (id, data) in [(&first, "1"), (&second, "2"), (&third, "3")] {
+                wal.append(&Record::Accepted {
+                    id: id.clone(),
+                    at: Utc::now(),
+                    mail: mail(data),
+                    priority: Priority::Normal,
+                    not_before: None,
+                    tenant: None,
+                    duplicate_of: None,
+                })
+                .await
+                .unwrap();
+            }
+            wal.append(&Record::Attempt {
+                id: first.clone(),
+                at: Utc::now(),
+                endpoint: None,
+            })
+            .await
+            .unwrap();
+            wal.append(&Record::Delivered {
+                id: first.clone(),
+                at: Utc::now(),
+            })
+            .await
+            .unwrap();
+            wal.append(&Record::Failed {
+                id: second.clone(),
+                at: Utc::now(),
+                error: "503".into(),
+                endpoint: None,
+            })
+            .await
+            .unwrap();
+        }
+        // Simulate a crash in the middle of a write
+        let mut log = std::fs::read_to_string(&path).unwrap();
+        log += r#"{"event":"delivered","id":"#;
+        std::fs::write(&path, log).unwrap();
+
+        let (_, pending) = Wal::open(&path).await.unwrap();
+        let pending = pending
+            .into_iter()
+            .map(|entry| (entry.id, entry.mail))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            pending,
+            vec![(second.clone(), mail("2")), (third, mail("3"))]
+        );
+        // Compaction keeps the pending messages around, and the history the others
+        let (wal, pending) = Wal::open(&path).await.unwrap();
+        assert_eq!(pending.len(), 2);
+        let records = wal.records().await.unwrap();
+        assert_eq!(records.len(), 6);
+        assert!(matches!(&records[5], Record::Failed { id, .. } if *id == second));
+        assert!(matches!(&records[0], Record::Accepted { id, .. } if *id == first));
+        assert!(matches!(&records[2], Record::Delivered { id, .. } if *id == first));
+        std::fs::remove_file(history_path(&path)).unwrap();
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_shared_between_instances() {
+        let path = std::env::temp_dir().join(format!("wal-test-{}", QueueId::generate()));
+        let (a, b) = (
+            Wal::open_shared(&path).await.unwrap(),
+            Wal::open_shared(&path).await.unwrap(),
+        );
+        let (first, second) = (QueueId::generate(), QueueId::generate());
+        for (wal, id) in [(&a, &first), (&b, &second)] {
+            wal.append(&Record::Accepted {
+                id: id.clone(),
+                at: Utc::now(),
+                mail: mail(id.as_str()),
+                priority: Priority::Normal,
+                not_before: None,
+                tenant: None,
+                duplicate_of: None,
+            })
+            .await
+            .unwrap();
+        }
+        a.append(&Record::Delivered {
+            id: second.clone(),
+            at: Utc::now(),
+        })
+        .await
+        .unwrap();
+        let pending = b.pending().await.unwrap();
+        assert_eq!(
+            pending
+                .into_iter()
+                .map(|entry| entry.id)
+                .collect::<Vec<_>>(),
+            vec![first]
+        );
+        std::fs::remove_file(path.with_extension("lock")).unwrap();
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_shared_compaction() {
+        let path = std::env::temp_dir().join(format!("wal-test-{}", QueueId::generate()));
+        let (leader, peer) = (
+            Wal::open_shared(&path).await.unwrap(),
+            Wal::open_shared(&path).await.unwrap(),
+        );
+        let accepted = |id: &QueueId| Record::Accepted {
+            id: id.clone(),
+            at: Utc::now(),
+            mail: mail(id.as_str()),
+            priority: Priority::Normal,
+            not_before: None,
+            tenant: None,
+            duplicate_of: None,
+        };
+        let (first, second, third) = (
+            QueueId::generate(),
+            QueueId::generate(),
+            QueueId::generate(),
+        );
+        peer.append(&accepted(&first)).await.unwrap();
+        leader.append(&accepted(&second)).await.unwrap();
+        assert_eq!(leader.pending().await.unwrap().len(), 2);
+        peer.append(&Record::Delivered {
+            id: first.clone(),
+            at: Utc::now(),
+        })
+        .await
+        .unwrap();
+        // Only what was appended since is read
+        let pending = leader.pending().await.unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].id, second);
+
+        // A retry scheduled by the peer holds once the leader takes the mail back
+        let until = Utc::now() + chrono::Duration::minutes(5);
+        peer.append(&Record::Deferred {
+            id: second.clone(),
+            at: Utc::now(),
+            attempts: 3,
+            until,
+        })
+        .await
+        .unwrap();
+        leader.compact_from(0).await.unwrap();
+        let log = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(log.lines().count(), 2);
+
+        // The peer appends to the compacted log, not the file it replaced
+        peer.append(&accepted(&third)).await.unwrap();
+        for wal in [&leader, &peer] {
+            let pending = wal.pending().await.unwrap();
+            assert_eq!(
+                pending.iter().map(|entry| &entry.id).collect::<Vec<_>>(),
+                [&second, &third]
+            );
+            assert_eq!(pending[0].attempts, 3);
+            assert_eq!(pending[0].not_before, Some(until));
+        }
+        assert_eq!(peer.records().await.unwrap().len(), 5);
+        std::fs::remove_file(history_path(&path)).unwrap();
+        std::fs::remove_file(path.with_extension("lock")).unwrap();
+        std::fs::remove_file(path).unwrap();
+    }
+}
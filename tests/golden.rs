@@ -0,0 +1,51 @@
+//! Snapshot tests of the payloads built from the messages in `tests/golden`.
+//! Each `<name>.eml` has its expected payload committed as `<name>.json`,
+//! so that any change to the forwarded JSON shows up in review.
+//! Run with `UPDATE_GOLDEN=1` to rewrite the expected files after a deliberate change.
+
+use std::path::Path;
+
+use smtp_forward::payload;
+
+#[test]
+fn test_golden_payloads() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden");
+    let update = std::env::var_os("UPDATE_GOLDEN").is_some();
+    let mut messages = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "eml"))
+        .collect::<Vec<_>>();
+    messages.sort();
+    assert!(!messages.is_empty(), "no messages in {}", dir.display());
+
+    let mut mismatches = Vec::new();
+    for eml in messages {
+        let raw = std::fs::read_to_string(&eml).unwrap();
+        let message = payload::build(raw.as_bytes(), &payload::Options::default())
+            .unwrap_or_else(|err| panic!("{}: {err:#}", eml.display()));
+        let actual = serde_json::to_value(&message).unwrap();
+        let golden = eml.with_extension("json");
+        if update {
+            let mut json = serde_json::to_string_pretty(&actual).unwrap();
+            json.push('\n');
+            std::fs::write(&golden, json).unwrap();
+            continue;
+        }
+        let expected = std::fs::read_to_string(&golden)
+            .map(|json| serde_json::from_str::<serde_json::Value>(&json).unwrap())
+            .unwrap_or_else(|err| panic!("{}: {err}", golden.display()));
+        if actual != expected {
+            mismatches.push(format!(
+                "{}:\n{}",
+                golden.display(),
+                serde_json::to_string_pretty(&actual).unwrap()
+            ));
+        }
+    }
+    assert!(
+        mismatches.is_empty(),
+        "payloads differ from the golden files, rerun with UPDATE_GOLDEN=1 if intended:\n{}",
+        mismatches.join("\n")
+    );
+}